@@ -0,0 +1,220 @@
+//! Negotiation of acknowledgement frequency, per draft-ietf-quic-ack-frequency
+
+use std::cmp;
+
+use crate::endpoint::Config;
+use crate::frame;
+use crate::transport_parameters::TransportParameters;
+use crate::TransportError;
+
+/// Acknowledgement behavior requested by and of the peer
+pub struct AckFrequency {
+    /// Number of ack-eliciting packets which may be received before acknowledging immediately
+    pub threshold: u32,
+    /// Maximum time acknowledgement of an ack-eliciting packet may be delayed (μs)
+    pub max_ack_delay: u64,
+    /// Whether packets received out of order should be acknowledged immediately
+    pub ack_reordered: bool,
+    /// Sequence number of the most recent ACK_FREQUENCY frame received
+    rx_sequence: Option<u64>,
+
+    /// Sequence number of the next ACK_FREQUENCY frame to send
+    tx_sequence: u64,
+    /// Ack-eliciting threshold and max ack delay most recently requested of the peer
+    requested: Option<(u64, u64)>,
+    /// Upper bound on the max ack delay the peer is using, if we've requested a change (μs)
+    peer_max_ack_delay: Option<u64>,
+}
+
+impl AckFrequency {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            threshold: config.ack_eliciting_threshold,
+            max_ack_delay: config.delayed_ack_timeout,
+            ack_reordered: true,
+            rx_sequence: None,
+
+            tx_sequence: 0,
+            requested: None,
+            peer_max_ack_delay: None,
+        }
+    }
+
+    /// Apply an ACK_FREQUENCY frame sent by the peer
+    ///
+    /// `min_ack_delay` is the value of our own transport parameter.
+    pub fn on_frame(
+        &mut self,
+        frame: frame::AckFrequency,
+        min_ack_delay: u64,
+    ) -> Result<(), TransportError> {
+        if frame.request_max_ack_delay < min_ack_delay {
+            return Err(TransportError::PROTOCOL_VIOLATION);
+        }
+        if self.rx_sequence.map_or(false, |x| frame.sequence <= x) {
+            // Superseded by a frame we've already seen
+            return Ok(());
+        }
+        self.rx_sequence = Some(frame.sequence);
+        // The frame counts the packets which may go unacknowledged, whereas we count up to and
+        // including the one which prompts an acknowledgement.
+        self.threshold = cmp::min(
+            frame.ack_eliciting_threshold,
+            u64::from(u32::max_value()) - 1,
+        ) as u32
+            + 1;
+        self.max_ack_delay = frame.request_max_ack_delay;
+        // Thresholds larger than 1 aren't distinguished; any reordering prompts an acknowledgement
+        self.ack_reordered = frame.reordering_threshold != 0;
+        Ok(())
+    }
+
+    /// Construct an ACK_FREQUENCY frame, if the peer should be sent one
+    ///
+    /// Requests that the peer acknowledge a few times per congestion window and within a fraction
+    /// of the RTT, but only sends a new request when that differs significantly from the last,
+    /// unless `force` is set.
+    pub fn next_frame(
+        &mut self,
+        force: bool,
+        params: &TransportParameters,
+        window: u64,
+        mtu: u16,
+        rtt: u64,
+    ) -> Option<frame::AckFrequency> {
+        let min_ack_delay = params.min_ack_delay?;
+        if rtt == 0 {
+            return None;
+        }
+        // Leave enough acknowledgements per window to keep transmissions ack-clocked
+        let packets = cmp::max(window / u64::from(mtu), ACKS_PER_WINDOW);
+        let threshold = cmp::min(packets / ACKS_PER_WINDOW, MAX_THRESHOLD);
+        // Delaying acknowledgements by a fraction of the RTT hardly slows our reaction to loss
+        let delay = cmp::max(rtt / 4, min_ack_delay);
+        let unchanged = self.requested.map_or(false, |(t, d)| {
+            t == threshold && delay * 4 >= d * 3 && delay * 4 <= d * 5
+        });
+        if unchanged && !force {
+            return None;
+        }
+
+        let frame = frame::AckFrequency {
+            sequence: self.tx_sequence,
+            ack_eliciting_threshold: threshold,
+            request_max_ack_delay: delay,
+            reordering_threshold: 1,
+        };
+        self.tx_sequence += 1;
+        self.requested = Some((threshold, delay));
+        // Until this frame is acknowledged, the peer may be using either the old or new delay
+        let old = self
+            .peer_max_ack_delay
+            .unwrap_or_else(|| params.max_ack_delay * 1000);
+        self.peer_max_ack_delay = Some(cmp::max(old, delay));
+        Some(frame)
+    }
+
+    /// The packet containing the ACK_FREQUENCY frame with sequence number `sequence` was acked
+    pub fn on_acked(&mut self, sequence: u64) {
+        if sequence + 1 == self.tx_sequence {
+            self.peer_max_ack_delay = self.requested.map(|(_, delay)| delay);
+        }
+    }
+
+    /// Maximum time the peer may delay acknowledgements, if changed at our request (μs)
+    pub fn peer_max_ack_delay(&self) -> Option<u64> {
+        self.peer_max_ack_delay
+    }
+}
+
+/// Number of acknowledgements to request per congestion window
+const ACKS_PER_WINDOW: u64 = 4;
+/// Largest ack-eliciting threshold to request
+const MAX_THRESHOLD: u64 = 10;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn policy() {
+        let params = TransportParameters {
+            min_ack_delay: Some(1000),
+            ..TransportParameters::default()
+        };
+        let mut state = AckFrequency::new(&Config::default());
+        // No RTT estimate yet
+        assert_eq!(state.next_frame(false, &params, 12000, 1200, 0), None);
+        let frame = state
+            .next_frame(false, &params, 40 * 1200, 1200, 100_000)
+            .unwrap();
+        assert_eq!(frame.ack_eliciting_threshold, 10);
+        assert_eq!(frame.request_max_ack_delay, 25_000);
+        assert_eq!(state.peer_max_ack_delay(), Some(25_000));
+        // Insignificant change
+        assert_eq!(
+            state.next_frame(false, &params, 40 * 1200, 1200, 110_000),
+            None
+        );
+        // Significant change
+        let frame = state
+            .next_frame(false, &params, 8 * 1200, 1200, 4000)
+            .unwrap();
+        assert_eq!(frame.sequence, 1);
+        assert_eq!(frame.ack_eliciting_threshold, 2);
+        assert_eq!(frame.request_max_ack_delay, 1000);
+        // Peer might still be using the larger delay
+        assert_eq!(state.peer_max_ack_delay(), Some(25_000));
+        state.on_acked(0);
+        assert_eq!(state.peer_max_ack_delay(), Some(25_000));
+        state.on_acked(1);
+        assert_eq!(state.peer_max_ack_delay(), Some(1000));
+    }
+
+    #[test]
+    fn unsupported() {
+        let mut state = AckFrequency::new(&Config::default());
+        let params = TransportParameters::default();
+        assert_eq!(
+            state.next_frame(true, &params, 40 * 1200, 1200, 100_000),
+            None
+        );
+    }
+
+    #[test]
+    fn apply() {
+        let mut state = AckFrequency::new(&Config::default());
+        let frame = frame::AckFrequency {
+            sequence: 1,
+            ack_eliciting_threshold: 9,
+            request_max_ack_delay: 50_000,
+            reordering_threshold: 0,
+        };
+        state.on_frame(frame, 1000).unwrap();
+        assert_eq!(state.threshold, 10);
+        assert_eq!(state.max_ack_delay, 50_000);
+        assert!(!state.ack_reordered);
+        // Stale frames are ignored
+        state
+            .on_frame(
+                frame::AckFrequency {
+                    sequence: 0,
+                    ..frame
+                },
+                1000,
+            )
+            .unwrap();
+        assert_eq!(state.threshold, 10);
+        // Delays shorter than we advertised are forbidden
+        assert!(state
+            .on_frame(
+                frame::AckFrequency {
+                    sequence: 2,
+                    request_max_ack_delay: 500,
+                    ..frame
+                },
+                1000
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,67 @@
+//! Logic for controlling the rate at which data is sent
+
+use std::sync::Arc;
+
+use crate::connection::RttEstimator;
+
+mod bbr;
+mod cubic;
+mod new_reno;
+
+pub use bbr::{Bbr, BbrConfig};
+pub use cubic::{Cubic, CubicConfig};
+pub use new_reno::{NewReno, NewRenoConfig};
+
+/// Common interface for different congestion controllers
+///
+/// All times are in microseconds, using the same clock as the rest of the connection.
+pub trait Controller: Send {
+    /// An ack-eliciting packet of `bytes` bytes was sent
+    fn on_sent(&mut self, _now: u64, _bytes: u64) {}
+
+    /// Bytes from a packet sent at `sent` were acknowledged
+    ///
+    /// Only invoked for packets which were counted against the congestion window.
+    fn on_ack(&mut self, now: u64, sent: u64, bytes: u64, rtt: &RttEstimator);
+
+    /// All packets newly acknowledged by an ACK frame have been passed to `on_ack`
+    ///
+    /// `in_flight` is the number of bytes which remain unacknowledged.
+    fn on_end_acks(&mut self, _now: u64, _in_flight: u64) {}
+
+    /// Packets were deemed lost, the most recently sent of which was sent at `sent`
+    ///
+    /// `bytes` is the total size of the lost packets that counted against the congestion window.
+    fn on_loss(&mut self, now: u64, sent: u64, bytes: u64);
+
+    /// The peer reported packets up to one sent at `sent` as having experienced congestion
+    ///
+    /// By default, this is treated as a loss of no data.
+    fn on_ecn_ce(&mut self, now: u64, sent: u64) {
+        self.on_loss(now, sent, 0);
+    }
+
+    /// Every packet sent during a period longer than the persistent congestion threshold was lost
+    ///
+    /// Invoked after `on_loss` for the same loss event.
+    fn on_persistent_congestion(&mut self, now: u64);
+
+    /// Every packet that led to the most recent call to `on_loss` has since been acknowledged
+    ///
+    /// The loss was caused by reordering rather than congestion, so any reduction made in response
+    /// to it should be reverted. Never invoked after `on_persistent_congestion` or `on_ecn_ce` for
+    /// the same congestion event. Ignored by default.
+    fn on_spurious_congestion_event(&mut self) {}
+
+    /// Number of ack-eliciting bytes that may be in flight
+    fn window(&self) -> u64;
+}
+
+/// Constructs controllers on demand
+pub trait ControllerFactory {
+    /// Construct a fresh `Controller`
+    ///
+    /// Called once for each new connection, and again whenever a connection migrates to a new
+    /// network path and must forget what it learned about the old one.
+    fn build(self: Arc<Self>) -> Box<dyn Controller>;
+}
@@ -0,0 +1,433 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use rand::Rng;
+
+use super::{Controller, ControllerFactory};
+use crate::connection::RttEstimator;
+
+/// Model-based congestion control in the style of BBR v1
+///
+/// Rather than treating loss as a signal of congestion, this estimates the bottleneck bandwidth
+/// from the rate at which data is acknowledged and the round-trip propagation time from the
+/// minimum observed RTT, and keeps a small multiple of the resulting bandwidth-delay product in
+/// flight. This makes it much more robust than loss-based controllers on links which drop packets
+/// for reasons other than congestion.
+///
+/// Only the congestion window is controlled; the gains which BBR would normally apply to its
+/// pacing rate are applied to the window instead.
+pub struct Bbr {
+    config: Arc<BbrConfig>,
+    state: State,
+    /// Maximum number of bytes in flight that may be sent.
+    window: u64,
+    /// Recent delivery rate samples (bytes/s)
+    max_bw: MaxFilter,
+    /// Minimum RTT seen within the last `probe_rtt_interval` (μs)
+    min_rtt: u64,
+    /// Time at which `min_rtt` was last lowered or refreshed, if it has been sampled
+    min_rtt_stamp: Option<u64>,
+    /// Total number of bytes acknowledged
+    delivered: u64,
+    /// Time of the most recent acknowledgement
+    delivered_time: u64,
+    /// Delivery progress as of the transmission of each unacknowledged packet, oldest first
+    sent: VecDeque<SentState>,
+    /// Number of round trips elapsed
+    round: u64,
+    /// Value of `delivered` which, once acknowledged as having been sent, ends the current round
+    next_round_delivered: u64,
+    /// Whether the current batch of acks started a new round
+    round_start: bool,
+    /// Bandwidth at the last time it grew substantially during startup (bytes/s)
+    full_bw: u64,
+    /// Number of rounds for which `full_bw` has not grown substantially
+    full_bw_count: u32,
+    /// Whether startup has found the bottleneck bandwidth
+    filled_pipe: bool,
+    /// Current index into `PROBE_BW_GAINS`
+    cycle_index: usize,
+    /// Time at which the current gain cycle phase began
+    cycle_stamp: u64,
+    /// Time at which the current ProbeRtt period ends, once the window has drained
+    probe_rtt_done: Option<u64>,
+    /// Window prior to entering ProbeRtt
+    prior_window: u64,
+}
+
+impl Bbr {
+    /// Construct a state using the given `config`
+    pub fn new(config: Arc<BbrConfig>) -> Self {
+        Self {
+            state: State::Startup,
+            window: config.initial_window,
+            max_bw: MaxFilter::new(config.bandwidth_window),
+            min_rtt: u64::max_value(),
+            min_rtt_stamp: None,
+            delivered: 0,
+            delivered_time: 0,
+            sent: VecDeque::new(),
+            round: 0,
+            next_round_delivered: 0,
+            round_start: false,
+            full_bw: 0,
+            full_bw_count: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            cycle_stamp: 0,
+            probe_rtt_done: None,
+            prior_window: 0,
+            config,
+        }
+    }
+
+    /// Estimated bandwidth-delay product of the path, if any samples have been taken
+    fn bdp(&self) -> Option<u64> {
+        let bw = self.max_bw.get();
+        if bw == 0 || self.min_rtt == u64::max_value() {
+            return None;
+        }
+        Some(bw * self.min_rtt / 1_000_000)
+    }
+
+    fn target_window(&self) -> u64 {
+        let bdp = match self.bdp() {
+            Some(x) => x,
+            None => return self.config.initial_window,
+        };
+        let gain = match self.state {
+            State::Startup => HIGH_GAIN,
+            State::Drain | State::ProbeRtt => 1.0,
+            State::ProbeBw => CWND_GAIN * PROBE_BW_GAINS[self.cycle_index],
+        };
+        cmp::max((bdp as f64 * gain) as u64, self.config.minimum_window)
+    }
+
+    fn probe_rtt_window(&self) -> u64 {
+        4 * self.config.max_datagram_size
+    }
+
+    fn check_full_pipe(&mut self) {
+        if self.filled_pipe {
+            return;
+        }
+        let bw = self.max_bw.get();
+        if bw >= self.full_bw + self.full_bw / 4 {
+            // Still growing
+            self.full_bw = bw;
+            self.full_bw_count = 0;
+            return;
+        }
+        self.full_bw_count += 1;
+        self.filled_pipe = self.full_bw_count >= 3;
+    }
+
+    fn enter_probe_bw(&mut self, now: u64) {
+        self.state = State::ProbeBw;
+        // Start anywhere but the draining phase, to desynchronize competing flows
+        self.cycle_index = (rand::thread_rng().gen_range(0, PROBE_BW_GAINS.len() - 1) + 2)
+            % PROBE_BW_GAINS.len();
+        self.cycle_stamp = now;
+    }
+
+    fn enter_probe_rtt(&mut self) {
+        self.state = State::ProbeRtt;
+        self.prior_window = self.window;
+        self.probe_rtt_done = None;
+    }
+}
+
+impl Controller for Bbr {
+    fn on_sent(&mut self, now: u64, _bytes: u64) {
+        if self.sent.is_empty() {
+            // Don't count idle time against the delivery rate
+            self.delivered_time = now;
+        }
+        self.sent.push_back(SentState {
+            time: now,
+            delivered: self.delivered,
+            delivered_time: self.delivered_time,
+        });
+    }
+
+    fn on_ack(&mut self, now: u64, sent: u64, bytes: u64, rtt: &RttEstimator) {
+        self.delivered += bytes;
+        self.delivered_time = now;
+
+        if rtt.smoothed() != 0 {
+            let expired = self
+                .min_rtt_stamp
+                .map_or(false, |stamp| now > stamp + self.config.probe_rtt_interval);
+            if rtt.latest() <= self.min_rtt || expired {
+                self.min_rtt = rtt.latest();
+                self.min_rtt_stamp = Some(now);
+            }
+            if expired && self.state != State::ProbeRtt {
+                self.enter_probe_rtt();
+            }
+        }
+
+        // Packets ahead of this one were lost, or acked out of order
+        while self.sent.front().map_or(false, |x| x.time < sent) {
+            self.sent.pop_front();
+        }
+        if self.sent.front().map_or(false, |x| x.time == sent) {
+            let state = self.sent.pop_front().unwrap();
+            if state.delivered >= self.next_round_delivered {
+                self.next_round_delivered = self.delivered;
+                self.round += 1;
+                self.round_start = true;
+            }
+            if now > state.delivered_time {
+                let bw = (self.delivered - state.delivered) * 1_000_000
+                    / (now - state.delivered_time);
+                self.max_bw.update(self.round, bw);
+            }
+        }
+
+        let target = self.target_window();
+        if self.filled_pipe {
+            self.window = cmp::min(self.window + bytes, target);
+        } else if self.window < target || self.delivered < self.config.initial_window {
+            self.window += bytes;
+        }
+        self.window = cmp::max(self.window, self.config.minimum_window);
+        if self.state == State::ProbeRtt {
+            self.window = cmp::min(self.window, self.probe_rtt_window());
+        }
+    }
+
+    fn on_end_acks(&mut self, now: u64, in_flight: u64) {
+        if self.round_start {
+            self.round_start = false;
+            self.check_full_pipe();
+        }
+
+        if self.state == State::Startup && self.filled_pipe {
+            self.state = State::Drain;
+        }
+        if self.state == State::Drain && self.bdp().map_or(false, |bdp| in_flight <= bdp) {
+            self.enter_probe_bw(now);
+        }
+        if self.state == State::ProbeBw && now - self.cycle_stamp > self.min_rtt {
+            self.cycle_index = (self.cycle_index + 1) % PROBE_BW_GAINS.len();
+            self.cycle_stamp = now;
+        }
+        if self.state == State::ProbeRtt {
+            self.window = cmp::min(self.window, self.probe_rtt_window());
+            match self.probe_rtt_done {
+                None if in_flight <= self.probe_rtt_window() => {
+                    self.probe_rtt_done = Some(
+                        now + cmp::max(self.config.probe_rtt_duration, self.min_rtt),
+                    );
+                }
+                Some(done) if now >= done => {
+                    self.min_rtt_stamp = Some(now);
+                    self.window = cmp::max(self.window, self.prior_window);
+                    if self.filled_pipe {
+                        self.enter_probe_bw(now);
+                    } else {
+                        self.state = State::Startup;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn on_loss(&mut self, _now: u64, _sent: u64, _bytes: u64) {
+        // Loss is not a congestion signal to this model; the bandwidth estimate already reflects
+        // the throughput actually achieved.
+    }
+
+    fn on_persistent_congestion(&mut self, _now: u64) {
+        // The path has changed out from under the model; start over.
+        self.max_bw = MaxFilter::new(self.config.bandwidth_window);
+        self.full_bw = 0;
+        self.full_bw_count = 0;
+        self.filled_pipe = false;
+        self.state = State::Startup;
+        self.window = self.config.minimum_window;
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+}
+
+/// Configuration for the `Bbr` congestion controller
+pub struct BbrConfig {
+    /// The max packet size that was used for calculating default and minimum congestion windows.
+    pub max_datagram_size: u64,
+    /// Default limit on the amount of outstanding data in bytes.
+    ///
+    /// Recommended value: `min(10 * max_datagram_size, max(2 * max_datagram_size, 14600))`
+    pub initial_window: u64,
+    /// Default minimum congestion window.
+    ///
+    /// Recommended value: `2 * max_datagram_size`.
+    pub minimum_window: u64,
+    /// Number of round trips over which the maximum delivery rate is taken as the bandwidth
+    /// estimate
+    pub bandwidth_window: u64,
+    /// How long a minimum RTT sample remains valid before the window is briefly reduced to measure
+    /// a fresh one (μs)
+    pub probe_rtt_interval: u64,
+    /// Minimum time to hold the window at its minimum while measuring the RTT (μs)
+    pub probe_rtt_duration: u64,
+}
+
+impl Default for BbrConfig {
+    fn default() -> Self {
+        const MAX_DATAGRAM_SIZE: u64 = 1200;
+        Self {
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            initial_window: cmp::min(
+                10 * MAX_DATAGRAM_SIZE,
+                cmp::max(2 * MAX_DATAGRAM_SIZE, 14600),
+            ),
+            minimum_window: 2 * MAX_DATAGRAM_SIZE,
+            bandwidth_window: 10,
+            probe_rtt_interval: 10 * 1000 * 1000,
+            probe_rtt_duration: 200 * 1000,
+        }
+    }
+}
+
+impl ControllerFactory for BbrConfig {
+    fn build(self: Arc<Self>) -> Box<dyn Controller> {
+        Box::new(Bbr::new(self))
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    /// Exponential growth to find the bottleneck bandwidth
+    Startup,
+    /// Draining the queue built up by startup
+    Drain,
+    /// Steady state, periodically probing for more bandwidth
+    ProbeBw,
+    /// Briefly minimizing data in flight to measure the propagation delay
+    ProbeRtt,
+}
+
+/// Window gain during startup, 2/ln(2)
+const HIGH_GAIN: f64 = 2.885;
+/// Window gain in steady state, leaving room for delayed and aggregated acks
+const CWND_GAIN: f64 = 2.0;
+/// Gain cycle used in ProbeBw, each phase lasting about one minimum RTT
+const PROBE_BW_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+
+/// Delivery progress as of the time a packet was sent
+struct SentState {
+    time: u64,
+    delivered: u64,
+    delivered_time: u64,
+}
+
+/// Maximum of the largest sample from each of the most recent rounds
+struct MaxFilter {
+    rounds: u64,
+    samples: VecDeque<(u64, u64)>,
+}
+
+impl MaxFilter {
+    fn new(rounds: u64) -> Self {
+        Self {
+            rounds,
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn update(&mut self, round: u64, value: u64) {
+        match self.samples.back_mut() {
+            Some(&mut (r, ref mut max)) if r == round => {
+                *max = cmp::max(*max, value);
+            }
+            _ => self.samples.push_back((round, value)),
+        }
+        while self
+            .samples
+            .front()
+            .map_or(false, |&(r, _)| r + self.rounds <= round)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    fn get(&self) -> u64 {
+        self.samples.iter().map(|&(_, x)| x).max().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Simulate a path of fixed bandwidth and RTT with the window always full
+    fn run(cc: &mut Bbr, now: &mut u64, rtts: u64) {
+        const RTT: u64 = 100_000;
+        const BW: u64 = 1_000_000; // bytes/s
+        let mss = cc.config.max_datagram_size;
+        let mut rtt = RttEstimator::new();
+        rtt.update(0, RTT);
+        for _ in 0..rtts {
+            // Send a window's worth, of which the bottleneck delivers at most one BDP per RTT
+            let window = cc.window();
+            let sent = *now;
+            for _ in 0..window / mss {
+                cc.on_sent(sent, mss);
+            }
+            let delivered = cmp::min(window, BW * RTT / 1_000_000);
+            let packets = delivered / mss;
+            for i in 0..packets {
+                let t = sent + RTT + i * RTT / packets;
+                cc.on_ack(t, sent, mss, &rtt);
+            }
+            *now = sent + 2 * RTT;
+            cc.on_end_acks(*now, 0);
+        }
+    }
+
+    #[test]
+    fn reaches_probe_bw() {
+        let mut cc = Bbr::new(Arc::new(BbrConfig::default()));
+        let mut now = 0;
+        run(&mut cc, &mut now, 20);
+        assert!(cc.filled_pipe);
+        assert_eq!(cc.state, State::ProbeBw);
+        // Window converges on a small multiple of the BDP, regardless of losses
+        let bdp = cc.bdp().unwrap();
+        assert!(bdp >= 50_000 && bdp <= 150_000, "bdp {}", bdp);
+        cc.on_loss(now, now, 10 * 1200);
+        assert!(cc.window() >= bdp);
+    }
+
+    #[test]
+    fn probe_rtt() {
+        let mut cc = Bbr::new(Arc::new(BbrConfig::default()));
+        let mut now = 0;
+        run(&mut cc, &mut now, 20);
+        let window = cc.window();
+        // Let the min RTT sample expire
+        now += cc.config.probe_rtt_interval;
+        run(&mut cc, &mut now, 1);
+        assert_eq!(cc.state, State::ProbeRtt);
+        assert_eq!(cc.window(), cc.probe_rtt_window());
+        run(&mut cc, &mut now, 3);
+        assert_eq!(cc.state, State::ProbeBw);
+        assert!(cc.window() >= window / 2);
+    }
+
+    #[test]
+    fn late_first_sample() {
+        let mut cc = Bbr::new(Arc::new(BbrConfig::default()));
+        // Connections may begin long after the clock's epoch
+        let mut now = 10 * cc.config.probe_rtt_interval;
+        run(&mut cc, &mut now, 1);
+        assert_eq!(cc.state, State::Startup);
+        assert!(cc.window() > cc.probe_rtt_window());
+    }
+}
@@ -0,0 +1,301 @@
+use std::cmp;
+use std::sync::Arc;
+
+use super::{Controller, ControllerFactory};
+use crate::connection::RttEstimator;
+
+/// CUBIC congestion control, as described in RFC 8312
+///
+/// Grows the window as a cubic function of the time since the last congestion event rather than
+/// of the number of round trips, allowing much faster recovery on paths with a large
+/// bandwidth-delay product while remaining no less aggressive than NewReno on short ones.
+pub struct Cubic {
+    config: Arc<CubicConfig>,
+    /// Maximum number of bytes in flight that may be sent.
+    window: u64,
+    /// Slow start threshold in bytes. When the congestion window is below ssthresh, the mode is
+    /// slow start and the window grows by the number of bytes acknowledged.
+    ssthresh: u64,
+    /// The time when QUIC first detects a loss, causing it to enter recovery. When a packet sent
+    /// after this time is acknowledged, QUIC exits recovery.
+    recovery_start_time: u64,
+    /// Window size just before the most recent reduction, in bytes
+    w_max: f64,
+    /// Start of the current congestion avoidance epoch, if one has begun
+    epoch_start: Option<u64>,
+    /// Time for the cubic function to grow from the window at the start of the epoch to `w_max` (s)
+    k: f64,
+    /// Estimate of the window a standard AIMD controller would have reached, in bytes
+    w_est: f64,
+    /// State prior to the most recent reduction, in case it proves spurious
+    prior: Option<Prior>,
+}
+
+impl Cubic {
+    /// Construct a state using the given `config`
+    pub fn new(config: Arc<CubicConfig>) -> Self {
+        Self {
+            window: config.initial_window,
+            ssthresh: u64::max_value(),
+            recovery_start_time: 0,
+            w_max: 0.0,
+            epoch_start: None,
+            k: 0.0,
+            w_est: 0.0,
+            prior: None,
+            config,
+        }
+    }
+
+    fn in_recovery(&self, sent: u64) -> bool {
+        sent <= self.recovery_start_time
+    }
+
+    fn beta(&self) -> f64 {
+        f64::from(self.config.beta) / 65536.0
+    }
+
+    /// Target window `t` seconds into the current epoch, in bytes
+    fn w_cubic(&self, t: f64) -> f64 {
+        let c = f64::from(self.config.c) / 65536.0;
+        c * (t - self.k).powi(3) * self.config.max_datagram_size as f64 + self.w_max
+    }
+}
+
+impl Controller for Cubic {
+    fn on_ack(&mut self, now: u64, sent: u64, bytes: u64, rtt: &RttEstimator) {
+        // Do not increase congestion window in recovery period.
+        if self.in_recovery(sent) {
+            return;
+        }
+        if self.window < self.ssthresh {
+            // Slow start.
+            self.window += bytes;
+            return;
+        }
+
+        let mss = self.config.max_datagram_size as f64;
+        let window = self.window as f64;
+        let epoch_start = match self.epoch_start {
+            Some(x) => x,
+            None => {
+                // First ack of a new congestion avoidance epoch
+                self.k = if window < self.w_max {
+                    let c = f64::from(self.config.c) / 65536.0;
+                    ((self.w_max - window) / mss / c).cbrt()
+                } else {
+                    self.w_max = window;
+                    0.0
+                };
+                self.w_est = window;
+                self.epoch_start = Some(now);
+                now
+            }
+        };
+
+        // TCP-friendly region: grow at least as fast as AIMD with the same average window would
+        let beta = self.beta();
+        let alpha = 3.0 * (1.0 - beta) / (1.0 + beta);
+        self.w_est += alpha * mss * bytes as f64 / window;
+
+        let t = (now - epoch_start) as f64 / 1e6;
+        if self.w_cubic(t) < self.w_est {
+            self.window = cmp::max(self.window, self.w_est as u64);
+            return;
+        }
+
+        // Concave or convex region: approach the cubic curve's value one round trip from now
+        let rtt = if rtt.smoothed() == 0 {
+            0.0
+        } else {
+            rtt.smoothed() as f64 / 1e6
+        };
+        let target = self.w_cubic(t + rtt).max(window).min(1.5 * window);
+        self.window += ((target - window) * bytes as f64 / window) as u64;
+    }
+
+    fn on_loss(&mut self, now: u64, sent: u64, _bytes: u64) {
+        // Start a new recovery epoch if the lost packet is larger than the end of the
+        // previous recovery epoch.
+        if self.in_recovery(sent) {
+            return;
+        }
+        self.prior = Some(Prior {
+            window: self.window,
+            ssthresh: self.ssthresh,
+            recovery_start_time: self.recovery_start_time,
+            w_max: self.w_max,
+            epoch_start: self.epoch_start,
+            k: self.k,
+            w_est: self.w_est,
+        });
+        self.recovery_start_time = now;
+        self.epoch_start = None;
+
+        let beta = self.beta();
+        let window = self.window as f64;
+        self.w_max = if self.config.fast_convergence && window < self.w_max {
+            // The window stopped short of the previous maximum, suggesting another flow has
+            // joined; release bandwidth to it more quickly.
+            window * (1.0 + beta) / 2.0
+        } else {
+            window
+        };
+        self.window = cmp::max((window * beta) as u64, self.config.minimum_window);
+        self.ssthresh = self.window;
+    }
+
+    fn on_persistent_congestion(&mut self, _now: u64) {
+        self.window = self.config.minimum_window;
+        self.epoch_start = None;
+        self.prior = None;
+    }
+
+    fn on_spurious_congestion_event(&mut self) {
+        let prior = match self.prior.take() {
+            Some(x) => x,
+            None => return,
+        };
+        self.window = cmp::max(self.window, prior.window);
+        self.ssthresh = prior.ssthresh;
+        self.recovery_start_time = prior.recovery_start_time;
+        self.w_max = prior.w_max;
+        self.epoch_start = prior.epoch_start;
+        self.k = prior.k;
+        self.w_est = prior.w_est;
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+}
+
+/// Snapshot of the `Cubic` state modified by a window reduction
+#[derive(Copy, Clone)]
+struct Prior {
+    window: u64,
+    ssthresh: u64,
+    recovery_start_time: u64,
+    w_max: f64,
+    epoch_start: Option<u64>,
+    k: f64,
+    w_est: f64,
+}
+
+/// Configuration for the `Cubic` congestion controller
+pub struct CubicConfig {
+    /// The max packet size that was used for calculating default and minimum congestion windows.
+    pub max_datagram_size: u64,
+    /// Default limit on the amount of outstanding data in bytes.
+    ///
+    /// Recommended value: `min(10 * max_datagram_size, max(2 * max_datagram_size, 14600))`
+    pub initial_window: u64,
+    /// Default minimum congestion window.
+    ///
+    /// Recommended value: `2 * max_datagram_size`.
+    pub minimum_window: u64,
+    /// Factor the congestion window is multiplied by when a new loss event is detected. 0.16 format
+    pub beta: u16,
+    /// Scaling constant of the cubic window function, in packets per second cubed. 0.16 format
+    pub c: u16,
+    /// Whether to reduce the remembered maximum window further when a loss occurs before it was
+    /// regained, to release bandwidth to competing flows sooner
+    pub fast_convergence: bool,
+}
+
+impl Default for CubicConfig {
+    fn default() -> Self {
+        const MAX_DATAGRAM_SIZE: u64 = 1200;
+        Self {
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            initial_window: cmp::min(
+                10 * MAX_DATAGRAM_SIZE,
+                cmp::max(2 * MAX_DATAGRAM_SIZE, 14600),
+            ),
+            minimum_window: 2 * MAX_DATAGRAM_SIZE,
+            beta: 0xb333, // 0.7
+            c: 0x6666,    // 0.4
+            fast_convergence: true,
+        }
+    }
+}
+
+impl ControllerFactory for CubicConfig {
+    fn build(self: Arc<Self>) -> Box<dyn Controller> {
+        Box::new(Cubic::new(self))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rtt(smoothed: u64) -> RttEstimator {
+        let mut rtt = RttEstimator::new();
+        rtt.update(0, smoothed);
+        rtt
+    }
+
+    #[test]
+    fn loss_reduction() {
+        let mut cc = Cubic::new(Arc::new(CubicConfig::default()));
+        let initial = cc.window();
+        cc.on_loss(1000, 500, 1200);
+        assert_eq!(cc.window(), (initial as f64 * cc.beta()) as u64);
+        // Losses from within the same recovery period are ignored
+        cc.on_loss(2000, 900, 1200);
+        assert_eq!(cc.window(), (initial as f64 * cc.beta()) as u64);
+    }
+
+    #[test]
+    fn spurious_loss_undo() {
+        let mut cc = Cubic::new(Arc::new(CubicConfig::default()));
+        let initial = cc.window();
+        cc.on_loss(1000, 500, 1200);
+        assert!(cc.window() < initial);
+        cc.on_spurious_congestion_event();
+        assert_eq!(cc.window(), initial);
+        assert_eq!(cc.ssthresh, u64::max_value());
+        // A later loss is treated as a new congestion event
+        cc.on_loss(2000, 900, 1200);
+        assert_eq!(cc.window(), (initial as f64 * cc.beta()) as u64);
+    }
+
+    #[test]
+    fn fast_convergence() {
+        let mut cc = Cubic::new(Arc::new(CubicConfig::default()));
+        let initial = cc.window() as f64;
+        cc.on_loss(1000, 500, 1200);
+        assert_eq!(cc.w_max, initial);
+        // A second loss before the window has regained its previous maximum
+        cc.on_loss(3000, 2000, 1200);
+        assert!(cc.w_max < initial * 0.7);
+    }
+
+    #[test]
+    fn regains_w_max_after_k() {
+        let config = Arc::new(CubicConfig::default());
+        let mut cc = Cubic::new(config.clone());
+        let rtt = rtt(100_000);
+        let mut now = 1_000_000;
+        // Leave slow start with a large window
+        cc.window = 100 * config.max_datagram_size;
+        cc.on_loss(now, now - 1, 0);
+        let w_max = cc.w_max;
+        assert!((cc.window() as f64) < w_max);
+        // Ack a window's worth of data every RTT
+        while now < 20_000_000 {
+            now += 100_000;
+            let window = cc.window();
+            for _ in 0..window / config.max_datagram_size {
+                cc.on_ack(now, now - 50_000, config.max_datagram_size, &rtt);
+            }
+            if now - 1_000_000 < (cc.k * 1e6) as u64 - 500_000 {
+                // Concave region
+                assert!((cc.window() as f64) < w_max);
+            }
+        }
+        // Convex region
+        assert!(cc.window() as f64 > w_max);
+    }
+}
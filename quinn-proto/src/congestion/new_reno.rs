@@ -0,0 +1,124 @@
+use std::cmp;
+use std::sync::Arc;
+
+use super::{Controller, ControllerFactory};
+use crate::connection::RttEstimator;
+
+/// A simple, standard congestion controller
+///
+/// Implements the algorithm described in the QUIC loss detection and congestion control draft.
+pub struct NewReno {
+    config: Arc<NewRenoConfig>,
+    /// Maximum number of bytes in flight that may be sent.
+    window: u64,
+    /// Slow start threshold in bytes. When the congestion window is below ssthresh, the mode is
+    /// slow start and the window grows by the number of bytes acknowledged.
+    ssthresh: u64,
+    /// The time when QUIC first detects a loss, causing it to enter recovery. When a packet sent
+    /// after this time is acknowledged, QUIC exits recovery.
+    recovery_start_time: u64,
+    /// `window`, `ssthresh`, and `recovery_start_time` prior to the most recent reduction, in case
+    /// it proves spurious
+    prior: Option<(u64, u64, u64)>,
+}
+
+impl NewReno {
+    /// Construct a state using the given `config`
+    pub fn new(config: Arc<NewRenoConfig>) -> Self {
+        Self {
+            window: config.initial_window,
+            ssthresh: u64::max_value(),
+            recovery_start_time: 0,
+            prior: None,
+            config,
+        }
+    }
+
+    fn in_recovery(&self, sent: u64) -> bool {
+        sent <= self.recovery_start_time
+    }
+}
+
+impl Controller for NewReno {
+    fn on_ack(&mut self, _now: u64, sent: u64, bytes: u64, _rtt: &RttEstimator) {
+        // Do not increase congestion window in recovery period.
+        if self.in_recovery(sent) {
+            return;
+        }
+        if self.window < self.ssthresh {
+            // Slow start.
+            self.window += bytes;
+        } else {
+            // Congestion avoidance.
+            self.window += self.config.max_datagram_size * bytes / self.window;
+        }
+    }
+
+    fn on_loss(&mut self, now: u64, sent: u64, _bytes: u64) {
+        // Start a new recovery epoch if the lost packet is larger than the end of the
+        // previous recovery epoch.
+        if self.in_recovery(sent) {
+            return;
+        }
+        self.prior = Some((self.window, self.ssthresh, self.recovery_start_time));
+        self.recovery_start_time = now;
+        // *= factor
+        self.window = (self.window * self.config.loss_reduction_factor as u64) >> 16;
+        self.window = cmp::max(self.window, self.config.minimum_window);
+        self.ssthresh = self.window;
+    }
+
+    fn on_persistent_congestion(&mut self, _now: u64) {
+        self.window = self.config.minimum_window;
+        self.prior = None;
+    }
+
+    fn on_spurious_congestion_event(&mut self) {
+        if let Some((window, ssthresh, recovery_start_time)) = self.prior.take() {
+            self.window = cmp::max(self.window, window);
+            self.ssthresh = ssthresh;
+            self.recovery_start_time = recovery_start_time;
+        }
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+}
+
+/// Configuration for the `NewReno` congestion controller
+pub struct NewRenoConfig {
+    /// The max packet size that was used for calculating default and minimum congestion windows.
+    pub max_datagram_size: u64,
+    /// Default limit on the amount of outstanding data in bytes.
+    ///
+    /// Recommended value: `min(10 * max_datagram_size, max(2 * max_datagram_size, 14600))`
+    pub initial_window: u64,
+    /// Default minimum congestion window.
+    ///
+    /// Recommended value: `2 * max_datagram_size`.
+    pub minimum_window: u64,
+    /// Reduction in congestion window when a new loss event is detected. 0.16 format
+    pub loss_reduction_factor: u16,
+}
+
+impl Default for NewRenoConfig {
+    fn default() -> Self {
+        const MAX_DATAGRAM_SIZE: u64 = 1200;
+        Self {
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            initial_window: cmp::min(
+                10 * MAX_DATAGRAM_SIZE,
+                cmp::max(2 * MAX_DATAGRAM_SIZE, 14600),
+            ),
+            minimum_window: 2 * MAX_DATAGRAM_SIZE,
+            loss_reduction_factor: 0x8000, // 1/2
+        }
+    }
+}
+
+impl ControllerFactory for NewRenoConfig {
+    fn build(self: Arc<Self>) -> Box<dyn Controller> {
+        Box::new(NewReno::new(self))
+    }
+}
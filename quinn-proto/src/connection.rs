@@ -9,23 +9,28 @@ use fnv::{FnvHashMap, FnvHashSet};
 use rand::{rngs::OsRng, Rng};
 use slog::Logger;
 
+use crate::ack_frequency::AckFrequency;
 use crate::coding::{BufExt, BufMutExt};
+use crate::congestion;
 use crate::crypto::{
     self, reset_token_for, Crypto, HeaderCrypto, Secrets, TlsSession, ACK_DELAY_EXPONENT,
 };
+use crate::datagrams::{DatagramState, SendDatagramError};
 use crate::dedup::Dedup;
 use crate::endpoint::{Config, Event, Timer};
 use crate::frame::FrameStruct;
+use crate::mtud::MtuDiscovery;
+use crate::pacing::Pacer;
 use crate::packet::{
     set_payload_length, ConnectionId, EcnCodepoint, Header, LongType, Packet, PacketNumber,
     PartialDecode, SpaceId, LONG_RESERVED_BITS, SHORT_RESERVED_BITS,
 };
 use crate::range_set::RangeSet;
 use crate::stream::{self, ReadError, Stream, WriteError};
-use crate::transport_parameters::{self, TransportParameters};
+use crate::transport_parameters::{self, TransportParameters, VersionInformation};
 use crate::{
     frame, Directionality, Frame, Side, StreamId, Transmit, TransportError, MIN_INITIAL_SIZE,
-    MIN_MTU, RESET_TOKEN_SIZE, TIMER_GRANULARITY, VERSION,
+    MIN_MTU, REM_CID_COUNT, RESET_TOKEN_SIZE, TIMER_GRANULARITY,
 };
 
 pub struct Connection {
@@ -34,6 +39,13 @@ pub struct Connection {
     rng: OsRng,
     tls: TlsSession,
     app_closed: bool,
+    /// QUIC version in use
+    version: u32,
+    /// Version of the client's first Initial packet, which 0-RTT packets continue to use after a
+    /// switch to a compatible version
+    orig_version: u32,
+    /// Whether the client switched versions in response to a Version Negotiation packet
+    version_negotiated: bool,
     /// DCID of Initial packet
     pub(crate) init_cid: ConnectionId,
     loc_cids: HashMap<u64, ConnectionId>,
@@ -43,11 +55,14 @@ pub struct Connection {
     /// The CID the peer initially chose, for use during the handshake
     rem_handshake_cid: ConnectionId,
     rem_cid_seq: u64,
+    /// Remote CIDs with lower sequence numbers than this have been retired at the peer's request
+    rem_cid_retire_prior_to: u64,
     remote: SocketAddr,
     prev_remote: Option<SocketAddr>,
     state: State,
     side: Side,
-    mtu: u16,
+    /// Path MTU discovery state, determining the largest packet we may send
+    mtud: MtuDiscovery,
     /// Highest received packet number
     rx_packet: u64,
     rx_packet_space: SpaceId,
@@ -61,15 +76,35 @@ pub struct Connection {
     /// Limit on outgoing data, dictated by peer
     max_data: u64,
     data_sent: u64,
+    /// Value of `max_data` most recently reported in a DATA_BLOCKED frame
+    data_blocked_at: Option<u64>,
     /// Sum of end offsets of all streams. Includes gaps, so it's an upper bound.
     data_recvd: u64,
     /// Limit on incoming data
     local_max_data: u64,
+    /// Total stream data read by the application or discarded due to resets
+    data_consumed: u64,
+    /// Amount of incoming data permitted beyond that consumed by the application
+    receive_window: stream::RecvWindow,
+    /// Upper bound on automatic growth of `receive_window`
+    max_receive_window: u64,
+    /// Receive window for newly opened streams
+    stream_receive_window: u64,
+    /// Upper bound on automatic growth of stream receive windows
+    max_stream_receive_window: u64,
+    /// Total stream data buffered that can't be read until earlier data arrives
+    out_of_order_data: u64,
     client_config: Option<ClientConfig>,
     /// ConnectionId sent by this client on the first Initial, if a Retry was received.
     orig_rem_cid: Option<ConnectionId>,
+    /// Source ConnectionId of the Retry received by this client, if any
+    retry_src_cid: Option<ConnectionId>,
     /// Total number of outgoing packets that have been deemed lost
     lost_packets: u64,
+    /// Total number of outgoing packets deemed lost that were later acknowledged
+    spurious_losses: u64,
+    /// Total number of DATA_BLOCKED, STREAM_DATA_BLOCKED, and STREAMS_BLOCKED frames sent
+    blocked_frames_sent: u64,
     io: IoQueue,
     events: VecDeque<Event>,
     /// Number of local connection IDs that have been issued in NEW_CONNECTION_ID frames.
@@ -87,12 +122,17 @@ pub struct Connection {
     /// Whether the remote endpoint has opened any streams the application doesn't know about yet
     stream_opened: bool,
     accepted_0rtt: bool,
+    /// Maximum number of bytes of 0-RTT packet payload to accept
+    max_early_data: u64,
+    /// Number of bytes of 0-RTT packet payload accepted so far
+    early_data_recvd: u64,
 
     //
     // Queued non-retransmittable 1-RTT data
     //
     path_challenge_pending: bool,
     ping_pending: bool,
+    immediate_ack_pending: bool,
     /// PATH_RESPONSEs to send on the current path
     path_response: Option<PathResponse>,
     /// PATH_RESPONSEs to send on alternate paths, due to path validation probes
@@ -101,33 +141,32 @@ pub struct Connection {
     //
     // Loss Detection
     //
-    /// The number of times all unacknowledged CRYPTO data has been retransmitted without receiving
-    /// an ack.
-    crypto_count: u32,
-    /// The number of times a PTO has been sent without receiving an ack.
+    /// The number of times a PTO has fired without receiving an ack.
     pto_count: u32,
-    /// The time at which the next packet will be considered lost based on early transmit or
-    /// exceeding the reordering window in time.
-    loss_time: u64,
-    /// The time the most recently sent retransmittable packet was sent.
-    time_of_last_sent_ack_eliciting_packet: u64,
-    /// The time the most recently sent handshake packet was sent.
-    time_of_last_sent_crypto_packet: u64,
+    /// Time at which the first RTT sample was taken
+    first_rtt_sample: Option<u64>,
+    /// Reordering threshold in packets, widened from `Config::packet_threshold` on spurious loss
+    packet_threshold: u32,
+    /// Reordering threshold as a fraction of the RTT, widened from `Config::time_threshold` on
+    /// spurious loss. 16.16 format, added to 1
+    time_threshold: u32,
+    /// Most recent congestion event caused by loss, which may yet prove spurious
+    loss_event: Option<LossEvent>,
     rtt: RttEstimator,
+    /// Acknowledgement frequency requested by and of the peer
+    ack_frequency: AckFrequency,
+    /// Unreliable application datagrams awaiting transmission or reading
+    datagrams: DatagramState,
 
     //
     // Congestion Control
     //
     /// Summary statistics of packets that have been sent, but not yet acked or deemed lost
     in_flight: InFlight,
-    /// Maximum number of bytes in flight that may be sent.
-    congestion_window: u64,
-    /// The time when QUIC first detects a loss, causing it to enter recovery. When a packet sent
-    /// after this time is acknowledged, QUIC exits recovery.
-    recovery_start_time: u64,
-    /// Slow start threshold in bytes. When the congestion window is below ssthresh, the mode is
-    /// slow start and the window grows by the number of bytes acknowledged.
-    ssthresh: u64,
+    /// Congestion control state, governing the maximum number of bytes in flight
+    congestion: Box<dyn congestion::Controller>,
+    /// Spreads transmissions of a congestion window's worth of data over the RTT
+    pacing: Pacer,
     /// Explicit congestion notification (ECN) counters
     ecn_counters: frame::EcnCounts,
     /// Whether we're enabling ECN on outgoing packets
@@ -147,6 +186,8 @@ impl Connection {
     pub fn new(
         log: Logger,
         config: Arc<Config>,
+        version: u32,
+        orig_version: u32,
         init_cid: ConnectionId,
         loc_cid: ConnectionId,
         rem_cid: ConnectionId,
@@ -154,6 +195,7 @@ impl Connection {
         client_config: Option<ClientConfig>,
         tls: TlsSession,
         remote_validated: bool,
+        max_early_data: u64,
     ) -> Self {
         let side = if client_config.is_some() {
             Side::Client
@@ -163,44 +205,53 @@ impl Connection {
         let rng = OsRng::new().expect("failed to construct RNG");
 
         let initial_space = PacketSpace {
-            crypto: Some(CryptoSpace::new(Crypto::new_initial(&init_cid, side))),
+            crypto: Some(CryptoSpace::new(Crypto::new_initial(
+                version, &init_cid, side,
+            ))),
             ..PacketSpace::new()
         };
         let mut streams = FnvHashMap::default();
         for i in 0..config.stream_window_uni {
             streams.insert(
                 StreamId::new(!side, Directionality::Uni, u64::from(i)),
-                stream::Recv::new().into(),
+                stream::Recv::new(config.stream_receive_window, config.stream_receive_window)
+                    .into(),
             );
         }
         for i in 0..config.stream_window_bidi {
             streams.insert(
                 StreamId::new(!side, Directionality::Bi, i as u64),
-                Stream::new_bi(),
+                Stream::new_bi(config.stream_receive_window, config.stream_receive_window),
             );
         }
         let mut loc_cids = HashMap::new();
         loc_cids.insert(0, loc_cid);
         let state = State::Handshake(state::Handshake {
             rem_cid_set: side.is_server(),
-            token: None,
+            token: client_config.as_ref().and_then(|x| x.token.clone()),
         });
+        let congestion = config.congestion_controller_factory.clone().build();
+        let pacing = Pacer::new(congestion.window(), MIN_MTU);
         let mut this = Self {
             log,
             rng,
             tls,
             app_closed: false,
+            version,
+            orig_version,
+            version_negotiated: false,
             init_cid,
             loc_cids,
             handshake_cid: loc_cid,
             rem_cid,
             rem_handshake_cid: rem_cid,
             rem_cid_seq: 0,
+            rem_cid_retire_prior_to: 0,
             remote,
             prev_remote: None,
             side,
             state,
-            mtu: MIN_MTU,
+            mtud: MtuDiscovery::new(config.max_mtu),
             rx_packet: 0,
             rx_packet_space: SpaceId::Initial,
             rx_packet_time: 0,
@@ -210,11 +261,21 @@ impl Connection {
             blocked_streams: FnvHashSet::default(),
             max_data: 0,
             data_sent: 0,
+            data_blocked_at: None,
             data_recvd: 0,
             local_max_data: config.receive_window as u64,
+            data_consumed: 0,
+            receive_window: stream::RecvWindow::new(config.receive_window),
+            max_receive_window: config.max_receive_window,
+            stream_receive_window: config.stream_receive_window,
+            max_stream_receive_window: config.max_stream_receive_window,
+            out_of_order_data: 0,
             client_config,
             orig_rem_cid: None,
+            retry_src_cid: None,
             lost_packets: 0,
+            spurious_losses: 0,
+            blocked_frames_sent: 0,
             io: IoQueue::new(),
             events: VecDeque::new(),
             cids_issued: 0,
@@ -225,23 +286,27 @@ impl Connection {
             path_challenge: None,
             stream_opened: false,
             accepted_0rtt: false,
+            max_early_data,
+            early_data_recvd: 0,
 
             path_challenge_pending: false,
             ping_pending: false,
+            immediate_ack_pending: false,
             path_response: None,
             offpath_responses: Vec::new(),
 
-            crypto_count: 0,
             pto_count: 0,
-            loss_time: 0,
-            time_of_last_sent_ack_eliciting_packet: 0,
-            time_of_last_sent_crypto_packet: 0,
+            first_rtt_sample: None,
+            packet_threshold: config.packet_threshold,
+            time_threshold: u32::from(config.time_threshold),
+            loss_event: None,
             rtt: RttEstimator::new(),
+            ack_frequency: AckFrequency::new(&config),
+            datagrams: DatagramState::new(),
 
             in_flight: InFlight::new(),
-            congestion_window: config.initial_window,
-            recovery_start_time: 0,
-            ssthresh: u64::max_value(),
+            congestion,
+            pacing,
             ecn_counters: frame::EcnCounts::ZERO,
             sending_ecn: true,
             receiving_ecn: false,
@@ -257,8 +322,15 @@ impl Connection {
                 max_bi: 0,
                 max_remote_uni: config.stream_window_uni,
                 max_remote_bi: config.stream_window_bidi,
+                max_concurrent_uni: config.stream_window_uni,
+                max_concurrent_bi: config.stream_window_bidi,
+                uni_credit_debt: 0,
+                bi_credit_debt: 0,
+                uni_blocked_at: None,
+                bi_blocked_at: None,
                 finished: Vec::new(),
                 incoming: VecDeque::new(),
+                pending: stream::PendingStreams::default(),
             },
             config,
             rem_cids: Vec::new(),
@@ -312,32 +384,22 @@ impl Connection {
     fn on_packet_sent(&mut self, now: u64, space: SpaceId, packet_number: u64, packet: SentPacket) {
         let SentPacket {
             size,
-            is_crypto_packet,
             ack_eliciting,
             ..
         } = packet;
 
         self.in_flight.insert(&packet);
+        if ack_eliciting {
+            self.congestion.on_sent(now, u64::from(size));
+            let space = self.space_mut(space);
+            space.ack_eliciting_in_flight += 1;
+            space.time_of_last_ack_eliciting_packet = now;
+        }
         self.space_mut(space)
             .sent_packets
             .insert(packet_number, packet);
         if size != 0 {
-            if ack_eliciting {
-                self.time_of_last_sent_ack_eliciting_packet = now;
-            }
-            if is_crypto_packet {
-                self.time_of_last_sent_crypto_packet = now;
-            }
-            self.set_loss_detection_timer();
-        }
-
-        if space == SpaceId::Handshake
-            && !self.state.is_handshake()
-            && self.side.is_server()
-            && !self.space(SpaceId::Handshake).permit_ack_only
-        {
-            // We've acked the TLS FIN.
-            self.set_key_discard_timer(now);
+            self.set_loss_detection_timer(now);
         }
     }
 
@@ -348,17 +410,7 @@ impl Connection {
         let prev_largest = *largest_acked_packet;
         *largest_acked_packet = cmp::max(ack.largest, *largest_acked_packet);
 
-        let largest_acked_time_sent;
-        if let Some(info) = self.space(space).sent_packets.get(&ack.largest).cloned() {
-            if info.ack_eliciting {
-                let delay = ack.delay << self.params.ack_delay_exponent;
-                self.rtt
-                    .update(cmp::min(delay, self.max_ack_delay()), now - info.time_sent);
-            }
-            largest_acked_time_sent = Some(info.time_sent);
-        } else {
-            largest_acked_time_sent = None;
-        }
+        self.detect_spurious_losses(space, &ack);
 
         // Avoid DoS from unreasonably huge ack ranges by filtering out just the new acks.
         let newly_acked = ack
@@ -368,22 +420,28 @@ impl Connection {
         if newly_acked.is_empty() {
             return;
         }
-        for &packet in &newly_acked {
-            self.on_packet_acked(space, packet);
+
+        let largest_acked_time_sent = self
+            .space(space)
+            .sent_packets
+            .get(&ack.largest)
+            .map(|x| x.time_sent);
+        if let Some(time_sent) = largest_acked_time_sent {
+            let sent_packets = &self.space(space).sent_packets;
+            if newly_acked.iter().any(|n| sent_packets[n].ack_eliciting) {
+                self.update_rtt(now, space, time_sent, ack.delay);
+            }
         }
 
-        if space == SpaceId::Handshake
-            && !self.state.is_handshake()
-            && self.side.is_client()
-            && self.in_flight.crypto == 0
-            && self.space(SpaceId::Handshake).pending.crypto.is_empty()
-        {
-            // All Handshake CRYPTO data sent and acked.
-            self.set_key_discard_timer(now);
+        for &packet in &newly_acked {
+            self.on_packet_acked(now, space, packet);
         }
+        self.congestion.on_end_acks(now, self.in_flight.bytes);
 
-        self.crypto_count = 0;
-        self.pto_count = 0;
+        // Protect a server that's still validating our address from repeated probes
+        if self.peer_completed_address_validation() {
+            self.pto_count = 0;
+        }
 
         // Explicit congestion notification
         if self.sending_ecn {
@@ -408,8 +466,8 @@ impl Connection {
             }
         }
 
-        self.detect_lost_packets(now);
-        self.set_loss_detection_timer();
+        self.detect_lost_packets(now, space);
+        self.set_loss_detection_timer(now);
         if was_blocked && !self.blocked() {
             for stream in self.blocked_streams.drain() {
                 self.events.push_back(Event::StreamWritable { stream });
@@ -417,6 +475,30 @@ impl Connection {
         }
     }
 
+    /// Take an RTT sample from the acknowledgement of a packet sent at `time_sent`
+    ///
+    /// `delay` is the encoded ACK Delay field of the ACK frame.
+    fn update_rtt(&mut self, now: u64, space: SpaceId, time_sent: u64, delay: u64) {
+        let ack_delay = match space {
+            // Delays in acknowledging Initial packets don't reflect the peer's max_ack_delay
+            SpaceId::Initial => 0,
+            _ => {
+                let delay = delay
+                    .checked_shl(self.params.ack_delay_exponent as u32)
+                    .unwrap_or(u64::max_value());
+                if self.handshake_confirmed() {
+                    cmp::min(delay, self.max_ack_delay())
+                } else {
+                    delay
+                }
+            }
+        };
+        self.rtt.update(ack_delay, now - time_sent);
+        if self.first_rtt_sample.is_none() {
+            self.first_rtt_sample = Some(now);
+        }
+    }
+
     /// Process a new ECN block from an in-order ACK
     fn process_ecn(
         &mut self,
@@ -437,36 +519,50 @@ impl Connection {
             }
             Ok(false) => {}
             Ok(true) => {
-                self.congestion_event(now, largest_sent_time);
+                self.congestion.on_ecn_ce(now, largest_sent_time);
+                // Genuine congestion, so the current congestion event must not be undone
+                match self.loss_event {
+                    Some(ref mut event) if largest_sent_time <= event.start => {
+                        event.undoable = false;
+                    }
+                    _ => {
+                        self.loss_event = Some(LossEvent {
+                            start: now,
+                            unconfirmed: 0,
+                            undoable: false,
+                        });
+                    }
+                }
             }
         }
     }
 
     // Not timing-aware, so it's safe to call this for inferred acks, such as arise from
     // high-latency handshakes
-    fn on_packet_acked(&mut self, space: SpaceId, packet: u64) {
+    fn on_packet_acked(&mut self, now: u64, space: SpaceId, packet: u64) {
         let info = if let Some(x) = self.space_mut(space).sent_packets.remove(&packet) {
             x
         } else {
             return;
         };
         self.in_flight.remove(&info);
+        self.space_mut(space).ack_eliciting_in_flight -= info.ack_eliciting as u64;
         if info.ack_eliciting {
             // Congestion control
-            // Do not increase congestion window in recovery period.
-            if !self.in_recovery(packet) {
-                if self.congestion_window < self.ssthresh {
-                    // Slow start.
-                    self.congestion_window += info.size as u64;
-                } else {
-                    // Congestion avoidance.
-                    self.congestion_window +=
-                        self.config.max_datagram_size * info.size as u64 / self.congestion_window;
-                }
-            }
+            self.congestion
+                .on_ack(now, info.time_sent, info.size as u64, &self.rtt);
+        }
+        if info.is_mtu_probe {
+            self.mtud.on_probe_acked(now, info.size);
+            trace!(self.log, "MTU probe acked"; "mtu" => self.mtud.mtu());
+        } else if info.size > MIN_MTU {
+            self.mtud.on_large_acked();
         }
 
         // Update state for confirmed delivery of frames
+        if let Some(sequence) = info.retransmits.ack_frequency {
+            self.ack_frequency.on_acked(sequence);
+        }
         for (id, _) in info.retransmits.rst_stream {
             if let stream::SendState::ResetSent { stop_reason } =
                 self.streams.get_send_mut(id).unwrap().state
@@ -478,24 +574,15 @@ impl Connection {
                 }
             }
         }
-        for frame in info.retransmits.stream {
-            let recvd = {
-                let ss = if let Some(x) = self.streams.get_send_mut(frame.id) {
-                    x
-                } else {
-                    continue;
-                };
-                ss.bytes_in_flight -= frame.data.len() as u64;
-                if ss.state == stream::SendState::DataSent && ss.bytes_in_flight == 0 {
-                    ss.state = stream::SendState::DataRecvd;
-                    true
-                } else {
-                    false
-                }
+        for frame in info.stream_frames {
+            let id = frame.id;
+            let recvd = match self.streams.get_send_mut(id) {
+                Some(ss) => ss.ack(frame),
+                None => continue,
             };
             if recvd {
-                self.maybe_cleanup(frame.id);
-                self.streams.finished.push(frame.id);
+                self.maybe_cleanup(id);
+                self.streams.finished.push(id);
             }
         }
         self.space_mut(space).pending_acks.subtract(&info.acks);
@@ -516,18 +603,13 @@ impl Connection {
                 self.on_loss_detection_timeout(now);
             }
             Timer::KeyDiscard => {
-                if self.spaces[SpaceId::Handshake as usize].crypto.is_some() {
-                    self.discard_space(SpaceId::Handshake);
-                    // Might have a key update to discard too
-                    self.set_key_discard_timer(now);
-                } else if let Some(ref prev) = self.prev_crypto {
-                    if prev
-                        .update_ack_time
-                        .map_or(false, |x| now.saturating_sub(x) >= self.pto() * 3)
-                    {
+                if let Some(ref prev) = self.prev_crypto {
+                    if prev.update_ack_time.map_or(false, |x| {
+                        now.saturating_sub(x) >= self.pto(SpaceId::Data) * 3
+                    }) {
                         self.prev_crypto = None;
                     } else {
-                        self.set_key_discard_timer(now);
+                        self.set_key_discard_timer();
                     }
                 }
             }
@@ -540,156 +622,330 @@ impl Connection {
                     self.remote_validated = true;
                 }
             }
+            Timer::Pacing => {}
+            Timer::MaxAckDelay => {
+                trace!(self.log, "max ack delay reached");
+                self.space_mut(SpaceId::Data).immediate_ack_required = true;
+            }
         }
         false
     }
 
-    fn set_key_discard_timer(&mut self, now: u64) {
-        let time = if self.spaces[SpaceId::Handshake as usize].crypto.is_some() {
-            now + self.pto() * 3
-        } else if let Some(ref time) = self.prev_crypto.as_ref().and_then(|x| x.update_ack_time) {
-            time + self.pto() * 3
-        } else {
+    fn set_key_discard_timer(&mut self) {
+        if let Some(time) = self.prev_crypto.as_ref().and_then(|x| x.update_ack_time) {
+            self.io
+                .timer_start(Timer::KeyDiscard, time + self.pto(SpaceId::Data) * 3);
+        }
+    }
+
+    fn on_loss_detection_timeout(&mut self, now: u64) {
+        if let Some((_, space)) = self.earliest_loss_time() {
+            // Time threshold loss detection
+            self.detect_lost_packets(now, space);
+            self.set_loss_detection_timer(now);
             return;
+        }
+
+        let space = match self.pto_time_and_space(now) {
+            Some((_, space)) => space,
+            None => {
+                self.set_loss_detection_timer(now);
+                return;
+            }
         };
-        self.io.timer_start(Timer::KeyDiscard, time);
+        trace!(
+            self.log,
+            "PTO fired";
+            "space" => ?space,
+            "in flight" => self.in_flight.bytes,
+            "count" => self.pto_count,
+        );
+        self.queue_probe(space);
+        self.pto_count = self.pto_count.saturating_add(1);
+        self.set_loss_detection_timer(now);
     }
 
-    fn on_loss_detection_timeout(&mut self, now: u64) {
-        if self.in_flight.crypto != 0 {
-            trace!(self.log, "retransmitting handshake packets");
-            for &space_id in [SpaceId::Initial, SpaceId::Handshake].iter() {
-                if self.spaces[space_id as usize].crypto.is_none() {
-                    continue;
+    /// Arrange for a pair of probe packets to be sent in `space`
+    ///
+    /// Probes carry new data if there is any. Otherwise, the CRYPTO frames of the oldest
+    /// unacknowledged packet in the space are sent again, since a handshake can't progress without
+    /// them, or failing that a PING.
+    fn queue_probe(&mut self, space_id: SpaceId) {
+        let streams_pending = space_id == SpaceId::Data && !self.streams.pending.is_empty();
+        let space = &mut self.spaces[space_id as usize];
+        space.loss_probes = 2;
+        if !space.pending.is_empty() || streams_pending {
+            return;
+        }
+        if let Some(packet) = space
+            .sent_packets
+            .values()
+            .find(|x| !x.retransmits.crypto.is_empty())
+        {
+            space
+                .pending
+                .crypto
+                .extend(packet.retransmits.crypto.iter().cloned());
+        }
+    }
+
+    /// Adapt to the acknowledgement of packets previously deemed lost
+    ///
+    /// Widens the reordering thresholds so that similar reordering isn't mistaken for loss again,
+    /// and undoes the most recent congestion event if every packet responsible for it turns out
+    /// to have been delivered.
+    fn detect_spurious_losses(&mut self, space_id: SpaceId, ack: &frame::Ack) {
+        let space = &mut self.spaces[space_id as usize];
+        let spurious = ack
+            .iter()
+            .flat_map(|range| space.declared_lost.range(range).map(|(&n, _)| n))
+            .collect::<Vec<_>>();
+        for packet in spurious {
+            let info = space.declared_lost.remove(&packet).unwrap();
+            self.spurious_losses += 1;
+            let reordering = info.largest_acked - packet;
+            if reordering >= u64::from(self.packet_threshold) {
+                // Deemed lost by the packet threshold
+                let threshold = cmp::min(reordering + 1, MAX_PACKET_THRESHOLD);
+                self.packet_threshold = cmp::max(self.packet_threshold, threshold as u32);
+            } else {
+                // Deemed lost by the time threshold
+                let threshold = cmp::min(2 * self.time_threshold, MAX_TIME_THRESHOLD);
+                self.time_threshold = cmp::max(self.time_threshold, threshold);
+            }
+            if let Some(ref mut event) = self.loss_event {
+                if info.loss_event == Some(event.start) {
+                    event.unconfirmed -= 1;
                 }
-                let sent_packets =
-                    mem::replace(&mut self.space_mut(space_id).sent_packets, BTreeMap::new());
-                self.lost_packets += sent_packets.len() as u64;
-                for (_, packet) in sent_packets {
-                    self.in_flight.remove(&packet);
-                    self.space_mut(space_id).pending += packet.retransmits;
-                }
-            }
-            self.crypto_count += 1;
-        } else if self.state.is_handshake() && self.side.is_client() {
-            trace!(self.log, "sending anti-deadlock handshake packet");
-            self.io.probes += 1;
-            self.crypto_count += 1;
-        } else if self.loss_time != 0 {
-            // Time threshold loss Detection
-            self.detect_lost_packets(now);
-        } else {
-            trace!(self.log, "PTO fired"; "in flight" => self.in_flight.bytes);
-            self.io.probes += 2;
-            self.pto_count += 1;
+            }
+            trace!(
+                self.log,
+                "packet {packet} spuriously deemed lost",
+                packet = packet;
+                "packet threshold" => self.packet_threshold,
+                "time threshold" => self.time_threshold,
+            );
+        }
+
+        match self.loss_event {
+            Some(ref event) if event.unconfirmed == 0 && event.undoable => {}
+            _ => return,
         }
-        self.set_loss_detection_timer();
+        debug!(self.log, "undoing spurious congestion event");
+        self.loss_event = None;
+        self.congestion.on_spurious_congestion_event();
     }
 
-    fn detect_lost_packets(&mut self, now: u64) {
-        self.loss_time = 0;
+    fn detect_lost_packets(&mut self, now: u64, space_id: SpaceId) {
         let mut lost_packets = Vec::<u64>::new();
         let rtt = cmp::max(self.rtt.latest, self.rtt.smoothed);
-        let loss_delay = rtt + ((rtt * self.config.time_threshold as u64) >> 16);
+        let loss_delay = rtt + ((rtt * u64::from(self.time_threshold)) >> 16);
+        let loss_delay = cmp::max(loss_delay, TIMER_GRANULARITY);
         let lost_send_time = now.saturating_sub(loss_delay);
+        // Persistent congestion can only be established from packets sent after an RTT sample was
+        // taken, since the PTO is meaningless before then.
+        let congestion_period =
+            self.pto(SpaceId::Data) * self.config.persistent_congestion_threshold as u64;
+        let first_rtt_sample = self.first_rtt_sample;
+        // Acknowledgements of packets deemed lost this long ago are no longer expected
+        let forget_time = now.saturating_sub(self.pto(space_id) * 3);
 
-        let mut lost_ack_eliciting = false;
-        let mut largest_lost_time = 0;
-        for space in self.spaces.iter_mut().filter(|x| x.crypto.is_some()) {
-            lost_packets.clear();
-            let lost_pn = space
-                .largest_acked_packet
-                .saturating_sub(self.config.packet_threshold as u64);
-            for (&packet, info) in space.sent_packets.range(0..space.largest_acked_packet) {
-                if info.time_sent <= lost_send_time || packet <= lost_pn {
-                    lost_packets.push(packet);
-                } else if self.loss_time == 0 {
-                    self.loss_time = info.time_sent + loss_delay;
-                } else {
-                    self.loss_time = cmp::min(self.loss_time, info.time_sent + loss_delay);
-                }
+        let space = &mut self.spaces[space_id as usize];
+        space.loss_time = None;
+        while let Some((&packet, info)) = space.declared_lost.iter().next() {
+            if info.time_lost > forget_time {
+                break;
             }
-
-            // OnPacketsLost
-            if let Some(largest_lost) = lost_packets.last().cloned() {
-                let old_bytes_in_flight = self.in_flight.bytes;
-                largest_lost_time = cmp::max(
-                    largest_lost_time,
-                    space.sent_packets[&largest_lost].time_sent,
-                );
-                self.lost_packets += lost_packets.len() as u64;
-                for packet in &lost_packets {
-                    let info = space.sent_packets.remove(&packet).unwrap();
-                    self.in_flight.remove(&info);
-                    space.pending += info.retransmits;
-                }
-                // Don't apply congestion penalty for lost ack-only packets
-                lost_ack_eliciting |= old_bytes_in_flight != self.in_flight.bytes;
+            space.declared_lost.remove(&packet);
+        }
+        let lost_pn = space
+            .largest_acked_packet
+            .saturating_sub(u64::from(self.packet_threshold));
+        for (&packet, info) in space.sent_packets.range(0..space.largest_acked_packet) {
+            if info.time_sent <= lost_send_time || packet <= lost_pn {
+                lost_packets.push(packet);
+            } else {
+                let t = info.time_sent + loss_delay;
+                space.loss_time = Some(space.loss_time.map_or(t, |x| cmp::min(x, t)));
             }
         }
-        if lost_ack_eliciting {
-            self.congestion_event(now, largest_lost_time)
+
+        // OnPacketsLost
+        let largest_lost = match lost_packets.last() {
+            Some(&x) => x,
+            None => return,
+        };
+        let largest_lost_time = space.sent_packets[&largest_lost].time_sent;
+        self.lost_packets += lost_packets.len() as u64;
+        // Mirrors the controller's notion of when a new recovery epoch begins
+        let loss_event = match self.loss_event {
+            Some(ref event) if largest_lost_time <= event.start => event.start,
+            _ => now,
+        };
+        let mut congestion_lost = 0;
+        let mut lost_bytes = 0;
+        let mut lost_mtu_probe = None;
+        let mut lost_large = false;
+        let mut persistent_congestion = false;
+        // Start time of the current run of consecutively numbered lost packets
+        let mut run: Option<(u64, u64)> = None;
+        for &packet in &lost_packets {
+            let info = space.sent_packets.remove(&packet).unwrap();
+            self.in_flight.remove(&info);
+            space.ack_eliciting_in_flight -= info.ack_eliciting as u64;
+            space.pending += info.retransmits;
+            for frame in info.stream_frames {
+                self.streams.retransmit(frame);
+            }
+            let congestion = !info.is_mtu_probe && info.size != 0;
+            if info.is_mtu_probe {
+                // Probes are expected to be lost and say nothing about congestion
+                lost_mtu_probe = Some(info.size);
+            } else {
+                // Lost ack-only packets have zero size, so incur no congestion penalty
+                lost_bytes += u64::from(info.size);
+                lost_large |= info.size > MIN_MTU;
+            }
+            congestion_lost += congestion as u64;
+            space.declared_lost.insert(
+                packet,
+                LostPacket {
+                    time_lost: now,
+                    largest_acked: space.largest_acked_packet,
+                    loss_event: if congestion { Some(loss_event) } else { None },
+                },
+            );
+            // A gap in packet numbers means something in between was acknowledged
+            let start = match run {
+                Some((prev, start)) if prev + 1 == packet => start,
+                _ => info.time_sent,
+            };
+            run = Some((packet, start));
+            persistent_congestion |= first_rtt_sample.map_or(false, |x| start > x)
+                && info.time_sent - start > congestion_period;
         }
-    }
 
-    fn congestion_event(&mut self, now: u64, sent_time: u64) {
-        // Start a new recovery epoch if the lost packet is larger than the end of the
-        // previous recovery epoch.
-        if self.in_recovery(sent_time) {
-            return;
+        if let Some(size) = lost_mtu_probe {
+            trace!(self.log, "MTU probe lost"; "size" => size);
+            self.mtud.on_probe_lost(now, size);
+        }
+        if lost_large && self.mtud.on_large_lost(now) {
+            debug!(self.log, "black hole detected, falling back to minimum MTU");
+        }
+        if lost_bytes != 0 {
+            self.congestion.on_loss(now, largest_lost_time, lost_bytes);
+            if self.loss_event.map_or(true, |x| x.start != loss_event) {
+                self.loss_event = Some(LossEvent {
+                    start: loss_event,
+                    unconfirmed: 0,
+                    undoable: true,
+                });
+            }
+            let event = self.loss_event.as_mut().unwrap();
+            event.unconfirmed += congestion_lost;
+            if persistent_congestion {
+                debug!(self.log, "persistent congestion detected");
+                self.congestion.on_persistent_congestion(now);
+                event.undoable = false;
+            }
         }
-        self.recovery_start_time = now;
-        // *= factor
-        self.congestion_window =
-            (self.congestion_window * self.config.loss_reduction_factor as u64) >> 16;
-        self.congestion_window = cmp::max(self.congestion_window, self.config.minimum_window);
-        self.ssthresh = self.congestion_window;
     }
 
-    fn in_recovery(&self, sent_time: u64) -> bool {
-        sent_time <= self.recovery_start_time
+    /// Earliest time at which a packet will be deemed lost by the time threshold, and its space
+    fn earliest_loss_time(&self) -> Option<(u64, SpaceId)> {
+        SpaceId::VALUES
+            .iter()
+            .filter_map(|&id| Some((self.space(id).loss_time?, id)))
+            .min_by_key(|&(time, _)| time)
     }
 
-    fn set_loss_detection_timer(&mut self) {
-        if self.in_flight.crypto != 0 || (self.state.is_handshake() && self.side.is_client()) {
-            // Handshake retransmission alarm.
-            let timeout = if self.rtt.smoothed == 0 {
-                2 * self.config.initial_rtt
+    /// Time at which the probe timeout should fire, and the space a probe should then be sent in
+    fn pto_time_and_space(&self, now: u64) -> Option<(u64, SpaceId)> {
+        let backoff = 2u64.pow(cmp::min(self.pto_count, MAX_BACKOFF_EXPONENT));
+        let mut duration = self.pto_base() * backoff;
+
+        if self.in_flight.ack_eliciting == 0 {
+            // Anti-deadlock probe, allowing a server limited by anti-amplification to send more
+            let space = if self.space(SpaceId::Handshake).crypto.is_some() {
+                SpaceId::Handshake
             } else {
-                2 * self.rtt.smoothed
+                SpaceId::Initial
             };
-            let timeout = cmp::max(timeout, TIMER_GRANULARITY) * 2u64.pow(self.crypto_count);
-            self.io.timer_start(
-                Timer::LossDetection,
-                self.time_of_last_sent_crypto_packet + timeout,
-            );
+            return Some((now + duration, space));
+        }
+
+        let mut result = None;
+        for &id in SpaceId::VALUES.iter() {
+            let space = self.space(id);
+            if space.ack_eliciting_in_flight == 0 {
+                continue;
+            }
+            if id == SpaceId::Data {
+                // Application data isn't probed for until the handshake is confirmed, since the
+                // peer may not be able to decrypt it until then
+                if !self.handshake_confirmed() {
+                    break;
+                }
+                duration += self.max_ack_delay() * backoff;
+            }
+            let time = space.time_of_last_ack_eliciting_packet + duration;
+            if result.map_or(true, |(earliest, _)| time < earliest) {
+                result = Some((time, id));
+            }
+        }
+        result
+    }
+
+    fn set_loss_detection_timer(&mut self, now: u64) {
+        if let Some((time, _)) = self.earliest_loss_time() {
+            // Time threshold loss detection.
+            self.io.timer_start(Timer::LossDetection, time);
             return;
         }
 
-        if self.in_flight.ack_eliciting == 0 {
+        if self.in_flight.ack_eliciting == 0 && self.peer_completed_address_validation() {
+            // There is nothing to detect lost, so no timer is set. However, the client needs to arm
+            // the timer if the server might be blocked by the anti-amplification limit.
             self.io.timer_stop(Timer::LossDetection);
             return;
         }
 
-        if self.loss_time != 0 {
-            // Time threshold loss detection.
-            self.io.timer_start(Timer::LossDetection, self.loss_time);
-            return;
+        match self.pto_time_and_space(now) {
+            Some((time, _)) => self.io.timer_start(Timer::LossDetection, time),
+            None => self.io.timer_stop(Timer::LossDetection),
         }
+    }
 
-        // Calculate PTO duration
-        let timeout = self.rtt.smoothed + 4 * self.rtt.var + self.max_ack_delay();
-        let timeout = cmp::max(timeout, TIMER_GRANULARITY) * 2u64.pow(self.pto_count);
-        self.io.timer_start(
-            Timer::LossDetection,
-            self.time_of_last_sent_ack_eliciting_packet + timeout,
-        );
+    /// Probe timeout duration, without backoff, for packets sent in `space`
+    fn pto(&self, space: SpaceId) -> u64 {
+        let max_ack_delay = match space {
+            SpaceId::Initial | SpaceId::Handshake => 0,
+            SpaceId::Data => self.max_ack_delay(),
+        };
+        self.pto_base() + max_ack_delay
+    }
+
+    /// Probe timeout duration, excluding the peer's ack delay
+    fn pto_base(&self) -> u64 {
+        let (smoothed, var) = if self.first_rtt_sample.is_none() {
+            (self.config.initial_rtt, self.config.initial_rtt / 2)
+        } else {
+            (self.rtt.smoothed, self.rtt.var)
+        };
+        smoothed + cmp::max(4 * var, TIMER_GRANULARITY)
+    }
+
+    /// Whether the handshake is complete and known by both endpoints to be so
+    fn handshake_confirmed(&self) -> bool {
+        // Handshake keys are discarded upon confirmation
+        !self.state.is_handshake() && self.space(SpaceId::Handshake).crypto.is_none()
     }
 
-    /// Probe Timeout
-    fn pto(&self) -> u64 {
-        let computed = self.rtt.smoothed + 4 * self.rtt.var + self.max_ack_delay();
-        cmp::max(computed, TIMER_GRANULARITY)
+    /// Whether the peer has validated our address, freeing it from the anti-amplification limit
+    fn peer_completed_address_validation(&self) -> bool {
+        // A client completing the handshake implies the server has received a Handshake packet
+        // from it, or will once the client's ack-eliciting Handshake packets are delivered.
+        self.side.is_server() || !self.state.is_handshake()
     }
 
     fn on_packet_authenticated(
@@ -725,9 +981,19 @@ impl Connection {
             && self.side.is_server()
         {
             // A server stops sending and processing Initial packets when it receives its first Handshake packet.
-            self.discard_space(SpaceId::Initial);
+            self.discard_space(now, SpaceId::Initial);
         }
         let space = &mut self.spaces[space_id as usize];
+        // Packets arriving out of order may indicate loss, which the peer should learn of promptly
+        if space
+            .largest_rx_packet
+            .map_or(false, |x| packet < x || packet > x + 1)
+        {
+            space.rx_reordered = true;
+        }
+        if space.largest_rx_packet.map_or(true, |x| packet > x) {
+            space.largest_rx_packet = Some(packet);
+        }
         space.pending_acks.insert_one(packet);
         if space.pending_acks.len() > MAX_ACK_BLOCKS {
             space.pending_acks.pop_min();
@@ -745,38 +1011,31 @@ impl Connection {
             self.io.timer_stop(Timer::Idle);
             return;
         }
-        let dt = if self.config.idle_timeout == 0 || self.params.idle_timeout == 0 {
-            cmp::max(self.config.idle_timeout, self.params.idle_timeout)
+        let dt = if self.config.idle_timeout == 0 || self.params.max_idle_timeout == 0 {
+            cmp::max(self.config.idle_timeout, self.params.max_idle_timeout)
         } else {
-            cmp::min(self.config.idle_timeout, self.params.idle_timeout)
+            cmp::min(self.config.idle_timeout, self.params.max_idle_timeout)
         };
-        self.io
-            .timer_start(Timer::Idle, now + dt as u64 * 1_000_000);
+        self.io.timer_start(Timer::Idle, now + dt * 1000);
     }
 
     fn queue_stream_data(&mut self, stream: StreamId, data: Bytes) {
         let ss = self.streams.get_send_mut(stream).unwrap();
         assert_eq!(ss.state, stream::SendState::Ready);
-        let offset = ss.offset;
-        ss.offset += data.len() as u64;
-        ss.bytes_in_flight += data.len() as u64;
+        let was_pending = ss.is_pending();
+        let priority = ss.priority;
         self.data_sent += data.len() as u64;
-        self.space_mut(SpaceId::Data)
-            .pending
-            .stream
-            .push_back(frame::Stream {
-                offset,
-                fin: false,
-                data,
-                id: stream,
-            });
+        ss.pending.write(data);
+        if !was_pending {
+            self.streams.pending.push(stream, priority);
+        }
     }
 
     /// Abandon transmitting data on a stream
     ///
     /// # Panics
     /// - when applied to a receive stream or an unopened send stream
-    pub fn reset(&mut self, stream_id: StreamId, error_code: u16) {
+    pub fn reset(&mut self, stream_id: StreamId, error_code: u64) {
         assert!(
             stream_id.directionality() == Directionality::Bi || stream_id.initiator() == self.side,
             "only streams supporting outgoing data may be reset"
@@ -797,6 +1056,8 @@ impl Connection {
             _ => {}
         }
         stream.state = stream::SendState::ResetSent { stop_reason: None };
+        let priority = stream.priority;
+        self.streams.pending.remove(stream_id, priority);
 
         self.spaces[SpaceId::Data as usize]
             .pending
@@ -823,6 +1084,7 @@ impl Connection {
             Side::Server,
             &mut io::Cursor::new(self.tls.get_quic_transport_parameters().unwrap()),
         )?;
+        self.validate_params(&params)?;
         self.set_params(params)?;
         self.write_tls();
         self.init_0rtt();
@@ -851,7 +1113,7 @@ impl Connection {
         }
         if let Some(secret) = self.tls.get_early_secret() {
             trace!(self.log, "0-RTT enabled");
-            let packet = Crypto::new_0rtt(secret);
+            let packet = Crypto::new_0rtt(self.orig_version, secret);
             self.zero_rtt_crypto = Some(CryptoSpace {
                 header: packet.header_crypto(),
                 packet,
@@ -859,7 +1121,7 @@ impl Connection {
         }
     }
 
-    fn read_tls(&mut self, space: SpaceId, crypto: &frame::Crypto) -> Result<(), TransportError> {
+    fn read_tls(&mut self, space: SpaceId, crypto: frame::Crypto) -> Result<(), TransportError> {
         let expected = if !self.state.is_handshake() {
             SpaceId::Data
         } else if self.highest_space == SpaceId::Initial {
@@ -880,15 +1142,15 @@ impl Connection {
         }
 
         let space = &mut self.spaces[space as usize];
-        space.crypto_stream.insert(crypto.offset, &crypto.data);
-        let mut buf = [0; 8192];
-        loop {
-            let n = space.crypto_stream.read(&mut buf);
-            if n == 0 {
-                return Ok(());
-            }
-            trace!(self.log, "read {} TLS bytes", n);
-            if let Err(e) = self.tls.read_hs(&buf[..n]) {
+        let max = space.crypto_stream.offset() + self.config.crypto_buffer_size;
+        if crypto.offset + crypto.data.len() as u64 > max {
+            debug!(self.log, "received too much out-of-order CRYPTO data"; "offset" => crypto.offset, "len" => crypto.data.len());
+            return Err(TransportError::CRYPTO_BUFFER_EXCEEDED);
+        }
+        space.crypto_stream.insert(crypto.offset, crypto.data);
+        while let Some(chunk) = space.crypto_stream.read_chunk(usize::max_value()) {
+            trace!(self.log, "read {} TLS bytes", chunk.len());
+            if let Err(e) = self.tls.read_hs(&chunk) {
                 debug!(self.log, "TLS error: {}", e);
                 return Err(if let Some(alert) = self.tls.get_alert() {
                     TransportError::crypto(alert)
@@ -897,6 +1159,7 @@ impl Connection {
                 });
             }
         }
+        Ok(())
     }
 
     fn write_tls(&mut self) {
@@ -938,7 +1201,13 @@ impl Connection {
     /// Switch to stronger cryptography during handshake
     fn upgrade_crypto(&mut self, space: SpaceId, secrets: Secrets) {
         let suite = self.tls.get_negotiated_ciphersuite().unwrap();
-        let crypto = Crypto::new(self.side, suite.get_hash(), suite.get_aead_alg(), secrets);
+        let crypto = Crypto::new(
+            self.version,
+            self.side,
+            suite.get_hash(),
+            suite.get_aead_alg(),
+            secrets,
+        );
         debug_assert!(
             self.spaces[space as usize].crypto.is_none(),
             "already reached packet space {:?}",
@@ -950,13 +1219,20 @@ impl Connection {
         self.highest_space = space;
     }
 
-    fn discard_space(&mut self, space: SpaceId) {
-        trace!(self.log, "discarding {space:?} keys", space = space);
-        self.space_mut(space).crypto = None;
-        let sent_packets = mem::replace(&mut self.space_mut(space).sent_packets, BTreeMap::new());
+    fn discard_space(&mut self, now: u64, space_id: SpaceId) {
+        trace!(self.log, "discarding {space:?} keys", space = space_id);
+        let space = self.space_mut(space_id);
+        space.crypto = None;
+        space.loss_time = None;
+        space.loss_probes = 0;
+        space.ack_eliciting_in_flight = 0;
+        space.declared_lost.clear();
+        let sent_packets = mem::replace(&mut space.sent_packets, BTreeMap::new());
         for (_, packet) in sent_packets.into_iter() {
             self.in_flight.remove(&packet);
         }
+        self.pto_count = 0;
+        self.set_loss_detection_timer(now);
     }
 
     pub fn handle_dgram(
@@ -991,7 +1267,11 @@ impl Connection {
     ) {
         let mut remaining = Some(data);
         while let Some(data) = remaining {
-            match PartialDecode::new(data, self.config.local_cid_len) {
+            match PartialDecode::new(
+                data,
+                self.config.local_cid_len,
+                &self.config.supported_versions,
+            ) {
                 Ok((partial_decode, rest)) => {
                     remaining = rest;
                     self.handle_decode(now, remote, ecn, partial_decode);
@@ -1011,6 +1291,31 @@ impl Connection {
         ecn: Option<EcnCodepoint>,
         partial_decode: PartialDecode,
     ) {
+        match partial_decode.version() {
+            Some(version) if version == self.version => {}
+            Some(version) if version == self.orig_version && partial_decode.is_0rtt() => {}
+            Some(version) if partial_decode.is_initial() && self.can_switch_version(version) => {
+                // The server chose a compatible version, per RFC 9368
+                trace!(
+                    self.log,
+                    "switching to compatible version {version:x}",
+                    version = version
+                );
+                self.version = version;
+                self.spaces[SpaceId::Initial as usize].crypto = Some(CryptoSpace::new(
+                    Crypto::new_initial(version, &self.rem_cid, self.side),
+                ));
+            }
+            Some(version) => {
+                debug!(
+                    self.log,
+                    "discarding packet with version {version:x}",
+                    version = version
+                );
+                return;
+            }
+            None => {}
+        }
         let header_crypto = if partial_decode.is_0rtt() {
             if let Some(ref crypto) = self.zero_rtt_crypto {
                 Some(&crypto.header)
@@ -1098,6 +1403,15 @@ impl Connection {
                         return;
                     }
                 } else {
+                    if packet.header.is_0rtt() {
+                        let len = packet.payload.len() as u64;
+                        if self.early_data_recvd + len > self.max_early_data {
+                            // The client will retransmit the contents once this goes unacknowledged
+                            debug!(self.log, "dropping 0-RTT packet exceeding early data limit");
+                            return;
+                        }
+                        self.early_data_recvd += len;
+                    }
                     if !self.state.is_closed() {
                         let spin = if let Header::Short { spin, .. } = packet.header {
                             spin
@@ -1163,43 +1477,35 @@ impl Connection {
             State::Handshake(ref state) => {
                 match packet.header {
                     Header::Retry {
-                        src_cid: rem_cid,
-                        orig_dst_cid,
-                        ..
+                        src_cid: rem_cid, ..
                     } => {
-                        if self.orig_rem_cid.is_some() || orig_dst_cid != self.rem_cid {
+                        if self.side.is_server()
+                            || self.orig_rem_cid.is_some()
+                            || packet.payload.len() <= crypto::RETRY_TAG_SIZE
+                            || !crypto::is_valid_retry(
+                                self.version,
+                                &self.rem_cid,
+                                &packet.header_data,
+                                &packet.payload,
+                            )
+                        {
                             // A client MUST accept and process at most one Retry packet for each
-                            // connection attempt, and clients MUST discard Retry packets that
-                            // contain an Original Destination Connection ID field that does not
-                            // match the Destination Connection ID from its Initial packet.
+                            // connection attempt, and MUST discard Retry packets with an empty
+                            // token or an integrity tag that doesn't match the Destination
+                            // Connection ID from its Initial packet.
                             return Ok(());
                         }
                         trace!(self.log, "retrying with CID {rem_cid}", rem_cid = rem_cid);
                         self.orig_rem_cid = Some(self.rem_cid);
+                        self.retry_src_cid = Some(rem_cid);
                         self.rem_cid = rem_cid;
                         self.rem_handshake_cid = rem_cid;
-                        self.on_packet_acked(SpaceId::Initial, 0);
-
-                        // Reset to initial state
-                        let client_config = self.client_config.as_ref().unwrap();
-                        self.tls = TlsSession::new_client(
-                            &client_config.tls_config,
-                            &client_config.server_name,
-                            &TransportParameters::new(&self.config),
-                        )
-                        .unwrap();
-                        self.discard_space(SpaceId::Initial); // Make sure we clean up after any retransmitted Initials
-                        self.spaces[0] = PacketSpace {
-                            crypto: Some(CryptoSpace::new(Crypto::new_initial(
-                                &rem_cid, self.side,
-                            ))),
-                            ..PacketSpace::new()
-                        };
-
-                        self.write_tls();
+                        self.restart_handshake(now);
 
+                        let mut token = packet.payload;
+                        token.truncate(token.len() - crypto::RETRY_TAG_SIZE);
                         self.state = State::Handshake(state::Handshake {
-                            token: Some(packet.payload.into()),
+                            token: Some(token.freeze()),
                             rem_cid_set: false,
                         });
                         Ok(())
@@ -1258,6 +1564,7 @@ impl Connection {
                                 debug!(self.log, "server reduced flow-control window wrt. 0-RTT");
                                 return Err(TransportError::PROTOCOL_VIOLATION.into());
                             }
+                            self.validate_params(&params)?;
                             self.set_params(params)?;
 
                             if self.has_0rtt() {
@@ -1271,6 +1578,11 @@ impl Connection {
                         self.events.push_back(Event::Connected);
                         self.state = State::Established;
                         trace!(self.log, "established");
+                        if self.side.is_server() {
+                            // The handshake is confirmed as soon as it completes at the server
+                            self.space_mut(SpaceId::Data).pending.handshake_done = true;
+                            self.discard_space(now, SpaceId::Handshake);
+                        }
                         Ok(())
                     }
                     Header::Initial {
@@ -1298,24 +1610,59 @@ impl Connection {
                         ty: LongType::ZeroRtt,
                         ..
                     } => {
-                        self.process_payload(now, remote, number.unwrap(), packet.payload.into())?;
+                        self.process_payload(
+                            now,
+                            remote,
+                            number.unwrap(),
+                            true,
+                            packet.payload.into(),
+                        )?;
                         Ok(())
                     }
-                    Header::VersionNegotiate { .. } => {
-                        let mut payload = io::Cursor::new(&packet.payload[..]);
+                    Header::VersionNegotiate { src_cid, .. } => {
+                        if self.side.is_server()
+                            || state.rem_cid_set
+                            || self.orig_rem_cid.is_some()
+                            || self.version_negotiated
+                            || src_cid != self.rem_cid
+                        {
+                            // Version Negotiation is only meaningful in response to the client's
+                            // first flight, before anything else has been heard from the server.
+                            return Ok(());
+                        }
                         if packet.payload.len() % 4 != 0 {
                             debug!(self.log, "malformed version negotiation");
                             return Err(TransportError::PROTOCOL_VIOLATION.into());
                         }
+                        let mut payload = io::Cursor::new(&packet.payload[..]);
+                        let mut offered = Vec::with_capacity(packet.payload.len() / 4);
                         while payload.has_remaining() {
-                            let version = payload.get::<u32>().unwrap();
-                            if version == VERSION {
-                                // Our version is supported, so this packet is spurious
-                                return Ok(());
-                            }
+                            offered.push(payload.get::<u32>().unwrap());
+                        }
+                        if offered.contains(&self.version) {
+                            // Our version is supported, so this packet is spurious
+                            return Ok(());
                         }
-                        debug!(self.log, "remote doesn't support our version");
-                        Err(ConnectionError::VersionMismatch)
+                        let version = match self.choose_version(&offered) {
+                            Some(x) => x,
+                            None => {
+                                debug!(self.log, "remote doesn't support any of our versions");
+                                return Err(ConnectionError::VersionMismatch);
+                            }
+                        };
+                        trace!(
+                            self.log,
+                            "switching to version {version:x}",
+                            version = version
+                        );
+                        self.version = version;
+                        self.orig_version = version;
+                        self.version_negotiated = true;
+                        self.restart_handshake(now);
+                        // 0-RTT keys are specific to the version and the TLS session
+                        self.zero_rtt_crypto = None;
+                        self.init_0rtt();
+                        Ok(())
                     }
                     // TODO: SHOULD buffer these to improve reordering tolerance.
                     Header::Short { .. } => {
@@ -1326,9 +1673,13 @@ impl Connection {
             }
             State::Established => {
                 match packet.header.space() {
-                    SpaceId::Data => {
-                        self.process_payload(now, remote, number.unwrap(), packet.payload.into())?
-                    }
+                    SpaceId::Data => self.process_payload(
+                        now,
+                        remote,
+                        number.unwrap(),
+                        packet.header.is_0rtt(),
+                        packet.payload.into(),
+                    )?,
                     _ => self.process_early_payload(now, packet)?,
                 }
                 Ok(())
@@ -1372,13 +1723,16 @@ impl Connection {
             match frame {
                 Frame::Ack(_) | Frame::Padding => {}
                 _ => {
-                    self.space_mut(packet.header.space()).permit_ack_only = true;
+                    // Handshake packets are acknowledged without delay
+                    let space = self.space_mut(packet.header.space());
+                    space.permit_ack_only = true;
+                    space.immediate_ack_required = true;
                 }
             }
             match frame {
-                Frame::Padding => {}
+                Frame::Padding | Frame::Ping => {}
                 Frame::Crypto(frame) => {
-                    self.read_tls(packet.header.space(), &frame)?;
+                    self.read_tls(packet.header.space(), frame)?;
                 }
                 Frame::Ack(ack) => {
                     self.on_ack_received(now, packet.header.space(), ack);
@@ -1410,6 +1764,11 @@ impl Connection {
         Ok(())
     }
 
+    /// Send the client an address validation token for use in future connections
+    pub fn issue_new_token(&mut self, token: Bytes) {
+        self.space_mut(SpaceId::Data).pending.new_tokens.push(token);
+    }
+
     pub fn issue_cid(&mut self, cid: ConnectionId) {
         let token = reset_token_for(&self.config.reset_key, &cid);
         self.cids_issued += 1;
@@ -1420,6 +1779,7 @@ impl Connection {
             .push(frame::NewConnectionId {
                 id: cid,
                 sequence,
+                retire_prior_to: 0,
                 reset_token: token,
             });
         self.loc_cids.insert(self.cids_issued, cid);
@@ -1430,10 +1790,12 @@ impl Connection {
         now: u64,
         remote: SocketAddr,
         number: u64,
+        zero_rtt: bool,
         payload: Bytes,
     ) -> Result<(), TransportError> {
         let is_0rtt = self.space(SpaceId::Data).crypto.is_none();
         let mut is_probing_packet = true;
+        let mut ack_eliciting = false;
         for frame in frame::Iter::new(payload) {
             match frame {
                 Frame::Padding => {}
@@ -1453,7 +1815,7 @@ impl Connection {
             match frame {
                 Frame::Ack(_) | Frame::Padding => {}
                 _ => {
-                    self.space_mut(SpaceId::Data).permit_ack_only = true;
+                    ack_eliciting = true;
                 }
             }
             match frame {
@@ -1479,7 +1841,7 @@ impl Connection {
                         debug!(self.log, "received CRYPTO in 0-RTT");
                         return Err(TransportError::PROTOCOL_VIOLATION);
                     }
-                    self.read_tls(SpaceId::Data, &frame)?;
+                    self.read_tls(SpaceId::Data, frame)?;
                 }
                 Frame::Stream(frame) => {
                     trace!(self.log, "got stream"; "id" => frame.id.0, "offset" => frame.offset, "len" => frame.data.len(), "fin" => frame.fin);
@@ -1506,12 +1868,12 @@ impl Connection {
                     if let Some(final_offset) = rs.final_offset() {
                         if end > final_offset || (frame.fin && end != final_offset) {
                             debug!(self.log, "final offset error"; "frame end" => end, "final offset" => final_offset);
-                            return Err(TransportError::FINAL_OFFSET_ERROR);
+                            return Err(TransportError::FINAL_SIZE_ERROR);
                         }
                     }
                     let prev_end = rs.limit();
                     let new_bytes = end.saturating_sub(prev_end);
-                    let stream_max_data = rs.bytes_read + self.config.stream_receive_window;
+                    let stream_max_data = rs.max_data;
                     if end > stream_max_data || data_recvd + new_bytes > max_data {
                         debug!(self.log, "flow control error";
                                    "stream" => frame.id.0, "recvd" => data_recvd, "new bytes" => new_bytes,
@@ -1523,14 +1885,51 @@ impl Connection {
                             *size = Some(end);
                         }
                     }
-                    rs.recvd.insert(frame.offset..end);
+                    let prev_out_of_order = rs.out_of_order_len();
+                    rs.zero_rtt |= zero_rtt && !frame.data.is_empty();
                     rs.buffer(frame.data, frame.offset);
+                    if rs.assembler.recvd().len() > self.config.max_stream_fragments {
+                        debug!(self.log, "stream too fragmented"; "stream" => frame.id.0);
+                        return Err(TransportError::PROTOCOL_VIOLATION);
+                    }
+                    self.out_of_order_data =
+                        self.out_of_order_data - prev_out_of_order + rs.out_of_order_len();
+                    if self.out_of_order_data > self.config.out_of_order_buffer_size {
+                        debug!(self.log, "too much out-of-order stream data"; "buffered" => self.out_of_order_data);
+                        return Err(TransportError::PROTOCOL_VIOLATION);
+                    }
                     if let stream::RecvState::Recv { size: Some(size) } = rs.state {
-                        if rs.recvd.len() == 1 && rs.recvd.iter().next().unwrap() == (0..size) {
+                        let recvd = rs.assembler.recvd();
+                        if size == 0
+                            || (recvd.len() == 1 && recvd.iter().next().unwrap() == (0..size))
+                        {
                             rs.state = stream::RecvState::DataRecvd { size };
                         }
                     }
 
+                    // Begin measuring how quickly the application consumes data
+                    rs.window.update(
+                        now,
+                        self.rtt.smoothed,
+                        rs.bytes_read,
+                        self.max_stream_receive_window,
+                    );
+                    if rs.needs_credit() && rs.receiving_unknown_size() {
+                        self.spaces[SpaceId::Data as usize]
+                            .pending
+                            .max_stream_data
+                            .insert(frame.id);
+                    }
+                    self.receive_window.update(
+                        now,
+                        self.rtt.smoothed,
+                        self.data_consumed,
+                        self.max_receive_window,
+                    );
+                    if self.data_consumed + self.receive_window.size() > self.local_max_data {
+                        self.spaces[SpaceId::Data as usize].pending.max_data = true;
+                    }
+
                     if mem::replace(&mut rs.fresh, false) {
                         self.stream_opened = true;
                         self.streams.incoming.push_back(frame.id);
@@ -1605,8 +2004,8 @@ impl Connection {
                     if let Some(ss) = self.streams.get_send_mut(id) {
                         if offset > ss.max_data {
                             trace!(self.log, "stream limit increased"; "stream" => id.0,
-                                   "old" => ss.max_data, "new" => offset, "current offset" => ss.offset);
-                            if ss.offset == ss.max_data {
+                                   "old" => ss.max_data, "new" => offset, "current offset" => ss.offset());
+                            if ss.offset() == ss.max_data {
                                 self.events.push_back(Event::StreamWritable { stream: id });
                             }
                             ss.max_data = offset;
@@ -1656,20 +2055,21 @@ impl Connection {
                     // Validate final_offset
                     if let Some(offset) = rs.final_offset() {
                         if offset != final_offset {
-                            return Err(TransportError::FINAL_OFFSET_ERROR);
+                            return Err(TransportError::FINAL_SIZE_ERROR);
                         }
                     } else if limit > final_offset {
-                        return Err(TransportError::FINAL_OFFSET_ERROR);
+                        return Err(TransportError::FINAL_SIZE_ERROR);
                     }
 
                     // State transition
+                    self.out_of_order_data -= rs.out_of_order_len();
                     rs.reset(error_code, final_offset);
 
                     // Update flow control
                     if rs.bytes_read != final_offset {
                         self.data_recvd += final_offset - limit;
                         // bytes_read is always <= limit, so this won't underflow.
-                        self.local_max_data += final_offset - rs.bytes_read;
+                        self.data_consumed += final_offset - rs.bytes_read;
                         self.space_mut(SpaceId::Data).pending.max_data = true;
                     }
 
@@ -1704,7 +2104,7 @@ impl Connection {
                 Frame::StopSending { id, error_code } => {
                     if self.streams.streams.get(&id).map_or(true, |x| {
                         x.send()
-                            .map_or(true, |ss| id.initiator() == self.side && ss.offset == 0)
+                            .map_or(true, |ss| id.initiator() == self.side && ss.offset() == 0)
                     }) {
                         debug!(
                             self.log,
@@ -1760,22 +2160,69 @@ impl Connection {
                         );
                         return Err(TransportError::PROTOCOL_VIOLATION);
                     }
-                    if self.params.stateless_reset_token.is_none() {
+                    let known = frame.sequence == self.rem_cid_seq
+                        || self.rem_cids.iter().any(|x| x.sequence == frame.sequence);
+                    if known {
+                        // Retransmission
+                    } else if self.params.stateless_reset_token.is_none() {
                         // We're a server using the initial remote CID for the client, so let's
                         // switch immediately to enable clientside stateless resets.
                         debug_assert!(self.side.is_server());
                         debug_assert_eq!(self.rem_cid_seq, 0);
                         self.update_rem_cid(frame);
                     } else {
-                        // Reasonable limit to bound memory use
-                        if self.rem_cids.len() < 32 {
-                            self.rem_cids.push(frame);
-                        }
+                        self.on_new_cid(frame)?;
+                    }
+                }
+                Frame::HandshakeDone => {
+                    if self.side.is_server() {
+                        debug!(self.log, "client sent HANDSHAKE_DONE");
+                        return Err(TransportError::PROTOCOL_VIOLATION);
+                    }
+                    if self.spaces[SpaceId::Handshake as usize].crypto.is_some() {
+                        self.discard_space(now, SpaceId::Handshake);
                     }
                 }
-                Frame::NewToken { .. } => {
+                Frame::NewToken { token } => {
+                    let server_name = match self.client_config {
+                        Some(ref x) => &x.server_name,
+                        None => {
+                            debug!(self.log, "client sent NEW_TOKEN");
+                            return Err(TransportError::PROTOCOL_VIOLATION);
+                        }
+                    };
+                    if token.is_empty() {
+                        debug!(self.log, "received empty NEW_TOKEN");
+                        return Err(TransportError::FRAME_ENCODING_ERROR);
+                    }
                     trace!(self.log, "got new token");
-                    // TODO: Cache, or perhaps forward to user?
+                    self.config.token_store.insert(server_name, token);
+                }
+                Frame::AckFrequency(frame) => {
+                    if let Err(e) = self
+                        .ack_frequency
+                        .on_frame(frame, self.config.min_ack_delay)
+                    {
+                        debug!(self.log, "requested max ack delay is below our minimum");
+                        return Err(e);
+                    }
+                }
+                Frame::ImmediateAck => {
+                    self.space_mut(SpaceId::Data).immediate_ack_required = true;
+                }
+                Frame::Datagram(frame) => {
+                    // Frames can't exceed our advertised limit of 64KiB, which is larger than any
+                    // UDP payload.
+                    let limit = match self.config.datagram_receive_buffer_size {
+                        Some(x) => x,
+                        None => {
+                            debug!(self.log, "unexpected DATAGRAM frame");
+                            return Err(TransportError::PROTOCOL_VIOLATION);
+                        }
+                    };
+                    if self.datagrams.received(frame.data, limit) {
+                        self.events.push_back(Event::DatagramReceived);
+                    }
                 }
             }
         }
@@ -1792,9 +2239,28 @@ impl Connection {
             }
         }
 
+        if ack_eliciting {
+            self.on_ack_eliciting_received(now);
+        }
+
         Ok(())
     }
 
+    /// Decide when to acknowledge an ack-eliciting 1-RTT or 0-RTT packet
+    fn on_ack_eliciting_received(&mut self, now: u64) {
+        let space = &mut self.spaces[SpaceId::Data as usize];
+        space.permit_ack_only = true;
+        space.unacked_ack_eliciting += 1;
+        if (space.rx_reordered && self.ack_frequency.ack_reordered)
+            || space.unacked_ack_eliciting >= self.ack_frequency.threshold
+        {
+            space.immediate_ack_required = true;
+        } else if space.unacked_ack_eliciting == 1 {
+            self.io
+                .timer_start(Timer::MaxAckDelay, now + self.ack_frequency.max_ack_delay);
+        }
+    }
+
     fn migrate(&mut self, now: u64, remote: SocketAddr) {
         trace!(
             self.log,
@@ -1804,8 +2270,11 @@ impl Connection {
         if remote.ip() != self.remote.ip() {
             // Reset rtt/congestion state for new path
             self.rtt = RttEstimator::new();
-            self.congestion_window = self.config.initial_window;
-            self.ssthresh = u64::max_value();
+            self.congestion = self.config.congestion_controller_factory.clone().build();
+            self.mtud.reset();
+            self.packet_threshold = self.config.packet_threshold;
+            self.time_threshold = u32::from(self.config.time_threshold);
+            self.loss_event = None;
         }
         self.prev_remote = Some(mem::replace(&mut self.remote, remote));
         self.remote_validated = false;
@@ -1813,12 +2282,48 @@ impl Connection {
         // Initiate path validation
         self.io.timer_start(
             Timer::PathValidation,
-            now + 3 * cmp::max(self.pto(), 2 * self.config.initial_rtt),
+            now + 3 * cmp::max(self.pto(SpaceId::Data), 2 * self.config.initial_rtt),
         );
         self.path_challenge = Some(self.rng.gen());
         self.path_challenge_pending = true;
     }
 
+    /// Store a CID newly issued by the peer, retiring any it no longer wants us to use
+    fn on_new_cid(&mut self, frame: frame::NewConnectionId) -> Result<(), TransportError> {
+        if frame.sequence < self.rem_cid_retire_prior_to {
+            // Already retired by an earlier frame
+            self.space_mut(SpaceId::Data)
+                .pending
+                .retire_cids
+                .push(frame.sequence);
+            return Ok(());
+        }
+        let retire_prior_to = frame.retire_prior_to;
+        self.rem_cids.push(frame);
+        if retire_prior_to > self.rem_cid_retire_prior_to {
+            self.rem_cid_retire_prior_to = retire_prior_to;
+            let retire_cids = &mut self.spaces[SpaceId::Data as usize].pending.retire_cids;
+            self.rem_cids.retain(|cid| {
+                if cid.sequence < retire_prior_to {
+                    retire_cids.push(cid.sequence);
+                    false
+                } else {
+                    true
+                }
+            });
+            if self.rem_cid_seq < retire_prior_to {
+                // The frame's own CID is never retired by it, so there's always a replacement
+                let next = self.rem_cids.pop().unwrap();
+                self.update_rem_cid(next);
+            }
+        }
+        if self.rem_cids.len() as u64 + 1 > REM_CID_COUNT {
+            debug!(self.log, "peer exceeded our active connection ID limit");
+            return Err(TransportError::CONNECTION_ID_LIMIT_ERROR);
+        }
+        Ok(())
+    }
+
     fn update_rem_cid(&mut self, new: frame::NewConnectionId) {
         trace!(
             self.log,
@@ -1840,10 +2345,12 @@ impl Connection {
         &mut self,
         now: u64,
         space_id: SpaceId,
+        mtu: u16,
         buf: &mut Vec<u8>,
-    ) -> (Retransmits, RangeSet) {
+    ) -> (Retransmits, Vec<frame::StreamMeta>, RangeSet) {
         let space = &mut self.spaces[space_id as usize];
         let mut sent = Retransmits::default();
+        let mut stream_frames = Vec::new();
         let zero_rtt_crypto = self.zero_rtt_crypto.as_ref();
         let tag_len = space
             .crypto
@@ -1859,7 +2366,7 @@ impl Connection {
             })
             .packet
             .tag_len();
-        let max_size = self.mtu as usize - tag_len;
+        let max_size = mtu as usize - tag_len;
         let is_0rtt = space_id == SpaceId::Data && space.crypto.is_none();
 
         // PING
@@ -1868,6 +2375,19 @@ impl Connection {
             buf.write(frame::Type::PING);
         }
 
+        // HANDSHAKE_DONE
+        if space_id == SpaceId::Data && mem::replace(&mut space.pending.handshake_done, false) {
+            trace!(self.log, "HANDSHAKE_DONE");
+            buf.write(frame::Type::HANDSHAKE_DONE);
+            sent.handshake_done = true;
+        }
+
+        // IMMEDIATE_ACK
+        if mem::replace(&mut self.immediate_ack_pending, false) {
+            trace!(self.log, "IMMEDIATE_ACK");
+            buf.write(frame::Type::IMMEDIATE_ACK);
+        }
+
         // ACK
         // 0-RTT packets must never carry acks (which would have to be of handshake packets)
         let acks = if !space.pending_acks.is_empty() {
@@ -1910,8 +2430,37 @@ impl Connection {
             }
         }
 
-        // CRYPTO
-        while buf.len() + frame::Crypto::SIZE_BOUND < max_size {
+        // ACK_FREQUENCY
+        // Only included alongside other ack-eliciting data, to avoid turning ACK-only packets into
+        // ack-eliciting ones.
+        if space_id == SpaceId::Data
+            && !is_0rtt
+            && (!space.pending.is_empty() || !self.streams.pending.is_empty())
+            && self.config.ack_frequency
+            && buf.len() + frame::AckFrequency::SIZE_BOUND < max_size
+        {
+            let force = space.pending.ack_frequency.is_some();
+            if let Some(frame) = self.ack_frequency.next_frame(
+                force,
+                &self.params,
+                self.congestion.window(),
+                mtu,
+                self.rtt.smoothed,
+            ) {
+                trace!(
+                    self.log,
+                    "ACK_FREQUENCY";
+                    "threshold" => frame.ack_eliciting_threshold,
+                    "max delay" => frame.request_max_ack_delay
+                );
+                frame.encode(buf);
+                sent.ack_frequency = Some(frame.sequence);
+            }
+            space.pending.ack_frequency = None;
+        }
+
+        // CRYPTO
+        while buf.len() + frame::Crypto::SIZE_BOUND < max_size {
             let mut frame = if let Some(x) = space.pending.crypto.pop_front() {
                 x
             } else {
@@ -1961,13 +2510,13 @@ impl Connection {
                 frame::ResetStream {
                     id,
                     error_code,
-                    final_offset: stream.send().unwrap().offset,
+                    final_offset: stream.send().unwrap().offset(),
                 }
                 .encode(buf);
             }
 
             // STOP_SENDING
-            while buf.len() + 11 < max_size {
+            while buf.len() + 17 < max_size {
                 let (id, error_code) = if let Some(x) = space.pending.stop_sending.pop() {
                     x
                 } else {
@@ -1985,12 +2534,22 @@ impl Connection {
                 sent.stop_sending.push((id, error_code));
                 buf.write(frame::Type::STOP_SENDING);
                 buf.write(id);
-                buf.write(error_code);
+                buf.write_var(error_code);
             }
         }
 
         // MAX_DATA
         if space.pending.max_data && buf.len() + 9 < max_size {
+            self.receive_window.update(
+                now,
+                self.rtt.smoothed,
+                self.data_consumed,
+                self.max_receive_window,
+            );
+            self.local_max_data = cmp::max(
+                self.local_max_data,
+                self.data_consumed + self.receive_window.size(),
+            );
             trace!(self.log, "MAX_DATA"; "value" => self.local_max_data);
             space.pending.max_data = false;
             sent.max_data = true;
@@ -2006,8 +2565,8 @@ impl Connection {
                 break;
             };
             space.pending.max_stream_data.remove(&id);
-            let rs = if let Some(x) = self.streams.streams.get(&id) {
-                x.recv().unwrap()
+            let rs = if let Some(x) = self.streams.streams.get_mut(&id) {
+                x.recv_mut().unwrap()
             } else {
                 continue;
             };
@@ -2015,7 +2574,13 @@ impl Connection {
                 continue;
             }
             sent.max_stream_data.insert(id);
-            let max = rs.bytes_read + self.config.stream_receive_window;
+            rs.window.update(
+                now,
+                self.rtt.smoothed,
+                rs.bytes_read,
+                self.max_stream_receive_window,
+            );
+            let max = rs.update_max_data();
             trace!(
                 self.log,
                 "MAX_STREAM_DATA: {stream} = {max}",
@@ -2045,8 +2610,68 @@ impl Connection {
             buf.write_var(self.streams.max_remote_bi);
         }
 
+        // DATA_BLOCKED
+        if space.pending.data_blocked && buf.len() + 9 < max_size {
+            space.pending.data_blocked = false;
+            // Don't bother if we've since been unblocked
+            if self.data_sent >= self.max_data {
+                sent.data_blocked = true;
+                trace!(self.log, "DATA_BLOCKED"; "offset" => self.max_data);
+                buf.write(frame::Type::DATA_BLOCKED);
+                buf.write_var(self.max_data);
+                self.blocked_frames_sent += 1;
+            }
+        }
+
+        // STREAM_DATA_BLOCKED
+        while buf.len() + 17 < max_size {
+            let id = if let Some(x) = space.pending.stream_data_blocked.iter().next() {
+                *x
+            } else {
+                break;
+            };
+            space.pending.stream_data_blocked.remove(&id);
+            let ss = match self.streams.streams.get(&id).and_then(|x| x.send()) {
+                Some(x) => x,
+                None => continue,
+            };
+            if ss.state != stream::SendState::Ready || ss.offset() < ss.max_data {
+                continue;
+            }
+            sent.stream_data_blocked.insert(id);
+            trace!(self.log, "STREAM_DATA_BLOCKED"; "stream" => id.0, "offset" => ss.max_data);
+            buf.write(frame::Type::STREAM_DATA_BLOCKED);
+            buf.write(id);
+            buf.write_var(ss.max_data);
+            self.blocked_frames_sent += 1;
+        }
+
+        // STREAMS_BLOCKED_UNI
+        if space.pending.uni_streams_blocked && buf.len() + 9 < max_size {
+            space.pending.uni_streams_blocked = false;
+            if self.streams.next_uni == self.streams.max_uni {
+                sent.uni_streams_blocked = true;
+                trace!(self.log, "STREAMS_BLOCKED (unidirectional)"; "limit" => self.streams.max_uni);
+                buf.write(frame::Type::STREAMS_BLOCKED_UNI);
+                buf.write_var(self.streams.max_uni);
+                self.blocked_frames_sent += 1;
+            }
+        }
+
+        // STREAMS_BLOCKED_BIDI
+        if space.pending.bi_streams_blocked && buf.len() + 9 < max_size {
+            space.pending.bi_streams_blocked = false;
+            if self.streams.next_bi == self.streams.max_bi {
+                sent.bi_streams_blocked = true;
+                trace!(self.log, "STREAMS_BLOCKED (bidirectional)"; "limit" => self.streams.max_bi);
+                buf.write(frame::Type::STREAMS_BLOCKED_BIDI);
+                buf.write_var(self.streams.max_bi);
+                self.blocked_frames_sent += 1;
+            }
+        }
+
         // NEW_CONNECTION_ID
-        while buf.len() + 44 < max_size {
+        while buf.len() + frame::NewConnectionId::SIZE_BOUND < max_size {
             let frame = if let Some(x) = space.pending.new_cids.pop() {
                 x
             } else {
@@ -2074,43 +2699,76 @@ impl Connection {
             sent.retire_cids.push(seq);
         }
 
-        // STREAM
-        while buf.len() + frame::Stream::SIZE_BOUND < max_size {
-            let mut stream = if let Some(x) = space.pending.stream.pop_front() {
-                x
-            } else {
+        // NEW_TOKEN
+        while let Some(token) = space.pending.new_tokens.pop() {
+            if buf.len() + 1 + 8 + token.len() >= max_size {
+                space.pending.new_tokens.push(token);
                 break;
+            }
+            trace!(self.log, "NEW_TOKEN");
+            buf.write(frame::Type::NEW_TOKEN);
+            buf.write_var(token.len() as u64);
+            buf.extend_from_slice(&token);
+            sent.new_tokens.push(token);
+        }
+
+        // DATAGRAM
+        if space_id == SpaceId::Data && !is_0rtt {
+            let max_datagram_size = self.max_datagram_size().unwrap_or(0);
+            while let Some(data) = self.datagrams.pop_outgoing(
+                max_size.saturating_sub(buf.len() + frame::Datagram::SIZE_BOUND),
+                max_datagram_size,
+            ) {
+                trace!(self.log, "DATAGRAM"; "len" => data.len());
+                frame::Datagram { data }.encode(true, buf);
+            }
+        }
+
+        // STREAM
+        while space_id == SpaceId::Data && buf.len() + frame::Stream::SIZE_BOUND < max_size {
+            let id = match self.streams.pending.pop() {
+                Some(x) => x,
+                None => break,
             };
-            if self
-                .streams
-                .streams
-                .get(&stream.id)
-                .map_or(true, |s| s.send().unwrap().state.was_reset())
-            {
+            let ss = match self.streams.get_send_mut(id) {
+                Some(ss) if !ss.state.was_reset() => ss,
+                _ => continue,
+            };
+            let max_len = max_size as usize - buf.len() - frame::Stream::SIZE_BOUND;
+            let (offset, data) = ss
+                .pending
+                .poll_transmit(max_len)
+                .unwrap_or_else(|| (ss.offset(), Bytes::new()));
+            let fin = ss.fin_pending && offset + data.len() as u64 == ss.offset();
+            if fin {
+                ss.fin_pending = false;
+            }
+            if ss.is_pending() {
+                // Move this stream behind others, so that streams of equal priority are served
+                // round-robin.
+                let priority = ss.priority;
+                self.streams.pending.push(id, priority);
+            }
+            if data.is_empty() && !fin {
                 continue;
             }
-            let len = cmp::min(
-                stream.data.len(),
-                max_size as usize - buf.len() - frame::Stream::SIZE_BOUND,
-            );
-            let data = stream.data.split_to(len);
-            let fin = stream.fin && stream.data.is_empty();
-            trace!(self.log, "STREAM"; "id" => stream.id.0, "off" => stream.offset, "len" => len, "fin" => fin);
-            let frame = frame::Stream {
-                id: stream.id,
-                offset: stream.offset,
+            let len = data.len() as u64;
+            trace!(self.log, "STREAM"; "id" => id.0, "off" => offset, "len" => len, "fin" => fin);
+            frame::Stream {
+                id,
+                offset,
                 fin,
                 data,
-            };
-            frame.encode(true, buf);
-            sent.stream.push_back(frame);
-            if !stream.data.is_empty() {
-                stream.offset += len as u64;
-                space.pending.stream.push_front(stream);
             }
+            .encode(true, buf);
+            stream_frames.push(frame::StreamMeta {
+                id,
+                offsets: offset..offset + len,
+                fin,
+            });
         }
 
-        (sent, acks)
+        (sent, stream_frames, acks)
     }
 
     /// Returns packets to transmit
@@ -2134,17 +2792,18 @@ impl Connection {
             _ => {
                 let id = SpaceId::VALUES
                     .iter()
-                    .find(|&&x| self.space(x).crypto.is_some() && self.space(x).can_send())
+                    .find(|&&x| {
+                        let space = self.space(x);
+                        space.crypto.is_some() && (self.can_send(x) || space.loss_probes != 0)
+                    })
                     .cloned()
                     .or_else(|| {
-                        if self.space(SpaceId::Data).crypto.is_some() && self.can_send_1rtt() {
-                            Some(SpaceId::Data)
-                        } else if self.io.probes != 0 {
-                            Some(self.highest_space)
-                        } else if self.zero_rtt_crypto.is_some()
+                        let one_rtt = self.space(SpaceId::Data).crypto.is_some()
+                            && (self.can_send_1rtt() || self.mtu_probe_size(now).is_some());
+                        let zero_rtt = self.zero_rtt_crypto.is_some()
                             && self.side.is_client()
-                            && (self.space(SpaceId::Data).can_send() || self.can_send_1rtt())
-                        {
+                            && (self.can_send(SpaceId::Data) || self.can_send_1rtt());
+                        if one_rtt || zero_rtt {
                             Some(SpaceId::Data)
                         } else {
                             None
@@ -2153,15 +2812,34 @@ impl Connection {
                 (id, false)
             }
         };
-        let probe = !close && self.io.probes != 0;
-        let mut ack_only = self.space(space_id).pending.is_empty();
-        if space_id == SpaceId::Data && !probe && !ack_only && self.congestion_blocked() {
-            return None;
+        let probe = !close && self.space(space_id).loss_probes != 0;
+        let mtu_probe = if !close && !probe && space_id == SpaceId::Data {
+            self.mtu_probe_size(now)
+        } else {
+            None
+        };
+        let ack_only = self.ack_only(space_id) && mtu_probe.is_none();
+        if space_id == SpaceId::Data && !probe && !ack_only {
+            if self.congestion_blocked() {
+                return None;
+            }
+            if let State::Established = self.state {
+                if let Some(time) = self.pacing.delay(
+                    self.rtt.smoothed,
+                    self.mtud.mtu(),
+                    self.congestion.window(),
+                    now,
+                ) {
+                    trace!(self.log, "blocked by pacing");
+                    self.io.timer_start(Timer::Pacing, time);
+                    return None;
+                }
+            }
         }
         if self.state.is_handshake()
             && !self.remote_validated
             && self.side.is_server()
-            && self.total_recvd * 3 < self.total_sent + self.mtu as u64
+            && self.total_recvd * 3 < self.total_sent + self.mtud.mtu() as u64
         {
             trace!(self.log, "blocked by anti-amplification");
             return None;
@@ -2171,14 +2849,109 @@ impl Connection {
         // From here on, we've determined that a packet will definitely be sent.
         //
 
-        self.io.probes = self.io.probes.saturating_sub(1);
+        if probe {
+            self.space_mut(space_id).loss_probes -= 1;
+        }
+        if probe
+            && space_id == SpaceId::Data
+            && self.space(SpaceId::Data).crypto.is_some()
+            && self.params.min_ack_delay.is_some()
+        {
+            // Ensure the peer doesn't delay its response to a loss probe
+            self.immediate_ack_pending = true;
+        }
+        // Probes and close packets are sent alone, for simplicity's sake
+        let coalesce = !close && !probe && mtu_probe.is_none() && self.offpath_responses.is_empty();
+
+        let mut buf = Vec::new();
+        let remote = self.write_packet(now, space_id, close, probe, mtu_probe, &mut buf);
+        if coalesce {
+            // Fill out the datagram with packets from later spaces, e.g. a server's Handshake
+            // packet following its Initial packet.
+            let mut prev = space_id;
+            while let Some(space_id) = self.next_coalesced_space(now, prev, buf.len()) {
+                self.write_packet(now, space_id, false, false, None, &mut buf);
+                prev = space_id;
+            }
+        }
+
+        trace!(
+            self.log,
+            "{len} bytes to {remote}",
+            len = buf.len(),
+            remote = remote
+        );
+        self.total_sent = self.total_sent.wrapping_add(buf.len() as u64);
+        self.pacing.on_transmit(buf.len() as u16);
+
+        self.reset_idle_timeout(now);
+        Some(Transmit {
+            destination: remote,
+            packet: buf.into(),
+            ecn: if self.sending_ecn {
+                Some(EcnCodepoint::ECT0)
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Select the space of the next packet to coalesce into a datagram of `len` bytes, if any
+    ///
+    /// Only spaces following `prev` are considered, as the short header of 1-RTT packets lacks a
+    /// length field and hence must come last.
+    fn next_coalesced_space(&mut self, now: u64, prev: SpaceId, len: usize) -> Option<SpaceId> {
+        let id = SpaceId::VALUES
+            .iter()
+            .filter(|&&x| x > prev)
+            .find(|&&x| {
+                self.space(x).crypto.is_some()
+                    && (self.can_send(x) || (x == SpaceId::Data && self.can_send_1rtt()))
+            })
+            .cloned()?;
+        // ACK frames aren't split across packets, so make sure they'll fit; each range takes at
+        // most two 8-byte varints.
+        let ack_size = self.space(id).pending_acks.len() * 16;
+        if len + MIN_COALESCED_PACKET_SIZE + ack_size > self.mtud.mtu() as usize {
+            return None;
+        }
+        if id == SpaceId::Data && !self.ack_only(id) {
+            if self.congestion_blocked() {
+                return None;
+            }
+            if let State::Established = self.state {
+                if let Some(time) = self.pacing.delay(
+                    self.rtt.smoothed,
+                    self.mtud.mtu(),
+                    self.congestion.window(),
+                    now,
+                ) {
+                    self.io.timer_start(Timer::Pacing, time);
+                    return None;
+                }
+            }
+        }
+        Some(id)
+    }
+
+    /// Append a packet from `space_id` to the datagram `datagram`, returning its destination
+    fn write_packet(
+        &mut self,
+        now: u64,
+        space_id: SpaceId,
+        close: bool,
+        probe: bool,
+        mtu_probe: Option<u16>,
+        datagram: &mut Vec<u8>,
+    ) -> SocketAddr {
+        let mut ack_only = self.ack_only(space_id) && mtu_probe.is_none();
         if self.spaces[SpaceId::Initial as usize].crypto.is_some()
             && space_id == SpaceId::Handshake
             && self.side.is_client()
         {
             // A client stops both sending and processing Initial packets when it
             // sends its first Handshake packet.
-            self.discard_space(SpaceId::Initial)
+            self.discard_space(now, SpaceId::Initial)
         }
         if let Some(ref mut prev) = self.prev_crypto {
             prev.update_unacked = false;
@@ -2202,17 +2975,20 @@ impl Connection {
             },
             SpaceId::Data => Header::Long {
                 ty: LongType::ZeroRtt,
+                version: self.orig_version,
                 src_cid: self.handshake_cid,
                 dst_cid: self.rem_cid,
                 number,
             },
             SpaceId::Handshake => Header::Long {
                 ty: LongType::Handshake,
+                version: self.version,
                 src_cid: self.handshake_cid,
                 dst_cid: self.rem_cid,
                 number,
             },
             SpaceId::Initial => Header::Initial {
+                version: self.version,
                 src_cid: self.handshake_cid,
                 dst_cid: self.rem_cid,
                 token: match self.state {
@@ -2228,9 +3004,21 @@ impl Connection {
 
         let (remote, sent) = if close {
             trace!(self.log, "sending CONNECTION_CLOSE");
-            let max_len =
-                self.mtu as usize - header_len - space.crypto.as_ref().unwrap().packet.tag_len();
+            let max_len = self.mtud.mtu() as usize
+                - header_len
+                - space.crypto.as_ref().unwrap().packet.tag_len();
             match self.state {
+                State::Closed(state::Closed {
+                    reason: state::CloseReason::Application(_),
+                }) if space_id != SpaceId::Data => {
+                    // Application errors are confidential until the handshake is complete
+                    frame::ConnectionClose {
+                        error_code: TransportError::APPLICATION_ERROR,
+                        frame_type: None,
+                        reason: Bytes::new(),
+                    }
+                    .encode(&mut buf, max_len)
+                }
                 State::Closed(state::Closed {
                     reason: state::CloseReason::Application(ref x),
                 }) => x.encode(&mut buf, max_len),
@@ -2240,6 +3028,15 @@ impl Connection {
                 _ => unreachable!("tried to make a close packet when the connection wasn't closed"),
             }
             (self.remote, None)
+        } else if let Some(size) = mtu_probe {
+            trace!(self.log, "PING"; "mtu probe" => size);
+            buf.write(frame::Type::PING);
+            let tag_len = space.crypto.as_ref().unwrap().packet.tag_len();
+            buf.resize(size as usize - tag_len, 0);
+            (
+                self.remote,
+                Some((Retransmits::default(), Vec::new(), RangeSet::new())),
+            )
         } else if let Some((remote, token)) = self.offpath_responses.pop() {
             // For simplicity's sake, we don't bother trying to batch together or deduplicate path
             // validation probes.
@@ -2248,13 +3045,16 @@ impl Connection {
             buf.write(token);
             (remote, None)
         } else {
+            // Loss probes are limited to the minimum MTU, so that they get through even if the
+            // path MTU has shrunk, allowing the loss of larger packets to be detected.
+            let mtu = if probe { MIN_MTU } else { self.mtud.mtu() } - datagram.len() as u16;
             (
                 self.remote,
-                Some(self.populate_packet(now, space_id, &mut buf)),
+                Some(self.populate_packet(now, space_id, mtu, &mut buf)),
             )
         };
 
-        if probe && ack_only && !self.state.is_handshake() {
+        if probe && ack_only {
             // Nothing ack-eliciting to send, so we need to make something up
             trace!(self.log, "PING");
             buf.write(frame::Type::PING);
@@ -2294,11 +3094,19 @@ impl Connection {
         crypto.packet.encrypt(exact_number, &mut buf, header_len);
         partial_encode.finish(&mut buf, &crypto.header);
 
-        if let Some((sent, acks)) = sent {
+        if let Some((sent, stream_frames, acks)) = sent {
             // If we sent any acks, don't immediately resend them. Setting this even if ack_only is
             // false needlessly prevents us from ACKing the next packet if it's ACK-only, but saves
             // the need for subtler logic to avoid double-transmitting acks all the time.
             space.permit_ack_only &= acks.is_empty();
+            if !acks.is_empty() {
+                space.unacked_ack_eliciting = 0;
+                space.immediate_ack_required = false;
+                space.rx_reordered = false;
+                if space_id == SpaceId::Data {
+                    self.io.timer_stop(Timer::MaxAckDelay);
+                }
+            }
 
             self.on_packet_sent(
                 now,
@@ -2312,38 +3120,26 @@ impl Connection {
                     } else {
                         0
                     },
-                    is_crypto_packet: space_id != SpaceId::Data && !ack_only,
                     ack_eliciting: !ack_only,
+                    is_mtu_probe: mtu_probe.is_some(),
                     retransmits: sent,
+                    stream_frames,
                 },
             );
+            if let Some(size) = mtu_probe {
+                self.mtud.on_probe_sent(size);
+            }
         }
 
-        trace!(
-            self.log,
-            "{len} bytes to {remote}",
-            len = buf.len(),
-            remote = remote
-        );
-        self.total_sent = self.total_sent.wrapping_add(buf.len() as u64);
-
-        self.reset_idle_timeout(now);
-        Some(Transmit {
-            destination: remote,
-            packet: buf.into(),
-            ecn: if self.sending_ecn {
-                Some(EcnCodepoint::ECT0)
-            } else {
-                None
-            },
-        })
+        datagram.extend_from_slice(&buf);
+        remote
     }
 
     /// Close a connection immediately
     ///
     /// This does not ensure delivery of outstanding data. It is the application's responsibility
     /// to call this only when all important communications have been completed.
-    pub fn close(&mut self, now: u64, error_code: u16, reason: Bytes) {
+    pub fn close(&mut self, now: u64, error_code: u64, reason: Bytes) {
         let was_closed = self.state.is_closed();
         let reason =
             state::CloseReason::Application(frame::ApplicationClose { error_code, reason });
@@ -2367,25 +3163,137 @@ impl Connection {
         self.io.timer_stop(Timer::Idle);
         self.io.timer_stop(Timer::KeyDiscard);
         self.io.timer_stop(Timer::PathValidation);
-        self.io.timer_start(Timer::Close, now + 3 * self.pto());
+        self.io
+            .timer_start(Timer::Close, now + 3 * self.pto(SpaceId::Data));
     }
 
-    fn set_params(&mut self, params: TransportParameters) -> Result<(), TransportError> {
-        // Validate
-        if self.side.is_client() && self.orig_rem_cid != params.original_connection_id {
+    /// Check that the connection IDs in the peer's transport parameters match those actually used
+    /// during the handshake, proving that they weren't tampered with
+    fn validate_params(&self, params: &TransportParameters) -> Result<(), TransportError> {
+        if params.initial_src_cid != Some(self.rem_handshake_cid) {
+            debug!(
+                self.log,
+                "initial source connection ID mismatch: expected {expected}, actual {actual:x?}",
+                expected = self.rem_handshake_cid,
+                actual = params.initial_src_cid
+            );
+            return Err(TransportError::TRANSPORT_PARAMETER_ERROR);
+        }
+        if self.side.is_client()
+            && (params.original_dst_cid != Some(self.init_cid)
+                || params.retry_src_cid != self.retry_src_cid)
+        {
             debug!(
                 self.log,
-                "original connection ID mismatch: expected {expected:x?}, actual {actual:x?}",
-                expected = self.orig_rem_cid,
-                actual = params.original_connection_id
+                "original or retry connection ID mismatch: expected {orig}, {retry:x?}, actual {orig_actual:x?}, {retry_actual:x?}",
+                orig = self.init_cid,
+                retry = self.retry_src_cid,
+                orig_actual = params.original_dst_cid,
+                retry_actual = params.retry_src_cid
             );
             return Err(TransportError::TRANSPORT_PARAMETER_ERROR);
         }
+        self.validate_version_information(params)
+    }
 
-        // Apply
+    /// Check that the peer's view of version negotiation matches ours, per RFC 9368
+    fn validate_version_information(
+        &self,
+        params: &TransportParameters,
+    ) -> Result<(), TransportError> {
+        let info = match params.version_information {
+            Some(ref x) => x,
+            // Having switched versions, we must confirm the server would have made the same choice
+            None if self.version_negotiated => {
+                debug!(
+                    self.log,
+                    "version information missing after version negotiation"
+                );
+                return Err(TransportError::VERSION_NEGOTIATION_ERROR);
+            }
+            None => return Ok(()),
+        };
+        // A client's choice is the version of its first Initial, while a server's is the one it
+        // continued the connection in
+        let expected = if self.side.is_server() {
+            self.orig_version
+        } else {
+            self.version
+        };
+        if info.chosen != expected {
+            debug!(
+                self.log,
+                "chosen version mismatch: expected {expected:x}, actual {actual:x}",
+                expected = expected,
+                actual = info.chosen
+            );
+            return Err(TransportError::VERSION_NEGOTIATION_ERROR);
+        }
+        if self.version_negotiated && self.choose_version(&info.available) != Some(self.version) {
+            // An attacker forged the Version Negotiation packet to force a downgrade
+            debug!(self.log, "version downgrade detected");
+            return Err(TransportError::VERSION_NEGOTIATION_ERROR);
+        }
+        Ok(())
+    }
+
+    /// Whether a client may follow the server to `version` upon receiving its first Initial
+    fn can_switch_version(&self, version: u32) -> bool {
+        let awaiting_server = match self.state {
+            State::Handshake(ref state) => !state.rem_cid_set,
+            _ => false,
+        };
+        self.side.is_client()
+            && awaiting_server
+            && self.version == self.orig_version
+            && self.config.supported_versions.contains(&version)
+    }
+
+    /// Our most preferred version among those `offered` by the peer
+    fn choose_version(&self, offered: &[u32]) -> Option<u32> {
+        self.config
+            .supported_versions
+            .iter()
+            .cloned()
+            .find(|x| offered.contains(x))
+    }
+
+    /// Begin the handshake afresh, e.g. after a Retry or Version Negotiation packet
+    fn restart_handshake(&mut self, now: u64) {
+        self.on_packet_acked(now, SpaceId::Initial, 0);
+
+        let client_config = self.client_config.as_ref().unwrap();
+        self.tls = TlsSession::new_client(
+            &client_config.tls_config,
+            &client_config.server_name,
+            &TransportParameters {
+                initial_src_cid: Some(self.handshake_cid),
+                version_information: Some(VersionInformation {
+                    chosen: self.version,
+                    available: self.config.supported_versions.clone(),
+                }),
+                ..TransportParameters::new(&self.config)
+            },
+        )
+        .unwrap();
+        self.discard_space(now, SpaceId::Initial); // Make sure we clean up after any retransmitted Initials
+        self.spaces[0] = PacketSpace {
+            crypto: Some(CryptoSpace::new(Crypto::new_initial(
+                self.version,
+                &self.rem_cid,
+                self.side,
+            ))),
+            ..PacketSpace::new()
+        };
+
+        self.write_tls();
+    }
+
+    fn set_params(&mut self, params: TransportParameters) -> Result<(), TransportError> {
         self.streams.max_bi = params.initial_max_streams_bidi;
         self.streams.max_uni = params.initial_max_streams_uni;
         self.max_data = params.initial_max_data as u64;
+        self.mtud.set_peer_max(params.max_udp_payload_size);
         for i in 0..self.streams.max_remote_bi {
             let id = StreamId::new(!self.side, Directionality::Bi, i as u64);
             self.streams.get_send_mut(id).unwrap().max_data =
@@ -2406,16 +3314,32 @@ impl Connection {
             }
             Directionality::Bi if self.streams.next_bi < self.streams.max_bi => {
                 self.streams.next_bi += 1;
-                let mut stream = Stream::new_bi();
+                let mut stream = Stream::new_bi(
+                    self.config.stream_receive_window,
+                    self.stream_receive_window,
+                );
                 stream.recv_mut().unwrap().fresh = false;
                 (
                     StreamId::new(self.side, direction, self.streams.next_bi - 1),
                     stream,
                 )
             }
-            _ => {
+            Directionality::Uni => {
+                let max = self.streams.max_uni;
+                if self.streams.uni_blocked_at != Some(max) {
+                    self.streams.uni_blocked_at = Some(max);
+                    self.space_mut(SpaceId::Data).pending.uni_streams_blocked = true;
+                }
+                return None;
+            }
+            Directionality::Bi => {
+                let max = self.streams.max_bi;
+                if self.streams.bi_blocked_at != Some(max) {
+                    self.streams.bi_blocked_at = Some(max);
+                    self.space_mut(SpaceId::Data).pending.bi_streams_blocked = true;
+                }
                 return None;
-            } // TODO: Queue STREAM_ID_BLOCKED
+            }
         };
         stream.send_mut().unwrap().max_data = match direction {
             Directionality::Uni => self.params.initial_max_stream_data_uni,
@@ -2460,7 +3384,10 @@ impl Connection {
                         Directionality::Bi,
                         self.streams.max_remote_bi - 1,
                     ),
-                    Stream::new_bi(),
+                    Stream::new_bi(
+                        self.config.stream_receive_window,
+                        self.stream_receive_window,
+                    ),
                 )
             }
             Directionality::Uni => {
@@ -2472,7 +3399,11 @@ impl Connection {
                         Directionality::Uni,
                         self.streams.max_remote_uni - 1,
                     ),
-                    stream::Recv::new().into(),
+                    stream::Recv::new(
+                        self.config.stream_receive_window,
+                        self.stream_receive_window,
+                    )
+                    .into(),
                 )
             }
         };
@@ -2481,30 +3412,115 @@ impl Connection {
 
     pub fn accept(&mut self) -> Option<StreamId> {
         let id = self.streams.incoming.pop_front()?;
-        self.alloc_remote_stream(id.directionality());
+        let debt = match id.directionality() {
+            Directionality::Uni => &mut self.streams.uni_credit_debt,
+            Directionality::Bi => &mut self.streams.bi_credit_debt,
+        };
+        if *debt > 0 {
+            *debt -= 1;
+        } else {
+            self.alloc_remote_stream(id.directionality());
+        }
         Some(id)
     }
 
+    /// Set the number of remotely initiated `dir` streams that may be concurrently open
+    ///
+    /// Streams count as open until accepted by the application. Raising the limit takes effect
+    /// immediately. Because stream credit can't be revoked, lowering it takes effect gradually:
+    /// no further streams are permitted until enough have been accepted to bring the total under
+    /// the new limit.
+    pub fn set_max_concurrent_streams(&mut self, dir: Directionality, count: u64) {
+        let (max, debt) = match dir {
+            Directionality::Uni => (
+                &mut self.streams.max_concurrent_uni,
+                &mut self.streams.uni_credit_debt,
+            ),
+            Directionality::Bi => (
+                &mut self.streams.max_concurrent_bi,
+                &mut self.streams.bi_credit_debt,
+            ),
+        };
+        let mut new = 0;
+        if count > *max {
+            let increase = count - *max;
+            let repaid = cmp::min(*debt, increase);
+            *debt -= repaid;
+            new = increase - repaid;
+        } else {
+            *debt += *max - count;
+        }
+        *max = count;
+        for _ in 0..new {
+            self.alloc_remote_stream(dir);
+        }
+    }
+
+    /// Set the connection-level receive window
+    ///
+    /// The peer is permitted to send this many bytes beyond those consumed by the application
+    /// across all streams. Disables automatic tuning beyond this size. Because flow control credit
+    /// can't be revoked, a reduction takes effect only once the application has caught up.
+    pub fn set_receive_window(&mut self, window: u64) {
+        self.receive_window = stream::RecvWindow::new(window);
+        self.max_receive_window = window;
+        if self.data_consumed + window > self.local_max_data {
+            self.space_mut(SpaceId::Data).pending.max_data = true;
+        }
+    }
+
+    /// Set the receive window of every stream, including those yet to be opened
+    ///
+    /// Like `set_receive_window`, but applies independently to each stream.
+    pub fn set_stream_receive_window(&mut self, window: u64) {
+        self.stream_receive_window = window;
+        self.max_stream_receive_window = window;
+        let space = &mut self.spaces[SpaceId::Data as usize];
+        for (&id, stream) in &mut self.streams.streams {
+            let rs = match stream.recv_mut() {
+                Some(x) => x,
+                None => continue,
+            };
+            rs.window = stream::RecvWindow::new(window);
+            // Don't issue credit for streams the peer hasn't opened, which would be illegal
+            if !rs.fresh && rs.needs_credit() && rs.receiving_unknown_size() {
+                space.pending.max_stream_data.insert(id);
+            }
+        }
+    }
+
     pub fn finish(&mut self, id: StreamId) {
         let ss = self
             .streams
             .get_send_mut(id)
             .expect("unknown or recv-only stream");
         assert_eq!(ss.state, stream::SendState::Ready);
-        ss.state = stream::SendState::DataSent;
-        let space = &mut self.spaces[SpaceId::Data as usize];
-        for frame in &mut space.pending.stream {
-            if frame.id == id && frame.offset + frame.data.len() as u64 == ss.offset {
-                frame.fin = true;
-                return;
-            }
+        ss.state = stream::SendState::DataSent {
+            finish_acked: false,
+        };
+        let was_pending = ss.is_pending();
+        ss.fin_pending = true;
+        if !was_pending {
+            let priority = ss.priority;
+            self.streams.pending.push(id, priority);
+        }
+    }
+
+    /// Set the priority of a stream
+    ///
+    /// Pending data on streams of higher priority is always sent before that of lower priority.
+    /// Streams of equal priority take turns. Streams start with a priority of 0.
+    pub fn set_priority(&mut self, id: StreamId, priority: i32) {
+        // set_priority is a noop on a closed stream
+        let ss = if let Some(x) = self.streams.get_send_mut(id) {
+            x
+        } else {
+            return;
+        };
+        let old = mem::replace(&mut ss.priority, priority);
+        if old != priority && self.streams.pending.remove(id, old) {
+            self.streams.pending.push(id, priority);
         }
-        space.pending.stream.push_back(frame::Stream {
-            id,
-            data: Bytes::new(),
-            offset: ss.offset,
-            fin: true,
-        });
     }
 
     pub fn read_unordered(&mut self, id: StreamId) -> Result<(Bytes, u64), ReadError> {
@@ -2512,11 +3528,12 @@ impl Connection {
             .streams
             .get_recv_mut(id)
             .expect("not an open recv stream");
+        // Buffered data becomes readable regardless of gaps once reads are unordered
+        self.out_of_order_data -= rs.out_of_order_len();
         let (buf, len) = rs.read_unordered()?;
         // TODO: Reduce granularity of flow control credit, while still avoiding stalls, to
         // reduce overhead
-        self.local_max_data += buf.len() as u64; // BUG: Don't issue credit for
-                                                 // already-received data!
+        self.data_consumed += buf.len() as u64;
         let space = &mut self.spaces[SpaceId::Data as usize];
         space.pending.max_data = true;
         if rs.receiving_unknown_size() {
@@ -2534,7 +3551,7 @@ impl Connection {
         let len = rs.read(buf)?;
         // TODO: Reduce granularity of flow control credit, while still avoiding stalls, to
         // reduce overhead
-        self.local_max_data += len as u64;
+        self.data_consumed += len as u64;
         let space = &mut self.spaces[SpaceId::Data as usize];
         space.pending.max_data = true;
         if rs.receiving_unknown_size() {
@@ -2544,7 +3561,7 @@ impl Connection {
         Ok(len)
     }
 
-    pub fn stop_sending(&mut self, id: StreamId, error_code: u16) {
+    pub fn stop_sending(&mut self, id: StreamId, error_code: u64) {
         assert!(
             id.directionality() == Directionality::Bi || id.initiator() != self.side,
             "only streams supporting incoming data may be stopped"
@@ -2565,12 +3582,25 @@ impl Connection {
 
     fn congestion_blocked(&self) -> bool {
         if let State::Established = self.state {
-            self.congestion_window.saturating_sub(self.in_flight.bytes) < self.mtu as u64
+            self.congestion.window().saturating_sub(self.in_flight.bytes) < self.mtud.mtu() as u64
         } else {
             false
         }
     }
 
+    /// Size of the path MTU discovery probe to send now, if any
+    fn mtu_probe_size(&self, now: u64) -> Option<u16> {
+        match self.state {
+            State::Established => {}
+            _ => return None,
+        }
+        let size = self.mtud.probe_size(now)?;
+        if self.in_flight.bytes + u64::from(size) > self.congestion.window() {
+            return None;
+        }
+        Some(size)
+    }
+
     fn blocked(&self) -> bool {
         self.data_sent >= self.max_data || self.congestion_blocked()
     }
@@ -2580,8 +3610,8 @@ impl Connection {
         now: u64,
         packet: &mut Packet,
     ) -> Result<Option<u64>, Option<TransportError>> {
-        if packet.header.is_retry() {
-            // Retry packets are not encrypted and have no packet number
+        if packet.header.is_retry() || packet.header.is_version_negotiate() {
+            // Retry and Version Negotiation packets are not encrypted and have no packet number
             return Ok(None);
         }
         let number = packet
@@ -2612,7 +3642,7 @@ impl Connection {
                     .as_ref()
                     .unwrap()
                     .packet
-                    .update(self.side, &self.tls),
+                    .update(self.side),
             );
             crypto_update.as_ref().unwrap()
         };
@@ -2632,7 +3662,7 @@ impl Connection {
             if prev.update_ack_time.is_none() && key_phase == self.key_phase {
                 // Key update newly acknowledged
                 prev.update_ack_time = Some(now);
-                self.set_key_discard_timer(now);
+                self.set_key_discard_timer();
             }
         }
 
@@ -2659,7 +3689,7 @@ impl Connection {
             self.update_keys(crypto, number, true);
             // No need to wait for confirmation of a remotely-initiated key update
             self.prev_crypto.as_mut().unwrap().update_ack_time = Some(now);
-            self.set_key_discard_timer(now);
+            self.set_key_discard_timer();
         }
 
         Ok(Some(number))
@@ -2667,12 +3697,7 @@ impl Connection {
 
     pub fn force_key_update(&mut self) {
         let space = self.space(SpaceId::Data);
-        let update = space
-            .crypto
-            .as_ref()
-            .unwrap()
-            .packet
-            .update(self.side, &self.tls);
+        let update = space.crypto.as_ref().unwrap().packet.update(self.side);
         self.update_keys(update, space.next_packet_number, false);
     }
 
@@ -2689,6 +3714,9 @@ impl Connection {
             } else {
                 trace!(self.log, "write blocked by connection-level flow control"; "stream" => stream.0);
             }
+            if self.data_sent >= self.max_data {
+                self.queue_data_blocked();
+            }
             self.blocked_streams.insert(stream);
             return Err(WriteError::Blocked);
         }
@@ -2707,17 +3735,49 @@ impl Connection {
             }
             Err(e @ WriteError::Blocked) => {
                 trace!(self.log, "write blocked by flow control"; "stream" => stream.0);
+                self.queue_stream_data_blocked(stream);
                 return Err(e);
             }
         };
 
         let conn_budget = self.max_data - self.data_sent;
         let n = conn_budget.min(stream_budget).min(data.len() as u64) as usize;
+        // Let the peer know if it's holding us back
+        if n < data.len() {
+            if n as u64 == conn_budget {
+                self.queue_data_blocked();
+            }
+            if n as u64 == stream_budget {
+                self.queue_stream_data_blocked(stream);
+            }
+        }
         self.queue_stream_data(stream, (&data[0..n]).into());
         trace!(self.log, "write"; "stream" => stream.0, "len" => n);
         Ok(n)
     }
 
+    /// Queue a DATA_BLOCKED frame, unless one was already sent for the current limit
+    fn queue_data_blocked(&mut self) {
+        if self.data_blocked_at == Some(self.max_data) {
+            return;
+        }
+        self.data_blocked_at = Some(self.max_data);
+        self.space_mut(SpaceId::Data).pending.data_blocked = true;
+    }
+
+    /// Queue a STREAM_DATA_BLOCKED frame, unless one was already sent for the current limit
+    fn queue_stream_data_blocked(&mut self, id: StreamId) {
+        let ss = self.streams.get_send_mut(id).unwrap();
+        if ss.blocked_at == Some(ss.max_data) {
+            return;
+        }
+        ss.blocked_at = Some(ss.max_data);
+        self.spaces[SpaceId::Data as usize]
+            .pending
+            .stream_data_blocked
+            .insert(id);
+    }
+
     fn update_keys(&mut self, crypto: Crypto, number: u64, remote: bool) {
         let old = mem::replace(
             &mut self.spaces[SpaceId::Data as usize]
@@ -2752,6 +3812,14 @@ impl Connection {
         self.zero_rtt_crypto.is_some()
     }
 
+    /// Whether any data on `id` was received in 0-RTT packets
+    ///
+    /// Such data may have been replayed by an attacker, so should not trigger non-idempotent
+    /// operations.
+    pub fn received_0rtt(&self, id: StreamId) -> bool {
+        self.streams.get_recv(id).map_or(false, |rs| rs.zero_rtt)
+    }
+
     pub fn has_1rtt(&self) -> bool {
         self.spaces[SpaceId::Data as usize].crypto.is_some()
     }
@@ -2760,6 +3828,16 @@ impl Connection {
         self.state.is_drained()
     }
 
+    /// Number of connection IDs the peer is willing to store for us, including the active one
+    pub(crate) fn peer_cid_limit(&self) -> u64 {
+        self.params.active_connection_id_limit
+    }
+
+    /// QUIC version in use
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
     /// Look up whether we're the client or server of this Connection
     pub fn side(&self) -> Side {
         self.side
@@ -2783,6 +3861,15 @@ impl Connection {
         self.tls.get_alpn_protocol()
     }
 
+    /// The certificate chain the peer authenticated with, if any
+    ///
+    /// Clients only present a chain when the server requests client authentication. Not available
+    /// until the handshake completes, nor on connections that resumed an earlier session, including
+    /// any that sent 0-RTT data, because the chain isn't retained across resumption.
+    pub fn peer_certificates(&self) -> Option<Vec<crypto::Certificate>> {
+        self.tls.get_peer_certificates()
+    }
+
     /// The number of bytes of packets containing retransmittable frames that have not been
     /// acknowledged or declared lost.
     pub fn bytes_in_flight(&self) -> u64 {
@@ -2791,7 +3878,57 @@ impl Connection {
 
     /// Number of bytes worth of non-ack-only packets that may be sent
     pub fn congestion_state(&self) -> u64 {
-        self.congestion_window.saturating_sub(self.in_flight.bytes)
+        self.congestion.window().saturating_sub(self.in_flight.bytes)
+    }
+
+    /// Largest UDP payload that may currently be sent, as determined by path MTU discovery
+    pub fn mtu(&self) -> u16 {
+        self.mtud.mtu()
+    }
+
+    /// Queue an unreliable, unordered datagram for transmission
+    ///
+    /// Datagrams are sent ahead of stream data, but are never retransmitted. Should they be
+    /// submitted faster than they can be sent, the oldest are dropped.
+    pub fn send_datagram(&mut self, data: Bytes) -> Result<(), SendDatagramError> {
+        let max = self
+            .max_datagram_size()
+            .ok_or(SendDatagramError::UnsupportedByPeer)?;
+        if data.len() > max {
+            return Err(SendDatagramError::TooLarge);
+        }
+        self.datagrams
+            .send(data, self.config.datagram_send_buffer_size);
+        Ok(())
+    }
+
+    /// Take a received datagram, if any
+    pub fn recv_datagram(&mut self) -> Option<Bytes> {
+        self.datagrams.recv()
+    }
+
+    /// Largest datagram that may currently be sent, or `None` if the peer doesn't accept them
+    ///
+    /// Depends on the path MTU, so may change over the lifetime of the connection.
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        let limit = self.params.max_datagram_frame_size?;
+        // A 1-RTT packet with the longest possible packet number, carrying only the frame
+        let tag_len = self
+            .space(SpaceId::Data)
+            .crypto
+            .as_ref()
+            .map_or(16, |x| x.packet.tag_len());
+        let overhead = 1 + self.rem_cid.len() + 4 + tag_len;
+        let frame_size = cmp::min(limit, u64::from(self.mtud.mtu()) - overhead as u64) as usize;
+        Some(frame_size.saturating_sub(frame::Datagram::SIZE_BOUND))
+    }
+
+    /// Total number of outgoing datagrams dropped because they exceeded `max_datagram_size`
+    ///
+    /// Datagrams that were small enough when queued may become too large to send if the path MTU
+    /// shrinks.
+    pub fn dropped_datagrams(&self) -> u64 {
+        self.datagrams.send_dropped()
     }
 
     /// The name a client supplied via SNI
@@ -2806,6 +3943,21 @@ impl Connection {
         self.lost_packets
     }
 
+    /// Total number of outgoing packets deemed lost that were later acknowledged
+    ///
+    /// Such packets were delayed by reordering rather than lost, and are included in
+    /// `lost_packets`.
+    pub fn spurious_losses(&self) -> u64 {
+        self.spurious_losses
+    }
+
+    /// Total number of DATA_BLOCKED, STREAM_DATA_BLOCKED, and STREAMS_BLOCKED frames sent
+    ///
+    /// Each indicates that the peer's flow control or stream limits held up the application.
+    pub fn blocked_frames_sent(&self) -> u64 {
+        self.blocked_frames_sent
+    }
+
     /// Whether explicit congestion notification is in use on outgoing packets.
     pub fn using_ecn(&self) -> bool {
         self.sending_ecn
@@ -2813,7 +3965,9 @@ impl Connection {
 
     /// Microseconds
     fn max_ack_delay(&self) -> u64 {
-        u64::from(self.params.max_ack_delay) * 1000
+        self.ack_frequency
+            .peer_max_ack_delay()
+            .unwrap_or_else(|| u64::from(self.params.max_ack_delay) * 1000)
     }
 
     fn space(&self, id: SpaceId) -> &PacketSpace {
@@ -2826,12 +3980,26 @@ impl Connection {
 
     /// Whether we have non-retransmittable 1-RTT data to send
     ///
-    /// See also `self.space(SpaceId::Data).can_send()`
+    /// See also `self.can_send(SpaceId::Data)`
     fn can_send_1rtt(&self) -> bool {
         self.path_challenge_pending
             || self.ping_pending
             || self.path_response.is_some()
             || !self.offpath_responses.is_empty()
+            || (self.datagrams.has_outgoing() && self.space(SpaceId::Data).crypto.is_some())
+    }
+
+    /// Whether there is retransmittable data, or an ACK that can't wait, to send in `space`
+    fn can_send(&self, space: SpaceId) -> bool {
+        self.space(space).can_send() || (space == SpaceId::Data && !self.streams.pending.is_empty())
+    }
+
+    /// Whether a packet sent in `space` now would contain only ACK frames
+    fn ack_only(&self, space: SpaceId) -> bool {
+        self.space(space).pending.is_empty()
+            && !(space == SpaceId::Data
+                && (!self.streams.pending.is_empty()
+                    || (self.space(space).crypto.is_some() && self.datagrams.has_outgoing())))
     }
 
     /// Reset state to account for 0-RTT being ignored by the server
@@ -2848,11 +4016,12 @@ impl Connection {
         }
         // Discard already-queued frames
         self.space_mut(SpaceId::Data).pending = Retransmits::default();
+        self.streams.pending.clear();
         // Discard 0-RTT packets
-        let sent_packets = mem::replace(
-            &mut self.space_mut(SpaceId::Data).sent_packets,
-            BTreeMap::new(),
-        );
+        let space = self.space_mut(SpaceId::Data);
+        space.ack_eliciting_in_flight = 0;
+        space.declared_lost.clear();
+        let sent_packets = mem::replace(&mut space.sent_packets, BTreeMap::new());
         for (_, packet) in sent_packets {
             self.in_flight.remove(&packet);
         }
@@ -2862,6 +4031,7 @@ impl Connection {
 }
 
 pub fn initial_close<R>(
+    version: u32,
     crypto: &Crypto,
     header_crypto: &HeaderCrypto,
     remote_id: &ConnectionId,
@@ -2874,6 +4044,7 @@ where
 {
     let number = PacketNumber::U8(packet_number);
     let header = Header::Initial {
+        version,
         dst_cid: *remote_id,
         src_cid: *local_id,
         number,
@@ -2905,9 +4076,21 @@ struct Streams {
     // Remotely initiated
     max_remote_uni: u64,
     max_remote_bi: u64,
+    /// Number of remotely initiated streams that may be concurrently open
+    max_concurrent_uni: u64,
+    max_concurrent_bi: u64,
+    /// Number of future remote streams whose acceptance shouldn't permit another, due to a
+    /// reduction in the number that may be concurrently open
+    uni_credit_debt: u64,
+    bi_credit_debt: u64,
+    /// Values of `max_uni` and `max_bi` most recently reported in STREAMS_BLOCKED frames
+    uni_blocked_at: Option<u64>,
+    bi_blocked_at: Option<u64>,
 
     finished: Vec<StreamId>,
     incoming: VecDeque<StreamId>,
+    /// Streams with data or a FIN bit to send
+    pending: stream::PendingStreams,
 }
 
 impl Streams {
@@ -2938,6 +4121,10 @@ impl Streams {
         Ok(self.streams.get_mut(&id))
     }
 
+    fn get_recv(&self, id: StreamId) -> Option<&stream::Recv> {
+        self.streams.get(&id)?.recv()
+    }
+
     fn get_recv_mut(&mut self, id: StreamId) -> Option<&mut stream::Recv> {
         self.streams.get_mut(&id)?.recv_mut()
     }
@@ -2945,6 +4132,21 @@ impl Streams {
     fn get_send_mut(&mut self, id: StreamId) -> Option<&mut stream::Send> {
         self.streams.get_mut(&id)?.send_mut()
     }
+
+    /// Queue the contents of a lost STREAM frame for retransmission
+    fn retransmit(&mut self, frame: frame::StreamMeta) {
+        let id = frame.id;
+        let ss = match self.get_send_mut(id) {
+            Some(ss) if !ss.state.was_reset() => ss,
+            _ => return,
+        };
+        let was_pending = ss.is_pending();
+        ss.retransmit(frame);
+        if !was_pending && ss.is_pending() {
+            let priority = ss.priority;
+            self.pending.push(id, priority);
+        }
+    }
 }
 
 /// Retransmittable data queue
@@ -2953,13 +4155,20 @@ struct Retransmits {
     max_data: bool,
     max_uni_stream_id: bool,
     max_bi_stream_id: bool,
-    stream: VecDeque<frame::Stream>,
-    rst_stream: Vec<(StreamId, u16)>,
-    stop_sending: Vec<(StreamId, u16)>,
+    data_blocked: bool,
+    stream_data_blocked: FnvHashSet<StreamId>,
+    uni_streams_blocked: bool,
+    bi_streams_blocked: bool,
+    rst_stream: Vec<(StreamId, u64)>,
+    stop_sending: Vec<(StreamId, u64)>,
     max_stream_data: FnvHashSet<StreamId>,
     crypto: VecDeque<frame::Crypto>,
     new_cids: Vec<frame::NewConnectionId>,
     retire_cids: Vec<u64>,
+    new_tokens: Vec<Bytes>,
+    /// Sequence number of an ACK_FREQUENCY frame
+    ack_frequency: Option<u64>,
+    handshake_done: bool,
 }
 
 impl Retransmits {
@@ -2967,13 +4176,19 @@ impl Retransmits {
         !self.max_data
             && !self.max_uni_stream_id
             && !self.max_bi_stream_id
-            && self.stream.is_empty()
+            && !self.data_blocked
+            && self.stream_data_blocked.is_empty()
+            && !self.uni_streams_blocked
+            && !self.bi_streams_blocked
             && self.rst_stream.is_empty()
             && self.stop_sending.is_empty()
             && self.max_stream_data.is_empty()
             && self.crypto.is_empty()
             && self.new_cids.is_empty()
             && self.retire_cids.is_empty()
+            && self.new_tokens.is_empty()
+            && self.ack_frequency.is_none()
+            && !self.handshake_done
     }
 }
 
@@ -2983,13 +4198,19 @@ impl Default for Retransmits {
             max_data: false,
             max_uni_stream_id: false,
             max_bi_stream_id: false,
-            stream: VecDeque::new(),
+            data_blocked: false,
+            stream_data_blocked: FnvHashSet::default(),
+            uni_streams_blocked: false,
+            bi_streams_blocked: false,
             rst_stream: Vec::new(),
             stop_sending: Vec::new(),
             max_stream_data: FnvHashSet::default(),
             crypto: VecDeque::new(),
             new_cids: Vec::new(),
             retire_cids: Vec::new(),
+            new_tokens: Vec::new(),
+            ack_frequency: None,
+            handshake_done: false,
         }
     }
 }
@@ -2999,13 +4220,19 @@ impl ::std::ops::AddAssign for Retransmits {
         self.max_data |= rhs.max_data;
         self.max_uni_stream_id |= rhs.max_uni_stream_id;
         self.max_bi_stream_id |= rhs.max_bi_stream_id;
-        self.stream.extend(rhs.stream.into_iter());
+        self.data_blocked |= rhs.data_blocked;
+        self.stream_data_blocked.extend(&rhs.stream_data_blocked);
+        self.uni_streams_blocked |= rhs.uni_streams_blocked;
+        self.bi_streams_blocked |= rhs.bi_streams_blocked;
         self.rst_stream.extend_from_slice(&rhs.rst_stream);
         self.stop_sending.extend_from_slice(&rhs.stop_sending);
         self.max_stream_data.extend(&rhs.max_stream_data);
         self.crypto.extend(rhs.crypto.into_iter());
         self.new_cids.extend(&rhs.new_cids);
         self.retire_cids.extend(rhs.retire_cids);
+        self.new_tokens.extend(rhs.new_tokens);
+        self.ack_frequency = cmp::max(self.ack_frequency, rhs.ack_frequency);
+        self.handshake_done |= rhs.handshake_done;
     }
 }
 
@@ -3174,6 +4401,8 @@ mod state {
 pub struct ClientConfig {
     pub server_name: String,
     pub tls_config: Arc<crypto::ClientConfig>,
+    /// Address validation token from a previous connection to the server, if any
+    pub token: Option<Bytes>,
 }
 
 /// Represents one or more packets subject to retransmission
@@ -3187,16 +4416,49 @@ struct SentPacket {
     size: u16,
     /// Whether an acknowledgement is expected directly in response to this packet.
     ack_eliciting: bool,
-    /// Whether the packet contains cryptographic handshake messages critical to the completion of
-    /// the QUIC handshake.
-    // FIXME: Implied by retransmits + space
-    is_crypto_packet: bool,
+    /// Whether the packet is a path MTU discovery probe
+    is_mtu_probe: bool,
     acks: RangeSet,
     retransmits: Retransmits,
+    /// Metadata for stream frames, whose contents are retransmitted from the streams' send buffers
+    stream_frames: Vec<frame::StreamMeta>,
+}
+
+/// A packet deemed lost, retained in case it's acknowledged after all
+#[derive(Debug, Copy, Clone)]
+struct LostPacket {
+    /// The time the packet was deemed lost
+    time_lost: u64,
+    /// The largest packet number acknowledged in the same space at that time
+    largest_acked: u64,
+    /// Start of the congestion event the loss contributed to, if any
+    loss_event: Option<u64>,
+}
+
+/// A congestion controller response to loss that may be reverted if the loss proves spurious
+#[derive(Debug, Copy, Clone)]
+struct LossEvent {
+    /// Time at which the event began, matching the controller's start of recovery
+    start: u64,
+    /// Number of packets deemed lost in this event that haven't since been acknowledged
+    unconfirmed: u64,
+    /// Whether the event may be reverted, i.e. wasn't also caused by ECN or persistent congestion
+    undoable: bool,
 }
 
 /// Ensures we can always fit all our ACKs in a single minimum-MTU packet with room to spare
 const MAX_ACK_BLOCKS: usize = 64;
+/// Smallest amount of room left in a datagram worth filling with another packet
+///
+/// Leaves space for a long header with maximum-length connection IDs, an AEAD tag, and a little
+/// payload.
+const MIN_COALESCED_PACKET_SIZE: usize = 128;
+/// Largest exponent of the PTO backoff, beyond which it stops growing
+const MAX_BACKOFF_EXPONENT: u32 = 16;
+/// Largest packet reordering threshold that spurious loss detection may widen to
+const MAX_PACKET_THRESHOLD: u64 = 64;
+/// Largest time reordering threshold that spurious loss detection may widen to, i.e. one RTT
+const MAX_TIME_THRESHOLD: u32 = 0x10000;
 
 /// I/O operations to be immediately executed the backend.
 #[derive(Debug)]
@@ -3210,24 +4472,22 @@ pub enum Io {
 /// Encoding of I/O operations to emit on upcoming `poll_io` calls
 #[derive(Debug)]
 struct IoQueue {
-    /// Number of probe packets to transmit
-    probes: u8,
     /// Whether to transmit a close packet
     close: bool,
-    /// Changes to the loss detection, idle, and close timers, in that order
+    /// Changes to the loss detection, idle, close, key discard, path validation, pacing, and
+    /// delayed ACK timers, in that order
     ///
     /// Note that this ordering exactly matches the values of the `Timer` enum for convenient
     /// indexing.
-    timers: [Option<TimerSetting>; 5],
+    timers: [Option<TimerSetting>; 7],
     retired_cids: Vec<ConnectionId>,
 }
 
 impl IoQueue {
     fn new() -> Self {
         Self {
-            probes: 0,
             close: false,
-            timers: [None; 5],
+            timers: [None; 7],
             retired_cids: Vec::new(),
         }
     }
@@ -3269,6 +4529,14 @@ struct PacketSpace {
     pending_acks: RangeSet,
     /// Set iff we have received a non-ack frame since the last ack-only packet we sent
     permit_ack_only: bool,
+    /// Number of ack-eliciting packets received since we last sent an ACK
+    unacked_ack_eliciting: u32,
+    /// Whether pending ACKs should be sent without waiting for more packets or a timer
+    immediate_ack_required: bool,
+    /// Largest packet number received
+    largest_rx_packet: Option<u64>,
+    /// Whether a packet has been received out of order since we last sent an ACK
+    rx_reordered: bool,
 
     /// The packet number of the next packet that will be sent, if any.
     next_packet_number: u64,
@@ -3277,6 +4545,17 @@ struct PacketSpace {
     /// Transmitted but not acked
     // We use a BTreeMap here so we can efficiently query by range on ACK and for loss detection
     sent_packets: BTreeMap<u64, SentPacket>,
+    /// Number of ack-eliciting packets in `sent_packets`
+    ack_eliciting_in_flight: u64,
+    /// The time the most recently sent ack-eliciting packet was sent
+    time_of_last_ack_eliciting_packet: u64,
+    /// The time at which the earliest unacknowledged packet will be deemed lost by the time
+    /// threshold, if any packets sent before the largest acknowledged remain unacknowledged
+    loss_time: Option<u64>,
+    /// Recently deemed lost, in case they're acknowledged late due to reordering
+    declared_lost: BTreeMap<u64, LostPacket>,
+    /// Number of loss probe packets to send
+    loss_probes: u32,
     /// Recent ECN counters sent by the peer in ACK frames
     ///
     /// Updated (and inspected) whenever we receive an ACK with a new highest acked packet
@@ -3300,10 +4579,19 @@ impl PacketSpace {
             pending: Retransmits::default(),
             pending_acks: RangeSet::new(),
             permit_ack_only: false,
+            unacked_ack_eliciting: 0,
+            immediate_ack_required: false,
+            largest_rx_packet: None,
+            rx_reordered: false,
 
             next_packet_number: 0,
             largest_acked_packet: 0,
             sent_packets: BTreeMap::new(),
+            ack_eliciting_in_flight: 0,
+            time_of_last_ack_eliciting_packet: 0,
+            loss_time: None,
+            declared_lost: BTreeMap::new(),
+            loss_probes: 0,
             ecn_feedback: frame::EcnCounts::ZERO,
 
             crypto_stream: stream::Assembler::new(),
@@ -3320,7 +4608,10 @@ impl PacketSpace {
     }
 
     fn can_send(&self) -> bool {
-        !self.pending.is_empty() || (self.permit_ack_only && !self.pending_acks.is_empty())
+        !self.pending.is_empty()
+            || (self.permit_ack_only
+                && self.immediate_ack_required
+                && !self.pending_acks.is_empty())
     }
 
     /// Verifies sanity of an ECN block and returns whether congestion was encountered.
@@ -3386,8 +4677,6 @@ struct InFlight {
     /// The size does not include IP or UDP overhead. Packets only containing ACK frames do not
     /// count towards this to ensure congestion control does not impede congestion feedback.
     bytes: u64,
-    /// Number of unacknowledged Initial or Handshake packets bearing CRYPTO frames
-    crypto: u64,
     /// Number of packets in flight containing frames other than ACK and PADDING
     ///
     /// This can be 0 even when bytes is not 0 because PADDING frames cause a packet to be
@@ -3400,30 +4689,28 @@ impl InFlight {
     pub fn new() -> Self {
         Self {
             bytes: 0,
-            crypto: 0,
             ack_eliciting: 0,
         }
     }
 
     fn insert(&mut self, packet: &SentPacket) {
         self.bytes += packet.size as u64;
-        self.crypto += packet.is_crypto_packet as u64;
         self.ack_eliciting += packet.ack_eliciting as u64;
     }
 
     /// Update counters to account for a packet becoming acknowledged, lost, or abandoned
     fn remove(&mut self, packet: &SentPacket) {
         self.bytes -= packet.size as u64;
-        self.crypto -= packet.is_crypto_packet as u64;
         self.ack_eliciting -= packet.ack_eliciting as u64;
     }
 }
 
-struct RttEstimator {
+/// Estimates of the round-trip time of a path
+pub struct RttEstimator {
     /// The most recent RTT measurement made when receiving an ack for a previously unacked packet.
     /// μs
     latest: u64,
-    /// The smoothed RTT of the connection, computed as described in RFC6298. μs
+    /// The smoothed RTT of the connection, computed as described in RFC 9002. μs
     smoothed: u64,
     /// The RTT variance, computed as described in RFC6298
     var: u64,
@@ -3432,7 +4719,7 @@ struct RttEstimator {
 }
 
 impl RttEstimator {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             latest: 0,
             smoothed: 0,
@@ -3441,23 +4728,42 @@ impl RttEstimator {
         }
     }
 
-    fn update(&mut self, ack_delay: u64, rtt: u64) {
+    /// The most recent RTT measurement, not adjusted for ack delay (μs)
+    pub fn latest(&self) -> u64 {
+        self.latest
+    }
+
+    /// The smoothed RTT of the path, or 0 if no samples have been taken (μs)
+    pub fn smoothed(&self) -> u64 {
+        self.smoothed
+    }
+
+    /// The minimum RTT seen on the path, ignoring ack delay (μs)
+    ///
+    /// `u64::max_value()` if no samples have been taken.
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    pub(crate) fn update(&mut self, ack_delay: u64, rtt: u64) {
         self.latest = rtt;
         // min_rtt ignores ack delay.
         self.min = cmp::min(self.min, self.latest);
-        // Adjust for ack delay if it's plausible.
-        if self.latest - self.min > ack_delay {
-            self.latest -= ack_delay;
-        }
-        // Based on RFC6298.
         if self.smoothed == 0 {
+            // The first sample isn't adjusted, since its ack delay can't be judged plausible
             self.smoothed = self.latest;
             self.var = self.latest / 2;
-        } else {
-            let var_sample = (self.smoothed as i64 - self.latest as i64).abs() as u64;
-            self.var = (3 * self.var + var_sample) / 4;
-            self.smoothed = (7 * self.smoothed + self.latest) / 8;
+            return;
         }
+        // Adjust for ack delay if it's plausible.
+        let adjusted = if self.latest >= self.min + ack_delay {
+            self.latest - ack_delay
+        } else {
+            self.latest
+        };
+        let var_sample = (self.smoothed as i64 - adjusted as i64).abs() as u64;
+        self.var = (3 * self.var + var_sample) / 4;
+        self.smoothed = (7 * self.smoothed + adjusted) / 8;
     }
 }
 
@@ -8,6 +8,7 @@ use bytes::{Buf, BufMut, BytesMut};
 use err_derive::Error;
 use ring::aead::quic::{HeaderProtectionKey, AES_128, AES_256, CHACHA20};
 use ring::aead::{self, Aad, Nonce};
+use ring::constant_time;
 use ring::digest;
 use ring::hkdf;
 use ring::hmac::{self, SigningKey};
@@ -21,7 +22,7 @@ use webpki::DNSNameRef;
 use crate::coding::{BufExt, BufMutExt};
 use crate::packet::{ConnectionId, PacketNumber, LONG_HEADER_FORM};
 use crate::transport_parameters::TransportParameters;
-use crate::{Side, MAX_CID_SIZE, MIN_CID_SIZE, RESET_TOKEN_SIZE};
+use crate::{Side, MAX_CID_SIZE, RESET_TOKEN_SIZE, VERSION_1, VERSION_2};
 
 pub enum TlsSession {
     Client(ClientSession),
@@ -39,15 +40,12 @@ impl TlsSession {
         Ok(TlsSession::Client(ClientSession::new_quic(
             &config,
             pki_server_name,
-            to_vec(Side::Client, params),
+            to_vec(params),
         )))
     }
 
     pub fn new_server(config: &Arc<ServerConfig>, params: &TransportParameters) -> TlsSession {
-        TlsSession::Server(ServerSession::new_quic(
-            config,
-            to_vec(Side::Server, params),
-        ))
+        TlsSession::Server(ServerSession::new_quic(config, to_vec(params)))
     }
 
     pub fn get_sni_hostname(&self) -> Option<&str> {
@@ -91,9 +89,15 @@ pub fn build_server_config() -> ServerConfig {
     cfg
 }
 
-fn to_vec(side: Side, params: &TransportParameters) -> Vec<u8> {
+// The transport parameters are carried in a TLS extension whose codepoint is chosen by rustls. QUIC
+// version 1 assigns it 0x39, but the rustls release we depend on still uses the draft codepoint
+// 0xffa5, so peers which only accept the final codepoint will not find our parameters. Releases
+// using 0x39 need ring 0.16, which can't be linked alongside the ring 0.14 used here, and either
+// hide the TLS secrets that QUIC version 2 keys are derived from (0.20) or can no longer persist
+// sessions as bytes for `SessionFileStore` (0.21 onwards), so upgrading is a migration of its own.
+fn to_vec(params: &TransportParameters) -> Vec<u8> {
     let mut bytes = Vec::new();
-    params.write(side, &mut bytes);
+    params.write(&mut bytes);
     bytes
 }
 
@@ -111,6 +115,7 @@ pub fn reset_token_for(key: &SigningKey, id: &ConnectionId) -> [u8; RESET_TOKEN_
 }
 
 pub struct Crypto {
+    version: u32,
     local_secret: Vec<u8>,
     local_iv: Vec<u8>,
     sealing_key: aead::SealingKey,
@@ -121,20 +126,21 @@ pub struct Crypto {
 }
 
 impl Crypto {
-    pub fn new_initial(id: &ConnectionId, side: Side) -> Self {
+    pub fn new_initial(version: u32, id: &ConnectionId, side: Side) -> Self {
         let (digest, cipher) = (&digest::SHA256, &aead::AES_128_GCM);
         const CLIENT_LABEL: &[u8] = b"client in";
         const SERVER_LABEL: &[u8] = b"server in";
-        let hs_secret = initial_secret(id);
+        let hs_secret = initial_secret(version, id);
         let secrets = Secrets {
             client: expanded_initial_secret(&hs_secret, CLIENT_LABEL),
             server: expanded_initial_secret(&hs_secret, SERVER_LABEL),
         };
-        Self::new(side, digest, cipher, secrets)
+        Self::new(version, side, digest, cipher, secrets)
     }
 
-    pub fn new_0rtt(secret: &[u8]) -> Self {
+    pub fn new_0rtt(version: u32, secret: &[u8]) -> Self {
         Self::new(
+            version,
             Side::Client, // Meaningless when the secrets are equal
             &digest::SHA256,
             &aead::AES_128_GCM,
@@ -146,6 +152,7 @@ impl Crypto {
     }
 
     pub fn new(
+        version: u32,
         side: Side,
         digest: &'static digest::Algorithm,
         cipher: &'static aead::Algorithm,
@@ -156,10 +163,11 @@ impl Crypto {
         } else {
             (secrets.server, secrets.client)
         };
-        let (local_key, local_iv) = Self::get_keys(digest, cipher, &local_secret);
-        let (remote_key, remote_iv) = Self::get_keys(digest, cipher, &remote_secret);
+        let (local_key, local_iv) = Self::get_keys(version, digest, cipher, &local_secret);
+        let (remote_key, remote_iv) = Self::get_keys(version, digest, cipher, &remote_secret);
 
         Crypto {
+            version,
             local_secret,
             sealing_key: aead::SealingKey::new(cipher, &local_key).unwrap(),
             local_iv,
@@ -227,17 +235,19 @@ impl Crypto {
     }
 
     fn get_keys(
+        version: u32,
         digest: &'static digest::Algorithm,
         cipher: &'static aead::Algorithm,
         secret: &[u8],
     ) -> (Vec<u8>, Vec<u8>) {
+        let params = VersionParams::get(version);
         let secret_key = SigningKey::new(digest, &secret);
 
         let mut key = vec![0; cipher.key_len()];
-        hkdf_expand(&secret_key, b"quic key", &mut key);
+        hkdf_expand(&secret_key, params.key_label, &mut key);
 
         let mut iv = vec![0; cipher.nonce_len()];
-        hkdf_expand(&secret_key, b"quic iv", &mut iv);
+        hkdf_expand(&secret_key, params.iv_label, &mut iv);
 
         (key, iv)
     }
@@ -246,20 +256,42 @@ impl Crypto {
         let local = SigningKey::new(self.digest, &self.local_secret);
         let remote = SigningKey::new(self.digest, &self.remote_secret);
         let cipher = self.sealing_key.algorithm();
+        let label = VersionParams::get(self.version).hp_label;
         HeaderCrypto {
-            local: header_key_from_secret(cipher, &local),
-            remote: header_key_from_secret(cipher, &remote),
+            local: header_key_from_secret(cipher, &local, label),
+            remote: header_key_from_secret(cipher, &remote, label),
         }
     }
 
-    pub fn update(&self, side: Side, tls: &TlsSession) -> Self {
-        let (client_secret, server_secret) = match side {
-            Side::Client => (&self.local_secret, &self.remote_secret),
-            Side::Server => (&self.remote_secret, &self.local_secret),
+    /// Derive the packet protection keys for the next key phase
+    ///
+    /// Header protection keys are not affected by key updates, so the `HeaderCrypto` of the
+    /// original keys remains in use.
+    pub fn update(&self, side: Side) -> Self {
+        let label = VersionParams::get(self.version).ku_label;
+        let next = |secret: &[u8]| {
+            let mut out = vec![0; self.digest.output_len];
+            hkdf_expand(&SigningKey::new(self.digest, secret), label, &mut out);
+            out
         };
-        let secrets = tls.update_secrets(client_secret, server_secret);
-        let suite = tls.get_negotiated_ciphersuite().unwrap();
-        Self::new(side, suite.get_hash(), suite.get_aead_alg(), secrets)
+        let (local, remote) = (next(&self.local_secret), next(&self.remote_secret));
+        let secrets = match side {
+            Side::Client => Secrets {
+                client: local,
+                server: remote,
+            },
+            Side::Server => Secrets {
+                client: remote,
+                server: local,
+            },
+        };
+        Self::new(
+            self.version,
+            side,
+            self.digest,
+            self.sealing_key.algorithm(),
+            secrets,
+        )
     }
 
     pub fn tag_len(&self) -> usize {
@@ -338,19 +370,22 @@ impl From<TLSError> for ConnectError {
     }
 }
 
-fn header_key_from_secret(aead: &aead::Algorithm, secret_key: &SigningKey) -> HeaderProtectionKey {
-    const LABEL: &[u8] = b"quic hp";
+fn header_key_from_secret(
+    aead: &aead::Algorithm,
+    secret_key: &SigningKey,
+    label: &[u8],
+) -> HeaderProtectionKey {
     if aead == &aead::AES_128_GCM {
         let mut pn = [0; 16];
-        hkdf_expand(&secret_key, LABEL, &mut pn);
+        hkdf_expand(&secret_key, label, &mut pn);
         HeaderProtectionKey::new(&AES_128, &pn).unwrap()
     } else if aead == &aead::AES_256_GCM {
         let mut pn = [0; 32];
-        hkdf_expand(&secret_key, LABEL, &mut pn);
+        hkdf_expand(&secret_key, label, &mut pn);
         HeaderProtectionKey::new(&AES_256, &pn).unwrap()
     } else if aead == &aead::CHACHA20_POLY1305 {
         let mut pn = [0; 32];
-        hkdf_expand(&secret_key, LABEL, &mut pn);
+        hkdf_expand(&secret_key, label, &mut pn);
         HeaderProtectionKey::new(&CHACHA20, &pn).unwrap()
     } else {
         unimplemented!()
@@ -374,15 +409,114 @@ pub fn hkdf_expand(key: &SigningKey, label: &[u8], out: &mut [u8]) {
     hkdf::expand(key, &info, out);
 }
 
-fn initial_secret(conn_id: &ConnectionId) -> SigningKey {
-    let key = SigningKey::new(&digest::SHA256, &INITIAL_SALT);
+fn initial_secret(version: u32, conn_id: &ConnectionId) -> SigningKey {
+    let key = SigningKey::new(&digest::SHA256, &VersionParams::get(version).initial_salt);
     hkdf::extract(&key, conn_id)
 }
 
-const INITIAL_SALT: [u8; 20] = [
-    0xef, 0x4f, 0xb0, 0xab, 0xb4, 0x74, 0x70, 0xc4, 0x1b, 0xef, 0xcf, 0x80, 0x31, 0x33, 0x4f, 0xae,
-    0x48, 0x5e, 0x09, 0xa0,
-];
+/// Cryptographic constants which differ between QUIC versions
+struct VersionParams {
+    initial_salt: [u8; 20],
+    key_label: &'static [u8],
+    iv_label: &'static [u8],
+    hp_label: &'static [u8],
+    ku_label: &'static [u8],
+    retry_integrity_key: [u8; 16],
+    retry_integrity_nonce: [u8; 12],
+}
+
+impl VersionParams {
+    fn get(version: u32) -> &'static Self {
+        match version {
+            VERSION_1 => &V1_PARAMS,
+            VERSION_2 => &V2_PARAMS,
+            _ => panic!("unsupported QUIC version {:#x}", version),
+        }
+    }
+}
+
+const V1_PARAMS: VersionParams = VersionParams {
+    initial_salt: [
+        0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c,
+        0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+    ],
+    key_label: b"quic key",
+    iv_label: b"quic iv",
+    hp_label: b"quic hp",
+    ku_label: b"quic ku",
+    retry_integrity_key: [
+        0xbe, 0x0c, 0x69, 0x0b, 0x9f, 0x66, 0x57, 0x5a, 0x1d, 0x76, 0x6b, 0x54, 0xe3, 0x68, 0xc8,
+        0x4e,
+    ],
+    retry_integrity_nonce: [
+        0x46, 0x15, 0x99, 0xd3, 0x5d, 0x63, 0x2b, 0xf2, 0x23, 0x98, 0x25, 0xbb,
+    ],
+};
+
+/// Parameters of QUIC version 2, per RFC 9369
+const V2_PARAMS: VersionParams = VersionParams {
+    initial_salt: [
+        0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26, 0x9d,
+        0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+    ],
+    key_label: b"quicv2 key",
+    iv_label: b"quicv2 iv",
+    hp_label: b"quicv2 hp",
+    ku_label: b"quicv2 ku",
+    retry_integrity_key: [
+        0x8f, 0xb4, 0xb0, 0x1b, 0x56, 0xac, 0x48, 0xe2, 0x60, 0xfb, 0xcb, 0xce, 0xad, 0x7c, 0xcc,
+        0x92,
+    ],
+    retry_integrity_nonce: [
+        0xd8, 0x69, 0x69, 0xbc, 0x2d, 0x7c, 0x6d, 0x99, 0x90, 0xef, 0xb0, 0x4a,
+    ],
+};
+
+/// Compute the integrity tag of a Retry packet
+///
+/// `packet` is the Retry packet up to but excluding the tag, and `orig_dst_cid` is the destination
+/// connection ID of the Initial packet that prompted it.
+pub fn retry_tag(version: u32, orig_dst_cid: &ConnectionId, packet: &[u8]) -> [u8; RETRY_TAG_SIZE] {
+    let mut pseudo_packet = Vec::with_capacity(1 + orig_dst_cid.len() + packet.len());
+    pseudo_packet.push(orig_dst_cid.len() as u8);
+    pseudo_packet.extend_from_slice(orig_dst_cid);
+    pseudo_packet.extend_from_slice(packet);
+
+    let params = VersionParams::get(version);
+    let key = aead::SealingKey::new(&aead::AES_128_GCM, &params.retry_integrity_key).unwrap();
+    let nonce = Nonce::assume_unique_for_key(params.retry_integrity_nonce);
+    let mut tag = [0; RETRY_TAG_SIZE];
+    aead::seal_in_place(
+        &key,
+        nonce,
+        Aad::from(&pseudo_packet[..]),
+        &mut tag,
+        RETRY_TAG_SIZE,
+    )
+    .unwrap();
+    tag
+}
+
+/// Check the integrity tag at the end of a Retry packet's `payload`
+pub fn is_valid_retry(
+    version: u32,
+    orig_dst_cid: &ConnectionId,
+    header: &[u8],
+    payload: &[u8],
+) -> bool {
+    let tag_start = match payload.len().checked_sub(RETRY_TAG_SIZE) {
+        Some(x) => x,
+        None => return false,
+    };
+    let mut packet = Vec::with_capacity(header.len() + tag_start);
+    packet.extend_from_slice(header);
+    packet.extend_from_slice(&payload[..tag_start]);
+    let tag = retry_tag(version, orig_dst_cid, &packet);
+    constant_time::verify_slices_are_equal(&tag, &payload[tag_start..]).is_ok()
+}
+
+/// Length of the integrity tag concluding a Retry packet
+pub const RETRY_TAG_SIZE: usize = 16;
 
 pub struct TokenKey {
     // TODO: Use AEAD to hide token details from clients for better stability guarantees:
@@ -401,15 +535,37 @@ impl TokenKey {
         Self { inner }
     }
 
-    pub(crate) fn generate(
+    /// Generate a token for a Retry sent in response to an Initial addressed to `dst_cid`
+    pub(crate) fn generate_retry(
         &self,
         address: &SocketAddr,
         dst_cid: &ConnectionId,
         issued: SystemTime,
     ) -> Vec<u8> {
         let mut buf = Vec::new();
+        buf.write(RETRY_TOKEN);
         buf.write(dst_cid.len() as u8);
         buf.put_slice(dst_cid);
+        self.sign(buf, address, issued)
+    }
+
+    /// Generate a token for a NEW_TOKEN frame, usable in future connections from `address`
+    ///
+    /// `nonce` should be random, so that tokens can't be linked to each other.
+    pub(crate) fn generate_new_token(
+        &self,
+        address: &SocketAddr,
+        nonce: u64,
+        issued: SystemTime,
+    ) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write(NEW_TOKEN);
+        buf.write(nonce);
+        self.sign(buf, address, issued)
+    }
+
+    /// Append the time of issue and an authentication tag for `buf` issued to `address`
+    fn sign(&self, mut buf: Vec<u8>, address: &SocketAddr, issued: SystemTime) -> Vec<u8> {
         buf.write::<u64>(
             issued
                 .duration_since(UNIX_EPOCH)
@@ -417,11 +573,8 @@ impl TokenKey {
                 .unwrap_or(0),
         );
         let signature_pos = buf.len();
-        match address.ip() {
-            IpAddr::V4(x) => buf.put_slice(&x.octets()),
-            IpAddr::V6(x) => buf.put_slice(&x.octets()),
-        }
-        buf.write(address.port());
+        let kind = buf[0];
+        Self::write_address(&mut buf, kind, address);
         let signature = hmac::sign(&self.inner, &buf);
         // No reason to actually encode the IP in the token, since we always have the remote addr for an incoming packet.
         buf.truncate(signature_pos);
@@ -429,36 +582,64 @@ impl TokenKey {
         buf
     }
 
-    pub(crate) fn check(
-        &self,
-        address: &SocketAddr,
-        data: &[u8],
-    ) -> Option<(ConnectionId, SystemTime)> {
+    /// Authenticate a token presented by `address`
+    pub(crate) fn check(&self, address: &SocketAddr, data: &[u8]) -> Option<ValidatedToken> {
         let mut reader = io::Cursor::new(data);
-        let dst_cid_len = reader.get::<u8>().ok()? as usize;
-        if dst_cid_len > reader.remaining()
-            || dst_cid_len != 0 && (dst_cid_len < MIN_CID_SIZE || dst_cid_len > MAX_CID_SIZE)
-        {
-            return None;
-        }
-        let dst_cid = ConnectionId::new(&data[1..=dst_cid_len]);
-        reader.advance(dst_cid_len);
+        let kind = reader.get::<u8>().ok()?;
+        let retry_dst_cid = match kind {
+            RETRY_TOKEN => {
+                let dst_cid_len = reader.get::<u8>().ok()? as usize;
+                if dst_cid_len > reader.remaining() || dst_cid_len > MAX_CID_SIZE {
+                    return None;
+                }
+                let start = reader.position() as usize;
+                reader.advance(dst_cid_len);
+                Some(ConnectionId::new(&data[start..start + dst_cid_len]))
+            }
+            NEW_TOKEN => {
+                reader.get::<u64>().ok()?;
+                None
+            }
+            _ => return None,
+        };
         let issued = UNIX_EPOCH + Duration::new(reader.get::<u64>().ok()?, 0);
         let signature_start = reader.position() as usize;
 
         let mut buf = Vec::new();
         buf.put_slice(&data[0..signature_start]);
+        Self::write_address(&mut buf, kind, address);
+
+        hmac::verify_with_own_key(&self.inner, &buf, &data[signature_start..]).ok()?;
+        Some(ValidatedToken {
+            retry_dst_cid,
+            issued,
+        })
+    }
+
+    /// Encode the part of `address` a token of type `kind` is bound to
+    fn write_address(buf: &mut Vec<u8>, kind: u8, address: &SocketAddr) {
         match address.ip() {
             IpAddr::V4(x) => buf.put_slice(&x.octets()),
             IpAddr::V6(x) => buf.put_slice(&x.octets()),
         }
-        buf.write(address.port());
-
-        hmac::verify_with_own_key(&self.inner, &buf, &data[signature_start..]).ok()?;
-        Some((dst_cid, issued))
+        // Clients may reasonably use a different port for each connection
+        if kind == RETRY_TOKEN {
+            buf.write(address.port());
+        }
     }
 }
 
+const RETRY_TOKEN: u8 = 0;
+const NEW_TOKEN: u8 = 1;
+
+/// An address validation token which was successfully authenticated
+pub(crate) struct ValidatedToken {
+    /// For tokens sent in a Retry, the destination CID of the Initial that prompted it
+    pub retry_dst_cid: Option<ConnectionId>,
+    /// When the token was issued, to the nearest second
+    pub issued: SystemTime,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -467,8 +648,8 @@ mod test {
     #[test]
     fn handshake_crypto_roundtrip() {
         let conn = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE);
-        let client = Crypto::new_initial(&conn, Side::Client);
-        let server = Crypto::new_initial(&conn, Side::Server);
+        let client = Crypto::new_initial(VERSION_1, &conn, Side::Client);
+        let server = Crypto::new_initial(VERSION_1, &conn, Side::Server);
 
         let mut buf = b"headerpayload".to_vec();
         client.encrypt(0, &mut buf, 6);
@@ -481,63 +662,137 @@ mod test {
 
     #[test]
     fn key_derivation() {
+        // Test vectors from RFC 9001 appendix A.1
         let id = ConnectionId::new(&hex!("8394c8f03e515708"));
         let digest = &digest::SHA256;
         let cipher = &aead::AES_128_GCM;
-        let initial_secret = initial_secret(&id);
+        let initial_secret = initial_secret(VERSION_1, &id);
         let client_secret = expanded_initial_secret(&initial_secret, b"client in");
         assert_eq!(
             &client_secret[..],
-            hex!("8a3515a14ae3c31b9c2d6d5bc58538ca 5cd2baa119087143e60887428dcb52f6")
+            hex!("c00cf151ca5be075ed0ebfb5c80323c4 2d6b7db67881289af4008f1f6c357aea")
         );
-        let (client_key, client_iv) = Crypto::get_keys(digest, cipher, &client_secret);
-        assert_eq!(&client_key[..], hex!("98b0d7e5e7a402c67c33f350fa65ea54"));
-        assert_eq!(&client_iv[..], hex!("19e94387805eb0b46c03a788"));
+        let (client_key, client_iv) = Crypto::get_keys(VERSION_1, digest, cipher, &client_secret);
+        assert_eq!(&client_key[..], hex!("1f369613dd76d5467730efcbe3b1a22d"));
+        assert_eq!(&client_iv[..], hex!("fa044b2f42a3fd3b46fb255c"));
 
         let server_secret = expanded_initial_secret(&initial_secret, b"server in");
         assert_eq!(
             &server_secret[..],
-            hex!("47b2eaea6c266e32c0697a9e2a898bdf 5c4fb3e5ac34f0e549bf2c58581a3811")
+            hex!("3c199828fd139efd216c155ad844cc81 fb82fa8d7446fa7d78be803acdda951b")
         );
-        let (server_key, server_iv) = Crypto::get_keys(digest, cipher, &server_secret);
-        assert_eq!(&server_key[..], hex!("9a8be902a9bdd91d16064ca118045fb4"));
-        assert_eq!(&server_iv[..], hex!("0a82086d32205ba22241d8dc"));
+        let (server_key, server_iv) = Crypto::get_keys(VERSION_1, digest, cipher, &server_secret);
+        assert_eq!(&server_key[..], hex!("cf3a5331653c364c88f0f379b6067e37"));
+        assert_eq!(&server_iv[..], hex!("0ac1493ca1905853b0bba03e"));
     }
 
     #[test]
-    fn packet_protection() {
+    fn key_derivation_v2() {
+        // Test vectors from RFC 9369 appendix A.1
         let id = ConnectionId::new(&hex!("8394c8f03e515708"));
-        let server = Crypto::new_initial(&id, Side::Server);
-        let server_header = server.header_crypto();
-        let client = Crypto::new_initial(&id, Side::Client);
-        let client_header = client.header_crypto();
-        let plaintext = hex!(
-            "c1ff00001205f067a5502a4262b50040740000
-             0d0000000018410a020000560303eefc e7f7b37ba1d1632e96677825ddf73988
-             cfc79825df566dc5430b9a045a120013 0100002e00330024001d00209d3c940d
-             89690b84d08a60993c144eca684d1081 287c834d5311bcf32bb9da1a002b0002
-             0304"
+        let digest = &digest::SHA256;
+        let cipher = &aead::AES_128_GCM;
+        let initial_secret = initial_secret(VERSION_2, &id);
+        let client_secret = expanded_initial_secret(&initial_secret, b"client in");
+        assert_eq!(
+            &client_secret[..],
+            hex!("14ec9d6eb9fd7af83bf5a668bc17a7e2 83766aade7ecd0891f70f9ff7f4bf47b")
         );
-        const HEADER_LEN: usize = 19;
-        let protected = hex!(
-            "c2ff00001205f067a5502a4262b50040 7428f63f2abf65a03e3e7ce041087cb1
-             1fd7ba338b4fcd9e22bbdb5cff66218a 8ac48269098d73577222d3e02af7eb40
-             1796a2d67c1c9e89d0dc5a5dfc6ceead f4ebd4eae0e3185dfe99a7f59288afaa
-             75539cfad2bab440126a57213325f86d 3b8a5cb13b33f73a6317e34f73ac35ba
-             3d7a1f0b5c"
+        let (client_key, client_iv) = Crypto::get_keys(VERSION_2, digest, cipher, &client_secret);
+        assert_eq!(&client_key[..], hex!("8b1a0bc121284290a29e0971b5cd045d"));
+        assert_eq!(&client_iv[..], hex!("91f73e2351d8fa91660e909f"));
+
+        let server_secret = expanded_initial_secret(&initial_secret, b"server in");
+        assert_eq!(
+            &server_secret[..],
+            hex!("0263db1782731bf4588e7e4d93b74639 07cb8cd8200b5da55a8bd488eafc37c1")
         );
+        let (server_key, server_iv) = Crypto::get_keys(VERSION_2, digest, cipher, &server_secret);
+        assert_eq!(&server_key[..], hex!("82db637861d55e1d011f19ea71d5d2a7"));
+        assert_eq!(&server_iv[..], hex!("dd13c276499c0249d3310652"));
+    }
+
+    #[test]
+    fn packet_protection() {
+        // ChaCha20-Poly1305 short header packet from RFC 9001 appendix A.5
+        let secret = hex!("9ac312a7f877468ebe69422748ad00a1 5443f18203a07d6060f688f30f21632b");
+        let secrets = || Secrets {
+            client: secret.to_vec(),
+            server: secret.to_vec(),
+        };
+        let cipher = &aead::CHACHA20_POLY1305;
+        let server = Crypto::new(VERSION_1, Side::Server, &digest::SHA256, cipher, secrets());
+        let server_header = server.header_crypto();
+        let client = Crypto::new(VERSION_1, Side::Client, &digest::SHA256, cipher, secrets());
+        let client_header = client.header_crypto();
+        assert_eq!(&server.local_iv[..], hex!("e0459b3474bdd0e44a41c144"));
+
+        const NUMBER: u64 = 654_360_564;
+        const HEADER_LEN: usize = 4;
+        let plaintext = hex!("4200bff4 01");
+        let protected = hex!("4cfe4189655e5cd55c41f69080575d7999c25a5bfb");
         let mut packet = plaintext.to_vec();
-        server.encrypt(0, &mut packet, HEADER_LEN);
-        server_header.encrypt(17, &mut packet);
+        server.encrypt(NUMBER, &mut packet, HEADER_LEN);
+        server_header.encrypt(1, &mut packet);
         assert_eq!(&packet[..], &protected[..]);
-        client_header.decrypt(17, &mut packet);
+        client_header.decrypt(1, &mut packet);
         let (header, payload) = packet.split_at(HEADER_LEN);
         assert_eq!(header, &plaintext[0..HEADER_LEN]);
         let mut payload = BytesMut::from(payload);
-        client.decrypt(0, &header, &mut payload).unwrap();
+        client.decrypt(NUMBER, &header, &mut payload).unwrap();
         assert_eq!(&payload, &plaintext[HEADER_LEN..]);
     }
 
+    #[test]
+    fn key_update() {
+        let secret = hex!("9ac312a7f877468ebe69422748ad00a1 5443f18203a07d6060f688f30f21632b");
+        let crypto = Crypto::new(
+            VERSION_1,
+            Side::Client,
+            &digest::SHA256,
+            &aead::CHACHA20_POLY1305,
+            Secrets {
+                client: secret.to_vec(),
+                server: secret.to_vec(),
+            },
+        );
+        let next = crypto.update(Side::Client);
+        assert_eq!(
+            &next.local_secret[..],
+            hex!("1223504755036d556342ee9361d25342 1a826c9ecdf3c7148684b36b714881f9")
+        );
+        assert_eq!(next.local_secret, next.remote_secret);
+    }
+
+    #[test]
+    fn retry_integrity() {
+        // Retry packet from RFC 9001 appendix A.4
+        let orig_dst_cid = ConnectionId::new(&hex!("8394c8f03e515708"));
+        let packet =
+            hex!("ff000000010008f067a5502a4262b5746f6b656e04a265ba2eff4d829058fb3f0f2496ba");
+        let (header, payload) = packet.split_at(15);
+        let tag_start = packet.len() - RETRY_TAG_SIZE;
+        assert_eq!(
+            &retry_tag(VERSION_1, &orig_dst_cid, &packet[..tag_start])[..],
+            &packet[tag_start..]
+        );
+        assert!(is_valid_retry(VERSION_1, &orig_dst_cid, header, payload));
+        let other_cid = ConnectionId::new(&hex!("8394c8f03e515709"));
+        assert!(!is_valid_retry(VERSION_1, &other_cid, header, payload));
+        // Tags are specific to the version
+        assert!(!is_valid_retry(VERSION_2, &orig_dst_cid, header, payload));
+    }
+
+    #[test]
+    fn retry_integrity_v2() {
+        // Retry packet from RFC 9369 appendix A.4
+        let orig_dst_cid = ConnectionId::new(&hex!("8394c8f03e515708"));
+        let packet =
+            hex!("cf6b3343cf0008f067a5502a4262b5746f6b656ec8646ce8bfe33952d955543665dcc7b6");
+        let (header, payload) = packet.split_at(15);
+        assert!(is_valid_retry(VERSION_2, &orig_dst_cid, header, payload));
+    }
+
     #[test]
     fn token_sanity() {
         use std::net::Ipv6Addr;
@@ -548,10 +803,33 @@ mod test {
         let addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 4433);
         let dst_cid = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE);
         let issued = UNIX_EPOCH + Duration::new(42, 0); // Fractional seconds would be lost
-        let token = key.generate(&addr, &dst_cid, issued);
-        let (dst_cid2, issued2) = key.check(&addr, &token).expect("token didn't validate");
-        assert_eq!(dst_cid, dst_cid2);
-        assert_eq!(issued, issued2);
+        let token = key.generate_retry(&addr, &dst_cid, issued);
+        let validated = key.check(&addr, &token).expect("token didn't validate");
+        assert_eq!(validated.retry_dst_cid, Some(dst_cid));
+        assert_eq!(validated.issued, issued);
+        // Retry tokens are bound to the port
+        let other_port = SocketAddr::new(addr.ip(), 4434);
+        assert!(key.check(&other_port, &token).is_none());
+    }
+
+    #[test]
+    fn new_token_sanity() {
+        use std::net::Ipv6Addr;
+
+        let mut key = [0; TokenKey::SIZE];
+        rand::thread_rng().fill_bytes(&mut key);
+        let key = TokenKey::new(&key);
+        let addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 4433);
+        let issued = UNIX_EPOCH + Duration::new(42, 0);
+        let token = key.generate_new_token(&addr, 1234, issued);
+        let other_port = SocketAddr::new(addr.ip(), 4434);
+        let validated = key
+            .check(&other_port, &token)
+            .expect("token didn't validate");
+        assert_eq!(validated.retry_dst_cid, None);
+        assert_eq!(validated.issued, issued);
+        let other_ip = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 4433);
+        assert!(key.check(&other_ip, &token).is_none());
     }
 
     #[test]
@@ -560,6 +838,7 @@ mod test {
         let digest = &digest::SHA256;
         let cipher = &aead::AES_128_GCM;
         let onertt = Crypto::new(
+            VERSION_1,
             Side::Client,
             digest,
             cipher,
@@ -0,0 +1,149 @@
+//! Unreliable application datagrams, as described in RFC 9221
+
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+use err_derive::Error;
+
+/// Buffers of application datagrams awaiting transmission or the application's attention
+pub struct DatagramState {
+    /// Received datagrams the application has not yet read
+    incoming: VecDeque<Bytes>,
+    /// Total size of `incoming`
+    recv_buffered: usize,
+    /// Datagrams the application has submitted but which have not yet been sent
+    outgoing: VecDeque<Bytes>,
+    /// Total size of `outgoing`
+    send_buffered: usize,
+    /// Number of outgoing datagrams discarded for exceeding the maximum datagram size
+    send_dropped: u64,
+}
+
+impl DatagramState {
+    pub fn new() -> Self {
+        Self {
+            incoming: VecDeque::new(),
+            recv_buffered: 0,
+            outgoing: VecDeque::new(),
+            send_buffered: 0,
+            send_dropped: 0,
+        }
+    }
+
+    /// Buffer a received datagram, dropping the oldest as necessary to stay within `limit` bytes
+    ///
+    /// Returns whether the application should be told that datagrams are available.
+    pub fn received(&mut self, data: Bytes, limit: usize) -> bool {
+        let was_empty = self.incoming.is_empty();
+        self.recv_buffered += data.len();
+        self.incoming.push_back(data);
+        while self.recv_buffered > limit {
+            let x = self.incoming.pop_front().unwrap();
+            self.recv_buffered -= x.len();
+        }
+        was_empty && !self.incoming.is_empty()
+    }
+
+    /// Take the oldest received datagram
+    pub fn recv(&mut self) -> Option<Bytes> {
+        let x = self.incoming.pop_front()?;
+        self.recv_buffered -= x.len();
+        Some(x)
+    }
+
+    /// Queue a datagram for transmission, dropping the oldest as necessary to stay within `limit`
+    /// bytes
+    pub fn send(&mut self, data: Bytes, limit: usize) {
+        self.send_buffered += data.len();
+        self.outgoing.push_back(data);
+        while self.send_buffered > limit {
+            let x = self.outgoing.pop_front().unwrap();
+            self.send_buffered -= x.len();
+        }
+    }
+
+    /// Whether any datagrams are awaiting transmission
+    pub fn has_outgoing(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+
+    /// Take the oldest outgoing datagram, if it's no larger than `max_size`
+    ///
+    /// Datagrams larger than `max_datagram_size` could never be sent, e.g. because the path MTU
+    /// shrank after they were queued, so they're dropped rather than holding up those behind them.
+    pub fn pop_outgoing(&mut self, max_size: usize, max_datagram_size: usize) -> Option<Bytes> {
+        while self.outgoing.front()?.len() > max_datagram_size {
+            let x = self.outgoing.pop_front().unwrap();
+            self.send_buffered -= x.len();
+            self.send_dropped += 1;
+        }
+        if self.outgoing.front()?.len() > max_size {
+            return None;
+        }
+        let x = self.outgoing.pop_front().unwrap();
+        self.send_buffered -= x.len();
+        Some(x)
+    }
+
+    /// Number of outgoing datagrams discarded for exceeding the maximum datagram size
+    pub fn send_dropped(&self) -> u64 {
+        self.send_dropped
+    }
+}
+
+/// Errors that can arise when sending a datagram
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+pub enum SendDatagramError {
+    /// The peer does not support receiving datagram frames
+    #[error(display = "datagrams not supported by peer")]
+    UnsupportedByPeer,
+    /// The datagram is larger than the connection can currently accommodate
+    ///
+    /// Indicates that the path MTU minus overhead or the limit advertised by the peer has been
+    /// exceeded.
+    #[error(display = "datagram too large")]
+    TooLarge,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn drop_oldest() {
+        let mut state = DatagramState::new();
+        state.send(Bytes::from(&b"one"[..]), 8);
+        state.send(Bytes::from(&b"two"[..]), 8);
+        state.send(Bytes::from(&b"three"[..]), 8);
+        assert_eq!(
+            state.pop_outgoing(100, 100).as_ref().map(|x| &x[..]),
+            Some(&b"two"[..])
+        );
+        // Too large for the space available
+        assert_eq!(state.pop_outgoing(4, 100), None);
+        assert_eq!(
+            state.pop_outgoing(5, 100).as_ref().map(|x| &x[..]),
+            Some(&b"three"[..])
+        );
+        assert!(!state.has_outgoing());
+
+        assert!(state.received(Bytes::from(&b"one"[..]), 4));
+        assert!(!state.received(Bytes::from(&b"two"[..]), 4));
+        assert_eq!(state.recv().as_ref().map(|x| &x[..]), Some(&b"two"[..]));
+        assert_eq!(state.recv(), None);
+    }
+
+    #[test]
+    fn drop_oversized() {
+        let mut state = DatagramState::new();
+        state.send(Bytes::from(&b"three"[..]), 100);
+        state.send(Bytes::from(&b"one"[..]), 100);
+        // The maximum datagram size fell below that of the first datagram after it was queued
+        assert_eq!(
+            state.pop_outgoing(100, 4).as_ref().map(|x| &x[..]),
+            Some(&b"one"[..])
+        );
+        assert_eq!(state.send_dropped(), 1);
+        assert!(!state.has_outgoing());
+    }
+}
@@ -0,0 +1,77 @@
+//! Server policy for accepting 0-RTT data
+
+use std::net::SocketAddr;
+
+use rustls::internal::msgs::codec::Reader;
+use rustls::internal::msgs::handshake::{
+    ConvertProtocolNameList, ConvertServerNameList, HandshakeMessagePayload, HandshakePayload,
+};
+use rustls::ProtocolVersion;
+
+/// Decides whether a server accepts 0-RTT data from a client resuming a session
+///
+/// 0-RTT data can be replayed by an attacker, so servers may wish to refuse it for some
+/// application protocols, or when under load. A client whose early data is refused learns so when
+/// the handshake completes, and must then resend anything it still wants delivered.
+pub trait EarlyDataPolicy: Send + Sync {
+    /// Whether to accept 0-RTT data on the connection described by `request`
+    fn accept(&self, request: &EarlyDataRequest<'_>) -> bool;
+}
+
+impl<F> EarlyDataPolicy for F
+where
+    F: Fn(&EarlyDataRequest<'_>) -> bool + Send + Sync,
+{
+    fn accept(&self, request: &EarlyDataRequest<'_>) -> bool {
+        self(request)
+    }
+}
+
+/// A client's attempt to send 0-RTT data, as seen in its ClientHello
+#[derive(Debug)]
+pub struct EarlyDataRequest<'a> {
+    /// The address the client is connecting from
+    pub remote: SocketAddr,
+    /// The server name the client indicated, if any
+    pub server_name: Option<&'a str>,
+    /// The application protocols the client offered, in order of preference
+    pub protocols: &'a [Vec<u8>],
+}
+
+/// The parts of a ClientHello inspected before a server creates a connection
+#[derive(Debug)]
+pub(crate) struct ClientHello {
+    pub server_name: Option<String>,
+    pub protocols: Vec<Vec<u8>>,
+    pub early_data: bool,
+    /// The client's encoded transport parameters
+    pub transport_parameters: Option<Vec<u8>>,
+}
+
+impl ClientHello {
+    /// Parse a ClientHello from the start of the Initial CRYPTO stream
+    ///
+    /// Returns `None` if `data` doesn't contain a complete, well-formed ClientHello.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut reader = Reader::init(data);
+        let message = HandshakeMessagePayload::read_version(&mut reader, ProtocolVersion::TLSv1_3)?;
+        let hello = match message.payload {
+            HandshakePayload::ClientHello(hello) => hello,
+            _ => return None,
+        };
+        Some(Self {
+            server_name: hello
+                .get_sni_extension()
+                .and_then(|x| x.get_hostname())
+                .map(|x| {
+                    let name: &str = x.into();
+                    name.into()
+                }),
+            protocols: hello
+                .get_alpn_extension()
+                .map_or_else(Vec::new, |x| x.to_vecs()),
+            early_data: hello.early_data_extension_offered(),
+            transport_parameters: hello.get_quic_params_extension(),
+        })
+    }
+}
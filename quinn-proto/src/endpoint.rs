@@ -1,5 +1,6 @@
 use std::cmp;
 use std::collections::VecDeque;
+use std::io;
 use std::net::SocketAddr;
 use std::ops::{Index, IndexMut};
 use std::sync::Arc;
@@ -15,18 +16,23 @@ use slab::Slab;
 use slog::{self, Logger};
 
 use crate::coding::BufMutExt;
+use crate::congestion;
 use crate::connection::{
     self, initial_close, ClientConfig, Connection, ConnectionError, TimerUpdate,
 };
 use crate::crypto::{
     self, reset_token_for, ConnectError, Crypto, HeaderCrypto, TlsSession, TokenKey,
 };
+use crate::datagrams::SendDatagramError;
+use crate::early_data::{ClientHello, EarlyDataPolicy, EarlyDataRequest};
+use crate::frame::{self, Frame};
 use crate::packet::{ConnectionId, EcnCodepoint, Header, Packet, PacketDecodeError, PartialDecode};
 use crate::stream::{ReadError, WriteError};
-use crate::transport_parameters::TransportParameters;
+use crate::token::{TokenMemoryCache, TokenStore};
+use crate::transport_parameters::{TransportParameters, VersionInformation};
 use crate::{
-    Directionality, Side, StreamId, Transmit, TransportError, MAX_CID_SIZE, MIN_CID_SIZE,
-    MIN_INITIAL_SIZE, RESET_TOKEN_SIZE, VERSION,
+    Directionality, Side, StreamId, Transmit, TransportError, MAX_CID_SIZE, MIN_INITIAL_SIZE,
+    RESET_TOKEN_SIZE, SUPPORTED_VERSIONS,
 };
 
 /// The main entry point to the library
@@ -62,9 +68,23 @@ impl Endpoint {
     ) -> Result<Self, EndpointError> {
         let rng = OsRng::new().unwrap();
         let config = Arc::new(config);
+        assert!(config.local_cid_len <= MAX_CID_SIZE);
         assert!(
-            (config.local_cid_len == 0 || config.local_cid_len >= MIN_CID_SIZE)
-                && config.local_cid_len <= MAX_CID_SIZE
+            !config.supported_versions.is_empty()
+                && config
+                    .supported_versions
+                    .iter()
+                    .all(|x| SUPPORTED_VERSIONS.contains(x)),
+            "supported_versions must be a nonempty subset of SUPPORTED_VERSIONS"
+        );
+        assert!(
+            config.crypto_buffer_size >= 4096,
+            "crypto_buffer_size must be at least 4096"
+        );
+        assert!(
+            config.max_stream_receive_window >= config.stream_receive_window
+                && config.max_receive_window >= config.receive_window,
+            "receive window maximums must be at least the initial windows"
         );
         Ok(Self {
             log,
@@ -141,6 +161,8 @@ impl Endpoint {
                     self.dirty_timers.insert(ch);
                     return Some(transmit);
                 } else {
+                    // May have been blocked by pacing, which sets a timer
+                    self.dirty_timers.insert(ch);
                     self.needs_transmit.remove(&ch);
                     break;
                 }
@@ -157,7 +179,11 @@ impl Endpoint {
         data: BytesMut,
     ) {
         let datagram_len = data.len();
-        let (partial_decode, rest) = match PartialDecode::new(data, self.config.local_cid_len) {
+        let (partial_decode, rest) = match PartialDecode::new(
+            data,
+            self.config.local_cid_len,
+            &self.config.supported_versions,
+        ) {
             Ok(x) => x,
             Err(PacketDecodeError::UnsupportedVersion {
                 source,
@@ -177,7 +203,9 @@ impl Endpoint {
                 }
                 .encode(&mut buf);
                 buf.write::<u32>(0x0a1a_2a3a); // reserved version
-                buf.write(VERSION); // supported version
+                for &version in &self.config.supported_versions {
+                    buf.write(version);
+                }
                 self.transmits.push_back(Transmit {
                     destination: remote,
                     ecn: None,
@@ -216,12 +244,20 @@ impl Endpoint {
         };
         if let Some(ch) = known_ch {
             let had_1rtt = self.connections[ch].has_1rtt();
+            let was_handshaking = self.connections[ch].is_handshaking();
             self.connections[ch].handle_dgram(now, remote, ecn, partial_decode, rest);
             if !had_1rtt
                 && (self.connections[ch].has_1rtt() || !self.connections[ch].is_handshaking())
             {
                 self.conn_ready(ch);
             }
+            if was_handshaking
+                && self.connections[ch].side().is_server()
+                && !self.connections[ch].is_handshaking()
+                && !self.connections[ch].is_closed()
+            {
+                self.issue_new_token(ch);
+            }
             self.needs_transmit.insert(ch);
             self.dirty_timers.insert(ch);
             self.eventful_conns.insert(ch);
@@ -253,7 +289,8 @@ impl Endpoint {
                     return;
                 }
 
-                let crypto = Crypto::new_initial(&partial_decode.dst_cid(), Side::Server);
+                let version = partial_decode.version().unwrap();
+                let crypto = Crypto::new_initial(version, &partial_decode.dst_cid(), Side::Server);
                 let header_crypto = crypto.header_crypto();
                 match partial_decode.finish(Some(&header_crypto)) {
                     Ok(packet) => {
@@ -337,12 +374,14 @@ impl Endpoint {
         let remote_id = ConnectionId::random(&mut self.rng, MAX_CID_SIZE);
         trace!(self.log, "initial dcid"; "value" => %remote_id);
         let ch = self.add_connection(
+            self.config.supported_versions[0],
             remote_id,
             remote_id,
             remote,
             ConnectionOpts::Client(ClientConfig {
                 tls_config: config.clone(),
                 server_name: server_name.into(),
+                token: self.config.token_store.take(server_name),
             }),
         )?;
         self.needs_transmit.insert(ch);
@@ -361,43 +400,74 @@ impl Endpoint {
 
     fn add_connection(
         &mut self,
+        version: u32,
         initial_id: ConnectionId,
         remote_id: ConnectionId,
         remote: SocketAddr,
         opts: ConnectionOpts,
     ) -> Result<ConnectionHandle, ConnectError> {
         let local_id = self.new_cid();
+        let version_information = Some(VersionInformation {
+            chosen: version,
+            available: self.config.supported_versions.clone(),
+        });
+        let (orig_version, remote_validated, max_early_data) = match opts {
+            ConnectionOpts::Server {
+                orig_version,
+                remote_validated,
+                ..
+            } => (
+                orig_version,
+                remote_validated,
+                self.server_config.as_ref().unwrap().max_early_data_size,
+            ),
+            ConnectionOpts::Client(_) => (version, false, 0),
+        };
         let (tls, client_config) = match opts {
             ConnectionOpts::Client(config) => (
                 TlsSession::new_client(
                     &config.tls_config,
                     &config.server_name,
-                    &TransportParameters::new(&self.config),
+                    &TransportParameters {
+                        initial_src_cid: Some(local_id),
+                        version_information,
+                        ..TransportParameters::new(&self.config)
+                    },
                 )?,
                 Some(config),
             ),
-            ConnectionOpts::Server { orig_dst_cid } => {
+            ConnectionOpts::Server {
+                orig_dst_cid,
+                retry_src_cid,
+                accept_early_data,
+                ..
+            } => {
+                let server_config = self.server_config.as_ref().unwrap();
+                let tls_config = if accept_early_data {
+                    Arc::clone(&server_config.tls_config)
+                } else {
+                    // Refusing early data in TLS tells the client its 0-RTT packets were discarded
+                    let mut tls_config = (*server_config.tls_config).clone();
+                    tls_config.max_early_data_size = 0;
+                    Arc::new(tls_config)
+                };
                 let server_params = TransportParameters {
                     stateless_reset_token: Some(reset_token_for(&self.config.reset_key, &local_id)),
-                    original_connection_id: orig_dst_cid,
+                    original_dst_cid: Some(orig_dst_cid),
+                    initial_src_cid: Some(local_id),
+                    retry_src_cid,
+                    version_information,
                     ..TransportParameters::new(&self.config)
                 };
-                (
-                    TlsSession::new_server(
-                        &self.server_config.as_ref().unwrap().tls_config,
-                        &server_params,
-                    ),
-                    None,
-                )
+                (TlsSession::new_server(&tls_config, &server_params), None)
             }
         };
 
-        let remote_validated = self.server_config.as_ref().map_or(false, |cfg| {
-            cfg.use_stateless_retry && client_config.is_none()
-        });
         let id = self.connections.insert(Connection::new(
             self.log.new(o!("connection" => local_id)),
             Arc::clone(&self.config),
+            version,
+            orig_version,
             initial_id,
             local_id,
             remote_id,
@@ -405,6 +475,7 @@ impl Endpoint {
             client_config,
             tls,
             remote_validated,
+            max_early_data,
         ));
         let ch = ConnectionHandle(id);
 
@@ -425,13 +496,14 @@ impl Endpoint {
         crypto: &Crypto,
         header_crypto: &HeaderCrypto,
     ) {
-        let (src_cid, dst_cid, token, packet_number) = match packet.header {
+        let (version, src_cid, dst_cid, token, packet_number) = match packet.header {
             Header::Initial {
+                version,
                 src_cid,
                 dst_cid,
                 ref token,
                 number,
-            } => (src_cid, dst_cid, token.clone(), number),
+            } => (version, src_cid, dst_cid, token.clone(), number),
             _ => panic!("non-initial packet in handle_initial()"),
         };
         let packet_number = packet_number.expand(0);
@@ -457,12 +529,13 @@ impl Endpoint {
                 destination: remote,
                 ecn: None,
                 packet: initial_close(
+                    version,
                     crypto,
                     header_crypto,
                     &src_cid,
                     &temp_loc_cid,
                     0,
-                    TransportError::SERVER_BUSY,
+                    TransportError::CONNECTION_REFUSED,
                 ),
             });
             return;
@@ -481,6 +554,7 @@ impl Endpoint {
                 destination: remote,
                 ecn: None,
                 packet: initial_close(
+                    version,
                     crypto,
                     header_crypto,
                     &src_cid,
@@ -492,59 +566,69 @@ impl Endpoint {
             return;
         }
 
+        let server_config = self.server_config.as_ref().unwrap();
         let mut retry_cid = None;
-        if self.server_config.as_ref().unwrap().use_stateless_retry {
-            if let Some((token_dst_cid, token_issued)) = self
-                .server_config
-                .as_ref()
-                .unwrap()
-                .token_key
-                .check(&remote, &token)
-            {
-                let expires = token_issued
-                    + Duration::from_micros(
-                        self.server_config.as_ref().unwrap().retry_token_lifetime,
-                    );
-                if expires > SystemTime::now() {
-                    retry_cid = Some(token_dst_cid);
-                } else {
-                    trace!(self.log, "sending stateless retry due to expired token");
-                }
+        let mut remote_validated = false;
+        if let Some(validated) = server_config.token_key.check(&remote, &token) {
+            let lifetime = match validated.retry_dst_cid {
+                Some(_) => server_config.retry_token_lifetime,
+                None => server_config.new_token_lifetime,
+            };
+            if validated.issued + Duration::from_micros(lifetime) > SystemTime::now() {
+                retry_cid = validated.retry_dst_cid;
+                remote_validated = true;
             } else {
-                trace!(self.log, "sending stateless retry due to invalid token");
+                trace!(self.log, "ignoring expired token");
             }
-            if retry_cid.is_none() {
-                let token = self.server_config.as_ref().unwrap().token_key.generate(
-                    &remote,
-                    &dst_cid,
-                    SystemTime::now(),
-                );
-                let mut buf = Vec::new();
-                let header = Header::Retry {
-                    src_cid: temp_loc_cid,
-                    dst_cid: src_cid,
-                    orig_dst_cid: dst_cid,
-                };
-                let encode = header.encode(&mut buf);
-                encode.finish(&mut buf, header_crypto);
-                buf.put_slice(&token);
+        } else if !token.is_empty() {
+            trace!(self.log, "ignoring invalid token");
+        }
+        if server_config.use_stateless_retry && !remote_validated {
+            trace!(self.log, "sending stateless retry");
+            let token =
+                server_config
+                    .token_key
+                    .generate_retry(&remote, &dst_cid, SystemTime::now());
+            let mut buf = Vec::new();
+            let header = Header::Retry {
+                version,
+                src_cid: temp_loc_cid,
+                dst_cid: src_cid,
+            };
+            let encode = header.encode(&mut buf);
+            encode.finish(&mut buf, header_crypto);
+            buf.put_slice(&token);
+            let tag = crypto::retry_tag(version, &dst_cid, &buf);
+            buf.put_slice(&tag);
 
-                self.transmits.push_back(Transmit {
-                    destination: remote,
-                    ecn: None,
-                    packet: buf.into(),
-                });
-                return;
-            }
+            self.transmits.push_back(Transmit {
+                destination: remote,
+                ecn: None,
+                packet: buf.into(),
+            });
+            return;
         }
 
+        let hello =
+            frame::Iter::new(Bytes::from(&packet.payload[..])).find_map(|frame| match frame {
+                Frame::Crypto(ref x) if x.offset == 0 => ClientHello::parse(&x.data),
+                _ => None,
+            });
+        let accept_early_data = self.accept_early_data(remote, hello.as_ref());
         let ch = self
             .add_connection(
+                self.negotiate_version(version, hello.as_ref()),
                 dst_cid,
                 src_cid,
                 remote,
                 ConnectionOpts::Server {
-                    orig_dst_cid: retry_cid,
+                    // After a Retry, the client's first Initial was addressed to the CID in the
+                    // token, and this one to the CID we chose for the Retry.
+                    orig_dst_cid: retry_cid.unwrap_or(dst_cid),
+                    retry_src_cid: retry_cid.map(|_| dst_cid),
+                    orig_version: version,
+                    remote_validated,
+                    accept_early_data,
                 },
             )
             .unwrap();
@@ -572,23 +656,87 @@ impl Endpoint {
                 self.transmits.push_back(Transmit {
                     destination: remote,
                     ecn: None,
-                    packet: initial_close(crypto, header_crypto, &src_cid, &temp_loc_cid, 0, e),
+                    packet: initial_close(
+                        version,
+                        crypto,
+                        header_crypto,
+                        &src_cid,
+                        &temp_loc_cid,
+                        0,
+                        e,
+                    ),
                 });
             }
         }
     }
 
+    /// Whether to permit 0-RTT on a connection whose first Initial packet carried `hello`
+    fn accept_early_data(&self, remote: SocketAddr, hello: Option<&ClientHello>) -> bool {
+        let server_config = self.server_config.as_ref().unwrap();
+        if server_config.tls_config.max_early_data_size == 0 {
+            // TLS will refuse early data regardless
+            return true;
+        }
+        match hello {
+            Some(hello) if !hello.early_data => true,
+            Some(hello) => server_config.early_data_policy.accept(&EarlyDataRequest {
+                remote,
+                server_name: hello.server_name.as_ref().map(|x| &x[..]),
+                protocols: &hello.protocols,
+            }),
+            None => {
+                debug!(
+                    self.log,
+                    "refusing 0-RTT with ClientHello spanning multiple packets"
+                );
+                false
+            }
+        }
+    }
+
+    /// The version to continue a connection in, given the version of the client's first Initial
+    ///
+    /// Performs compatible version negotiation per RFC 9368, switching to our most preferred
+    /// version among those the client lists as available. Every pair of supported versions is
+    /// compatible.
+    fn negotiate_version(&self, original: u32, hello: Option<&ClientHello>) -> u32 {
+        let available = match hello
+            .and_then(|x| x.transport_parameters.as_ref())
+            .and_then(|x| TransportParameters::read(Side::Server, &mut io::Cursor::new(x)).ok())
+            .and_then(|x| x.version_information)
+        {
+            Some(x) => x.available,
+            None => return original,
+        };
+        let version = self
+            .config
+            .supported_versions
+            .iter()
+            .cloned()
+            .find(|x| available.contains(x))
+            .unwrap_or(original);
+        if version != original {
+            trace!(
+                self.log,
+                "switching from version {original:x} to compatible version {version:x}",
+                original = original,
+                version = version
+            );
+        }
+        version
+    }
+
     /// Connection is either ready to accept data or failed.
     fn conn_ready(&mut self, ch: ConnectionHandle) {
         if self.connections[ch].side().is_server() {
             self.incoming.push_back(ch);
         }
         if self.config.local_cid_len != 0 && !self.connections[ch].is_closed() {
-            /// Draft 17 §5.1.1: endpoints SHOULD provide and maintain at least eight
-            /// connection IDs
-            const LOCAL_CID_COUNT: usize = 8;
+            /// Number of CIDs we'd like the peer to have available, if it can store that many
+            const LOCAL_CID_COUNT: u64 = 8;
+            let count = cmp::min(LOCAL_CID_COUNT, self.connections[ch].peer_cid_limit());
             // We've already issued one CID as part of the normal handshake process.
-            for _ in 1..LOCAL_CID_COUNT {
+            for _ in 1..count {
                 let cid = self.new_cid();
                 self.connection_ids.insert(cid, ch);
                 self.connections[ch].issue_cid(cid);
@@ -596,6 +744,18 @@ impl Endpoint {
         }
     }
 
+    /// Give a client whose handshake has completed a token for use in future connections
+    fn issue_new_token(&mut self, ch: ConnectionHandle) {
+        let remote = self.connections[ch].remote();
+        let token = self
+            .server_config
+            .as_ref()
+            .unwrap()
+            .token_key
+            .generate_new_token(&remote, self.rng.gen(), SystemTime::now());
+        self.connections[ch].issue_new_token(token.into());
+    }
+
     fn forget(&mut self, ch: ConnectionHandle) {
         if self.connections[ch].side().is_server() {
             self.connection_ids_initial
@@ -621,7 +781,7 @@ impl Endpoint {
         }
         self.dirty_timers.insert(ch);
         match timer {
-            Timer::LossDetection => {
+            Timer::LossDetection | Timer::Pacing | Timer::MaxAckDelay => {
                 self.needs_transmit.insert(ch);
             }
             Timer::Idle => {
@@ -688,7 +848,8 @@ impl Endpoint {
     /// Unlike `read`, this interface is not subject to head-of-line blocking within the stream,
     /// and hence can achieve higher throughput over lossy links.
     ///
-    /// Some segments may be received multiple times.
+    /// Some segments may be received multiple times. Data that has already been received is
+    /// returned in order of offset rather than in the order it arrived.
     ///
     /// On success, returns `Ok((data, offset))` where `offset` is the position `data` begins in
     /// the stream.
@@ -714,16 +875,66 @@ impl Endpoint {
     ///
     /// # Panics
     /// - when applied to a receive stream or an unopened send stream
-    pub fn reset(&mut self, ch: ConnectionHandle, stream: StreamId, error_code: u16) {
+    pub fn reset(&mut self, ch: ConnectionHandle, stream: StreamId, error_code: u64) {
         self.connections[ch].reset(stream, error_code);
         self.needs_transmit.insert(ch);
     }
 
+    /// Queue an unreliable, unordered datagram for transmission
+    ///
+    /// See `Connection::send_datagram`.
+    pub fn send_datagram(
+        &mut self,
+        ch: ConnectionHandle,
+        data: Bytes,
+    ) -> Result<(), SendDatagramError> {
+        self.connections[ch].send_datagram(data)?;
+        self.needs_transmit.insert(ch);
+        Ok(())
+    }
+
+    /// Take a received datagram, if any
+    pub fn recv_datagram(&mut self, ch: ConnectionHandle) -> Option<Bytes> {
+        self.connections[ch].recv_datagram()
+    }
+
+    /// Set the priority of a stream
+    ///
+    /// See `Connection::set_priority`. Has no effect on streams that are closed or can't send.
+    pub fn set_priority(&mut self, ch: ConnectionHandle, stream: StreamId, priority: i32) {
+        self.connections[ch].set_priority(stream, priority);
+    }
+
+    /// Set the number of remotely initiated `dir` streams that may be concurrently open
+    ///
+    /// See `Connection::set_max_concurrent_streams`.
+    pub fn set_max_concurrent_streams(
+        &mut self,
+        ch: ConnectionHandle,
+        dir: Directionality,
+        count: u64,
+    ) {
+        self.connections[ch].set_max_concurrent_streams(dir, count);
+        self.needs_transmit.insert(ch);
+    }
+
+    /// Set the connection-level receive window, overriding `Config::receive_window`
+    pub fn set_receive_window(&mut self, ch: ConnectionHandle, window: u64) {
+        self.connections[ch].set_receive_window(window);
+        self.needs_transmit.insert(ch);
+    }
+
+    /// Set the receive window of every stream, overriding `Config::stream_receive_window`
+    pub fn set_stream_receive_window(&mut self, ch: ConnectionHandle, window: u64) {
+        self.connections[ch].set_stream_receive_window(window);
+        self.needs_transmit.insert(ch);
+    }
+
     /// Instruct the peer to abandon transmitting data on a stream
     ///
     /// # Panics
     /// - when applied to a stream that has not begun receiving data
-    pub fn stop_sending(&mut self, ch: ConnectionHandle, stream: StreamId, error_code: u16) {
+    pub fn stop_sending(&mut self, ch: ConnectionHandle, stream: StreamId, error_code: u64) {
         self.connections[ch].stop_sending(stream, error_code);
         self.needs_transmit.insert(ch);
     }
@@ -748,7 +959,7 @@ impl Endpoint {
     ///
     /// This does not ensure delivery of outstanding data. It is the application's responsibility
     /// to call this only when all important communications have been completed.
-    pub fn close(&mut self, now: u64, ch: ConnectionHandle, error_code: u16, reason: Bytes) {
+    pub fn close(&mut self, now: u64, ch: ConnectionHandle, error_code: u64, reason: Bytes) {
         if self.connections[ch].is_drained() {
             self.forget(ch);
             return;
@@ -807,58 +1018,121 @@ pub struct Config {
     /// the desired number of streams opened per unit time, multiplied by the round trip time.
     ///
     /// Note that worst-case memory use is directly proportional to `stream_window_bidi *
-    /// stream_receive_window`, with an upper bound proportional to `receive_window`.
+    /// max_stream_receive_window`, with an upper bound proportional to `max_receive_window`.
     pub stream_window_bidi: u64,
     /// Variant of `stream_window_bidi` affecting unidirectional streams
     pub stream_window_uni: u64,
-    /// Maximum duration of inactivity to accept before timing out the connection (s).
+    /// Maximum duration of inactivity to accept before timing out the connection (ms).
     ///
     /// The actual value used is the minimum of this and the peer's own idle timeout. 0 for none.
     pub idle_timeout: u64,
-    /// Maximum number of bytes the peer may transmit without acknowledgement on any one stream
+    /// Initial number of bytes the peer may transmit without acknowledgement on any one stream
     /// before becoming blocked.
     ///
-    /// This should be set to at least the expected connection latency multiplied by the maximum
-    /// desired throughput. Setting this smaller than `receive_window` helps ensure that a single
-    /// stream doesn't monopolize receive buffers, which may otherwise occur if the application
-    /// chooses not to read from a large stream for a time while still requiring data on other
-    /// streams.
+    /// The window grows automatically, up to `max_stream_receive_window`, when the application
+    /// reads data fast enough that the window limits throughput. Setting this smaller than
+    /// `receive_window` helps ensure that a single stream doesn't monopolize receive buffers, which
+    /// may otherwise occur if the application chooses not to read from a large stream for a time
+    /// while still requiring data on other streams.
     pub stream_receive_window: u64,
-    /// Maximum number of bytes the peer may transmit across all streams of a connection before
+    /// Initial number of bytes the peer may transmit across all streams of a connection before
     /// becoming blocked.
     ///
-    /// This should be set to at least the expected connection latency multiplied by the maximum
-    /// desired throughput. Larger values can be useful to allow maximum throughput within a
-    /// stream while another is blocked.
+    /// Grows automatically up to `max_receive_window`. Larger values can be useful to allow
+    /// maximum throughput within a stream while another is blocked.
     pub receive_window: u64,
+    /// Upper bound on the automatically tuned receive window of any one stream
+    ///
+    /// This should be set to at least the highest expected connection latency multiplied by the
+    /// maximum desired throughput. Set equal to `stream_receive_window` to disable tuning.
+    pub max_stream_receive_window: u64,
+    /// Upper bound on the automatically tuned receive window of a connection
+    ///
+    /// Set equal to `receive_window` to disable tuning.
+    pub max_receive_window: u64,
+    /// Maximum number of bytes of stream data to buffer across all streams of a connection that
+    /// can't be read because earlier data has yet to arrive
+    ///
+    /// Bounds the memory a peer can force the connection to consume by deliberately leaving gaps
+    /// in streams. A peer exceeding this has its connection closed. Flow control alone limits such
+    /// data to `max_receive_window`, so a smaller value bounds memory more tightly, at the risk of
+    /// closing connections that suffer heavy loss while a large window is in flight.
+    pub out_of_order_buffer_size: u64,
+    /// Maximum number of disjoint ranges of data that may be received on any one stream
+    ///
+    /// Each range incurs bookkeeping and buffering overhead regardless of its size. A peer
+    /// exceeding this has its connection closed.
+    pub max_stream_fragments: usize,
+    /// Maximum number of bytes of handshake data to accept beyond that which has been processed
+    ///
+    /// Must be at least 4096. A peer exceeding this has its connection closed.
+    pub crypto_buffer_size: u64,
 
-    /// Maximum number of tail loss probes before an RTO fires.
-    pub max_tlps: u32,
     /// Maximum reordering in packet number space before FACK style loss detection considers a
     /// packet lost.
+    ///
+    /// Widened automatically on connections that observe greater reordering.
     pub packet_threshold: u32,
     /// Maximum reordering in time space before time based loss detection considers a packet lost.
     /// 0.16 format, added to 1
+    ///
+    /// Widened automatically, up to one RTT, on connections that observe greater reordering.
     pub time_threshold: u16,
-    /// The length of the peer’s delayed ack timer (μs).
+    /// Maximum time to wait before acknowledging an ack-eliciting packet (μs).
+    ///
+    /// Advertised to the peer, rounded up to the nearest millisecond, so it can account for the
+    /// delay when estimating the RTT.
     pub delayed_ack_timeout: u64,
+    /// Number of ack-eliciting packets which may be received before an acknowledgement is sent
+    /// immediately, rather than after `delayed_ack_timeout`.
+    ///
+    /// Packets received out of order are always acknowledged immediately.
+    pub ack_eliciting_threshold: u32,
+    /// Smallest `delayed_ack_timeout` the peer may request using ACK_FREQUENCY frames (μs).
+    pub min_ack_delay: u64,
+    /// Whether to ask peers supporting the ACK frequency extension to acknowledge less often
+    ///
+    /// Thresholds are chosen based on the congestion window and RTT, reducing the number of
+    /// acknowledgements sent without impairing loss recovery or congestion control. Disabled by
+    /// default.
+    pub ack_frequency: bool,
     /// The RTT used before an RTT sample is taken (μs)
     pub initial_rtt: u64,
 
-    /// The max packet size that was used for calculating default and minimum congestion windows.
-    pub max_datagram_size: u64,
-    /// Default limit on the amount of outstanding data in bytes.
+    /// Multiple of the probe timeout that a run of consecutive lost packets must span for the
+    /// network to be considered to be experiencing persistent congestion.
+    pub persistent_congestion_threshold: u32,
+    /// How to construct new `congestion::Controller`s
     ///
-    /// Recommended value: `min(10 * max_datagram_size, max(2 * max_datagram_size, 14600))`
-    pub initial_window: u64,
-    /// Default minimum congestion window.
+    /// Typically the refcounted configuration of a `congestion::Controller`, e.g. a
+    /// `congestion::NewRenoConfig`.
+    pub congestion_controller_factory: Arc<dyn congestion::ControllerFactory + Send + Sync>,
+    /// Upper bound on the UDP payload size probed for by path MTU discovery (bytes)
     ///
-    /// Recommended value: `2 * max_datagram_size`.
-    pub minimum_window: u64,
-    /// Reduction in congestion window when a new loss event is detected. 0.16 format
-    pub loss_reduction_factor: u16,
-    /// Number of consecutive PTOs after which network is considered to be experiencing persistent congestion.
-    pub persistent_congestion_threshold: u32,
+    /// Every path is assumed to support payloads of 1232 bytes, so setting this at or below that
+    /// disables discovery. The peer's `max_udp_payload_size` transport parameter further limits
+    /// the size used. The default accommodates a 1500 byte Ethernet MTU with IPv6 and UDP headers.
+    pub max_mtu: u16,
+
+    /// Maximum number of bytes of incoming application datagrams to buffer, or `None` to refuse
+    /// datagrams
+    ///
+    /// The peer is told datagrams are supported iff this is `Some`. Should the application not
+    /// read datagrams quickly enough, the oldest are dropped to make room for new ones.
+    pub datagram_receive_buffer_size: Option<usize>,
+    /// Maximum number of bytes of outgoing application datagrams to buffer
+    ///
+    /// Should datagrams be sent faster than the connection can transmit them, the oldest are
+    /// dropped to make room for new ones.
+    pub datagram_send_buffer_size: usize,
+
+    /// QUIC versions the endpoint is willing to use, in order of preference
+    ///
+    /// Must be a nonempty subset of `SUPPORTED_VERSIONS`. Clients make their first attempt with the
+    /// first entry, falling back to the most preferred version the server also supports if the
+    /// server responds with a Version Negotiation packet. Servers accept connections in any of
+    /// these versions, switching to the most preferred one the client also supports.
+    pub supported_versions: Vec<u32>,
 
     /// Length of connection IDs for the endpoint.
     ///
@@ -872,6 +1146,12 @@ pub struct Config {
     ///
     /// Must be persisted across restarts to be useful.
     pub reset_key: SigningKey,
+
+    /// Where to keep address validation tokens received from servers
+    ///
+    /// Consulted when connecting to a server, so that a token received in a previous connection
+    /// can spare the new one from address validation.
+    pub token_store: Arc<dyn TokenStore>,
 }
 
 impl Default for Config {
@@ -881,7 +1161,7 @@ impl Default for Config {
                                                         // Window size needed to avoid pipeline
                                                         // stalls
         const STREAM_RWND: u64 = MAX_STREAM_BANDWIDTH / 1000 * EXPECTED_RTT;
-        const MAX_DATAGRAM_SIZE: u64 = 1200;
+        const MAX_RWND: u64 = 32 * STREAM_RWND;
 
         let mut reset_value = [0; 64];
         rand::thread_rng().fill_bytes(&mut reset_value);
@@ -889,27 +1169,34 @@ impl Default for Config {
         Self {
             stream_window_bidi: 32,
             stream_window_uni: 32,
-            idle_timeout: 10,
+            idle_timeout: 10_000,
             stream_receive_window: STREAM_RWND,
             receive_window: 8 * STREAM_RWND,
+            max_stream_receive_window: 8 * STREAM_RWND,
+            max_receive_window: MAX_RWND,
+            out_of_order_buffer_size: MAX_RWND,
+            max_stream_fragments: 1024,
+            crypto_buffer_size: 64 * 1024,
 
-            max_tlps: 2,
             packet_threshold: 3,
             time_threshold: 0x2000, // 1/8
             delayed_ack_timeout: 25 * 1000,
+            ack_eliciting_threshold: 2,
+            min_ack_delay: 1000,
+            ack_frequency: false,
             initial_rtt: EXPECTED_RTT as u64 * 1000,
 
-            max_datagram_size: MAX_DATAGRAM_SIZE,
-            initial_window: cmp::min(
-                10 * MAX_DATAGRAM_SIZE,
-                cmp::max(2 * MAX_DATAGRAM_SIZE, 14600),
-            ),
-            minimum_window: 2 * MAX_DATAGRAM_SIZE,
-            loss_reduction_factor: 0x8000, // 1/2
-            persistent_congestion_threshold: 2,
+            persistent_congestion_threshold: 3,
+            congestion_controller_factory: Arc::new(congestion::NewRenoConfig::default()),
+            max_mtu: 1452,
 
+            datagram_receive_buffer_size: Some(STREAM_RWND as usize),
+            datagram_send_buffer_size: 1024 * 1024,
+
+            supported_versions: SUPPORTED_VERSIONS.to_vec(),
             local_cid_len: 8,
             reset_key: SigningKey::new(&digest::SHA512_256, &reset_value),
+            token_store: Arc::new(TokenMemoryCache::default()),
         }
     }
 }
@@ -929,6 +1216,24 @@ pub struct ServerConfig {
     pub use_stateless_retry: bool,
     /// Microseconds after a stateless retry token was issued for which it's considered valid.
     pub retry_token_lifetime: u64,
+    /// Microseconds after a token sent in a NEW_TOKEN frame was issued for which it's considered
+    /// valid.
+    ///
+    /// A token is issued to each client once its handshake completes, allowing a future
+    /// connection from the same address to skip stateless retry.
+    pub new_token_lifetime: u64,
+
+    /// Decides whether to accept 0-RTT data on each incoming connection that offers it.
+    ///
+    /// Consulted only when `tls_config.max_early_data_size` is nonzero. Accepts everything by
+    /// default. Connections which refuse 0-RTT are issued session tickets that don't permit it.
+    pub early_data_policy: Arc<dyn EarlyDataPolicy>,
+    /// Maximum number of bytes of 0-RTT packet payload accepted on a connection.
+    ///
+    /// Further 0-RTT packets are dropped, and their contents retransmitted by the client once the
+    /// handshake completes. Unlike `tls_config.max_early_data_size`, which QUIC requires to be
+    /// either 0 or `0xffff_ffff`, this bounds how much potentially replayed data a server acts on.
+    pub max_early_data_size: u64,
 
     /// Maximum number of incoming connections to buffer.
     ///
@@ -950,6 +1255,10 @@ impl Default for ServerConfig {
             token_key: TokenKey::new(&token_value),
             use_stateless_retry: false,
             retry_token_lifetime: 15_000_000,
+            new_token_lifetime: 14 * 24 * 60 * 60 * 1_000_000,
+
+            early_data_policy: Arc::new(|_: &EarlyDataRequest<'_>| true),
+            max_early_data_size: u64::max_value(),
 
             accept_buffer: 1024,
         }
@@ -990,6 +1299,8 @@ pub enum Event {
     StreamFinished { stream: StreamId },
     /// At least one new stream of a certain directionality may be opened
     StreamAvailable { directionality: Directionality },
+    /// One or more application datagrams have been received and may be read with `recv_datagram`
+    DatagramReceived,
 }
 
 impl From<ConnectionError> for Event {
@@ -1006,15 +1317,21 @@ pub enum Timer {
     Close = 2,
     KeyDiscard = 3,
     PathValidation = 4,
+    /// When the pacing timer expires, transmission of more data may resume.
+    Pacing = 5,
+    /// When the delayed ACK timer expires, received packets must be acknowledged.
+    MaxAckDelay = 6,
 }
 
 impl Timer {
-    pub(crate) const VALUES: [Timer; 5] = [
+    pub(crate) const VALUES: [Timer; 7] = [
         Timer::LossDetection,
         Timer::Idle,
         Timer::Close,
         Timer::KeyDiscard,
         Timer::PathValidation,
+        Timer::Pacing,
+        Timer::MaxAckDelay,
     ];
 }
 
@@ -1053,5 +1370,16 @@ impl IndexMut<ConnectionHandle> for Slab<Connection> {
 
 enum ConnectionOpts {
     Client(ClientConfig),
-    Server { orig_dst_cid: Option<ConnectionId> },
+    Server {
+        /// Destination CID of the client's first Initial packet
+        orig_dst_cid: ConnectionId,
+        /// Source CID of the Retry packet we sent, if any
+        retry_src_cid: Option<ConnectionId>,
+        /// Version of the client's first Initial packet
+        orig_version: u32,
+        /// Whether the client presented a valid address validation token
+        remote_validated: bool,
+        /// Whether 0-RTT data may be accepted, if the client offers it
+        accept_early_data: bool,
+    },
 }
@@ -7,8 +7,7 @@ use crate::coding::{self, BufExt, BufMutExt, UnexpectedEnd};
 use crate::packet::EcnCodepoint;
 use crate::range_set::RangeSet;
 use crate::{
-    varint, ConnectionId, Directionality, StreamId, TransportError, MAX_CID_SIZE, MIN_CID_SIZE,
-    RESET_TOKEN_SIZE,
+    varint, ConnectionId, Directionality, StreamId, TransportError, MAX_CID_SIZE, RESET_TOKEN_SIZE,
 };
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -115,6 +114,11 @@ frame_types! {
     PATH_RESPONSE = 0x1b,
     CONNECTION_CLOSE = 0x1c,
     APPLICATION_CLOSE = 0x1d,
+    HANDSHAKE_DONE = 0x1e,
+    IMMEDIATE_ACK = 0x1f,
+    DATAGRAM = 0x30,
+    DATAGRAM_LEN = 0x31,
+    ACK_FREQUENCY = 0xaf,
 }
 
 const STREAM_TY_MIN: u64 = 0x08;
@@ -128,7 +132,7 @@ pub enum Frame {
     ResetStream(ResetStream),
     StopSending {
         id: StreamId,
-        error_code: u16,
+        error_code: u64,
     },
     Crypto(Crypto),
     NewToken {
@@ -163,6 +167,10 @@ pub enum Frame {
     PathResponse(u64),
     ConnectionClose(ConnectionClose),
     ApplicationClose(ApplicationClose),
+    HandshakeDone,
+    AckFrequency(AckFrequency),
+    ImmediateAck,
+    Datagram(Datagram),
     Invalid(Type),
     Illegal(Type),
 }
@@ -200,7 +208,7 @@ impl Frame {
             RetireConnectionId { .. } => Type::RETIRE_CONNECTION_ID,
             Ack(_) => Type::ACK,
             Stream(ref x) => {
-                let mut ty = 0x10;
+                let mut ty = STREAM_TY_MIN;
                 if x.fin {
                     ty |= 0x01;
                 }
@@ -214,6 +222,10 @@ impl Frame {
             NewConnectionId { .. } => Type::NEW_CONNECTION_ID,
             Crypto(_) => Type::CRYPTO,
             NewToken { .. } => Type::NEW_TOKEN,
+            HandshakeDone => Type::HANDSHAKE_DONE,
+            AckFrequency(_) => Type::ACK_FREQUENCY,
+            ImmediateAck => Type::IMMEDIATE_ACK,
+            Datagram(_) => Type::DATAGRAM_LEN,
             Invalid(ty) => ty,
             Illegal(ty) => ty,
         }
@@ -249,17 +261,18 @@ impl From<TransportError> for ConnectionClose {
 }
 
 impl FrameStruct for ConnectionClose {
-    const SIZE_BOUND: usize = 1 + 2 + 8 + 8;
+    const SIZE_BOUND: usize = 1 + 8 + 8 + 8;
 }
 
 impl ConnectionClose {
     pub fn encode<W: BufMut>(&self, out: &mut W, max_len: usize) {
         out.write(Type::CONNECTION_CLOSE); // 1 byte
-        out.write(self.error_code); // 2 bytes
+        out.write(self.error_code); // <= 8 bytes
         let ty = self.frame_type.map_or(0, |x| x.0);
         out.write_var(ty); // <= 8 bytes
         let max_len = max_len
-            - 3
+            - 1
+            - varint::size(u64::from(self.error_code)).unwrap()
             - varint::size(ty).unwrap()
             - varint::size(self.reason.len() as u64).unwrap();
         let actual_len = self.reason.len().min(max_len);
@@ -270,7 +283,7 @@ impl ConnectionClose {
 
 #[derive(Debug, Clone)]
 pub struct ApplicationClose {
-    pub error_code: u16,
+    pub error_code: u64,
     pub reason: Bytes,
 }
 
@@ -289,14 +302,17 @@ impl fmt::Display for ApplicationClose {
 }
 
 impl FrameStruct for ApplicationClose {
-    const SIZE_BOUND: usize = 1 + 2 + 8;
+    const SIZE_BOUND: usize = 1 + 8 + 8;
 }
 
 impl ApplicationClose {
     pub fn encode<W: BufMut>(&self, out: &mut W, max_len: usize) {
         out.write(Type::APPLICATION_CLOSE); // 1 byte
-        out.write(self.error_code); // 2 bytes
-        let max_len = max_len as usize - 3 - varint::size(self.reason.len() as u64).unwrap();
+        out.write_var(self.error_code); // <= 8 bytes
+        let max_len = max_len
+            - 1
+            - varint::size(self.error_code).unwrap()
+            - varint::size(self.reason.len() as u64).unwrap();
         let actual_len = self.reason.len().min(max_len);
         out.write_var(actual_len as u64); // <= 8 bytes
         out.put_slice(&self.reason[0..actual_len]); // whatever's left
@@ -425,6 +441,14 @@ impl Stream {
     }
 }
 
+/// Metadata from a stream frame
+#[derive(Debug, Clone)]
+pub struct StreamMeta {
+    pub id: StreamId,
+    pub offsets: Range<u64>,
+    pub fin: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct Crypto {
     pub offset: u64,
@@ -442,6 +466,55 @@ impl Crypto {
     }
 }
 
+/// Request for the peer to adjust how often it sends acknowledgements
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AckFrequency {
+    /// Identifies the most recent request, as frames may arrive out of order
+    pub sequence: u64,
+    /// Number of ack-eliciting packets the peer may receive without acknowledging immediately
+    pub ack_eliciting_threshold: u64,
+    /// Maximum time the peer may delay acknowledgements (μs)
+    pub request_max_ack_delay: u64,
+    /// Extent of reordering which prompts an immediate acknowledgement, or 0 to ignore reordering
+    pub reordering_threshold: u64,
+}
+
+impl FrameStruct for AckFrequency {
+    const SIZE_BOUND: usize = 2 + 8 + 8 + 8 + 8;
+}
+
+impl AckFrequency {
+    pub fn encode<W: BufMut>(&self, out: &mut W) {
+        out.write(Type::ACK_FREQUENCY);
+        out.write_var(self.sequence);
+        out.write_var(self.ack_eliciting_threshold);
+        out.write_var(self.request_max_ack_delay);
+        out.write_var(self.reordering_threshold);
+    }
+}
+
+/// An unreliable application datagram
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    pub data: Bytes,
+}
+
+impl FrameStruct for Datagram {
+    const SIZE_BOUND: usize = 1 + 8;
+}
+
+impl Datagram {
+    pub fn encode<W: BufMut>(&self, length: bool, out: &mut W) {
+        if length {
+            out.write(Type::DATAGRAM_LEN);
+            out.write_var(self.data.len() as u64);
+        } else {
+            out.write(Type::DATAGRAM);
+        }
+        out.put_slice(&self.data);
+    }
+}
+
 pub struct Iter {
     // TODO: ditch io::Cursor after bytes 0.5
     bytes: io::Cursor<Bytes>,
@@ -479,6 +552,13 @@ impl Iter {
         Ok(self.bytes.get_ref().slice(start, start + len as usize))
     }
 
+    fn take_remaining(&mut self) -> Bytes {
+        let mut x = mem::replace(self.bytes.get_mut(), Bytes::new());
+        x.advance(self.bytes.position() as usize);
+        self.bytes.set_position(0);
+        x
+    }
+
     fn try_next(&mut self) -> Result<Frame, IterErr> {
         let ty_start = self.bytes.position();
         let ty = self.bytes.get::<Type>()?;
@@ -491,7 +571,7 @@ impl Iter {
             Type::PADDING => Frame::Padding,
             Type::RESET_STREAM => Frame::ResetStream(ResetStream {
                 id: self.bytes.get()?,
-                error_code: self.bytes.get()?,
+                error_code: self.bytes.get_var()?,
                 final_offset: self.bytes.get_var()?,
             }),
             Type::CONNECTION_CLOSE => Frame::ConnectionClose(ConnectionClose {
@@ -507,7 +587,7 @@ impl Iter {
                 reason: self.take_len()?,
             }),
             Type::APPLICATION_CLOSE => Frame::ApplicationClose(ApplicationClose {
-                error_code: self.bytes.get()?,
+                error_code: self.bytes.get_var()?,
                 reason: self.take_len()?,
             }),
             Type::MAX_DATA => Frame::MaxData(self.bytes.get_var()?),
@@ -541,7 +621,7 @@ impl Iter {
             },
             Type::STOP_SENDING => Frame::StopSending {
                 id: self.bytes.get()?,
-                error_code: self.bytes.get()?,
+                error_code: self.bytes.get_var()?,
             },
             Type::RETIRE_CONNECTION_ID => Frame::RetireConnectionId {
                 sequence: self.bytes.get_var()?,
@@ -573,8 +653,12 @@ impl Iter {
             Type::PATH_RESPONSE => Frame::PathResponse(self.bytes.get()?),
             Type::NEW_CONNECTION_ID => {
                 let sequence = self.bytes.get_var()?;
+                let retire_prior_to = self.bytes.get_var()?;
+                if retire_prior_to > sequence {
+                    return Err(IterErr::Malformed);
+                }
                 let length = self.bytes.get::<u8>()? as usize;
-                if length < MIN_CID_SIZE || length > MAX_CID_SIZE {
+                if length == 0 || length > MAX_CID_SIZE {
                     return Err(IterErr::Malformed);
                 }
                 if length > self.bytes.remaining() {
@@ -590,6 +674,7 @@ impl Iter {
                 self.bytes.copy_to_slice(&mut reset_token);
                 Frame::NewConnectionId(NewConnectionId {
                     sequence,
+                    retire_prior_to,
                     id,
                     reset_token,
                 })
@@ -601,6 +686,20 @@ impl Iter {
             Type::NEW_TOKEN => Frame::NewToken {
                 token: self.take_len()?,
             },
+            Type::ACK_FREQUENCY => Frame::AckFrequency(AckFrequency {
+                sequence: self.bytes.get_var()?,
+                ack_eliciting_threshold: self.bytes.get_var()?,
+                request_max_ack_delay: self.bytes.get_var()?,
+                reordering_threshold: self.bytes.get_var()?,
+            }),
+            Type::HANDSHAKE_DONE => Frame::HandshakeDone,
+            Type::IMMEDIATE_ACK => Frame::ImmediateAck,
+            Type::DATAGRAM_LEN => Frame::Datagram(Datagram {
+                data: self.take_len()?,
+            }),
+            Type::DATAGRAM => Frame::Datagram(Datagram {
+                data: self.take_remaining(),
+            }),
             _ => match ty.stream() {
                 Some(s) => Frame::Stream(Stream {
                     id: self.bytes.get()?,
@@ -609,10 +708,7 @@ impl Iter {
                     data: if s.len() {
                         self.take_len()?
                     } else {
-                        let mut x = mem::replace(self.bytes.get_mut(), Bytes::new());
-                        x.advance(self.bytes.position() as usize);
-                        self.bytes.set_position(0);
-                        x
+                        self.take_remaining()
                     },
                 }),
                 None => {
@@ -687,19 +783,19 @@ impl<'a> Iterator for AckIter<'a> {
 #[derive(Debug, Copy, Clone)]
 pub struct ResetStream {
     pub id: StreamId,
-    pub error_code: u16,
+    pub error_code: u64,
     pub final_offset: u64,
 }
 
 impl FrameStruct for ResetStream {
-    const SIZE_BOUND: usize = 1 + 8 + 2 + 8;
+    const SIZE_BOUND: usize = 1 + 8 + 8 + 8;
 }
 
 impl ResetStream {
     pub fn encode<W: BufMut>(&self, out: &mut W) {
         out.write(Type::RESET_STREAM); // 1 byte
         out.write_var(self.id.0); // <= 8 bytes
-        out.write(self.error_code); // 2 bytes
+        out.write_var(self.error_code); // <= 8 bytes
         out.write_var(self.final_offset); // <= 8 bytes
     }
 }
@@ -707,14 +803,21 @@ impl ResetStream {
 #[derive(Debug, Copy, Clone)]
 pub struct NewConnectionId {
     pub sequence: u64,
+    /// CIDs with sequence numbers below this must be retired
+    pub retire_prior_to: u64,
     pub id: ConnectionId,
     pub reset_token: [u8; 16],
 }
 
+impl FrameStruct for NewConnectionId {
+    const SIZE_BOUND: usize = 1 + 8 + 8 + 1 + MAX_CID_SIZE + RESET_TOKEN_SIZE;
+}
+
 impl NewConnectionId {
     pub fn encode<W: BufMut>(&self, out: &mut W) {
         out.write(Type::NEW_CONNECTION_ID);
         out.write_var(self.sequence);
+        out.write_var(self.retire_prior_to);
         out.write(self.id.len() as u8);
         out.put_slice(&self.id);
         out.put_slice(&self.reset_token);
@@ -727,6 +830,7 @@ pub const RETIRE_CONNECTION_ID_SIZE_BOUND: usize = 9;
 #[cfg(test)]
 mod test {
     use super::*;
+    use rustls::internal::msgs::enums::AlertDescription;
 
     #[test]
     fn ack_coding() {
@@ -754,4 +858,62 @@ mod test {
             ref x => panic!("incorrect frame {:?}", x),
         }
     }
+
+    #[test]
+    fn server_initial_payload() {
+        // From RFC 9001 Appendix A.3
+        let payload = hex!(
+            "02000000000600405a020000560303ee fce7f7b37ba1d1632e96677825ddf739
+             88cfc79825df566dc5430b9a045a1200 130100002e00330024001d00209d3c94
+             0d89690b84d08a60993c144eca684d10 81287c834d5311bcf32bb9da1a002b00
+             020304"
+        );
+        let frames = Iter::new(Bytes::from(&payload[..])).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 2);
+        match frames[0] {
+            Frame::Ack(ref ack) => {
+                assert_eq!(ack.iter().collect::<Vec<_>>(), [0..1]);
+                assert_eq!(ack.delay, 0);
+            }
+            ref x => panic!("incorrect frame {:?}", x),
+        }
+        assert_matches!(frames[1], Frame::Crypto(ref x) if x.offset == 0 && x.data[..] == payload[9..]);
+    }
+
+    #[test]
+    fn close_coding() {
+        let mut buf = Vec::new();
+        ConnectionClose {
+            error_code: TransportError::crypto(AlertDescription::HandshakeFailure),
+            frame_type: Some(Type::CRYPTO),
+            reason: Bytes::from(&b"oops"[..]),
+        }
+        .encode(&mut buf, 64);
+        ApplicationClose {
+            error_code: 0x1234_5678,
+            reason: Bytes::new(),
+        }
+        .encode(&mut buf, 64);
+        assert_eq!(buf[..], hex!("1c 4128 06 04 6f6f7073 1d 92345678 00")[..]);
+        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        assert_matches!(frames[0], Frame::ConnectionClose(ref x) if x.error_code == TransportError::crypto(AlertDescription::HandshakeFailure) && x.frame_type == Some(Type::CRYPTO));
+        assert_matches!(frames[1], Frame::ApplicationClose(ref x) if x.error_code == 0x1234_5678);
+    }
+
+    #[test]
+    fn ack_frequency_coding() {
+        let frame = AckFrequency {
+            sequence: 7,
+            ack_eliciting_threshold: 10,
+            request_max_ack_delay: 123_456,
+            reordering_threshold: 0,
+        };
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        buf.write(Type::IMMEDIATE_ACK);
+        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 2);
+        assert_matches!(frames[0], Frame::AckFrequency(x) if x == frame);
+        assert_matches!(frames[1], Frame::ImmediateAck);
+    }
 }
@@ -14,24 +14,35 @@ use std::fmt;
 use std::net::SocketAddr;
 use std::ops;
 
+mod ack_frequency;
 mod coding;
+pub mod congestion;
 mod dedup;
+mod mtud;
+mod pacing;
 mod range_set;
+mod send_buffer;
 #[cfg(test)]
 mod tests;
 mod transport_parameters;
 mod varint;
 
 mod connection;
-pub use crate::connection::{ConnectionError, TimerSetting, TimerUpdate};
+pub use crate::connection::{ConnectionError, RttEstimator, TimerSetting, TimerUpdate};
 
 mod crypto;
 pub use crate::crypto::{ClientConfig, ConnectError, TokenKey};
 
+mod datagrams;
+pub use crate::datagrams::SendDatagramError;
+
 mod frame;
 use crate::frame::Frame;
 pub use crate::frame::{ApplicationClose, ConnectionClose};
 
+mod early_data;
+pub use crate::early_data::{EarlyDataPolicy, EarlyDataRequest};
+
 mod endpoint;
 pub use crate::endpoint::{
     Config, ConnectionHandle, Endpoint, EndpointError, Event, ServerConfig, Timer,
@@ -40,17 +51,31 @@ pub use crate::endpoint::{
 mod packet;
 pub use crate::packet::{ConnectionId, EcnCodepoint};
 
+mod session;
+pub use crate::session::{set_session_store, SessionFileStore, SessionMemoryCache, SessionStore};
+
 mod stream;
 pub use crate::stream::{ReadError, WriteError};
 
+mod token;
+pub use crate::token::{TokenMemoryCache, TokenStore};
+
 mod transport_error;
 pub use crate::transport_error::Error as TransportError;
 
-/// The QUIC protocol version implemented
-pub const VERSION: u32 = 0xff00_0011;
+/// QUIC version 1, as described in RFC 9000
+///
+/// Not yet interoperable with other implementations: the transport parameters travel in the TLS
+/// extension codepoint chosen by rustls, which is still the draft value 0xffa5 rather than 0x39.
+pub const VERSION_1: u32 = 0x0000_0001;
+/// QUIC version 2, as described in RFC 9369
+pub const VERSION_2: u32 = 0x6b33_43cf;
+
+/// The QUIC protocol versions implemented, in order of preference
+pub const SUPPORTED_VERSIONS: &[u32] = &[VERSION_1, VERSION_2];
 
-/// TLS ALPN value for HTTP over QUIC
-pub const ALPN_QUIC_HTTP: &[u8] = b"hq-17";
+/// TLS ALPN value for HTTP/0.9 over QUIC, as used for interoperability testing
+pub const ALPN_QUIC_HTTP: &[u8] = b"hq-interop";
 
 /// Whether an endpoint was the initiator of a connection
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -208,8 +233,9 @@ pub struct Transmit {
 //
 
 const RESET_TOKEN_SIZE: usize = 16;
-const MAX_CID_SIZE: usize = 18;
-const MIN_CID_SIZE: usize = 4;
+const MAX_CID_SIZE: usize = 20;
+/// Number of connection IDs issued by the peer that we're willing to store, including the active one
+const REM_CID_COUNT: u64 = 8;
 const MIN_INITIAL_SIZE: usize = 1200;
 const MIN_MTU: u16 = 1232;
 const TIMER_GRANULARITY: u64 = 1000;
@@ -0,0 +1,207 @@
+//! Datagram packetization layer path MTU discovery, as described in RFC 8899
+
+use std::cmp;
+
+use crate::MIN_MTU;
+
+/// Searches for the largest UDP payload the path to the peer can carry
+///
+/// Candidate sizes are tested by sending probe packets consisting of a PING frame padded out to
+/// the candidate size. A binary search is performed between the largest size known to work and
+/// the upper bound, and repeated every `RAISE_INTERVAL` in case the path has improved. Should
+/// packets larger than `MIN_MTU` repeatedly go missing without any being acknowledged in between,
+/// the path is assumed to have become a black hole for them and the MTU falls back to `MIN_MTU`.
+pub struct MtuDiscovery {
+    /// Largest payload size currently in use
+    current: u16,
+    /// Largest payload size that may be probed for
+    max: u16,
+    /// Largest size known to work in the current search
+    lo: u16,
+    /// Smallest size known not to work in the current search, or one past `max`
+    hi: u16,
+    /// Size of the outstanding probe, if any
+    probe_in_flight: Option<u16>,
+    /// Number of probes of the current candidate size that have been lost
+    failures: u8,
+    /// Earliest time at which probes may be sent
+    next_search: u64,
+    /// Number of loss events involving packets larger than `MIN_MTU` since one was last acked
+    suspicious_losses: u8,
+}
+
+impl MtuDiscovery {
+    /// Start discovery with an upper bound of `max`
+    pub fn new(max: u16) -> Self {
+        let max = cmp::min(max, MAX_UDP_PAYLOAD);
+        Self {
+            current: MIN_MTU,
+            max,
+            lo: MIN_MTU,
+            hi: max + 1,
+            probe_in_flight: None,
+            failures: 0,
+            next_search: 0,
+            suspicious_losses: 0,
+        }
+    }
+
+    /// Largest UDP payload that may currently be sent
+    pub fn mtu(&self) -> u16 {
+        self.current
+    }
+
+    /// Apply the peer's `max_udp_payload_size` transport parameter
+    pub fn set_peer_max(&mut self, peer_max: u64) {
+        if peer_max < u64::from(self.max) {
+            self.max = cmp::max(peer_max as u16, MIN_MTU);
+            self.hi = cmp::min(self.hi, self.max + 1);
+        }
+    }
+
+    /// Forget everything learned about the path, e.g. due to migration
+    pub fn reset(&mut self) {
+        *self = Self::new(self.max);
+    }
+
+    /// Size of the next probe to send, if one should be sent at `now`
+    pub fn probe_size(&self, now: u64) -> Option<u16> {
+        if self.probe_in_flight.is_some()
+            || now < self.next_search
+            || self.hi.saturating_sub(self.lo) <= SEARCH_THRESHOLD
+        {
+            return None;
+        }
+        Some(self.lo + (self.hi - self.lo) / 2)
+    }
+
+    pub fn on_probe_sent(&mut self, size: u16) {
+        self.probe_in_flight = Some(size);
+    }
+
+    pub fn on_probe_acked(&mut self, now: u64, size: u16) {
+        if self.probe_in_flight != Some(size) {
+            // Stale probe from before a reset
+            return;
+        }
+        self.probe_in_flight = None;
+        self.failures = 0;
+        self.suspicious_losses = 0;
+        self.lo = size;
+        self.current = size;
+        self.check_done(now);
+    }
+
+    pub fn on_probe_lost(&mut self, now: u64, size: u16) {
+        if self.probe_in_flight != Some(size) {
+            return;
+        }
+        self.probe_in_flight = None;
+        self.failures += 1;
+        if self.failures == MAX_PROBES {
+            self.failures = 0;
+            self.hi = size;
+            self.check_done(now);
+        }
+    }
+
+    /// A non-probe packet larger than `MIN_MTU` was acknowledged
+    pub fn on_large_acked(&mut self) {
+        self.suspicious_losses = 0;
+    }
+
+    /// One or more non-probe packets larger than `MIN_MTU` were deemed lost
+    ///
+    /// Returns whether the MTU was reduced as a result.
+    pub fn on_large_lost(&mut self, now: u64) -> bool {
+        if self.current == MIN_MTU {
+            return false;
+        }
+        self.suspicious_losses += 1;
+        if self.suspicious_losses < BLACK_HOLE_THRESHOLD {
+            return false;
+        }
+        *self = Self {
+            next_search: now + RAISE_INTERVAL,
+            ..Self::new(self.max)
+        };
+        true
+    }
+
+    fn check_done(&mut self, now: u64) {
+        if self.hi.saturating_sub(self.lo) > SEARCH_THRESHOLD {
+            return;
+        }
+        // Search again later, in case the path has changed
+        self.lo = self.current;
+        self.hi = self.max + 1;
+        self.next_search = now + RAISE_INTERVAL;
+    }
+}
+
+/// Largest UDP payload that can be carried by IPv4
+const MAX_UDP_PAYLOAD: u16 = 65527;
+/// Search is considered complete when the candidate sizes are this close together (bytes)
+const SEARCH_THRESHOLD: u16 = 20;
+/// Number of lost probes after which a size is deemed not to work
+const MAX_PROBES: u8 = 3;
+/// Number of suspicious loss events after which the path is deemed to be a black hole
+const BLACK_HOLE_THRESHOLD: u8 = 3;
+/// Interval between successive searches (μs)
+const RAISE_INTERVAL: u64 = 600 * 1000 * 1000;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run a search against a path carrying payloads up to `path_mtu`
+    fn search(mtud: &mut MtuDiscovery, path_mtu: u16, now: u64) {
+        while let Some(size) = mtud.probe_size(now) {
+            mtud.on_probe_sent(size);
+            if size <= path_mtu {
+                mtud.on_probe_acked(now, size);
+            } else {
+                mtud.on_probe_lost(now, size);
+            }
+        }
+    }
+
+    #[test]
+    fn converges() {
+        let mut mtud = MtuDiscovery::new(1500);
+        search(&mut mtud, 1400, 0);
+        assert!(mtud.mtu() <= 1400 && mtud.mtu() > 1400 - SEARCH_THRESHOLD);
+        // No further probes until the raise interval has passed
+        assert_eq!(mtud.probe_size(1), None);
+        assert!(mtud.probe_size(RAISE_INTERVAL).is_some());
+    }
+
+    #[test]
+    fn peer_max() {
+        let mut mtud = MtuDiscovery::new(9000);
+        mtud.set_peer_max(1300);
+        search(&mut mtud, 9000, 0);
+        assert!(mtud.mtu() <= 1300 && mtud.mtu() > 1300 - SEARCH_THRESHOLD);
+    }
+
+    #[test]
+    fn disabled() {
+        let mtud = MtuDiscovery::new(MIN_MTU);
+        assert_eq!(mtud.probe_size(0), None);
+        assert_eq!(mtud.mtu(), MIN_MTU);
+    }
+
+    #[test]
+    fn black_hole() {
+        let mut mtud = MtuDiscovery::new(1500);
+        search(&mut mtud, 1500, 0);
+        assert!(mtud.mtu() > MIN_MTU);
+        assert!(!mtud.on_large_lost(1));
+        mtud.on_large_acked();
+        assert!(!mtud.on_large_lost(2));
+        assert!(!mtud.on_large_lost(3));
+        assert!(mtud.on_large_lost(4));
+        assert_eq!(mtud.mtu(), MIN_MTU);
+        assert_eq!(mtud.probe_size(5), None);
+    }
+}
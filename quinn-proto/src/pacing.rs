@@ -0,0 +1,164 @@
+//! Pacing of packet transmissions
+
+use std::cmp;
+
+/// A token bucket spreading transmissions over the smoothed RTT
+///
+/// Tokens accumulate at a rate of roughly one congestion window per smoothed RTT, up to a burst
+/// allowance, and each transmitted byte consumes a token. This prevents a full window from being
+/// emitted back-to-back and overflowing shallow buffers along the path.
+pub struct Pacer {
+    /// Maximum number of tokens, i.e. the largest burst that may be sent at once (bytes)
+    capacity: u64,
+    /// Smoothed RTT `capacity` was computed for
+    last_rtt: u64,
+    /// Congestion window `capacity` was computed for
+    last_window: u64,
+    /// MTU `capacity` was computed for
+    last_mtu: u16,
+    /// Number of bytes that may currently be sent
+    tokens: u64,
+    /// Time at which `tokens` was last refilled
+    prev: u64,
+}
+
+impl Pacer {
+    pub fn new(window: u64, mtu: u16) -> Self {
+        let capacity = optimal_capacity(0, window, mtu);
+        Self {
+            capacity,
+            last_rtt: 0,
+            last_window: window,
+            last_mtu: mtu,
+            tokens: capacity,
+            prev: 0,
+        }
+    }
+
+    /// Record that a packet of `bytes` bytes has been sent
+    pub fn on_transmit(&mut self, bytes: u16) {
+        self.tokens = self.tokens.saturating_sub(u64::from(bytes));
+    }
+
+    /// Determine when a packet of `mtu` bytes may be sent
+    ///
+    /// Returns `None` if it may be sent immediately, otherwise the earliest time at which it may be.
+    pub fn delay(&mut self, smoothed_rtt: u64, mtu: u16, window: u64, now: u64) -> Option<u64> {
+        debug_assert_ne!(window, 0, "zero-sized congestion window");
+        if smoothed_rtt != self.last_rtt || window != self.last_window || mtu != self.last_mtu {
+            self.capacity = optimal_capacity(smoothed_rtt, window, mtu);
+            self.tokens = cmp::min(self.tokens, self.capacity);
+            self.last_rtt = smoothed_rtt;
+            self.last_window = window;
+            self.last_mtu = mtu;
+        }
+
+        let needed = u64::from(mtu);
+        if self.tokens >= needed {
+            return None;
+        }
+        if smoothed_rtt == 0 {
+            // No RTT estimate to pace over yet
+            return None;
+        }
+
+        // Refill slightly faster than one window per RTT, so that the congestion window rather than
+        // the pacer remains the limiting factor when transmissions are ack-clocked.
+        let elapsed = now.saturating_sub(self.prev);
+        let new_tokens = (u128::from(window) * u128::from(elapsed)).saturating_mul(5)
+            / 4
+            / u128::from(smoothed_rtt);
+        let new_tokens = cmp::min(new_tokens, u128::from(self.capacity)) as u64;
+        self.tokens = cmp::min(self.tokens + new_tokens, self.capacity);
+        self.prev = now;
+        if self.tokens >= needed {
+            return None;
+        }
+
+        // Round up, so enough tokens will have accumulated by then despite truncation above
+        let (scaled, divisor) = ((needed - self.tokens) * smoothed_rtt * 4, window * 5);
+        let delay = scaled / divisor + (scaled % divisor != 0) as u64;
+        Some(now + delay)
+    }
+}
+
+/// Largest burst that should be sent at once given the window, RTT, and MTU
+///
+/// Allows a window's worth of transmissions to be sent in batches spaced `BURST_INTERVAL` apart,
+/// clamped to a reasonable number of packets.
+fn optimal_capacity(smoothed_rtt: u64, window: u64, mtu: u16) -> u64 {
+    let mtu = u64::from(mtu);
+    if smoothed_rtt == 0 {
+        return MAX_BURST_SIZE * mtu;
+    }
+    let capacity = window.saturating_mul(BURST_INTERVAL) / smoothed_rtt;
+    cmp::max(
+        cmp::min(capacity, MAX_BURST_SIZE * mtu),
+        MIN_BURST_SIZE * mtu,
+    )
+}
+
+/// Period of bursts when transmitting at the pacing rate (μs)
+const BURST_INTERVAL: u64 = 2000;
+/// Smallest burst allowance, in packets
+const MIN_BURST_SIZE: u64 = 10;
+/// Largest burst allowance, in packets
+const MAX_BURST_SIZE: u64 = 256;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn burst_then_pace() {
+        const MTU: u16 = 1232;
+        const RTT: u64 = 100_000;
+        let window = 100 * u64::from(MTU);
+        let mut pacer = Pacer::new(window, MTU);
+        let mut now = 0;
+        // Burst allowance
+        for _ in 0..MIN_BURST_SIZE {
+            assert_eq!(pacer.delay(RTT, MTU, window, now), None);
+            pacer.on_transmit(MTU);
+        }
+        // Subsequent packets are spread out at about one window per RTT
+        let mut sent = 0;
+        while now < RTT {
+            match pacer.delay(RTT, MTU, window, now) {
+                None => {
+                    pacer.on_transmit(MTU);
+                    sent += 1;
+                }
+                Some(t) => {
+                    assert!(t > now);
+                    now = t;
+                }
+            }
+        }
+        assert!(
+            sent >= 100 && sent <= 140,
+            "sent {} packets in one RTT",
+            sent
+        );
+    }
+
+    #[test]
+    fn no_rtt_no_pacing() {
+        let mut pacer = Pacer::new(12000, 1200);
+        for _ in 0..1000 {
+            assert_eq!(pacer.delay(0, 1200, 12000, 0), None);
+            pacer.on_transmit(1200);
+        }
+    }
+
+    #[test]
+    fn long_idle() {
+        const MTU: u16 = 1200;
+        let window = u64::max_value() / 2;
+        let mut pacer = Pacer::new(window, MTU);
+        pacer.on_transmit(MTU);
+        pacer.tokens = 0;
+        // Refilling after a long pause with a huge window mustn't overflow
+        assert_eq!(pacer.delay(1, MTU, window, u64::max_value() / 2), None);
+    }
+}
@@ -8,7 +8,7 @@ use slog;
 use crate::coding::{self, BufExt, BufMutExt};
 use crate::crypto::HeaderCrypto;
 use crate::varint;
-use crate::{MAX_CID_SIZE, MIN_CID_SIZE, VERSION};
+use crate::{MAX_CID_SIZE, VERSION_2};
 
 // Due to packet number encryption, it is impossible to fully decode a header
 // (which includes a variable-length packet number) without crypto context.
@@ -29,9 +29,10 @@ impl PartialDecode {
     pub fn new(
         bytes: BytesMut,
         local_cid_len: usize,
+        supported_versions: &[u32],
     ) -> Result<(Self, Option<BytesMut>), PacketDecodeError> {
         let mut buf = io::Cursor::new(bytes);
-        let plain_header = PlainHeader::decode(&mut buf, local_cid_len)?;
+        let plain_header = PlainHeader::decode(&mut buf, local_cid_len, supported_versions)?;
         let dgram_len = buf.get_ref().len();
         let packet_len = plain_header
             .payload_len()
@@ -89,6 +90,15 @@ impl PartialDecode {
         self.plain_header.dst_cid()
     }
 
+    /// QUIC version of a long header packet
+    pub fn version(&self) -> Option<u32> {
+        use self::PlainHeader::*;
+        match self.plain_header {
+            Initial { version, .. } | Long { version, .. } | Retry { version, .. } => Some(version),
+            Short { .. } | VersionNegotiate { .. } => None,
+        }
+    }
+
     /// Length of data being decoded
     ///
     /// May account for multiple packets.
@@ -104,6 +114,7 @@ impl PartialDecode {
         } = self;
 
         if let Initial {
+            version,
             dst_cid,
             src_cid,
             token_pos,
@@ -118,6 +129,7 @@ impl PartialDecode {
             let token = header_data.slice(token_pos.start, token_pos.end);
             return Ok(Packet {
                 header: Header::Initial {
+                    version,
                     dst_cid,
                     src_cid,
                     token,
@@ -130,24 +142,26 @@ impl PartialDecode {
 
         let header = match plain_header {
             Long {
+                version,
                 ty,
                 dst_cid,
                 src_cid,
                 ..
             } => Header::Long {
+                version,
                 ty,
                 dst_cid,
                 src_cid,
                 number: Self::decrypt_header(&mut buf, header_crypto.unwrap())?,
             },
             Retry {
+                version,
                 dst_cid,
                 src_cid,
-                orig_dst_cid,
             } => Header::Retry {
+                version,
                 dst_cid,
                 src_cid,
-                orig_dst_cid,
             },
             Short { spin, dst_cid, .. } => {
                 let number = Self::decrypt_header(&mut buf, header_crypto.unwrap())?;
@@ -208,21 +222,24 @@ pub struct Packet {
 #[derive(Debug, Clone)]
 pub enum Header {
     Initial {
+        version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         token: Bytes,
         number: PacketNumber,
     },
     Long {
+        version: u32,
         ty: LongType,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         number: PacketNumber,
     },
+    /// Followed by the token and the integrity tag
     Retry {
+        version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
-        orig_dst_cid: ConnectionId,
     },
     Short {
         spin: bool,
@@ -242,19 +259,20 @@ impl Header {
         use self::Header::*;
         match *self {
             Initial {
+                version,
                 ref dst_cid,
                 ref src_cid,
                 ref token,
                 number,
             } => {
-                w.write(u8::from(LongHeaderType::Initial) | number.tag());
-                w.write(VERSION);
+                w.write(LongHeaderType::Initial.to_byte(version) | number.tag());
+                w.write(version);
                 Self::encode_cids(w, dst_cid, src_cid);
                 w.write_var(token.len() as u64);
                 w.put_slice(token);
                 w.write::<u16>(0); // Placeholder for payload length; see `set_payload_length`
                 number.encode(w);
-                let pn_pos = 8
+                let pn_pos = 9
                     + dst_cid.len()
                     + src_cid.len()
                     + varint::size(token.len() as u64).unwrap()
@@ -262,33 +280,28 @@ impl Header {
                 PartialEncode { pn: Some(pn_pos) }
             }
             Long {
+                version,
                 ty,
                 ref dst_cid,
                 ref src_cid,
                 number,
             } => {
-                w.write(u8::from(LongHeaderType::Standard(ty)) | number.tag());
-                w.write(VERSION);
+                w.write(LongHeaderType::Standard(ty).to_byte(version) | number.tag());
+                w.write(version);
                 Self::encode_cids(w, dst_cid, src_cid);
                 w.write::<u16>(0); // Placeholder for payload length; see `set_payload_length`
                 number.encode(w);
-                let pn_pos = 8 + dst_cid.len() + src_cid.len();
+                let pn_pos = 9 + dst_cid.len() + src_cid.len();
                 PartialEncode { pn: Some(pn_pos) }
             }
             Retry {
+                version,
                 ref dst_cid,
                 ref src_cid,
-                ref orig_dst_cid,
             } => {
-                let odcil = if orig_dst_cid.len() == 0 {
-                    0
-                } else {
-                    orig_dst_cid.len() as u8 - 3
-                };
-                w.write(u8::from(LongHeaderType::Retry) | odcil);
-                w.write(VERSION);
+                w.write(LongHeaderType::Retry.to_byte(version));
+                w.write(version);
                 Self::encode_cids(w, dst_cid, src_cid);
-                w.put_slice(orig_dst_cid);
                 PartialEncode { pn: None }
             }
             Short {
@@ -323,16 +336,9 @@ impl Header {
     }
 
     fn encode_cids<W: BufMut>(w: &mut W, dst_cid: &ConnectionId, src_cid: &ConnectionId) {
-        let mut dcil = dst_cid.len() as u8;
-        if dcil > 0 {
-            dcil -= 3;
-        }
-        let mut scil = src_cid.len() as u8;
-        if scil > 0 {
-            scil -= 3;
-        }
-        w.write(dcil << 4 | scil);
+        w.write(dst_cid.len() as u8);
         w.put_slice(dst_cid);
+        w.write(src_cid.len() as u8);
         w.put_slice(src_cid);
     }
 
@@ -343,6 +349,13 @@ impl Header {
         }
     }
 
+    pub fn is_version_negotiate(&self) -> bool {
+        match *self {
+            Header::VersionNegotiate { .. } => true,
+            _ => false,
+        }
+    }
+
     pub fn number(&self) -> Option<PacketNumber> {
         use self::Header::*;
         Some(match *self {
@@ -421,21 +434,23 @@ impl PartialEncode {
 
 pub enum PlainHeader {
     Initial {
+        version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         token_pos: Range<usize>,
         len: u64,
     },
     Long {
+        version: u32,
         ty: LongType,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         len: u64,
     },
     Retry {
+        version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
-        orig_dst_cid: ConnectionId,
     },
     Short {
         first: u8,
@@ -472,6 +487,7 @@ impl PlainHeader {
     fn decode(
         buf: &mut io::Cursor<BytesMut>,
         local_cid_len: usize,
+        supported_versions: &[u32],
     ) -> Result<Self, PacketDecodeError> {
         let first = buf.get::<u8>()?;
         if first & LONG_HEADER_FORM == 0 {
@@ -491,23 +507,8 @@ impl PlainHeader {
             })
         } else {
             let version = buf.get::<u32>()?;
-            let ci_lengths = buf.get::<u8>()?;
-            let mut dcil = (ci_lengths >> 4) as usize;
-            if dcil > 0 {
-                dcil += 3
-            };
-            let mut scil = (ci_lengths & 0xF) as usize;
-            if scil > 0 {
-                scil += 3
-            };
-            if buf.remaining() < (dcil + scil) as usize {
-                return Err(PacketDecodeError::InvalidHeader(
-                    "connection IDs longer than packet",
-                ));
-            }
-
-            let dst_cid = Self::get_cid(buf, dcil);
-            let src_cid = Self::get_cid(buf, scil);
+            let dst_cid = Self::get_long_cid(buf)?;
+            let src_cid = Self::get_long_cid(buf)?;
 
             if version == 0 {
                 let random = first & !LONG_HEADER_FORM;
@@ -518,14 +519,14 @@ impl PlainHeader {
                 });
             }
 
-            if version != VERSION {
+            if !supported_versions.contains(&version) {
                 return Err(PacketDecodeError::UnsupportedVersion {
                     source: src_cid,
                     destination: dst_cid,
                 });
             }
 
-            match LongHeaderType::from_byte(first)? {
+            match LongHeaderType::from_byte(first, version)? {
                 LongHeaderType::Initial => {
                     let token_len = buf.get_var()? as usize;
                     let token_start = buf.position() as usize;
@@ -533,24 +534,20 @@ impl PlainHeader {
 
                     let len = buf.get_var()?;
                     Ok(PlainHeader::Initial {
+                        version,
                         dst_cid,
                         src_cid,
                         token_pos: token_start..token_start + token_len,
                         len,
                     })
                 }
-                LongHeaderType::Retry => {
-                    let odcil = first & 0xf;
-                    let odcil = if odcil == 0 { 0 } else { (odcil + 3) as usize };
-                    let orig_dst_cid = Self::get_cid(buf, odcil);
-
-                    Ok(PlainHeader::Retry {
-                        dst_cid,
-                        src_cid,
-                        orig_dst_cid,
-                    })
-                }
+                LongHeaderType::Retry => Ok(PlainHeader::Retry {
+                    version,
+                    dst_cid,
+                    src_cid,
+                }),
                 LongHeaderType::Standard(ty) => Ok(PlainHeader::Long {
+                    version,
                     ty,
                     dst_cid,
                     src_cid,
@@ -560,6 +557,20 @@ impl PlainHeader {
         }
     }
 
+    /// Decode a length-prefixed connection ID from a long header
+    fn get_long_cid<R: Buf>(buf: &mut R) -> Result<ConnectionId, PacketDecodeError> {
+        let len = buf.get::<u8>()? as usize;
+        if len > MAX_CID_SIZE {
+            return Err(PacketDecodeError::InvalidHeader("connection ID too long"));
+        }
+        if buf.remaining() < len {
+            return Err(PacketDecodeError::InvalidHeader(
+                "connection ID longer than packet",
+            ));
+        }
+        Ok(Self::get_cid(buf, len))
+    }
+
     fn get_cid<R: Buf>(buf: &mut R, len: usize) -> ConnectionId {
         let cid = ConnectionId::new(&buf.bytes()[..len]);
         buf.advance(len);
@@ -679,31 +690,51 @@ pub enum LongHeaderType {
 }
 
 impl LongHeaderType {
-    fn from_byte(b: u8) -> Result<Self, PacketDecodeError> {
+    fn from_byte(b: u8, version: u32) -> Result<Self, PacketDecodeError> {
         use self::{LongHeaderType::*, LongType::*};
         if b & FIXED_BIT == 0 {
             return Err(PacketDecodeError::InvalidHeader("fixed bit unset"));
         }
         debug_assert!(b & LONG_HEADER_FORM != 0, "not a long packet");
-        Ok(match (b & 0x30) >> 4 {
-            0x0 => Initial,
-            0x1 => Standard(ZeroRtt),
-            0x2 => Standard(Handshake),
-            0x3 => Retry,
-            _ => unreachable!(),
+        let ty = (b & 0x30) >> 4;
+        Ok(if version == VERSION_2 {
+            match ty {
+                0x0 => Retry,
+                0x1 => Initial,
+                0x2 => Standard(ZeroRtt),
+                0x3 => Standard(Handshake),
+                _ => unreachable!(),
+            }
+        } else {
+            match ty {
+                0x0 => Initial,
+                0x1 => Standard(ZeroRtt),
+                0x2 => Standard(Handshake),
+                0x3 => Retry,
+                _ => unreachable!(),
+            }
         })
     }
-}
 
-impl From<LongHeaderType> for u8 {
-    fn from(ty: LongHeaderType) -> u8 {
+    /// First byte of a long header of this type in `version`, excluding the type-specific bits
+    fn to_byte(self, version: u32) -> u8 {
         use self::{LongHeaderType::*, LongType::*};
-        match ty {
-            Initial => LONG_HEADER_FORM | FIXED_BIT,
-            Standard(ZeroRtt) => LONG_HEADER_FORM | FIXED_BIT | (0x1 << 4),
-            Standard(Handshake) => LONG_HEADER_FORM | FIXED_BIT | (0x2 << 4),
-            Retry => LONG_HEADER_FORM | FIXED_BIT | (0x3 << 4),
-        }
+        let ty = if version == VERSION_2 {
+            match self {
+                Retry => 0x0,
+                Initial => 0x1,
+                Standard(ZeroRtt) => 0x2,
+                Standard(Handshake) => 0x3,
+            }
+        } else {
+            match self {
+                Initial => 0x0,
+                Standard(ZeroRtt) => 0x1,
+                Standard(Handshake) => 0x2,
+                Retry => 0x3,
+            }
+        };
+        LONG_HEADER_FORM | FIXED_BIT | ty << 4
     }
 }
 
@@ -764,9 +795,7 @@ pub struct ConnectionId {
 
 impl ConnectionId {
     pub fn new(bytes: &[u8]) -> Self {
-        debug_assert!(
-            bytes.is_empty() || (bytes.len() >= MIN_CID_SIZE && bytes.len() <= MAX_CID_SIZE)
-        );
+        debug_assert!(bytes.len() <= MAX_CID_SIZE);
         let mut res = Self {
             len: bytes.len() as u8,
             bytes: [0; MAX_CID_SIZE],
@@ -898,7 +927,7 @@ impl slog::Value for SpaceId {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{crypto::Crypto, Side};
+    use crate::{crypto::Crypto, Side, SUPPORTED_VERSIONS, VERSION_1};
     use std::io;
 
     fn check_pn(typed: PacketNumber, encoded: &[u8]) {
@@ -936,55 +965,92 @@ mod tests {
 
     #[test]
     fn header_encoding() {
-        let dcid = ConnectionId::new(&hex!("06b858ec6f80452b"));
-        let client_crypto = Crypto::new_initial(&dcid, Side::Client);
-        let client_header_crypto = client_crypto.header_crypto();
+        // Server Initial from RFC 9001 appendix A.3
+        let dcid = ConnectionId::new(&hex!("8394c8f03e515708"));
+        let server_crypto = Crypto::new_initial(VERSION_1, &dcid, Side::Server);
+        let server_header_crypto = server_crypto.header_crypto();
         let mut buf = Vec::new();
         let header = Header::Initial {
-            number: PacketNumber::U8(0),
-            src_cid: ConnectionId::new(&[]),
-            dst_cid: dcid,
+            number: PacketNumber::U16(1),
+            src_cid: ConnectionId::new(&hex!("f067a5502a4262b5")),
+            dst_cid: ConnectionId::new(&[]),
             token: Bytes::new(),
+            version: VERSION_1,
         };
         let encode = header.encode(&mut buf);
         let header_len = buf.len();
-        buf.resize(header_len + 16, 0);
-        set_payload_length(&mut buf, header_len, 1, client_crypto.tag_len());
+        buf.extend_from_slice(&PAYLOAD);
+        set_payload_length(&mut buf, header_len, 2, server_crypto.tag_len());
         assert_eq!(
-            buf[..],
-            hex!("c0ff0000115006b858ec6f80452b00402100 00000000000000000000000000000000")[..]
+            buf[..header_len],
+            hex!("c1000000010008f067a5502a4262b50040750001")[..]
         );
 
-        client_crypto.encrypt(0, &mut buf, header_len);
-        encode.finish(&mut buf, &client_header_crypto);
+        server_crypto.encrypt(1, &mut buf, header_len);
+        encode.finish(&mut buf, &server_header_crypto);
         assert_eq!(
             buf[..],
             hex!(
-                "c8ff0000115006b858ec6f80452b004021a7
-                 f037a410591e943c31d1eefad0927b97e620160d59c776720c7118b9699a15b3"
+                "cf000000010008f067a5502a4262b5004075c0d95a482cd0991cd25b0aac406a
+                 5816b6394100f37a1c69797554780bb38cc5a99f5ede4cf73c3ec2493a1839b3
+                 dbcba3f6ea46c5b7684df3548e7ddeb9c3bf9c73cc3f3bded74b562bfb19fb84
+                 022f8ef4cdd93795d77d06edbb7aaf2f58891850abbdca3d20398c276456cbc4
+                 2158407dd074ee"
             )[..]
         );
 
-        let server_crypto = Crypto::new_initial(&dcid, Side::Server);
-        let server_header_crypto = server_crypto.header_crypto();
-        let decode = PartialDecode::new(buf.clone().into(), 0).unwrap().0;
-        let mut packet = decode.finish(Some(&server_header_crypto)).unwrap();
+        let client_crypto = Crypto::new_initial(VERSION_1, &dcid, Side::Client);
+        let client_header_crypto = client_crypto.header_crypto();
+        let decode = PartialDecode::new(buf.clone().into(), 0, SUPPORTED_VERSIONS)
+            .unwrap()
+            .0;
+        let mut packet = decode.finish(Some(&client_header_crypto)).unwrap();
         assert_eq!(
             packet.header_data[..],
-            hex!("c0ff0000115006b858ec6f80452b00402100")[..]
+            hex!("c1000000010008f067a5502a4262b50040750001")[..]
         );
-        server_crypto
-            .decrypt(0, &packet.header_data, &mut packet.payload)
+        client_crypto
+            .decrypt(1, &packet.header_data, &mut packet.payload)
             .unwrap();
-        assert_eq!(packet.payload[..], [0; 16]);
+        assert_eq!(packet.payload[..], PAYLOAD[..]);
         match packet.header {
             Header::Initial {
-                number: PacketNumber::U8(0),
+                number: PacketNumber::U16(1),
+                ref src_cid,
                 ..
-            } => {}
+            } if src_cid[..] == hex!("f067a5502a4262b5")[..] => {}
             _ => {
                 panic!("unexpected header {:?}", packet.header);
             }
         }
     }
+
+    #[test]
+    fn long_cid() {
+        let dcid = ConnectionId::new(&[0xab; MAX_CID_SIZE]);
+        let mut buf = Vec::new();
+        Header::VersionNegotiate {
+            random: 0,
+            src_cid: ConnectionId::new(&[]),
+            dst_cid: dcid,
+        }
+        .encode(&mut buf);
+        assert_eq!(buf[5], MAX_CID_SIZE as u8);
+        let decode = PartialDecode::new(buf.clone().into(), 0, SUPPORTED_VERSIONS)
+            .unwrap()
+            .0;
+        assert_eq!(decode.dst_cid(), dcid);
+
+        // Connection IDs longer than 20 bytes are not valid in QUIC version 1
+        buf[5] = MAX_CID_SIZE as u8 + 1;
+        buf.push(0);
+        assert!(PartialDecode::new(buf.into(), 0, SUPPORTED_VERSIONS).is_err());
+    }
+
+    const PAYLOAD: [u8; 99] = hex!(
+        "02000000000600405a020000560303eefce7f7b37ba1d1632e96677825ddf739
+         88cfc79825df566dc5430b9a045a1200130100002e00330024001d00209d3c94
+         0d89690b84d08a60993c144eca684d1081287c834d5311bcf32bb9da1a002b00
+         020304"
+    );
 }
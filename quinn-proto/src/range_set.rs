@@ -167,6 +167,27 @@ impl RangeSet {
         }
     }
 
+    /// Subranges of `x` that aren't in the set, in ascending order
+    pub fn gaps(&self, x: Range<u64>) -> Vec<Range<u64>> {
+        let mut result = Vec::new();
+        if x.start >= x.end {
+            return result;
+        }
+        let mut start = self
+            .pred(x.start)
+            .map_or(x.start, |(_, end)| cmp::max(x.start, end));
+        for (&next_start, &next_end) in self.0.range((Excluded(x.start), Excluded(x.end))) {
+            if next_start > start {
+                result.push(start..next_start);
+            }
+            start = cmp::max(start, next_end);
+        }
+        if start < x.end {
+            result.push(start..x.end);
+        }
+        result
+    }
+
     pub fn pop_min(&mut self) -> Option<Range<u64>> {
         let (&start, &end) = self.0.iter().next()?;
         self.0.remove(&start);
@@ -323,4 +344,16 @@ mod tests {
         assert!(set.remove(0..5));
         assert!(set.is_empty());
     }
+
+    #[test]
+    fn gaps() {
+        let mut set = RangeSet::new();
+        set.insert(2..4);
+        set.insert(6..8);
+        assert_eq!(set.gaps(0..10), vec![0..2, 4..6, 8..10]);
+        assert_eq!(set.gaps(3..7), vec![4..6]);
+        assert_eq!(set.gaps(2..4), vec![]);
+        assert_eq!(set.gaps(4..6), vec![4..6]);
+        assert_eq!(RangeSet::new().gaps(1..3), vec![1..3]);
+    }
 }
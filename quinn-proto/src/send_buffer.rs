@@ -0,0 +1,217 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use bytes::Bytes;
+
+use crate::range_set::RangeSet;
+
+/// Buffer of outgoing retransmittable stream data
+///
+/// Data is retained until acknowledged, so that lost ranges can be resent from it without keeping
+/// a copy for every packet in flight.
+#[derive(Debug, Default)]
+pub struct SendBuffer {
+    /// Data queued by the application but not yet acknowledged. May or may not have been sent.
+    unacked_segments: VecDeque<Bytes>,
+    /// Total size of `unacked_segments`
+    unacked_len: usize,
+    /// The first offset that hasn't been written by the application, i.e. the offset past the end
+    /// of `unacked_segments`
+    offset: u64,
+    /// The first offset that hasn't been sent
+    ///
+    /// Always lies in `(offset - unacked_len)..=offset`
+    unsent: u64,
+    /// Acknowledged ranges which couldn't be discarded yet as they don't include the earliest
+    /// offset in `unacked_segments`
+    acks: RangeSet,
+    /// Previously transmitted ranges deemed lost and not since acknowledged
+    retransmits: RangeSet,
+}
+
+impl SendBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append application data to the end of the stream
+    pub fn write(&mut self, data: Bytes) {
+        self.unacked_len += data.len();
+        self.offset += data.len() as u64;
+        self.unacked_segments.push_back(data);
+    }
+
+    /// Discard a range of acknowledged stream data
+    pub fn ack(&mut self, mut range: Range<u64>) {
+        // Clamp the range to data which is still tracked
+        range.start = cmp::max(range.start, self.base_offset());
+        range.end = cmp::min(range.end, self.unsent);
+        if range.start >= range.end {
+            return;
+        }
+        self.retransmits.remove(range.clone());
+        self.acks.insert(range);
+
+        while self.acks.min() == Some(self.base_offset()) {
+            let prefix = self.acks.pop_min().unwrap();
+            let mut to_advance = (prefix.end - prefix.start) as usize;
+            self.unacked_len -= to_advance;
+            while to_advance > 0 {
+                let front = self.unacked_segments.front_mut().unwrap();
+                if front.len() <= to_advance {
+                    to_advance -= front.len();
+                    self.unacked_segments.pop_front();
+                } else {
+                    front.advance(to_advance);
+                    to_advance = 0;
+                }
+            }
+        }
+    }
+
+    /// Queue a sent range of stream data for retransmission, excluding anything since acknowledged
+    pub fn retransmit(&mut self, mut range: Range<u64>) {
+        range.start = cmp::max(range.start, self.base_offset());
+        if range.start >= range.end {
+            return;
+        }
+        debug_assert!(range.end <= self.unsent, "unsent data can't be lost");
+        self.retransmits.insert(range);
+        self.retransmits.subtract(&self.acks);
+    }
+
+    /// Take the next chunk of data to send, preferring data to be retransmitted
+    ///
+    /// Returns the offset of the chunk and its contents, which are at most `max_len` bytes and
+    /// non-empty if `max_len` is nonzero, or `None` if there's nothing to send.
+    pub fn poll_transmit(&mut self, max_len: usize) -> Option<(u64, Bytes)> {
+        if let Some(range) = self.retransmits.pop_min() {
+            let len = cmp::min(max_len as u64, range.end - range.start) as usize;
+            let data = self.get(range.start, len);
+            let end = range.start + data.len() as u64;
+            if end != range.end {
+                self.retransmits.insert(end..range.end);
+            }
+            return Some((range.start, data));
+        }
+        if self.unsent == self.offset {
+            return None;
+        }
+        let offset = self.unsent;
+        let len = cmp::min(max_len as u64, self.offset - offset) as usize;
+        let data = self.get(offset, len);
+        self.unsent += data.len() as u64;
+        Some((offset, data))
+    }
+
+    /// Up to `max_len` contiguously stored bytes starting at `offset`
+    ///
+    /// May return fewer bytes if the data spans multiple writes.
+    fn get(&self, offset: u64, max_len: usize) -> Bytes {
+        let mut segment_offset = self.base_offset();
+        for segment in &self.unacked_segments {
+            let segment_end = segment_offset + segment.len() as u64;
+            if offset < segment_end {
+                let start = (offset - segment_offset) as usize;
+                let end = cmp::min(segment.len(), start + max_len);
+                return segment.slice(start, end);
+            }
+            segment_offset = segment_end;
+        }
+        Bytes::new()
+    }
+
+    /// Offset of the first byte that has not been acknowledged
+    fn base_offset(&self) -> u64 {
+        self.offset - self.unacked_len as u64
+    }
+
+    /// The first offset that hasn't been written by the application
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Whether there's data to send or resend
+    pub fn has_unsent_data(&self) -> bool {
+        self.unsent != self.offset || !self.retransmits.is_empty()
+    }
+
+    /// Whether all written data has been acknowledged
+    pub fn is_fully_acked(&self) -> bool {
+        self.unacked_len == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn buffer(data: &[&'static [u8]]) -> SendBuffer {
+        let mut buf = SendBuffer::new();
+        for &x in data {
+            buf.write(Bytes::from_static(x));
+        }
+        buf
+    }
+
+    #[test]
+    fn chunks_respect_writes() {
+        let mut buf = buffer(&[b"hello", b"world"]);
+        assert_eq!(buf.poll_transmit(3), Some((0, Bytes::from_static(b"hel"))));
+        assert_eq!(buf.poll_transmit(10), Some((3, Bytes::from_static(b"lo"))));
+        assert_eq!(
+            buf.poll_transmit(10),
+            Some((5, Bytes::from_static(b"world")))
+        );
+        assert_eq!(buf.poll_transmit(10), None);
+        assert!(!buf.has_unsent_data());
+    }
+
+    #[test]
+    fn retransmit_only_unacked() {
+        let mut buf = buffer(&[b"0123456789"]);
+        assert_eq!(
+            buf.poll_transmit(10),
+            Some((0, Bytes::from_static(b"0123456789")))
+        );
+        // A later packet covering the middle of the range was acknowledged
+        buf.ack(3..6);
+        buf.retransmit(0..10);
+        assert_eq!(buf.poll_transmit(10), Some((0, Bytes::from_static(b"012"))));
+        assert_eq!(
+            buf.poll_transmit(10),
+            Some((6, Bytes::from_static(b"6789")))
+        );
+        assert_eq!(buf.poll_transmit(10), None);
+        buf.ack(6..10);
+        assert!(!buf.is_fully_acked());
+        buf.ack(0..3);
+        assert!(buf.is_fully_acked());
+        assert!(buf.unacked_segments.is_empty());
+    }
+
+    #[test]
+    fn late_ack_cancels_retransmit() {
+        let mut buf = buffer(&[b"hello", b"world"]);
+        buf.poll_transmit(10);
+        buf.poll_transmit(10);
+        buf.retransmit(0..10);
+        buf.ack(0..10);
+        assert!(!buf.has_unsent_data());
+        assert!(buf.is_fully_acked());
+        assert_eq!(buf.offset(), 10);
+    }
+
+    #[test]
+    fn partial_ack_frees_memory() {
+        let mut buf = buffer(&[b"hello", b"world"]);
+        buf.poll_transmit(10);
+        buf.poll_transmit(10);
+        buf.ack(0..7);
+        assert_eq!(buf.unacked_len, 3);
+        assert_eq!(buf.unacked_segments.len(), 1);
+        buf.retransmit(0..10);
+        assert_eq!(buf.poll_transmit(10), Some((7, Bytes::from_static(b"rld"))));
+    }
+}
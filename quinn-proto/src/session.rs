@@ -0,0 +1,186 @@
+//! Storage for TLS sessions used to resume connections and send 0-RTT data
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use byteorder::{BigEndian, ByteOrder};
+use rustls::StoresClientSessions;
+
+use crate::crypto::ClientConfig;
+
+/// Retains TLS sessions established with servers for use in future connections
+///
+/// Each stored value holds a session ticket together with the transport parameters the server sent
+/// during that session, both of which are needed to send 0-RTT data when reconnecting.
+pub trait SessionStore: Send + Sync {
+    /// Record `value` under `key`, replacing any previous value, and return whether it was stored
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool;
+
+    /// Look up the value most recently stored under `key`, if any
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Configure `config` to resume sessions from, and record new sessions in, `store`
+pub fn set_session_store(config: &mut ClientConfig, store: Arc<dyn SessionStore>) {
+    config.session_persistence = Arc::new(Persistence(store));
+}
+
+/// Exposes a `SessionStore` to rustls
+struct Persistence(Arc<dyn SessionStore>);
+
+impl StoresClientSessions for Persistence {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.get(key)
+    }
+}
+
+/// A `SessionStore` which keeps sessions in memory for the lifetime of the process
+#[derive(Debug, Default)]
+pub struct SessionMemoryCache {
+    sessions: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl SessionStore for SessionMemoryCache {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.sessions.lock().unwrap().insert(key, value);
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.sessions.lock().unwrap().get(key).cloned()
+    }
+}
+
+/// A `SessionStore` which persists sessions to a file, allowing 0-RTT across process restarts
+///
+/// The entire store is rewritten whenever a session is recorded, so it is only suitable for clients
+/// that talk to a modest number of servers.
+///
+/// The file is secret: the resumption secrets it holds let anyone who reads it decrypt the 0-RTT
+/// data of future connections and impersonate the client to the servers it names. On unix it is
+/// created readable and writable only by its owner, and it should be kept somewhere private.
+#[derive(Debug)]
+pub struct SessionFileStore {
+    path: PathBuf,
+    sessions: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl SessionFileStore {
+    /// Open the store at `path`, loading any sessions previously recorded there
+    ///
+    /// The file is created when the first session is recorded if it does not exist yet.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let sessions = match File::open(&path) {
+            Ok(mut file) => {
+                let mut buf = Vec::new();
+                file.read_to_end(&mut buf)?;
+                decode(&buf).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "malformed session store")
+                })?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            sessions: Mutex::new(sessions),
+        })
+    }
+
+    /// The file sessions are persisted to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn save(&self, sessions: &HashMap<Vec<u8>, Vec<u8>>) -> io::Result<()> {
+        // Write to a temporary file first so a crash can't leave a truncated store behind
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        // Discard any file left by a crash, which might not have restrictive permissions
+        let _ = fs::remove_file(&tmp);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp)?;
+        file.write_all(&encode(sessions))?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl SessionStore for SessionFileStore {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(key, value);
+        self.save(&sessions).is_ok()
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.sessions.lock().unwrap().get(key).cloned()
+    }
+}
+
+/// Serialize sessions as a sequence of length-prefixed key/value pairs
+fn encode(sessions: &HashMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (key, value) in sessions {
+        for field in &[key, value] {
+            let mut len = [0; 4];
+            BigEndian::write_u32(&mut len, field.len() as u32);
+            buf.extend_from_slice(&len);
+            buf.extend_from_slice(field);
+        }
+    }
+    buf
+}
+
+fn decode(mut buf: &[u8]) -> Option<HashMap<Vec<u8>, Vec<u8>>> {
+    fn field<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+        if buf.len() < 4 {
+            return None;
+        }
+        let len = BigEndian::read_u32(buf) as usize;
+        if buf.len() - 4 < len {
+            return None;
+        }
+        let (field, rest) = buf[4..].split_at(len);
+        *buf = rest;
+        Some(field)
+    }
+
+    let mut sessions = HashMap::new();
+    while !buf.is_empty() {
+        let key = field(&mut buf)?;
+        let value = field(&mut buf)?;
+        sessions.insert(key.to_vec(), value.to_vec());
+    }
+    Some(sessions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn coding() {
+        let mut sessions = HashMap::new();
+        sessions.insert(b"session".to_vec(), b"ticket".to_vec());
+        sessions.insert(b"kx-hint".to_vec(), Vec::new());
+        let buf = encode(&sessions);
+        assert_eq!(decode(&buf), Some(sessions));
+        assert_eq!(decode(&buf[..buf.len() - 1]), None);
+        assert_eq!(decode(&[]), Some(HashMap::new()));
+    }
+}
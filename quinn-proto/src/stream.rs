@@ -1,9 +1,13 @@
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
 
 use bytes::Bytes;
 use err_derive::Error;
 
+use crate::frame;
 use crate::range_set::RangeSet;
+use crate::send_buffer::SendBuffer;
+use crate::StreamId;
 
 #[derive(Debug)]
 pub enum Stream {
@@ -13,8 +17,8 @@ pub enum Stream {
 }
 
 impl Stream {
-    pub fn new_bi() -> Self {
-        Stream::Both(Send::new(), Recv::new())
+    pub fn new_bi(initial_max_data: u64, window: u64) -> Self {
+        Stream::Both(Send::new(), Recv::new(initial_max_data, window))
     }
 
     pub fn send(&self) -> Option<&Send> {
@@ -68,20 +72,67 @@ impl From<Recv> for Stream {
 
 #[derive(Debug)]
 pub struct Send {
-    pub offset: u64,
     pub max_data: u64,
     pub state: SendState,
-    /// Number of bytes sent but unacked
-    pub bytes_in_flight: u64,
+    /// Data written by the application and not yet acknowledged
+    pub pending: SendBuffer,
+    /// Whether the stream has been finished and the FIN bit must be sent (again)
+    pub fin_pending: bool,
+    /// Streams with higher priority are sent first
+    pub priority: i32,
+    /// Value of `max_data` most recently reported in a STREAM_DATA_BLOCKED frame
+    pub blocked_at: Option<u64>,
 }
 
 impl Send {
     pub fn new() -> Self {
         Self {
-            offset: 0,
             max_data: 0,
             state: SendState::Ready,
-            bytes_in_flight: 0,
+            pending: SendBuffer::new(),
+            fin_pending: false,
+            priority: 0,
+            blocked_at: None,
+        }
+    }
+
+    /// Number of bytes written by the application
+    pub fn offset(&self) -> u64 {
+        self.pending.offset()
+    }
+
+    /// Whether there's data or a FIN bit to send
+    pub fn is_pending(&self) -> bool {
+        self.pending.has_unsent_data() || self.fin_pending
+    }
+
+    /// Process the acknowledgement of a STREAM frame, returning whether all data has now been
+    /// received by the peer
+    pub fn ack(&mut self, frame: frame::StreamMeta) -> bool {
+        self.pending.ack(frame.offsets);
+        match self.state {
+            SendState::DataSent {
+                ref mut finish_acked,
+            } => {
+                *finish_acked |= frame.fin;
+                if *finish_acked && self.pending.is_fully_acked() {
+                    self.state = SendState::DataRecvd;
+                    return true;
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Queue the contents of a lost STREAM frame for retransmission
+    pub fn retransmit(&mut self, frame: frame::StreamMeta) {
+        self.pending.retransmit(frame.offsets);
+        if let SendState::DataSent {
+            finish_acked: false,
+        } = self.state
+        {
+            self.fin_pending |= frame.fin;
         }
     }
 
@@ -100,7 +151,7 @@ impl Send {
             _ => {}
         };
 
-        let budget = self.max_data - self.offset;
+        let budget = self.max_data - self.offset();
         if budget == 0 {
             Err(WriteError::Blocked)
         } else {
@@ -125,14 +176,12 @@ pub enum WriteError {
     Blocked,
     /// The peer is no longer accepting data on this stream.
     #[error(display = "stopped by peer: error {}", error_code)]
-    Stopped { error_code: u16 },
+    Stopped { error_code: u64 },
 }
 
 #[derive(Debug)]
 pub struct Recv {
     pub state: RecvState,
-    pub recvd: RangeSet,
-    pub buffered: VecDeque<(Bytes, u64)>,
     /// Whether any unordered reads have been performed, making this stream unusable for ordered
     /// reads
     pub unordered: bool,
@@ -142,46 +191,64 @@ pub struct Recv {
     /// Number of bytes read by the application. Equal to assembler.offset when `unordered` is
     /// false.
     pub bytes_read: u64,
+    /// Highest offset the peer has been permitted to send up to
+    pub max_data: u64,
+    /// Number of bytes past `bytes_read` the peer should be permitted to send
+    pub window: RecvWindow,
+    /// Whether any data was received in 0-RTT packets, and hence might have been replayed
+    pub zero_rtt: bool,
 }
 
 impl Recv {
-    pub fn new() -> Self {
+    pub fn new(initial_max_data: u64, window: u64) -> Self {
         Self {
             state: RecvState::Recv { size: None },
-            recvd: RangeSet::new(),
-            buffered: VecDeque::new(),
             unordered: false,
             assembler: Assembler::new(),
             fresh: true,
             bytes_read: 0,
+            max_data: initial_max_data,
+            window: RecvWindow::new(window),
+            zero_rtt: false,
         }
     }
 
+    /// Whether the peer should be permitted to send beyond `max_data`
+    pub fn needs_credit(&self) -> bool {
+        self.bytes_read + self.window.size() > self.max_data
+    }
+
+    /// Raise `max_data` to reflect the data read and the current window, returning the new value
+    ///
+    /// Never lowers `max_data`, since credit can't be revoked once issued.
+    pub fn update_max_data(&mut self) -> u64 {
+        self.max_data = cmp::max(self.max_data, self.bytes_read + self.window.size());
+        self.max_data
+    }
+
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
         assert!(
             !self.unordered,
             "cannot perform ordered reads following unordered reads on a stream"
         );
 
-        for (data, offset) in self.buffered.drain(..) {
-            self.assembler.insert(offset, &data);
+        if self.assembler.blocked() {
+            return Err(self.read_blocked());
         }
-
-        if !self.assembler.blocked() {
-            let n = self.assembler.read(buf);
-            self.bytes_read += n as u64;
-            Ok(n)
-        } else {
-            Err(self.read_blocked())
+        let mut n = 0;
+        while let Some(chunk) = self.assembler.read_chunk(buf.len() - n) {
+            buf[n..n + chunk.len()].copy_from_slice(&chunk);
+            n += chunk.len();
         }
+        self.bytes_read += n as u64;
+        Ok(n)
     }
 
     pub fn read_unordered(&mut self) -> Result<(Bytes, u64), ReadError> {
         self.unordered = true;
-        // TODO: Drain rs.assembler to handle ordered-then-unordered reads reliably
 
         // Return data we already have buffered, regardless of state
-        if let Some(x) = self.buffered.pop_front() {
+        if let Some(x) = self.assembler.read_unordered() {
             self.bytes_read += x.0.len() as u64;
             Ok(x)
         } else {
@@ -225,16 +292,24 @@ impl Recv {
     }
 
     pub fn buffer(&mut self, data: Bytes, offset: u64) {
-        // TODO: Dedup
         if data.is_empty() {
             return;
         }
-        self.buffered.push_back((data, offset));
+        self.assembler.insert(offset, data);
+    }
+
+    /// Number of buffered bytes that can't be read until earlier data arrives
+    pub fn out_of_order_len(&self) -> u64 {
+        match self.state {
+            RecvState::ResetRecvd { .. } | RecvState::Closed => 0,
+            _ if self.unordered => 0,
+            _ => self.assembler.out_of_order_len(),
+        }
     }
 
     /// Offset after the largest byte received
     pub fn limit(&self) -> u64 {
-        self.recvd.max().map_or(0, |x| x + 1)
+        self.assembler.recvd().max().map_or(0, |x| x + 1)
     }
 
     pub fn final_offset(&self) -> Option<u64> {
@@ -246,7 +321,7 @@ impl Recv {
         }
     }
 
-    pub fn reset(&mut self, error_code: u16, final_offset: u64) {
+    pub fn reset(&mut self, error_code: u64, final_offset: u64) {
         if self.is_closed() {
             return;
         }
@@ -258,7 +333,6 @@ impl Recv {
         // issue flow control credit redundant to that already issued. We could instead special-case
         // reset streams during read, but it's unclear if there's any benefit to retaining data for
         // reset streams.
-        self.buffered.clear();
         self.assembler.clear();
     }
 }
@@ -270,7 +344,7 @@ pub enum ReadError {
     Blocked,
     /// The peer abandoned transmitting data on this stream.
     #[error(display = "reset by peer: error {}", error_code)]
-    Reset { error_code: u16 },
+    Reset { error_code: u64 },
     /// The data on this stream has been fully delivered and no more will be transmitted.
     #[error(display = "finished")]
     Finished,
@@ -279,10 +353,10 @@ pub enum ReadError {
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum SendState {
     Ready,
-    DataSent,
-    ResetSent { stop_reason: Option<u16> },
+    DataSent { finish_acked: bool },
+    ResetSent { stop_reason: Option<u64> },
     DataRecvd,
-    ResetRecvd { stop_reason: Option<u16> },
+    ResetRecvd { stop_reason: Option<u64> },
 }
 
 impl SendState {
@@ -299,76 +373,164 @@ impl SendState {
 pub enum RecvState {
     Recv { size: Option<u64> },
     DataRecvd { size: u64 },
-    ResetRecvd { size: u64, error_code: u16 },
+    ResetRecvd { size: u64, error_code: u64 },
     Closed,
 }
 
+/// Streams with data or a FIN bit to send, grouped by priority
+///
+/// Streams of equal priority are kept in the order they became pending, so that they can be served
+/// round-robin.
+#[derive(Debug, Default)]
+pub struct PendingStreams {
+    levels: BTreeMap<i32, VecDeque<StreamId>>,
+}
+
+impl PendingStreams {
+    pub fn push(&mut self, id: StreamId, priority: i32) {
+        self.levels.entry(priority).or_default().push_back(id);
+    }
+
+    /// Take the longest-pending stream of the highest priority
+    pub fn pop(&mut self) -> Option<StreamId> {
+        let priority = *self.levels.keys().next_back()?;
+        let queue = self.levels.get_mut(&priority).unwrap();
+        let id = queue.pop_front();
+        if queue.is_empty() {
+            self.levels.remove(&priority);
+        }
+        id
+    }
+
+    /// Forget `id`, which was pushed with `priority`, returning whether it was pending
+    pub fn remove(&mut self, id: StreamId, priority: i32) -> bool {
+        let queue = match self.levels.get_mut(&priority) {
+            Some(x) => x,
+            None => return false,
+        };
+        let len = queue.len();
+        queue.retain(|&x| x != id);
+        let removed = queue.len() != len;
+        if queue.is_empty() {
+            self.levels.remove(&priority);
+        }
+        removed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.levels.clear();
+    }
+}
+
+/// A flow control receive window that grows when the application keeps up with the peer
+///
+/// The window doubles whenever the application consumes half of it within two round trips of the
+/// previous measurement, indicating that the peer's transmission rate is limited by the window
+/// rather than by how fast data can be processed.
+#[derive(Debug, Copy, Clone)]
+pub struct RecvWindow {
+    size: u64,
+    /// Time and total bytes consumed when the current measurement began
+    epoch: Option<(u64, u64)>,
+}
+
+impl RecvWindow {
+    pub fn new(size: u64) -> Self {
+        Self { size, epoch: None }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Account for `consumed` total bytes having been read by `now`, growing up to `max`
+    ///
+    /// Returns the amount by which the window grew.
+    pub fn update(&mut self, now: u64, rtt: u64, consumed: u64, max: u64) -> u64 {
+        let (start, start_consumed) = match self.epoch {
+            Some(x) => x,
+            None => {
+                self.epoch = Some((now, consumed));
+                return 0;
+            }
+        };
+        if consumed - start_consumed < self.size / 2 {
+            return 0;
+        }
+        self.epoch = Some((now, consumed));
+        if now - start >= 2 * rtt || self.size >= max {
+            return 0;
+        }
+        let old = self.size;
+        self.size = cmp::min(2 * self.size, max);
+        self.size - old
+    }
+}
+
 /// Helper to assemble unordered stream frames into an ordered stream
+///
+/// Received data is retained as the `Bytes` it arrived in, so that reads need not copy it.
 #[derive(Debug)]
 pub struct Assembler {
+    /// Offset of the first byte not yet returned by ordered reads
     offset: u64,
-    data: VecDeque<u8>,
-    /// bitmap of data bytes; 0 = written, 1 = not
-    written: VecDeque<u8>,
-    /// number of bits of written to skip; always < 8
-    written_offset: u8,
+    /// Received data not yet read, keyed by offset, with any overlap already removed
+    data: BTreeMap<u64, Bytes>,
+    /// Ranges of the stream that have been received, whether or not they've since been read
+    recvd: RangeSet,
+    /// Total size of `recvd`
+    recvd_len: u64,
 }
 
+/// Size below which received data is copied rather than sharing the buffer it arrived in
+const MIN_SHARED_CHUNK: usize = 1024;
+
 impl Assembler {
     pub fn new() -> Self {
         Self {
             offset: 0,
-            data: VecDeque::new(),
-            written: VecDeque::new(),
-            written_offset: 0,
+            data: BTreeMap::new(),
+            recvd: RangeSet::new(),
+            recvd_len: 0,
         }
     }
 
-    /// Whether `read` will return nonzero
+    /// Whether `read_chunk` will return `None`
     pub fn blocked(&self) -> bool {
-        let mask = !0 >> self.written_offset;
-        self.written.front().map_or(true, |x| x & mask == mask)
+        self.data.keys().next() != Some(&self.offset)
     }
 
-    /// Leading written bytes
-    fn prefix_len(&self) -> usize {
-        for i in 0..self.written.len() {
-            let x = self.written[i];
-            if x == 0 || (i == 0 && x << self.written_offset == 0) {
-                continue;
-            }
-            return (i * 8 + x.leading_zeros() as usize) - self.written_offset as usize;
+    /// Take up to `max_len` bytes of data following that previously read, if available
+    pub fn read_chunk(&mut self, max_len: usize) -> Option<Bytes> {
+        if self.blocked() || max_len == 0 {
+            return None;
+        }
+        let mut chunk = self.data.remove(&self.offset).unwrap();
+        if chunk.len() > max_len {
+            let rest = chunk.split_off(max_len);
+            self.data.insert(self.offset + max_len as u64, rest);
         }
-        self.written.len()
+        self.offset += chunk.len() as u64;
+        Some(chunk)
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> usize {
-        let (a, b) = self.data.as_slices();
-        let available = self.prefix_len();
-        let a_len = a.len().min(available);
-        let (a, b) = (&a[0..a_len], &b[0..(available - a_len)]);
-        let a_n = a.len().min(buf.len());
-        buf[0..a_n].copy_from_slice(&a[0..a_n]);
-        let b_n = b.len().min(buf.len().saturating_sub(a.len()));
-        buf[a_n..(a_n + b_n)].copy_from_slice(&b[0..b_n]);
-        let n = a_n + b_n;
-
-        self.offset += n as u64;
-        self.data.drain(0..n);
-        let q = n / 8;
-        let r = n % 8;
-        let carry = (self.written_offset as usize + r) / 8;
-        self.written.drain(0..(q + carry));
-        self.written_offset = (self.written_offset as usize + r - carry * 8) as u8;
-
-        n
+    /// Take the chunk of received data with the lowest offset, regardless of what's been read
+    pub fn read_unordered(&mut self) -> Option<(Bytes, u64)> {
+        let offset = *self.data.keys().next()?;
+        let chunk = self.data.remove(&offset).unwrap();
+        Some((chunk, offset))
     }
 
     #[cfg(test)]
     fn next(&mut self) -> Option<Box<[u8]>> {
         let mut buf = Vec::new();
-        buf.resize(self.prefix_len(), 0);
-        self.read(&mut buf);
+        while let Some(chunk) = self.read_chunk(usize::max_value()) {
+            buf.extend_from_slice(&chunk);
+        }
         if !buf.is_empty() {
             Some(buf.into())
         } else {
@@ -376,28 +538,29 @@ impl Assembler {
         }
     }
 
-    pub fn insert(&mut self, mut offset: u64, mut data: &[u8]) {
+    pub fn insert(&mut self, mut offset: u64, mut data: Bytes) {
         if let Some(advance) = self.offset.checked_sub(offset) {
             if advance >= data.len() as u64 {
                 return;
             }
-            data = &data[advance as usize..];
+            data.advance(advance as usize);
             offset += advance;
         }
-        let start = (offset - self.offset) as usize;
-        let end = start + data.len();
-        if end > self.data.len() {
-            self.data.resize(end, 0);
-            // 1 extra to leave room for written_extra
-            self.written
-                .resize(end / 8 + (end % 8 != 0) as usize + 1, !0);
-        }
-        for (i, b) in data.iter().enumerate() {
-            let position = start + i;
-            self.data[position] = *b;
-            let bit = self.written_offset as usize + position;
-            self.written[bit / 8] &= !(1 << (7 - bit % 8));
+        let end = offset + data.len() as u64;
+        // Only retain data that hasn't been received before
+        for range in self.recvd.gaps(offset..end) {
+            let start = (range.start - offset) as usize;
+            let end = (range.end - offset) as usize;
+            let chunk = if end - start < MIN_SHARED_CHUNK {
+                // Don't let a small chunk keep the entire datagram it arrived in alive
+                Bytes::from(&data[start..end])
+            } else {
+                data.slice(start, end)
+            };
+            self.data.insert(range.start, chunk);
+            self.recvd_len += range.end - range.start;
         }
+        self.recvd.insert(offset..end);
     }
 
     /// Current position in the stream
@@ -405,10 +568,25 @@ impl Assembler {
         self.offset
     }
 
+    /// Ranges of the stream that have been received
+    pub fn recvd(&self) -> &RangeSet {
+        &self.recvd
+    }
+
+    /// Number of bytes received following the first gap in the stream
+    ///
+    /// None of this data can be read in order until the gap is filled.
+    pub fn out_of_order_len(&self) -> u64 {
+        let prefix = match self.recvd.iter().next() {
+            Some(range) if range.start == 0 => range.end,
+            _ => 0,
+        };
+        self.recvd_len - prefix
+    }
+
     /// Discard all buffered data
     pub fn clear(&mut self) {
-        self.written = VecDeque::new();
-        self.data = VecDeque::new();
+        self.data = BTreeMap::new();
     }
 }
 
@@ -416,26 +594,66 @@ impl Assembler {
 mod test {
     use super::*;
 
+    #[test]
+    fn window_growth() {
+        const RTT: u64 = 100;
+        let mut x = RecvWindow::new(1000);
+        assert_eq!(x.update(0, RTT, 0, 4000), 0);
+        // Slow consumption leaves the window alone
+        assert_eq!(x.update(250, RTT, 600, 4000), 0);
+        assert_eq!(x.size(), 1000);
+        // Fast consumption grows it, up to the limit
+        assert_eq!(x.update(300, RTT, 1100, 4000), 1000);
+        assert_eq!(x.update(350, RTT, 2100, 4000), 2000);
+        assert_eq!(x.update(400, RTT, 4100, 4000), 0);
+        assert_eq!(x.size(), 4000);
+    }
+
     #[test]
     fn assemble_ordered() {
         let mut x = Assembler::new();
         assert_matches!(x.next(), None);
-        x.insert(0, &b"123"[..]);
+        x.insert(0, Bytes::from_static(b"123"));
         assert_matches!(x.next(), Some(ref y) if &y[..] == b"123");
-        x.insert(3, &b"456"[..]);
+        x.insert(3, Bytes::from_static(b"456"));
         assert_matches!(x.next(), Some(ref y) if &y[..] == b"456");
-        x.insert(6, &b"789"[..]);
-        x.insert(9, &b"10"[..]);
+        x.insert(6, Bytes::from_static(b"789"));
+        x.insert(9, Bytes::from_static(b"10"));
         assert_matches!(x.next(), Some(ref y) if &y[..] == b"78910");
         assert_matches!(x.next(), None);
     }
 
+    #[test]
+    fn assemble_small_chunks_copied() {
+        let datagram = Bytes::from(vec![0; 1200]);
+        let mut x = Assembler::new();
+        x.insert(100, datagram.slice(0, 100));
+        x.insert(200, datagram.slice(0, MIN_SHARED_CHUNK));
+        let shares = |chunk: &Bytes| {
+            let start = datagram.as_ptr() as usize;
+            let ptr = chunk.as_ptr() as usize;
+            ptr >= start && ptr < start + datagram.len()
+        };
+        assert_matches!(x.read_unordered(), Some((ref y, 100)) if !shares(y));
+        assert_matches!(x.read_unordered(), Some((ref y, 200)) if shares(y));
+    }
+
+    #[test]
+    fn assemble_gap_at_front() {
+        let mut x = Assembler::new();
+        x.insert(1, Bytes::from_static(b"23"));
+        assert!(x.blocked());
+        x.insert(0, Bytes::from_static(b"1"));
+        assert!(!x.blocked());
+        assert_matches!(x.next(), Some(ref y) if &y[..] == b"123");
+    }
+
     #[test]
     fn assemble_unordered() {
         let mut x = Assembler::new();
-        x.insert(3, &b"456"[..]);
+        x.insert(3, Bytes::from_static(b"456"));
         assert_matches!(x.next(), None);
-        x.insert(0, &b"123"[..]);
+        x.insert(0, Bytes::from_static(b"123"));
         assert_matches!(x.next(), Some(ref y) if &y[..] == b"123456");
         assert_matches!(x.next(), None);
     }
@@ -443,8 +661,8 @@ mod test {
     #[test]
     fn assemble_duplicate() {
         let mut x = Assembler::new();
-        x.insert(0, &b"123"[..]);
-        x.insert(0, &b"123"[..]);
+        x.insert(0, Bytes::from_static(b"123"));
+        x.insert(0, Bytes::from_static(b"123"));
         assert_matches!(x.next(), Some(ref y) if &y[..] == b"123");
         assert_matches!(x.next(), None);
     }
@@ -452,8 +670,8 @@ mod test {
     #[test]
     fn assemble_contained() {
         let mut x = Assembler::new();
-        x.insert(0, &b"12345"[..]);
-        x.insert(1, &b"234"[..]);
+        x.insert(0, Bytes::from_static(b"12345"));
+        x.insert(1, Bytes::from_static(b"234"));
         assert_matches!(x.next(), Some(ref y) if &y[..] == b"12345");
         assert_matches!(x.next(), None);
     }
@@ -461,8 +679,8 @@ mod test {
     #[test]
     fn assemble_contains() {
         let mut x = Assembler::new();
-        x.insert(1, &b"234"[..]);
-        x.insert(0, &b"12345"[..]);
+        x.insert(1, Bytes::from_static(b"234"));
+        x.insert(0, Bytes::from_static(b"12345"));
         assert_matches!(x.next(), Some(ref y) if &y[..] == b"12345");
         assert_matches!(x.next(), None);
     }
@@ -470,8 +688,8 @@ mod test {
     #[test]
     fn assemble_overlapping() {
         let mut x = Assembler::new();
-        x.insert(0, &b"123"[..]);
-        x.insert(1, &b"234"[..]);
+        x.insert(0, Bytes::from_static(b"123"));
+        x.insert(1, Bytes::from_static(b"234"));
         assert_matches!(x.next(), Some(ref y) if &y[..] == b"1234");
         assert_matches!(x.next(), None);
     }
@@ -479,20 +697,52 @@ mod test {
     #[test]
     fn assemble_complex() {
         let mut x = Assembler::new();
-        x.insert(0, &b"1"[..]);
-        x.insert(2, &b"3"[..]);
-        x.insert(4, &b"5"[..]);
-        x.insert(0, &b"123456"[..]);
+        x.insert(0, Bytes::from_static(b"1"));
+        x.insert(2, Bytes::from_static(b"3"));
+        x.insert(4, Bytes::from_static(b"5"));
+        x.insert(0, Bytes::from_static(b"123456"));
         assert_matches!(x.next(), Some(ref y) if &y[..] == b"123456");
         assert_matches!(x.next(), None);
     }
 
+    #[test]
+    fn assemble_unordered_dedup() {
+        let mut x = Assembler::new();
+        x.insert(3, Bytes::from_static(b"456"));
+        assert_matches!(x.read_unordered(), Some((ref y, 3)) if &y[..] == b"456");
+        x.insert(0, Bytes::from_static(b"123456"));
+        assert_matches!(x.read_unordered(), Some((ref y, 0)) if &y[..] == b"123");
+        assert_matches!(x.read_unordered(), None);
+    }
+
+    #[test]
+    fn assemble_chunked() {
+        let mut x = Assembler::new();
+        x.insert(0, Bytes::from_static(b"12345"));
+        assert_matches!(x.read_chunk(2), Some(ref y) if &y[..] == b"12");
+        assert_matches!(x.read_chunk(5), Some(ref y) if &y[..] == b"345");
+        assert_matches!(x.read_chunk(5), None);
+        assert_eq!(x.offset(), 5);
+    }
+
+    #[test]
+    fn assemble_out_of_order_len() {
+        let mut x = Assembler::new();
+        x.insert(2, Bytes::from_static(b"3"));
+        x.insert(4, Bytes::from_static(b"56"));
+        assert_eq!(x.out_of_order_len(), 3);
+        x.insert(0, Bytes::from_static(b"12"));
+        assert_eq!(x.out_of_order_len(), 2);
+        x.insert(3, Bytes::from_static(b"4"));
+        assert_eq!(x.out_of_order_len(), 0);
+    }
+
     #[test]
     fn assemble_old() {
         let mut x = Assembler::new();
-        x.insert(0, b"1234");
+        x.insert(0, Bytes::from_static(b"1234"));
         assert_matches!(x.next(), Some(ref y) if &y[..] == b"1234");
-        x.insert(0, b"1234");
+        x.insert(0, Bytes::from_static(b"1234"));
         assert_matches!(x.next(), None);
     }
 }
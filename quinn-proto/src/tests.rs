@@ -4,15 +4,18 @@ use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::ops::RangeFrom;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use std::{env, fmt, mem, str};
+use std::{cmp, env, fmt, fs, mem, process, str};
 
 use byteorder::{BigEndian, ByteOrder};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use rand::RngCore;
 use ring::digest;
 use ring::hmac::SigningKey;
 use rustls::internal::msgs::enums::AlertDescription;
-use rustls::{KeyLogFile, ProtocolVersion};
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, KeyLogFile,
+    ProtocolVersion, RootCertStore,
+};
 use slog::{Drain, Logger, KV};
 use untrusted::Input;
 
@@ -71,6 +74,12 @@ struct Pair {
     /// Number of spin bit flips
     spins: u64,
     last_spin: bool,
+    /// If set, every `n`th 1-RTT datagram sent by either side is dropped
+    loss_interval: Option<u64>,
+    /// Number of 1-RTT datagrams sent by either side
+    short_sent: u64,
+    /// If set, datagrams larger than this sent by either side are dropped
+    path_mtu: Option<usize>,
 }
 
 impl Default for Pair {
@@ -95,6 +104,32 @@ fn server_config() -> ServerConfig {
     }
 }
 
+/// Server configuration which authenticates clients against `CERTIFICATE`
+fn client_auth_server_config(required: bool) -> ServerConfig {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&rustls::Certificate(CERTIFICATE.serialize_der()))
+        .unwrap();
+    let verifier = if required {
+        AllowAnyAuthenticatedClient::new(roots)
+    } else {
+        AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+    };
+    let mut tls_config = crypto::ServerConfig::new(verifier);
+    tls_config.versions = vec![ProtocolVersion::TLSv1_3];
+    tls_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
+    tls_config
+        .set_single_cert(
+            vec![rustls::Certificate(CERTIFICATE.serialize_der())],
+            rustls::PrivateKey(CERTIFICATE.serialize_private_key_der()),
+        )
+        .unwrap();
+    ServerConfig {
+        tls_config: Arc::new(tls_config),
+        ..Default::default()
+    }
+}
+
 fn client_config() -> Arc<ClientConfig> {
     let cert = CERTIFICATE.serialize_der();
     let anchor = webpki::trust_anchor_util::cert_der_as_trust_anchor(Input::from(&cert)).unwrap();
@@ -138,6 +173,9 @@ impl Pair {
             latency: 0,
             spins: 0,
             last_spin: false,
+            loss_interval: None,
+            short_sent: 0,
+            path_mtu: None,
         }
     }
 
@@ -187,6 +225,16 @@ impl Pair {
                 let spin = x.packet[0] & packet::SPIN_BIT != 0;
                 self.spins += (spin == self.last_spin) as u64;
                 self.last_spin = spin;
+                self.short_sent += 1;
+                let sent = self.short_sent;
+                if self.loss_interval.map_or(false, |n| sent % n == 0) {
+                    trace!(self.log, "dropping client datagram");
+                    continue;
+                }
+            }
+            if self.path_mtu.map_or(false, |mtu| x.packet.len() > mtu) {
+                trace!(self.log, "dropping oversized client datagram");
+                continue;
             }
             if let Some(ref socket) = self.client.socket {
                 socket.send_to(&x.packet, x.destination).unwrap();
@@ -203,6 +251,18 @@ impl Pair {
         trace!(self.log, "server running");
         self.server.drive(&self.log, self.time, self.client.addr);
         for x in self.server.outbound.drain(..) {
+            if x.packet[0] & packet::LONG_HEADER_FORM == 0 {
+                self.short_sent += 1;
+                let sent = self.short_sent;
+                if self.loss_interval.map_or(false, |n| sent % n == 0) {
+                    trace!(self.log, "dropping server datagram");
+                    continue;
+                }
+            }
+            if self.path_mtu.map_or(false, |mtu| x.packet.len() > mtu) {
+                trace!(self.log, "dropping oversized server datagram");
+                continue;
+            }
             if let Some(ref socket) = self.server.socket {
                 socket.send_to(&x.packet, x.destination).unwrap();
             }
@@ -226,6 +286,40 @@ impl Pair {
         assert_matches!(self.server.poll(), Some((ch, Event::Connected { .. })) if ch == server_ch);
         (client_ch, server_ch)
     }
+
+    /// Send `size` bytes from the client to the server on a new stream, returning once the server
+    /// has read all of it
+    fn transfer(&mut self, client_ch: ConnectionHandle, server_ch: ConnectionHandle, size: usize) {
+        let s = self.client.open(client_ch, Directionality::Uni).unwrap();
+        let msg = [0xAB; 1024];
+        let mut buf = [0; 4096];
+        let (mut sent, mut recvd) = (0, 0);
+        loop {
+            while sent < size {
+                let n = cmp::min(msg.len(), size - sent);
+                match self.client.write(client_ch, s, &msg[..n]) {
+                    Ok(n) => sent += n,
+                    Err(WriteError::Blocked) => break,
+                    Err(e) => panic!("unexpected write error: {}", e),
+                }
+                if sent == size {
+                    self.client.finish(client_ch, s);
+                }
+            }
+            assert!(self.step(), "connection idle before transfer completed");
+            loop {
+                match self.server.read(server_ch, s, &mut buf) {
+                    Ok(n) => recvd += n,
+                    Err(ReadError::Blocked) => break,
+                    Err(ReadError::Finished) => {
+                        assert_eq!(recvd, size);
+                        return;
+                    }
+                    Err(e) => panic!("unexpected read error: {}", e),
+                }
+            }
+        }
+    }
 }
 
 struct TestEndpoint {
@@ -233,7 +327,7 @@ struct TestEndpoint {
     endpoint: Endpoint,
     addr: SocketAddr,
     socket: Option<UdpSocket>,
-    timers: [u64; 5],
+    timers: [u64; 7],
     conn: Option<ConnectionHandle>,
     outbound: VecDeque<Transmit>,
     delayed: VecDeque<Transmit>,
@@ -256,7 +350,7 @@ impl TestEndpoint {
             endpoint,
             addr,
             socket,
-            timers: [u64::max_value(); 5],
+            timers: [u64::max_value(); 7],
             conn: None,
             outbound: VecDeque::new(),
             delayed: VecDeque::new(),
@@ -340,6 +434,28 @@ impl TestEndpoint {
         self.outbound.extend(self.delayed.drain(..));
     }
 
+    /// Split coalesced datagrams in `inbound` into one datagram per packet, as a peer that doesn't
+    /// coalesce packets would have sent them
+    fn split_inbound(&mut self) {
+        let config = Config::default();
+        let mut split = VecDeque::new();
+        for (time, ecn, datagram) in self.inbound.drain(..) {
+            let mut rest = Some(BytesMut::from(&datagram[..]));
+            while let Some(data) = rest.take() {
+                let (_, remaining) = packet::PartialDecode::new(
+                    data.clone(),
+                    config.local_cid_len,
+                    &config.supported_versions,
+                )
+                .unwrap();
+                let len = data.len() - remaining.as_ref().map_or(0, |x| x.len());
+                split.push_back((time, ecn, data[..len].into()));
+                rest = remaining;
+            }
+        }
+        self.inbound = split;
+    }
+
     fn assert_accept(&mut self) -> ConnectionHandle {
         if let Some((c, Event::Handshaking)) = self.poll() {
             self.accept();
@@ -380,7 +496,7 @@ fn version_negotiate() {
         // Long-header packet with reserved version number
         hex!(
             "80 0a1a2a3a
-                        11 00000000 00000000
+                        04 00000000 04 00000000
                         00"
         )[..]
             .into(),
@@ -389,10 +505,10 @@ fn version_negotiate() {
     assert!(io.is_some());
     if let Some(Transmit { packet, .. }) = io {
         assert_ne!(packet[0] & 0x80, 0);
-        assert_eq!(&packet[1..14], hex!("00000000 11 00000000 00000000"));
-        assert!(packet[14..]
+        assert_eq!(&packet[1..15], hex!("00000000 04 00000000 04 00000000"));
+        assert!(packet[15..]
             .chunks(4)
-            .any(|x| BigEndian::read_u32(x) == VERSION));
+            .any(|x| BigEndian::read_u32(x) == VERSION_1));
     }
     assert_matches!(server.poll_transmit(0), None);
     assert_matches!(server.poll(), None);
@@ -431,6 +547,207 @@ fn stateless_retry() {
     pair.connect();
 }
 
+/// Time taken from initiating a connection until the client considers it established
+fn time_to_connect(pair: &mut Pair) -> u64 {
+    let start = pair.time;
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    loop {
+        assert!(pair.step(), "connection idle before handshake completed");
+        while let Some((ch, event)) = pair.client.poll() {
+            if let Event::Connected { .. } = event {
+                if ch == client_ch {
+                    return pair.time - start;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn new_token_skips_retry() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config::default(),
+        ServerConfig {
+            use_stateless_retry: true,
+            ..server_config()
+        },
+    );
+    pair.latency = 10 * 1000;
+    let first = time_to_connect(&mut pair);
+    pair.drive();
+    let second = time_to_connect(&mut pair);
+    // The stateless retry costs one round trip
+    assert_eq!(first, second + 2 * pair.latency);
+}
+
+#[test]
+fn listening_endpoint_connects_out() {
+    let mut pair = Pair::default();
+    // Only the server side of a connection may send NEW_TOKEN, even from a listening endpoint
+    pair.client.endpoint = Endpoint::new(
+        pair.log.new(o!("side" => "Client")),
+        Config::default(),
+        Some(server_config()),
+    )
+    .unwrap();
+    pair.connect();
+    pair.drive();
+    assert_matches!(pair.client.poll(), None);
+    assert_matches!(pair.server.poll(), None);
+}
+
+#[test]
+fn version_2() {
+    let config = || Config {
+        supported_versions: vec![VERSION_2],
+        ..Config::default()
+    };
+    let mut pair = Pair::new(config(), config(), server_config());
+    let (client_ch, server_ch) = pair.connect();
+    assert_eq!(pair.client.connection(client_ch).version(), VERSION_2);
+    assert_eq!(pair.server.connection(server_ch).version(), VERSION_2);
+}
+
+#[test]
+fn version_negotiation_fallback() {
+    let mut pair = Pair::new(
+        Config {
+            supported_versions: vec![VERSION_2],
+            ..Config::default()
+        },
+        Config {
+            supported_versions: vec![VERSION_1, VERSION_2],
+            ..Config::default()
+        },
+        server_config(),
+    );
+    let (client_ch, server_ch) = pair.connect();
+    assert_eq!(pair.client.connection(client_ch).version(), VERSION_2);
+    assert_eq!(pair.server.connection(server_ch).version(), VERSION_2);
+}
+
+#[test]
+fn version_negotiation_fallback_with_retry() {
+    let mut pair = Pair::new(
+        Config {
+            supported_versions: vec![VERSION_2],
+            ..Config::default()
+        },
+        Config {
+            supported_versions: vec![VERSION_1, VERSION_2],
+            ..Config::default()
+        },
+        ServerConfig {
+            use_stateless_retry: true,
+            ..server_config()
+        },
+    );
+    let (client_ch, _) = pair.connect();
+    assert_eq!(pair.client.connection(client_ch).version(), VERSION_2);
+}
+
+#[test]
+fn compatible_version_negotiation() {
+    let mut pair = Pair::new(
+        Config {
+            supported_versions: vec![VERSION_2, VERSION_1],
+            ..Config::default()
+        },
+        Config::default(),
+        server_config(),
+    );
+    let (client_ch, server_ch) = pair.connect();
+    assert_eq!(pair.client.connection(client_ch).version(), VERSION_2);
+    assert_eq!(pair.server.connection(server_ch).version(), VERSION_2);
+    assert_eq!(pair.client.connection(client_ch).lost_packets(), 0);
+}
+
+#[test]
+fn compatible_version_negotiation_0rtt() {
+    let mut pair = Pair::new(
+        Config {
+            supported_versions: vec![VERSION_2, VERSION_1],
+            ..Config::default()
+        },
+        Config::default(),
+        server_config(),
+    );
+    const MSG: &[u8] = b"Hello, 0-RTT!";
+    let (client_ch, s) = resume_with_0rtt(&mut pair, MSG);
+    // The connection switched to version 2, while its 0-RTT packets stayed in version 1
+    assert_eq!(pair.client.connection(client_ch).version(), VERSION_2);
+    assert!(pair.client.connection(client_ch).accepted_0rtt());
+    let server_ch = pair.server.assert_accept();
+    assert!(pair.server.connection(server_ch).received_0rtt(s));
+    assert_matches!(pair.server.read_unordered(server_ch, s), Ok((ref data, 0)) if data == MSG);
+}
+
+#[test]
+fn version_downgrade() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config {
+            supported_versions: vec![VERSION_2, VERSION_1],
+            ..Config::default()
+        },
+        server_config(),
+    );
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    info!(pair.log, "forging version negotiation");
+    let initial = pair.client.outbound.pop_front().unwrap().packet;
+    pair.client.outbound.clear();
+    let dst_cid_len = initial[5] as usize;
+    let dst_cid = &initial[6..6 + dst_cid_len];
+    let src_cid_len = initial[6 + dst_cid_len] as usize;
+    let src_cid = &initial[7 + dst_cid_len..7 + dst_cid_len + src_cid_len];
+    let mut forged = vec![0x80, 0, 0, 0, 0];
+    forged.push(src_cid_len as u8);
+    forged.extend_from_slice(src_cid);
+    forged.push(dst_cid_len as u8);
+    forged.extend_from_slice(dst_cid);
+    forged.extend_from_slice(&VERSION_1.to_be_bytes());
+    pair.client
+        .inbound
+        .push_back((pair.time, None, forged.into()));
+    pair.drive();
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                        error_code
+                    }})) if conn == client_ch && error_code == TransportError::VERSION_NEGOTIATION_ERROR);
+}
+
+#[test]
+fn no_common_version() {
+    let mut pair = Pair::new(
+        Config {
+            supported_versions: vec![VERSION_2],
+            ..Config::default()
+        },
+        Config {
+            supported_versions: vec![VERSION_1],
+            ..Config::default()
+        },
+        server_config(),
+    );
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive();
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::VersionMismatch }))
+                    if conn == client_ch);
+    assert_matches!(pair.server.poll(), None);
+}
+
 #[test]
 fn server_stateless_reset() {
     let mut reset_value = [0; 64];
@@ -521,6 +838,122 @@ fn finish_stream() {
     );
 }
 
+#[test]
+fn stream_priority() {
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    let bulk = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    let urgent = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.set_priority(client_ch, urgent, 1);
+    pair.client.write(client_ch, bulk, &[0xAB; 8192]).unwrap();
+    pair.client.write(client_ch, urgent, b"urgent").unwrap();
+    pair.drive_client();
+    // The first packet sent carries the urgent data
+    pair.server.inbound.truncate(1);
+    pair.drive_server();
+
+    let mut buf = [0; 16];
+    assert_matches!(pair.server.read(server_ch, urgent, &mut buf), Ok(6));
+    assert_eq!(&buf[..6], b"urgent");
+}
+
+#[test]
+fn stream_reprioritize() {
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    let bulk = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    let urgent = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, bulk, &[0xAB; 8192]).unwrap();
+    pair.client.write(client_ch, urgent, b"urgent").unwrap();
+    // Takes effect even though the stream is already waiting to be sent
+    pair.client.set_priority(client_ch, urgent, 1);
+    pair.drive_client();
+    pair.server.inbound.truncate(1);
+    pair.drive_server();
+
+    let mut buf = [0; 16];
+    assert_matches!(pair.server.read(server_ch, urgent, &mut buf), Ok(6));
+    assert_eq!(&buf[..6], b"urgent");
+}
+
+#[test]
+fn set_priority_closed() {
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.client.finish(client_ch, s);
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamFinished { stream })) if conn == client_ch && stream == s);
+    pair.client.set_priority(client_ch, s, 1);
+}
+
+#[test]
+fn stream_round_robin() {
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    let a = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    let b = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    for &s in &[a, b] {
+        for _ in 0..32 {
+            pair.client.write(client_ch, s, &[0xAB; 1024]).unwrap();
+        }
+    }
+    pair.drive_client();
+    pair.drive_server();
+
+    // Both streams make progress, even though `b`'s data was queued after all of `a`'s
+    let mut buf = [0; 1024];
+    assert_matches!(pair.server.read(server_ch, a, &mut buf), Ok(_));
+    assert_matches!(pair.server.read(server_ch, b, &mut buf), Ok(_));
+}
+
+#[test]
+fn datagram_send_recv() {
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    let max = pair
+        .client
+        .connection(client_ch)
+        .max_datagram_size()
+        .unwrap();
+    assert_matches!(
+        pair.client
+            .send_datagram(client_ch, Bytes::from(vec![0; max + 1])),
+        Err(SendDatagramError::TooLarge)
+    );
+
+    const DATA: &[u8] = b"whee";
+    pair.client
+        .send_datagram(client_ch, Bytes::from(DATA))
+        .unwrap();
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::DatagramReceived)) if conn == server_ch);
+    assert_matches!(pair.server.recv_datagram(server_ch), Some(ref x) if x == DATA);
+    assert_matches!(pair.server.recv_datagram(server_ch), None);
+}
+
+#[test]
+fn datagram_unsupported() {
+    let server = Config {
+        datagram_receive_buffer_size: None,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(server, Config::default(), server_config());
+    let (client_ch, _) = pair.connect();
+    assert_matches!(pair.client.connection(client_ch).max_datagram_size(), None);
+    assert_matches!(
+        pair.client
+            .send_datagram(client_ch, Bytes::from(&b"hi"[..])),
+        Err(SendDatagramError::UnsupportedByPeer)
+    );
+}
+
 #[test]
 fn reset_stream() {
     let mut pair = Pair::default();
@@ -533,7 +966,7 @@ fn reset_stream() {
     pair.drive();
 
     info!(pair.log, "resetting stream");
-    const ERROR: u16 = 42;
+    const ERROR: u64 = 42;
     pair.client.reset(client_ch, s, ERROR);
     pair.drive();
 
@@ -558,7 +991,7 @@ fn stop_stream() {
     pair.drive();
 
     info!(pair.log, "stopping stream");
-    const ERROR: u16 = 42;
+    const ERROR: u64 = 42;
     pair.server.stop_sending(server_ch, s, ERROR);
     pair.drive();
 
@@ -577,31 +1010,91 @@ fn stop_stream() {
 }
 
 #[test]
-fn reject_self_signed_cert() {
-    let mut client_config = ClientConfig::new();
-    client_config.versions = vec![ProtocolVersion::TLSv1_3];
-    client_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
-
-    let mut pair = Pair::default();
-    info!(pair.log, "connecting");
+fn client_certificate() {
+    let mut pair = Pair::new(
+        Default::default(),
+        Default::default(),
+        client_auth_server_config(true),
+    );
+    // Each serialization carries a fresh signature, so only serialize once
+    let cert = rustls::Certificate(CERTIFICATE.serialize_der());
+    let mut client_config = (*client_config()).clone();
+    client_config.set_single_client_cert(
+        vec![cert.clone()],
+        rustls::PrivateKey(CERTIFICATE.serialize_private_key_der()),
+    );
     let client_ch = pair
         .client
         .connect(pair.server.addr, &Arc::new(client_config), "localhost")
         .unwrap();
     pair.drive();
-    assert_matches!(pair.client.poll(),
-                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
-                        error_code
-                    }})) if conn == client_ch && error_code == TransportError::crypto(AlertDescription::BadCertificate));
+    let server_ch = pair.server.assert_accept();
+    assert_eq!(
+        pair.server.connection(server_ch).peer_certificates(),
+        Some(vec![cert])
+    );
+    assert_matches!(pair.client.connection(client_ch).peer_certificates(), Some(ref x) if x.len() == 1);
 }
 
 #[test]
-fn congestion() {
-    let mut pair = Pair::default();
-    let (client_ch, _) = pair.connect();
+fn client_certificate_required() {
+    let mut pair = Pair::new(
+        Default::default(),
+        Default::default(),
+        client_auth_server_config(true),
+    );
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive();
+    // The server only checks the client's certificate after the client considers itself connected
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected)) if conn == client_ch);
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { .. })) if conn == client_ch);
+}
 
-    let initial_congestion_state = pair.client.connection(client_ch).congestion_state();
-    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+#[test]
+fn client_certificate_optional() {
+    let mut pair = Pair::new(
+        Default::default(),
+        Default::default(),
+        client_auth_server_config(false),
+    );
+    pair.client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    assert_eq!(pair.server.connection(server_ch).peer_certificates(), None);
+}
+
+#[test]
+fn reject_self_signed_cert() {
+    let mut client_config = ClientConfig::new();
+    client_config.versions = vec![ProtocolVersion::TLSv1_3];
+    client_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
+
+    let mut pair = Pair::default();
+    info!(pair.log, "connecting");
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &Arc::new(client_config), "localhost")
+        .unwrap();
+    pair.drive();
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                        error_code
+                    }})) if conn == client_ch && error_code == TransportError::crypto(AlertDescription::BadCertificate));
+}
+
+#[test]
+fn congestion() {
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+
+    let initial_congestion_state = pair.client.connection(client_ch).congestion_state();
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
     loop {
         match pair.client.write(client_ch, s, &[42; 1024]) {
             Ok(n) => {
@@ -621,6 +1114,319 @@ fn congestion() {
     pair.client.write(client_ch, s, &[42; 1024]).unwrap();
 }
 
+#[test]
+fn custom_congestion_controller() {
+    const WINDOW: u64 = 4000;
+
+    struct Fixed;
+    impl congestion::Controller for Fixed {
+        fn on_ack(&mut self, _: u64, _: u64, _: u64, _: &RttEstimator) {}
+        fn on_loss(&mut self, _: u64, _: u64, _: u64) {}
+        fn on_persistent_congestion(&mut self, _: u64) {}
+        fn window(&self) -> u64 {
+            WINDOW
+        }
+    }
+    impl congestion::ControllerFactory for Fixed {
+        fn build(self: Arc<Self>) -> Box<dyn congestion::Controller> {
+            Box::new(Fixed)
+        }
+    }
+
+    let mut pair = Pair::new(
+        Config::default(),
+        Config {
+            congestion_controller_factory: Arc::new(Fixed),
+            ..Config::default()
+        },
+        server_config(),
+    );
+    let (client_ch, _) = pair.connect();
+    assert_eq!(pair.client.connection(client_ch).congestion_state(), WINDOW);
+
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    let mut written = 0;
+    loop {
+        match pair.client.write(client_ch, s, &[42; 1024]) {
+            Ok(n) => {
+                written += n as u64;
+                pair.drive_client();
+            }
+            Err(WriteError::Blocked) => break,
+            Err(e) => panic!("unexpected write error: {}", e),
+        }
+    }
+    assert!(written <= WINDOW);
+    assert!(pair.client.connection(client_ch).bytes_in_flight() <= WINDOW);
+    pair.drive();
+    assert_eq!(pair.client.connection(client_ch).congestion_state(), WINDOW);
+}
+
+#[test]
+fn cubic_lossy_transfer() {
+    let cubic = lossy_transfer_time(Arc::new(congestion::CubicConfig::default()));
+    let new_reno = lossy_transfer_time(Arc::new(congestion::NewRenoConfig::default()));
+    // Shallower reductions and rapid regrowth towards the window at the last loss beat NewReno's
+    // halving and linear growth under the same loss pattern
+    assert!(
+        cubic < new_reno,
+        "CUBIC: {}μs, NewReno: {}μs",
+        cubic,
+        new_reno
+    );
+}
+
+#[test]
+fn cubic_high_latency_transfer() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config {
+            congestion_controller_factory: Arc::new(congestion::CubicConfig::default()),
+            ..Config::default()
+        },
+        server_config(),
+    );
+    pair.latency = 200 * 1000;
+    let (client_ch, server_ch) = pair.connect();
+    let initial_window = pair.client.connection(client_ch).congestion_state();
+    pair.transfer(client_ch, server_ch, 256 * 1024);
+    pair.drive();
+    // No loss, so the window should only have grown
+    assert!(pair.client.connection(client_ch).congestion_state() > initial_window);
+}
+
+/// Time taken to transfer 1MiB over a lossy link using the given congestion controller
+fn lossy_transfer_time(factory: Arc<dyn congestion::ControllerFactory + Send + Sync>) -> u64 {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config {
+            congestion_controller_factory: factory,
+            ..Config::default()
+        },
+        server_config(),
+    );
+    pair.latency = 25 * 1000;
+    let (client_ch, server_ch) = pair.connect();
+    pair.loss_interval = Some(20);
+    let start = pair.time;
+    pair.transfer(client_ch, server_ch, 1024 * 1024);
+    pair.time - start
+}
+
+#[test]
+fn bbr_lossy_transfer() {
+    let bbr = lossy_transfer_time(Arc::new(congestion::BbrConfig::default()));
+    let new_reno = lossy_transfer_time(Arc::new(congestion::NewRenoConfig::default()));
+    // Random loss shouldn't collapse the window
+    assert!(bbr < new_reno, "BBR: {}μs, NewReno: {}μs", bbr, new_reno);
+}
+
+#[test]
+fn bbr_late_connection() {
+    let config = Arc::new(congestion::BbrConfig::default());
+    let mut pair = Pair::new(
+        Config::default(),
+        Config {
+            congestion_controller_factory: config.clone(),
+            ..Config::default()
+        },
+        server_config(),
+    );
+    pair.latency = 10 * 1000;
+    // Connect long after the endpoints' epoch, as on a long-running server
+    pair.time = 6 * config.probe_rtt_interval;
+    let (client_ch, _) = pair.connect();
+    // The first RTT sample mustn't be mistaken for a stale one, which would enter ProbeRtt
+    assert!(pair.client.connection(client_ch).congestion_state() >= config.initial_window);
+}
+
+#[test]
+fn pacing() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config {
+            congestion_controller_factory: Arc::new(congestion::NewRenoConfig {
+                initial_window: 128 * 1200,
+                ..congestion::NewRenoConfig::default()
+            }),
+            ..Config::default()
+        },
+        server_config(),
+    );
+    pair.latency = 50 * 1000;
+    let (client_ch, server_ch) = pair.connect();
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    assert_eq!(
+        pair.client.write(client_ch, s, &[0xAB; 64 * 1024]),
+        Ok(64 * 1024)
+    );
+    pair.drive_client();
+    // Though the congestion window would allow it, not all data is sent at once
+    let burst = pair.server.inbound.len();
+    assert!(burst < 20, "sent {} packets in one burst", burst);
+    assert_ne!(pair.client.timers[Timer::Pacing as usize], u64::max_value());
+    pair.drive();
+    let mut buf = [0; 64 * 1024];
+    assert_eq!(pair.server.read(server_ch, s, &mut buf), Ok(64 * 1024));
+}
+
+#[test]
+fn mtu_discovery() {
+    let mut pair = Pair::default();
+    pair.path_mtu = Some(1400);
+    let (client_ch, server_ch) = pair.connect();
+    pair.drive();
+    for &mtu in &[
+        pair.client.connection(client_ch).mtu(),
+        pair.server.connection(server_ch).mtu(),
+    ] {
+        assert!(mtu <= 1400 && mtu > 1300, "discovered MTU {}", mtu);
+    }
+    // Bulk transfers at the discovered size don't trip black hole detection
+    pair.transfer(client_ch, server_ch, 64 * 1024);
+    assert!(pair.client.connection(client_ch).mtu() > 1300);
+}
+
+#[test]
+fn mtu_discovery_disabled() {
+    let mut pair = Pair::new(
+        Config {
+            max_mtu: MIN_MTU,
+            ..Config::default()
+        },
+        Config {
+            max_mtu: MIN_MTU,
+            ..Config::default()
+        },
+        server_config(),
+    );
+    let (client_ch, server_ch) = pair.connect();
+    pair.drive();
+    assert_eq!(pair.client.connection(client_ch).mtu(), MIN_MTU);
+    assert_eq!(pair.server.connection(server_ch).mtu(), MIN_MTU);
+}
+
+#[test]
+fn mtu_black_hole() {
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    pair.drive();
+    assert!(pair.client.connection(client_ch).mtu() > MIN_MTU);
+    // Path no longer carries the larger packets
+    pair.path_mtu = Some(MIN_MTU as usize);
+    pair.transfer(client_ch, server_ch, 64 * 1024);
+    assert_eq!(pair.client.connection(client_ch).mtu(), MIN_MTU);
+}
+
+#[test]
+fn delayed_ack() {
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    // A lone ack-eliciting packet isn't acknowledged immediately
+    assert!(pair.client.inbound.is_empty());
+    assert_ne!(
+        pair.server.timers[Timer::MaxAckDelay as usize],
+        u64::max_value()
+    );
+    pair.drive();
+    assert_eq!(pair.client.connection(client_ch).bytes_in_flight(), 0);
+
+    // A second one is
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.drive_client();
+    pair.client.write(client_ch, s, b"world").unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.client.inbound.len(), 1);
+}
+
+#[test]
+fn ack_frequency() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config {
+            ack_frequency: true,
+            ..Config::default()
+        },
+        server_config(),
+    );
+    let (client_ch, _) = pair.connect();
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    // The request for less frequent acknowledgements rides along with the first data sent
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.drive();
+
+    for _ in 0..2 {
+        pair.client.write(client_ch, s, b"hello").unwrap();
+        pair.drive_client();
+    }
+    pair.drive_server();
+    assert!(pair.client.inbound.is_empty());
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.client.inbound.len(), 1);
+}
+
+#[test]
+fn ack_frequency_probe() {
+    let mut pair = Pair::new(
+        Config::default(),
+        Config {
+            ack_frequency: true,
+            ..Config::default()
+        },
+        server_config(),
+    );
+    let (client_ch, _) = pair.connect();
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.drive();
+
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.drive_client();
+    info!(pair.log, "dropping acks until the client probes");
+    loop {
+        pair.time = cmp::min(pair.client.next_wakeup(), pair.server.next_wakeup());
+        pair.server.drive(&pair.log, pair.time, pair.client.addr);
+        pair.server.outbound.clear();
+        pair.drive_client();
+        if !pair.server.inbound.is_empty() {
+            break;
+        }
+    }
+    // The loss probe carries IMMEDIATE_ACK, so the server acknowledges it without delay
+    pair.time = pair.server.inbound.back().unwrap().0;
+    pair.server.drive(&pair.log, pair.time, pair.client.addr);
+    assert!(!pair.server.outbound.is_empty());
+}
+
+#[test]
+fn reordered_ack() {
+    let mut pair = Pair::new(
+        Config {
+            ack_eliciting_threshold: 10,
+            ..Config::default()
+        },
+        Config::default(),
+        server_config(),
+    );
+    let (client_ch, _) = pair.connect();
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, s, b"hello").unwrap();
+    pair.drive_client();
+    pair.client.write(client_ch, s, b"world").unwrap();
+    pair.drive_client();
+    // Packets received out of order are acknowledged immediately
+    pair.server.inbound.swap(0, 1);
+    pair.drive_server();
+    assert_eq!(pair.client.inbound.len(), 1);
+}
+
 #[test]
 fn high_latency_handshake() {
     let mut pair = Pair::default();
@@ -663,10 +1469,152 @@ fn zero_rtt() {
     pair.drive();
     assert!(pair.client.connection(client_ch).accepted_0rtt());
     let server_ch = pair.server.assert_accept();
+    assert!(pair.server.connection(server_ch).received_0rtt(s));
     assert_matches!(pair.server.read_unordered(server_ch, s), Ok((ref data, 0)) if data == MSG);
     assert_eq!(pair.client.connection(client_ch).lost_packets(), 0);
 }
 
+/// Establish and close a connection, then resume the session from a new address, sending `msg`
+/// on a new stream as 0-RTT data
+fn resume_with_0rtt(pair: &mut Pair, msg: &[u8]) -> (ConnectionHandle, StreamId) {
+    let config = client_config();
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &config, "localhost")
+        .unwrap();
+    pair.drive();
+    pair.server.assert_accept();
+    pair.client.close(pair.time, client_ch, 0, [][..].into());
+    pair.drive();
+
+    pair.client.addr = SocketAddr::new(
+        Ipv6Addr::LOCALHOST.into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    info!(pair.log, "resuming session");
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &config, "localhost")
+        .unwrap();
+    assert!(pair.client.connection(client_ch).has_0rtt());
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, s, msg).unwrap();
+    pair.drive();
+    (client_ch, s)
+}
+
+#[test]
+fn zero_rtt_policy() {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let mut server_config = server_config();
+    let log = requests.clone();
+    server_config.early_data_policy = Arc::new(move |request: &EarlyDataRequest<'_>| {
+        log.lock().unwrap().push((
+            request.remote,
+            request.server_name.map(String::from),
+            request.protocols.to_vec(),
+        ));
+        false
+    });
+    let mut pair = Pair::new(Default::default(), Default::default(), server_config);
+
+    const MSG: &[u8] = b"Hello, 0-RTT!";
+    let (client_ch, s) = resume_with_0rtt(&mut pair, MSG);
+    assert!(!pair.client.connection(client_ch).accepted_0rtt());
+    let server_ch = pair.server.assert_accept();
+    assert_matches!(
+        pair.server.read_unordered(server_ch, s),
+        Err(ReadError::Blocked)
+    );
+    assert!(!pair.server.connection(server_ch).received_0rtt(s));
+
+    // Only the resumed connection offered early data
+    assert_eq!(
+        *requests.lock().unwrap(),
+        [(
+            pair.client.addr,
+            Some("localhost".into()),
+            vec![ALPN_QUIC_HTTP.to_vec()]
+        )]
+    );
+}
+
+#[test]
+fn zero_rtt_size_limit() {
+    let mut server_config = server_config();
+    server_config.max_early_data_size = 0;
+    let mut pair = Pair::new(Default::default(), Default::default(), server_config);
+
+    const MSG: &[u8] = b"Hello, 0-RTT!";
+    let (client_ch, s) = resume_with_0rtt(&mut pair, MSG);
+    // TLS accepted early data, but the packet carrying it was discarded and had to be resent
+    assert!(pair.client.connection(client_ch).accepted_0rtt());
+    assert!(pair.client.connection(client_ch).lost_packets() > 0);
+    let server_ch = pair.server.assert_accept();
+    assert!(!pair.server.connection(server_ch).received_0rtt(s));
+    assert_matches!(pair.server.read_unordered(server_ch, s), Ok((ref data, 0)) if data == MSG);
+}
+
+#[test]
+fn zero_rtt_persistent_session() {
+    let path = env::temp_dir().join(format!("quinn-sessions-{}", process::id()));
+    let _ = fs::remove_file(&path);
+    let client_config = || {
+        let mut config = (*client_config()).clone();
+        let store = SessionFileStore::open(&path).unwrap();
+        set_session_store(&mut config, Arc::new(store));
+        Arc::new(config)
+    };
+
+    let mut pair = Pair::default();
+    let config = client_config();
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &config, "localhost")
+        .unwrap();
+    assert!(!pair.client.connection(client_ch).has_0rtt());
+    pair.drive();
+    pair.server.assert_accept();
+    pair.client.close(pair.time, client_ch, 0, [][..].into());
+    pair.drive();
+
+    // A fresh endpoint, as after a restart, shares nothing with the first but the session file
+    let endpoint = Endpoint::new(
+        pair.log.new(o!("side" => "Client")),
+        Config::default(),
+        None,
+    );
+    pair.client = TestEndpoint::new(
+        Side::Client,
+        endpoint.unwrap(),
+        SocketAddr::new(
+            Ipv6Addr::LOCALHOST.into(),
+            CLIENT_PORTS.lock().unwrap().next().unwrap(),
+        ),
+    );
+    info!(pair.log, "resuming session");
+    let config = client_config();
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &config, "localhost")
+        .unwrap();
+    assert!(pair.client.connection(client_ch).has_0rtt());
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"Hello, 0-RTT!";
+    pair.client.write(client_ch, s, MSG).unwrap();
+    pair.drive();
+    assert!(pair.client.connection(client_ch).accepted_0rtt());
+    let server_ch = pair.server.assert_accept();
+    assert_matches!(pair.server.read_unordered(server_ch, s), Ok((ref data, 0)) if data == MSG);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    fs::remove_file(&path).unwrap();
+}
+
 #[test]
 fn zero_rtt_rejection() {
     let mut pair = Pair::default();
@@ -830,13 +1778,14 @@ fn key_update_reordered() {
     assert_matches!(pair.server.accept_stream(server_ch), Some(stream) if stream == s);
     assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream })) if conn == server_ch && stream == s);
     assert_matches!(pair.server.poll(), None);
+    // Buffered data is read in offset order, even though MSG2 arrived first
     assert_matches!(
         pair.server.read_unordered(server_ch, s),
-        Ok((ref data, 1)) if data == MSG2
+        Ok((ref data, 0)) if data == MSG1
     );
     assert_matches!(
         pair.server.read_unordered(server_ch, s),
-        Ok((ref data, 0)) if data == MSG1
+        Ok((ref data, 1)) if data == MSG2
     );
 
     assert_eq!(pair.client.connection(client_ch).lost_packets(), 0);
@@ -883,8 +1832,9 @@ fn instant_close_2() {
     pair.drive();
     assert_matches!(pair.client.poll(), None);
     pair.server.assert_accept();
-    assert_matches!(pair.server.poll(), Some((_, Event::ConnectionLost { reason: ConnectionError::ApplicationClosed {
-        reason: ApplicationClose { error_code: 42, ref reason }
+    // The application's error code is withheld until the handshake completes
+    assert_matches!(pair.server.poll(), Some((_, Event::ConnectionLost { reason: ConnectionError::ConnectionClosed {
+        reason: ConnectionClose { error_code: TransportError::APPLICATION_ERROR, frame_type: None, ref reason }
     }})) if reason.is_empty());
 }
 
@@ -944,7 +1894,7 @@ fn server_busy() {
                     ConnectionError::ConnectionClosed {
                         reason:
                             frame::ConnectionClose {
-                                error_code: TransportError::SERVER_BUSY,
+                                error_code: TransportError::CONNECTION_REFUSED,
                                 ..
                             },
                     },
@@ -962,6 +1912,7 @@ fn server_hs_retransmit() {
         .connect(pair.server.addr, &client_config(), "localhost")
         .unwrap();
     pair.step();
+    pair.client.split_inbound();
     assert!(pair.client.inbound.len() > 1); // Initial + Handshakes
     info!(
         pair.log,
@@ -983,16 +1934,148 @@ fn server_hs_retransmit() {
     assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_ch);
 }
 
+#[test]
+fn handshake_loss() {
+    let mut pair = Pair::default();
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.step();
+    info!(pair.log, "dropping server's first flight");
+    pair.client.inbound.clear();
+    pair.drive_client();
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_ch);
+    // Recovered by a single probe timeout based on the initial RTT estimate
+    assert!(pair.time < 4 * Config::default().initial_rtt);
+}
+
+#[test]
+fn tail_loss() {
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    let start = pair.time;
+
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_ch, s, MSG).unwrap();
+    pair.client.finish(client_ch, s);
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    info!(pair.log, "dropping the only packet bearing stream data");
+    pair.client.outbound.clear();
+    pair.drive();
+
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamOpened)) if conn == server_ch);
+    assert_matches!(pair.server.accept_stream(server_ch), Some(stream) if stream == s);
+    assert_matches!(pair.server.read_unordered(server_ch, s), Ok((ref data, 0)) if data == MSG);
+    // The probe's acknowledgement reveals the loss without waiting for further timeouts
+    assert_eq!(pair.client.connection(client_ch).lost_packets(), 1);
+    // Recovered by a single probe timeout, dominated by the peer's maximum ack delay
+    assert!(pair.time - start < 4 * Config::default().delayed_ack_timeout);
+}
+
+#[test]
+fn spurious_loss() {
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+    let window = pair.client.connection(client_ch).congestion_state();
+
+    for round in 0..2 {
+        // Send several packets and deliver the first after the rest have been acknowledged
+        for _ in 0..5 {
+            let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+            pair.client.write(client_ch, s, b"hello").unwrap();
+            pair.client.finish(client_ch, s);
+            pair.drive_client();
+        }
+        assert_eq!(pair.server.inbound.len(), 5);
+        let delayed = pair.server.inbound.pop_front().unwrap();
+        pair.drive_server();
+        pair.drive_client();
+        info!(pair.log, "delivering reordered packet");
+        pair.server.inbound.push_back(delayed);
+        pair.drive();
+
+        // The reordering is mistaken for loss only the first time
+        let conn = pair.client.connection(client_ch);
+        assert_eq!(conn.lost_packets(), 1, "round {}", round);
+        assert_eq!(conn.spurious_losses(), 1, "round {}", round);
+        // ...and the congestion window reduction is reverted
+        assert!(conn.congestion_state() >= window);
+    }
+}
+
+/// Send several packets of stream data to a server using `config`, dropping those at `drop`
+fn stream_gaps(config: Config, drop: &[usize]) -> (Pair, ConnectionHandle) {
+    let mut pair = Pair::new(config, Default::default(), server_config());
+    let (client_ch, _) = pair.connect();
+    let s = pair.client.open(client_ch, Directionality::Uni).unwrap();
+    pair.client.write(client_ch, s, &[0; 6000]).unwrap();
+    pair.drive_client();
+    assert!(pair.server.inbound.len() >= 5);
+    info!(pair.log, "dropping packets {:?}", drop);
+    for &i in drop.iter().rev() {
+        pair.server.inbound.remove(i);
+    }
+    pair.drive();
+    (pair, client_ch)
+}
+
+#[test]
+fn out_of_order_limit() {
+    let config = |limit| Config {
+        out_of_order_buffer_size: limit,
+        ..Config::default()
+    };
+    // Data buffered behind the lost first packet fits
+    let (mut pair, _) = stream_gaps(config(6000), &[0]);
+    assert_matches!(pair.client.poll(), None);
+
+    // A cap below the flow control window is enforced
+    let (mut pair, client_ch) = stream_gaps(config(2048), &[0]);
+    assert_matches!(
+        pair.client.poll(),
+        Some((conn, Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed {
+                reason: frame::ConnectionClose { error_code: TransportError::PROTOCOL_VIOLATION, .. }
+            }
+        })) if conn == client_ch
+    );
+}
+
+#[test]
+fn stream_fragment_limit() {
+    let config = || Config {
+        max_stream_fragments: 2,
+        ..Config::default()
+    };
+    // A single gap splits the received data into two fragments
+    let (mut pair, _) = stream_gaps(config(), &[1]);
+    assert_matches!(pair.client.poll(), None);
+
+    let (mut pair, client_ch) = stream_gaps(config(), &[1, 3]);
+    assert_matches!(
+        pair.client.poll(),
+        Some((conn, Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed {
+                reason: frame::ConnectionClose { error_code: TransportError::PROTOCOL_VIOLATION, .. }
+            }
+        })) if conn == client_ch
+    );
+}
+
 #[test]
 fn decode_coalesced() {
-    // We can't currently generate coalesced packets natively, but we must support decoding
-    // them. Hack around the problem by manually concatenating the server's first flight.
+    // Peers may coalesce more packets than we do, and we must support decoding them. Simulate
+    // that by manually concatenating every packet of the server's first flight.
     let mut pair = Pair::default();
     let client_ch = pair
         .client
         .connect(pair.server.addr, &client_config(), "localhost")
         .unwrap();
     pair.step();
+    pair.client.split_inbound();
     assert!(
         pair.client.inbound.len() > 1,
         "if the server's flight isn't multiple packets, this test is redundant"
@@ -1009,6 +2092,21 @@ fn decode_coalesced() {
     assert_eq!(pair.client.connection(client_ch).lost_packets(), 0);
 }
 
+#[test]
+fn coalesce_handshake() {
+    let mut pair = Pair::default();
+    let client_ch = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.step();
+    // The server's Initial and Handshake packets share a datagram
+    assert_eq!(pair.client.inbound.len(), 1);
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_ch);
+    assert_eq!(pair.client.connection(client_ch).lost_packets(), 0);
+}
+
 #[test]
 fn migration() {
     let mut pair = Pair::default();
@@ -1084,6 +2182,7 @@ fn stream_flow_control() {
     test_flow_control(
         Config {
             stream_receive_window: 2000,
+            max_stream_receive_window: 2000,
             ..Config::default()
         },
         2000,
@@ -1095,18 +2194,176 @@ fn conn_flow_control() {
     test_flow_control(
         Config {
             receive_window: 2000,
+            max_receive_window: 2000,
             ..Config::default()
         },
         2000,
     );
 }
 
+/// Time taken to transfer a stream to a server that reads as soon as data arrives
+fn window_limited_transfer(max_stream_receive_window: u64) -> u64 {
+    let server = Config {
+        stream_receive_window: 2000,
+        max_stream_receive_window,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(server, Config::default(), server_config());
+    pair.latency = 10 * 1000;
+    let (client_conn, server_conn) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let msg = vec![0xAB; 64 * 1024];
+    let mut buf = [0; 4096];
+    let start = pair.time;
+    let (mut written, mut read) = (0, 0);
+    while read < msg.len() {
+        if let Ok(n) = pair.client.write(client_conn, s, &msg[written..]) {
+            assert!(n as u64 <= max_stream_receive_window);
+            written += n;
+        }
+        pair.step();
+        // Writes are only attempted between steps, so credit that arrived during the step would
+        // otherwise go unused until the clock next advances, possibly to a delayed ACK timer tens
+        // of milliseconds away. Such stalls would dominate the transfer time regardless of window
+        // size, hiding the effect of auto-tuning.
+        pair.drive_client();
+        while let Ok(n) = pair.server.read(server_conn, s, &mut buf) {
+            read += n;
+        }
+    }
+    pair.time - start
+}
+
+#[test]
+fn stream_window_autotuning() {
+    let fixed = window_limited_transfer(2000);
+    let tuned = window_limited_transfer(16000);
+    assert!(tuned * 2 < fixed, "tuned: {}, fixed: {}", tuned, fixed);
+}
+
+#[test]
+fn blocked_frames() {
+    let server = Config {
+        stream_window_uni: 1,
+        stream_receive_window: 2000,
+        max_stream_receive_window: 2000,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(server, Config::default(), server_config());
+    let (client_conn, server_conn) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[0; 4000]), Ok(2000));
+    assert_eq!(
+        pair.client.write(client_conn, s, &[0; 4000]),
+        Err(WriteError::Blocked)
+    );
+    assert_matches!(pair.client.open(client_conn, Directionality::Uni), None);
+    assert_matches!(pair.client.open(client_conn, Directionality::Uni), None);
+    pair.drive();
+    // Repeated attempts against the same limit are reported once
+    assert_eq!(pair.client.connection(client_conn).blocked_frames_sent(), 2);
+
+    // Once the limit is raised, hitting it again is reported anew
+    let mut buf = [0; 4096];
+    assert_eq!(pair.server.read(server_conn, s, &mut buf), Ok(2000));
+    pair.drive();
+    assert_eq!(pair.client.write(client_conn, s, &[0; 4000]), Ok(2000));
+    pair.drive();
+    assert_eq!(pair.client.connection(client_conn).blocked_frames_sent(), 3);
+}
+
+#[test]
+fn adjust_concurrent_streams() {
+    let server = Config {
+        stream_window_uni: 1,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(server, Config::default(), server_config());
+    let (client_conn, server_conn) = pair.connect();
+    let mut streams = vec![pair.client.open(client_conn, Directionality::Uni).unwrap()];
+    assert_matches!(pair.client.open(client_conn, Directionality::Uni), None);
+
+    info!(pair.log, "raising limit");
+    pair.server
+        .set_max_concurrent_streams(server_conn, Directionality::Uni, 3);
+    pair.drive();
+    for _ in 0..2 {
+        streams.push(pair.client.open(client_conn, Directionality::Uni).unwrap());
+    }
+    assert_matches!(pair.client.open(client_conn, Directionality::Uni), None);
+    for &s in &streams {
+        pair.client.write(client_conn, s, b"hello").unwrap();
+    }
+    pair.drive();
+
+    info!(pair.log, "lowering limit");
+    pair.server
+        .set_max_concurrent_streams(server_conn, Directionality::Uni, 1);
+    for &s in &streams {
+        assert_eq!(pair.server.accept_stream(server_conn), Some(s));
+    }
+    pair.drive();
+    assert!(pair.client.open(client_conn, Directionality::Uni).is_some());
+    assert_matches!(pair.client.open(client_conn, Directionality::Uni), None);
+}
+
+#[test]
+fn adjust_receive_windows() {
+    let server = Config {
+        stream_receive_window: 2000,
+        max_stream_receive_window: 2000,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(server, Config::default(), server_config());
+    let (client_conn, server_conn) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let msg = [0; 8000];
+    let mut buf = [0; 8000];
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(2000));
+    pair.drive();
+
+    info!(pair.log, "raising stream window");
+    pair.server.set_stream_receive_window(server_conn, 4000);
+    pair.drive();
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(2000));
+    pair.drive();
+
+    info!(pair.log, "lowering stream window");
+    pair.server.set_stream_receive_window(server_conn, 1000);
+    assert_eq!(pair.server.read(server_conn, s, &mut buf), Ok(4000));
+    pair.drive();
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(1000));
+
+    let server = Config {
+        receive_window: 2000,
+        max_receive_window: 2000,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(server, Config::default(), server_config());
+    let (client_conn, server_conn) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(2000));
+    pair.drive();
+
+    info!(pair.log, "raising connection window");
+    pair.server.set_receive_window(server_conn, 3000);
+    pair.drive();
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(1000));
+    pair.drive();
+
+    info!(pair.log, "lowering connection window");
+    pair.server.set_receive_window(server_conn, 500);
+    assert_eq!(pair.server.read(server_conn, s, &mut buf), Ok(3000));
+    pair.drive();
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(500));
+}
+
 #[test]
 fn stop_opens_bidi() {
     let mut pair = Pair::default();
     let (client_conn, server_conn) = pair.connect();
     let s = pair.client.open(client_conn, Directionality::Bi).unwrap();
-    const ERROR: u16 = 42;
+    const ERROR: u64 = 42;
     pair.client.stop_sending(server_conn, s, ERROR);
     pair.drive();
 
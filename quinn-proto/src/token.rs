@@ -0,0 +1,72 @@
+//! Storage for address validation tokens issued by servers
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+/// Retains address validation tokens received from servers for use in future connections
+///
+/// Presenting a token lets a server skip validating the client's address, which saves a round trip
+/// when the server uses stateless retry.
+pub trait TokenStore: Send + Sync {
+    /// Record a token received from the server named `server_name`
+    fn insert(&self, server_name: &str, token: Bytes);
+
+    /// Take a token to present when connecting to the server named `server_name`, if any
+    ///
+    /// Each token should be used at most once, as reuse allows an observer to link connections.
+    fn take(&self, server_name: &str) -> Option<Bytes>;
+}
+
+/// A `TokenStore` which keeps the most recent tokens from each server in memory
+#[derive(Debug, Default)]
+pub struct TokenMemoryCache {
+    tokens: Mutex<HashMap<String, VecDeque<Bytes>>>,
+}
+
+impl TokenMemoryCache {
+    /// Number of tokens retained per server
+    const TOKENS_PER_SERVER: usize = 2;
+}
+
+impl TokenStore for TokenMemoryCache {
+    fn insert(&self, server_name: &str, token: Bytes) {
+        let mut tokens = self.tokens.lock().unwrap();
+        let queue = tokens.entry(server_name.into()).or_default();
+        if queue.len() == Self::TOKENS_PER_SERVER {
+            queue.pop_front();
+        }
+        queue.push_back(token);
+    }
+
+    fn take(&self, server_name: &str) -> Option<Bytes> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let queue = tokens.get_mut(server_name)?;
+        // Newer tokens are likely to remain valid for longer
+        let token = queue.pop_back();
+        if queue.is_empty() {
+            tokens.remove(server_name);
+        }
+        token
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_cache() {
+        let cache = TokenMemoryCache::default();
+        assert_eq!(cache.take("example.com"), None);
+        for &x in &[&b"1"[..], b"2", b"3"] {
+            cache.insert("example.com", Bytes::from(x));
+        }
+        cache.insert("example.net", Bytes::from_static(b"4"));
+        assert_eq!(cache.take("example.com"), Some(Bytes::from_static(b"3")));
+        assert_eq!(cache.take("example.com"), Some(Bytes::from_static(b"2")));
+        assert_eq!(cache.take("example.com"), None);
+        assert_eq!(cache.take("example.net"), Some(Bytes::from_static(b"4")));
+    }
+}
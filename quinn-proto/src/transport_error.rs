@@ -7,25 +7,25 @@ use crate::coding::{self, BufExt, BufMutExt};
 use rustls::internal::msgs::{codec::Codec, enums::AlertDescription};
 
 #[derive(Copy, Clone, Eq, PartialEq)]
-pub struct Error(u16);
+pub struct Error(u64);
 
 impl Error {
     pub fn crypto(alert: AlertDescription) -> Self {
-        Error(0x100 | alert.get_u8() as u16)
+        Error(0x100 | u64::from(alert.get_u8()))
     }
 }
 
 impl coding::Codec for Error {
     fn decode<B: Buf>(buf: &mut B) -> coding::Result<Self> {
-        Ok(Error(buf.get::<u16>()?))
+        Ok(Error(buf.get_var()?))
     }
     fn encode<B: BufMut>(&self, buf: &mut B) {
-        buf.write::<u16>(self.0)
+        buf.write_var(self.0)
     }
 }
 
-impl From<Error> for u16 {
-    fn from(x: Error) -> u16 {
+impl From<Error> for u64 {
+    fn from(x: Error) -> u64 {
         x.0
     }
 }
@@ -41,7 +41,7 @@ macro_rules! errors {
                 match self.0 {
                     $($val => f.write_str(stringify!($name)),)*
                     x if x >= 0x100 && x < 0x200 => write!(f, "Error::crypto({:?})", AlertDescription::read_bytes(&[self.0 as u8]).unwrap()),
-                    _ => write!(f, "Error({:04x})", self.0),
+                    _ => write!(f, "Error({:02x})", self.0),
                 }
             }
         }
@@ -73,14 +73,20 @@ impl slog::Value for Error {
 errors! {
     NO_ERROR(0x0) "the connection is being closed abruptly in the absence of any error";
     INTERNAL_ERROR(0x1) "the endpoint encountered an internal error and cannot continue with the connection";
-    SERVER_BUSY(0x2) "the server is currently busy and does not accept any new connections";
+    CONNECTION_REFUSED(0x2) "the server refused to accept a new connection";
     FLOW_CONTROL_ERROR(0x3) "received more data than permitted in advertised data limits";
     STREAM_LIMIT_ERROR(0x4) "received a frame for a stream identifier that exceeded advertised the stream limit for the corresponding stream type";
     STREAM_STATE_ERROR(0x5) "received a frame for a stream that was not in a state that permitted that frame";
-    FINAL_OFFSET_ERROR(0x6) "received a STREAM frame containing data that exceeded the previously established final offset, or a RST_STREAM frame containing a final offset that was lower than the maximum offset of data that was already received, or a RST_STREAM frame containing a different final offset to the one already established";
+    FINAL_SIZE_ERROR(0x6) "received a STREAM frame containing data that exceeded the previously established final size, or a RESET_STREAM frame containing a final size that was lower than the size of data that was already received, or a STREAM or RESET_STREAM frame containing a different final size to the one already established";
     FRAME_ENCODING_ERROR(0x7) "received a frame that was badly formatted";
     TRANSPORT_PARAMETER_ERROR(0x8) "received transport parameters that were badly formatted, included an invalid value, was absent even though it is mandatory, was present though it is forbidden, or is otherwise in error";
-    VERSION_NEGOTIATION_ERROR(0x9) "received transport parameters that contained version negotiation parameters that disagreed with the version negotiation that was performed, constituting a potential version downgrade attack";
+    CONNECTION_ID_LIMIT_ERROR(0x9) "the number of connection IDs provided by the peer exceeds the advertised active_connection_id_limit";
     PROTOCOL_VIOLATION(0xA) "detected an error with protocol compliance that was not covered by more specific error codes";
-    INVALID_MIGRATION(0xC) "received a PATH_RESPONSE frame that did not correspond to any PATH_CHALLENGE frame that it previously sent";
+    INVALID_TOKEN(0xB) "received an invalid Retry Token in a client Initial";
+    APPLICATION_ERROR(0xC) "the application or application protocol caused the connection to be closed during the handshake";
+    CRYPTO_BUFFER_EXCEEDED(0xD) "received more data in CRYPTO frames than can be buffered";
+    KEY_UPDATE_ERROR(0xE) "key update error";
+    AEAD_LIMIT_REACHED(0xF) "the endpoint has reached the confidentiality or integrity limit for the AEAD algorithm";
+    NO_VIABLE_PATH(0x10) "no viable network path exists";
+    VERSION_NEGOTIATION_ERROR(0x11) "version negotiation was inconsistent with the versions the endpoints support";
 }
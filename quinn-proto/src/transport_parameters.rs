@@ -1,4 +1,4 @@
-use std::net::{IpAddr, SocketAddr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 
 use bytes::{Buf, BufMut};
 use err_derive::Error;
@@ -6,7 +6,7 @@ use err_derive::Error;
 use crate::coding::{BufExt, BufMutExt, UnexpectedEnd};
 use crate::endpoint::Config;
 use crate::packet::ConnectionId;
-use crate::{varint, Side, TransportError, MAX_CID_SIZE, MIN_CID_SIZE, RESET_TOKEN_SIZE, VERSION};
+use crate::{varint, Side, TransportError, MAX_CID_SIZE, REM_CID_COUNT, RESET_TOKEN_SIZE};
 
 // Apply a given macro to a list of all the transport parameters having integer types, along with
 // their codes and default values. Using this helps us avoid error-prone duplication of the
@@ -17,8 +17,8 @@ macro_rules! apply_params {
     ($macro:ident) => {
         $macro! {
             // name (id) = default,
-            idle_timeout(0x0001) = 0,
-            max_packet_size(0x0003) = 65527,
+            max_idle_timeout(0x0001) = 0,
+            max_udp_payload_size(0x0003) = 65527,
 
             initial_max_data(0x0004) = 0,
             initial_max_stream_data_bidi_local(0x0005) = 0,
@@ -30,20 +30,36 @@ macro_rules! apply_params {
 
             ack_delay_exponent(0x000a) = 3,
             max_ack_delay(0x000b) = 25,
+            active_connection_id_limit(0x000e) = 2,
         }
     };
 }
 
 macro_rules! make_struct {
     {$($name:ident ($code:expr) = $default:expr,)*} => {
-        #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+        #[derive(Debug, Clone, Eq, PartialEq)]
         pub struct TransportParameters {
             $(pub $name : u64,)*
 
             pub disable_migration: bool,
+            /// Smallest delay the endpoint may be asked to wait before acknowledging (μs)
+            ///
+            /// Present iff the endpoint supports ACK_FREQUENCY frames.
+            pub min_ack_delay: Option<u64>,
+            /// Largest DATAGRAM frame the endpoint is willing to receive
+            ///
+            /// Present iff the endpoint supports DATAGRAM frames.
+            pub max_datagram_frame_size: Option<u64>,
+            /// Source connection ID of the first Initial packet sent by the endpoint
+            pub initial_src_cid: Option<ConnectionId>,
+            /// Versions supported by the endpoint and the one in use, per RFC 9368
+            pub version_information: Option<VersionInformation>,
 
             // Server-only
-            pub original_connection_id: Option<ConnectionId>,
+            /// Destination connection ID of the first Initial packet sent by the client
+            pub original_dst_cid: Option<ConnectionId>,
+            /// Source connection ID of the Retry packet sent by the server, if any
+            pub retry_src_cid: Option<ConnectionId>,
             pub stateless_reset_token: Option<[u8; RESET_TOKEN_SIZE]>,
             pub preferred_address: Option<PreferredAddress>,
         }
@@ -55,8 +71,13 @@ macro_rules! make_struct {
                     $($name: $default,)*
 
                     disable_migration: false,
+                    min_ack_delay: None,
+                    max_datagram_frame_size: None,
+                    initial_src_cid: None,
+                    version_information: None,
 
-                    original_connection_id: None,
+                    original_dst_cid: None,
+                    retry_src_cid: None,
                     stateless_reset_token: None,
                     preferred_address: None,
                 }
@@ -69,6 +90,11 @@ apply_params!(make_struct);
 
 impl TransportParameters {
     pub fn new(config: &Config) -> Self {
+        // Rounded up, since actual delays must not exceed the advertised value
+        let mut max_ack_delay = config.delayed_ack_timeout / 1000;
+        if max_ack_delay * 1000 < config.delayed_ack_timeout {
+            max_ack_delay += 1;
+        }
         TransportParameters {
             initial_max_streams_bidi: config.stream_window_bidi as u64,
             initial_max_streams_uni: config.stream_window_uni as u64,
@@ -76,8 +102,13 @@ impl TransportParameters {
             initial_max_stream_data_bidi_local: config.stream_receive_window,
             initial_max_stream_data_bidi_remote: config.stream_receive_window,
             initial_max_stream_data_uni: config.stream_receive_window,
-            idle_timeout: config.idle_timeout,
-            max_ack_delay: 0, // Unimplemented
+            max_idle_timeout: config.idle_timeout,
+            max_ack_delay,
+            active_connection_id_limit: REM_CID_COUNT,
+            min_ack_delay: Some(config.min_ack_delay),
+            max_datagram_frame_size: config
+                .datagram_receive_buffer_size
+                .map(|_| u64::from(u16::max_value())),
             ..Self::default()
         }
     }
@@ -85,93 +116,116 @@ impl TransportParameters {
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct PreferredAddress {
-    address: SocketAddr,
+    address_v4: Option<SocketAddrV4>,
+    address_v6: Option<SocketAddrV6>,
     connection_id: ConnectionId,
     stateless_reset_token: [u8; RESET_TOKEN_SIZE],
 }
 
 impl PreferredAddress {
-    fn wire_size(&self) -> u16 {
-        let ip_len = match self.address {
-            SocketAddr::V4(_) => 4,
-            SocketAddr::V6(_) => 16,
-        };
-        2 + ip_len + 3 + self.connection_id.len() as u16 + 16
+    fn wire_size(&self) -> u64 {
+        4 + 2 + 16 + 2 + 1 + self.connection_id.len() as u64 + 16
     }
 
     fn write<W: BufMut>(&self, w: &mut W) {
-        match self.address {
-            SocketAddr::V4(ref x) => {
-                w.write::<u8>(4);
-                w.write::<u8>(4);
-                w.put_slice(&x.ip().octets());
-            }
-            SocketAddr::V6(ref x) => {
-                w.write::<u8>(6);
-                w.write::<u8>(16);
-                w.put_slice(&x.ip().octets());
-            }
-        }
-        w.write::<u16>(self.address.port());
+        // An absent address family is encoded as all zeroes
+        let v4 = self
+            .address_v4
+            .unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+        w.put_slice(&v4.ip().octets());
+        w.write::<u16>(v4.port());
+        let v6 = self
+            .address_v6
+            .unwrap_or_else(|| SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0));
+        w.put_slice(&v6.ip().octets());
+        w.write::<u16>(v6.port());
         w.write::<u8>(self.connection_id.len() as u8);
         w.put_slice(&self.connection_id);
         w.put_slice(&self.stateless_reset_token);
     }
 
     fn read<R: Buf>(r: &mut R) -> Result<Self, Error> {
-        if r.remaining() < 2 {
-            return Err(Error::Malformed);
-        }
-        let ip_ver = r.get::<u8>().unwrap();
-        let ip_len = r.get::<u8>().unwrap();
-        if r.remaining() < ip_len as usize {
+        let mut ip_v4 = [0; 4];
+        if r.remaining() < ip_v4.len() {
             return Err(Error::Malformed);
         }
-        let ip = match (ip_ver, ip_len) {
-            (4, 4) => {
-                let mut bytes = [0; 4];
-                r.copy_to_slice(&mut bytes);
-                IpAddr::V4(bytes.into())
-            }
-            (6, 16) => {
-                let mut bytes = [0; 16];
-                r.copy_to_slice(&mut bytes);
-                IpAddr::V6(bytes.into())
-            }
-            _ => {
-                return Err(Error::Malformed);
-            }
-        };
-        if r.remaining() < 3 {
+        r.copy_to_slice(&mut ip_v4);
+        let port_v4 = r.get::<u16>()?;
+        let mut ip_v6 = [0; 16];
+        if r.remaining() < ip_v6.len() {
             return Err(Error::Malformed);
         }
-        let port = r.get::<u16>().unwrap();
-        let cid_len = r.get::<u8>().unwrap();
-        if r.remaining() < cid_len as usize
-            || (cid_len != 0 && (cid_len < MIN_CID_SIZE as u8 || cid_len > MAX_CID_SIZE as u8))
-        {
+        r.copy_to_slice(&mut ip_v6);
+        let port_v6 = r.get::<u16>()?;
+        let cid_len = r.get::<u8>()? as usize;
+        // A preferred address is always accompanied by a new, non-empty connection ID
+        if r.remaining() < cid_len || cid_len == 0 || cid_len > MAX_CID_SIZE {
             return Err(Error::Malformed);
         }
         let mut stage = [0; MAX_CID_SIZE];
-        r.copy_to_slice(&mut stage[0..cid_len as usize]);
-        let cid = ConnectionId::new(&stage[0..cid_len as usize]);
-        if r.remaining() < 16 {
+        r.copy_to_slice(&mut stage[0..cid_len]);
+        let cid = ConnectionId::new(&stage[0..cid_len]);
+        if r.remaining() != RESET_TOKEN_SIZE {
             return Err(Error::Malformed);
         }
         let mut token = [0; RESET_TOKEN_SIZE];
         r.copy_to_slice(&mut token);
+
+        let address_v4 = Ipv4Addr::from(ip_v4);
+        let address_v6 = Ipv6Addr::from(ip_v6);
         Ok(Self {
-            address: SocketAddr::new(ip, port),
+            address_v4: if address_v4.is_unspecified() && port_v4 == 0 {
+                None
+            } else {
+                Some(SocketAddrV4::new(address_v4, port_v4))
+            },
+            address_v6: if address_v6.is_unspecified() && port_v6 == 0 {
+                None
+            } else {
+                Some(SocketAddrV6::new(address_v6, port_v6, 0, 0))
+            },
             connection_id: cid,
             stateless_reset_token: token,
         })
     }
 }
 
+/// Contents of the `version_information` transport parameter
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VersionInformation {
+    /// Version of the packets the parameter was sent in
+    pub chosen: u32,
+    /// Versions the endpoint supports, in order of preference
+    pub available: Vec<u32>,
+}
+
+impl VersionInformation {
+    fn write<W: BufMut>(&self, w: &mut W) {
+        w.write_var(VERSION_INFORMATION_ID);
+        w.write_var(4 * (1 + self.available.len() as u64));
+        w.write(self.chosen);
+        for &version in &self.available {
+            w.write(version);
+        }
+    }
+
+    fn read<R: Buf>(r: &mut R, len: usize) -> Result<Self, Error> {
+        if len < 4 || len % 4 != 0 {
+            return Err(Error::Malformed);
+        }
+        let chosen = r.get::<u32>()?;
+        let available = (1..len / 4)
+            .map(|_| r.get::<u32>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if chosen == 0 || available.contains(&0) {
+            return Err(Error::IllegalValue);
+        }
+        Ok(Self { chosen, available })
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
 pub enum Error {
-    #[error(display = "version negotiation was tampered with")]
-    VersionNegotiation,
     #[error(display = "parameter had illegal value")]
     IllegalValue,
     #[error(display = "parameters were malformed")]
@@ -181,7 +235,6 @@ pub enum Error {
 impl From<Error> for TransportError {
     fn from(e: Error) -> Self {
         match e {
-            Error::VersionNegotiation => TransportError::VERSION_NEGOTIATION_ERROR,
             Error::IllegalValue | Error::Malformed => TransportError::TRANSPORT_PARAMETER_ERROR,
         }
     }
@@ -194,94 +247,64 @@ impl From<UnexpectedEnd> for Error {
 }
 
 impl TransportParameters {
-    pub fn write<W: BufMut>(&self, side: Side, w: &mut W) {
-        if side.is_server() {
-            w.write::<u32>(VERSION); // Negotiated version
-            w.write::<u8>(8); // Bytes of supported versions
-            w.write::<u32>(0x0a1a_2a3a); // Reserved version
-            w.write::<u32>(VERSION); // Real supported version
-        } else {
-            w.write::<u32>(VERSION); // Initially requested version
-        }
-
-        let mut buf = Vec::new();
-
+    pub fn write<W: BufMut>(&self, w: &mut W) {
         macro_rules! write_params {
             {$($name:ident ($code:expr) = $default:expr,)*} => {
                 $(
                     if self.$name != $default {
-                        buf.write::<u16>($code);
-                        buf.write::<u16>(varint::size(self.$name).expect("value too large") as u16);
-                        buf.write_var(self.$name);
+                        write_var_param(w, $code, self.$name);
                     }
                 )*
             }
         }
         apply_params!(write_params);
 
-        if let Some(ref x) = self.original_connection_id {
-            buf.write::<u16>(0x0000);
-            buf.write::<u16>(x.len() as u16);
-            buf.put_slice(x);
+        if let Some(ref x) = self.original_dst_cid {
+            write_cid_param(w, 0x00, x);
         }
 
         if let Some(ref x) = self.stateless_reset_token {
-            buf.write::<u16>(0x0002);
-            buf.write::<u16>(16);
-            buf.put_slice(x);
+            w.write_var(0x02);
+            w.write_var(x.len() as u64);
+            w.put_slice(x);
         }
 
         if self.disable_migration {
-            buf.write::<u16>(0x000c);
-            buf.write::<u16>(0);
+            w.write_var(0x0c);
+            w.write_var(0);
         }
 
         if let Some(ref x) = self.preferred_address {
-            buf.write::<u16>(0x000d);
-            buf.write::<u16>(x.wire_size());
-            x.write(&mut buf);
+            w.write_var(0x0d);
+            w.write_var(x.wire_size());
+            x.write(w);
         }
 
-        w.write::<u16>(buf.len() as u16);
-        w.put_slice(&buf);
-    }
+        if let Some(ref x) = self.initial_src_cid {
+            write_cid_param(w, 0x0f, x);
+        }
 
-    pub fn read<R: Buf>(side: Side, r: &mut R) -> Result<Self, Error> {
-        if side.is_server() {
-            if r.remaining() < 26 {
-                return Err(Error::Malformed);
-            }
-            // We only support one version, so there is no validation to do here.
-            r.get::<u32>().unwrap();
-        } else {
-            if r.remaining() < 31 {
-                return Err(Error::Malformed);
-            }
-            let negotiated = r.get::<u32>().unwrap();
-            if negotiated != VERSION {
-                return Err(Error::VersionNegotiation);
-            }
-            let supported_bytes = r.get::<u8>().unwrap();
-            if supported_bytes < 4 || supported_bytes > 252 || supported_bytes % 4 != 0 {
-                return Err(Error::Malformed);
-            }
-            let mut found = false;
-            for _ in 0..(supported_bytes / 4) {
-                found |= r.get::<u32>().unwrap() == negotiated;
-            }
-            if !found {
-                return Err(Error::VersionNegotiation);
-            }
+        if let Some(ref x) = self.retry_src_cid {
+            write_cid_param(w, 0x10, x);
         }
 
-        // Initialize to protocol-specified defaults
-        let mut params = TransportParameters::default();
+        if let Some(x) = self.min_ack_delay {
+            write_var_param(w, MIN_ACK_DELAY_ID, x);
+        }
 
-        let params_len = r.get::<u16>().unwrap();
-        if params_len as usize != r.remaining() {
-            return Err(Error::Malformed);
+        if let Some(x) = self.max_datagram_frame_size {
+            write_var_param(w, MAX_DATAGRAM_FRAME_SIZE_ID, x);
         }
 
+        if let Some(ref x) = self.version_information {
+            x.write(w);
+        }
+    }
+
+    pub fn read<R: Buf>(side: Side, r: &mut R) -> Result<Self, Error> {
+        // Initialize to protocol-specified defaults
+        let mut params = TransportParameters::default();
+
         // State to check for duplicate transport parameters.
         macro_rules! param_state {
             {$($name:ident ($code:expr) = $default:expr,)*} => {{
@@ -297,59 +320,80 @@ impl TransportParameters {
         let mut got = apply_params!(param_state);
 
         while r.has_remaining() {
-            if r.remaining() < 4 {
-                return Err(Error::Malformed);
-            }
-            let id = r.get::<u16>().unwrap();
-            let len = r.get::<u16>().unwrap();
-            if r.remaining() < len as usize {
+            let id = r.get_var()?;
+            let len = r.get_var()?;
+            if (r.remaining() as u64) < len {
                 return Err(Error::Malformed);
             }
+            let len = len as usize;
 
             match id {
-                0x0000 => {
-                    if len < MIN_CID_SIZE as u16
-                        || len > MAX_CID_SIZE as u16
-                        || params.original_connection_id.is_some()
-                    {
+                0x00 => {
+                    if params.original_dst_cid.is_some() {
                         return Err(Error::Malformed);
                     }
-                    let mut staging = [0; MAX_CID_SIZE];
-                    r.copy_to_slice(&mut staging[0..len as usize]);
-                    params.original_connection_id =
-                        Some(ConnectionId::new(&staging[0..len as usize]));
+                    params.original_dst_cid = Some(read_cid(r, len)?);
                 }
-                0x0002 => {
-                    if len != 16 || params.stateless_reset_token.is_some() {
+                0x02 => {
+                    if len != RESET_TOKEN_SIZE || params.stateless_reset_token.is_some() {
                         return Err(Error::Malformed);
                     }
                     let mut tok = [0; RESET_TOKEN_SIZE];
                     r.copy_to_slice(&mut tok);
                     params.stateless_reset_token = Some(tok);
                 }
-                0x000c => {
+                0x0c => {
                     if len != 0 || params.disable_migration {
                         return Err(Error::Malformed);
                     }
                     params.disable_migration = true;
                 }
-                0x000d => {
+                0x0d => {
                     if params.preferred_address.is_some() {
                         return Err(Error::Malformed);
                     }
-                    params.preferred_address =
-                        Some(PreferredAddress::read(&mut r.take(len as usize))?);
+                    params.preferred_address = Some(PreferredAddress::read(&mut r.take(len))?);
+                }
+                0x0f => {
+                    if params.initial_src_cid.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    params.initial_src_cid = Some(read_cid(r, len)?);
+                }
+                0x10 => {
+                    if params.retry_src_cid.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    params.retry_src_cid = Some(read_cid(r, len)?);
+                }
+                MIN_ACK_DELAY_ID => {
+                    if params.min_ack_delay.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    params.min_ack_delay = Some(read_var_param(r, len)?);
+                }
+                MAX_DATAGRAM_FRAME_SIZE_ID => {
+                    if params.max_datagram_frame_size.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    params.max_datagram_frame_size = Some(read_var_param(r, len)?);
+                }
+                VERSION_INFORMATION_ID => {
+                    if params.version_information.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    params.version_information = Some(VersionInformation::read(r, len)?);
                 }
                 _ => {
                     macro_rules! parse {
                         {$($name:ident ($code:expr) = $default:expr,)*} => {
                             match id {
                                 $($code => {
-                                    params.$name = r.get_var()?;
-                                    if len != varint::size(params.$name).unwrap() as u16 || got.$name { return Err(Error::Malformed); }
+                                    if got.$name { return Err(Error::Malformed); }
+                                    params.$name = read_var_param(r, len)?;
                                     got.$name = true;
                                 })*
-                                _ => r.advance(len as usize),
+                                _ => r.advance(len),
                             }
                         }
                     }
@@ -359,8 +403,17 @@ impl TransportParameters {
         }
 
         if params.ack_delay_exponent > 20
+            || params.max_ack_delay >= 1 << 14
+            || params.max_udp_payload_size < 1200
+            || params.active_connection_id_limit < 2
+            || params
+                .min_ack_delay
+                .map_or(false, |x| x > params.max_ack_delay.saturating_mul(1000))
             || (side.is_server()
-                && (params.stateless_reset_token.is_some() || params.preferred_address.is_some()))
+                && (params.original_dst_cid.is_some()
+                    || params.retry_src_cid.is_some()
+                    || params.stateless_reset_token.is_some()
+                    || params.preferred_address.is_some()))
         {
             return Err(Error::IllegalValue);
         }
@@ -369,6 +422,41 @@ impl TransportParameters {
     }
 }
 
+fn write_var_param<W: BufMut>(w: &mut W, id: u64, value: u64) {
+    w.write_var(id);
+    w.write_var(varint::size(value).expect("value too large") as u64);
+    w.write_var(value);
+}
+
+fn write_cid_param<W: BufMut>(w: &mut W, id: u64, cid: &ConnectionId) {
+    w.write_var(id);
+    w.write_var(cid.len() as u64);
+    w.put_slice(cid);
+}
+
+fn read_var_param<R: Buf>(r: &mut R, len: usize) -> Result<u64, Error> {
+    let x = r.get_var()?;
+    if len != varint::size(x).unwrap() {
+        return Err(Error::Malformed);
+    }
+    Ok(x)
+}
+
+fn read_cid<R: Buf>(r: &mut R, len: usize) -> Result<ConnectionId, Error> {
+    if len > MAX_CID_SIZE {
+        return Err(Error::Malformed);
+    }
+    let mut staging = [0; MAX_CID_SIZE];
+    r.copy_to_slice(&mut staging[0..len]);
+    Ok(ConnectionId::new(&staging[0..len]))
+}
+/// Identifier of the `min_ack_delay` parameter from the ACK frequency extension
+const MIN_ACK_DELAY_ID: u64 = 0xff04_de1a;
+/// Identifier of the `max_datagram_frame_size` parameter from RFC 9221
+const MAX_DATAGRAM_FRAME_SIZE_ID: u64 = 0x0020;
+/// Identifier of the `version_information` parameter from RFC 9368
+const VERSION_INFORMATION_ID: u64 = 0x0011;
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -381,18 +469,76 @@ mod test {
             initial_max_streams_bidi: 16,
             initial_max_streams_uni: 16,
             ack_delay_exponent: 2,
-            max_packet_size: 1200,
+            max_udp_payload_size: 1200,
+            active_connection_id_limit: 8,
+            min_ack_delay: Some(1000),
+            max_datagram_frame_size: Some(65535),
+            initial_src_cid: Some(ConnectionId::new(&[0x12; 8])),
+            original_dst_cid: Some(ConnectionId::new(&[0x34; MAX_CID_SIZE])),
+            retry_src_cid: Some(ConnectionId::new(&[])),
+            version_information: Some(VersionInformation {
+                chosen: 0x6b33_43cf,
+                available: vec![0x6b33_43cf, 0x0000_0001],
+            }),
             preferred_address: Some(PreferredAddress {
-                address: SocketAddr::new(IpAddr::V4([127, 0, 0, 1].into()), 42),
-                connection_id: ConnectionId::new(&[]),
+                address_v4: Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 42)),
+                address_v6: None,
+                connection_id: ConnectionId::new(&[0x56; 4]),
                 stateless_reset_token: [0xab; RESET_TOKEN_SIZE],
             }),
             ..TransportParameters::default()
         };
-        params.write(Side::Server, &mut buf);
+        params.write(&mut buf);
         assert_eq!(
             TransportParameters::read(Side::Client, &mut buf.into_buf()).unwrap(),
             params
         );
     }
+
+    #[test]
+    fn reject_zero_version() {
+        let mut buf = Vec::new();
+        VersionInformation {
+            chosen: 0,
+            available: vec![0x0000_0001],
+        }
+        .write(&mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Client, &mut buf.into_buf()),
+            Err(Error::IllegalValue)
+        );
+    }
+
+    #[test]
+    fn server_only() {
+        let mut buf = Vec::new();
+        let params = TransportParameters {
+            original_dst_cid: Some(ConnectionId::new(&[0x34; 8])),
+            ..TransportParameters::default()
+        };
+        params.write(&mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Server, &mut buf.into_buf()),
+            Err(Error::IllegalValue)
+        );
+    }
+
+    #[test]
+    fn reject_short_payload_limit() {
+        let mut buf = Vec::new();
+        write_var_param(&mut buf, 0x03, 1199);
+        assert_eq!(
+            TransportParameters::read(Side::Client, &mut buf.into_buf()),
+            Err(Error::IllegalValue)
+        );
+    }
+
+    #[test]
+    fn max_ack_delay_rounds_up() {
+        let config = Config {
+            delayed_ack_timeout: 25_500,
+            ..Config::default()
+        };
+        assert_eq!(TransportParameters::new(&config).max_ack_delay, 26);
+    }
 }
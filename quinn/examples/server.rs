@@ -157,6 +157,7 @@ fn handle_connection(root: &PathBuf, log: &Logger, conn: quinn::NewConnection) {
     let quinn::NewConnection {
         incoming,
         connection,
+        ..
     } = conn;
     let log = log.clone();
     info!(log, "got connection";
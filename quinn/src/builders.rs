@@ -12,10 +12,14 @@ use err_derive::Error;
 use fnv::FnvHashMap;
 use futures::stream::futures_unordered::FuturesUnordered;
 use quinn_proto as quinn;
-use rustls::{KeyLogFile, ProtocolVersion, TLSError};
+use rustls::sign;
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, KeyLogFile,
+    ProtocolVersion, ResolvesServerCert, RootCertStore, TLSError,
+};
 use slog::Logger;
 
-use quinn_proto::{Config, ServerConfig};
+use quinn_proto::{Config, ServerConfig, SessionStore};
 
 use crate::tls::{Certificate, CertificateChain, PrivateKey};
 use crate::udp::UdpSocket;
@@ -191,6 +195,53 @@ impl ServerConfigBuilder {
         Ok(self)
     }
 
+    /// Select the certificate chain presented to each client with `resolver`.
+    ///
+    /// Replaces any certificate set by `set_certificate`. An `SniResolver` selects certificates by
+    /// the server name clients request, and can be updated while the endpoint is running.
+    pub fn set_certificate_resolver(&mut self, resolver: Arc<dyn ResolvesServerCert>) -> &mut Self {
+        {
+            let tls_server_config = Arc::get_mut(&mut self.config.tls_config).unwrap();
+            tls_server_config.cert_resolver = resolver;
+        }
+        self
+    }
+
+    /// Authenticate clients with certificates issued by one of `roots`.
+    ///
+    /// If `required`, clients which don't present a valid certificate are refused. Otherwise,
+    /// clients may also connect anonymously, which `Connection::peer_certificates` reveals.
+    pub fn set_client_auth(
+        &mut self,
+        roots: &[Certificate],
+        required: bool,
+    ) -> Result<&mut Self, EndpointError> {
+        let mut store = RootCertStore::empty();
+        for cert in roots {
+            store.add(&cert.inner)?;
+        }
+        let verifier = if required {
+            AllowAnyAuthenticatedClient::new(store)
+        } else {
+            AllowAnyAnonymousOrAuthenticatedClient::new(store)
+        };
+        // The verifier can only be supplied on construction, so carry over everything else
+        let old = &self.config.tls_config;
+        let mut config = rustls::ServerConfig::new(verifier);
+        config.ciphersuites = old.ciphersuites.clone();
+        config.ignore_client_order = old.ignore_client_order;
+        config.mtu = old.mtu;
+        config.session_storage = old.session_storage.clone();
+        config.ticketer = old.ticketer.clone();
+        config.cert_resolver = old.cert_resolver.clone();
+        config.alpn_protocols = old.alpn_protocols.clone();
+        config.versions = old.versions.clone();
+        config.key_log = old.key_log.clone();
+        config.max_early_data_size = old.max_early_data_size;
+        self.config.tls_config = Arc::new(config);
+        Ok(self)
+    }
+
     /// Set the application-layer protocols to accept.
     ///
     /// When set, clients which don't declare support for at least one of the supplied protocols will be rejected.
@@ -268,6 +319,20 @@ impl ClientConfigBuilder {
         self
     }
 
+    /// Set the certificate chain presented to servers which request client authentication.
+    pub fn set_certificate(
+        &mut self,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+    ) -> Result<&mut Self, TLSError> {
+        // Check the key up front, as rustls panics on unsupported keys
+        sign::any_supported_type(&key.inner)
+            .map_err(|()| TLSError::General("unsupported private key".into()))?;
+        self.config
+            .set_single_client_cert(cert_chain.certs, key.inner);
+        Ok(self)
+    }
+
     /// Set application-layer protocols to declare support for.
     pub fn set_protocols(&mut self, protocols: &[&[u8]]) -> &mut Self {
         self.config.alpn_protocols = protocols
@@ -281,6 +346,15 @@ impl ClientConfigBuilder {
         self
     }
 
+    /// Set where TLS sessions are kept for resumption and 0-RTT.
+    ///
+    /// Sessions are kept in memory by default. A persistent store such as `SessionFileStore` allows
+    /// 0-RTT data to be sent on the first connection a new process makes to a server.
+    pub fn set_session_store(&mut self, store: Arc<dyn SessionStore>) -> &mut Self {
+        quinn::set_session_store(&mut self.config, store);
+        self
+    }
+
     /// Begin connecting from `endpoint` to `addr`.
     pub fn build(self) -> ClientConfig {
         ClientConfig {
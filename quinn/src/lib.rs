@@ -82,9 +82,11 @@ use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_timer::Delay;
 
 pub use crate::quinn::{
-    Config, ConnectError, ConnectionError, ConnectionId, ServerConfig, ALPN_QUIC_HTTP,
+    Config, ConnectError, ConnectionError, ConnectionId, EarlyDataPolicy, EarlyDataRequest,
+    SendDatagramError, ServerConfig, SessionFileStore, SessionMemoryCache, SessionStore,
+    TokenMemoryCache, TokenStore, ALPN_QUIC_HTTP,
 };
-pub use crate::tls::{Certificate, CertificateChain, PrivateKey};
+pub use crate::tls::{Certificate, CertificateChain, PrivateKey, SniResolver};
 
 pub use crate::builders::{
     ClientConfig, ClientConfigBuilder, EndpointBuilder, EndpointError, ServerConfigBuilder,
@@ -287,6 +289,12 @@ impl Future for Driver {
                             x.notify();
                         }
                     }
+                    DatagramReceived => {
+                        let pending = endpoint.pending.get_mut(&ch).unwrap();
+                        if let Some(x) = pending.datagram_reader.take() {
+                            x.notify();
+                        }
+                    }
                     StreamReadable { stream } => {
                         let pending = endpoint.pending.get_mut(&ch).unwrap();
                         if let Some(reader) = pending.blocked_readers.remove(&stream) {
@@ -507,8 +515,9 @@ struct Pending {
     connecting: Option<oneshot::Sender<Option<ConnectionError>>>,
     uni_opening: VecDeque<oneshot::Sender<Result<StreamId, ConnectionError>>>,
     bi_opening: VecDeque<oneshot::Sender<Result<StreamId, ConnectionError>>>,
-    cancel_timers: [Option<oneshot::Sender<()>>; 5],
+    cancel_timers: [Option<oneshot::Sender<()>>; 7],
     incoming_streams_reader: Option<Task>,
+    datagram_reader: Option<Task>,
     finishing: FnvHashMap<StreamId, oneshot::Sender<Option<ConnectionError>>>,
     error: Option<ConnectionError>,
     closing: Option<oneshot::Sender<()>>,
@@ -524,8 +533,9 @@ impl Pending {
             connecting,
             uni_opening: VecDeque::new(),
             bi_opening: VecDeque::new(),
-            cancel_timers: [None, None, None, None, None],
+            cancel_timers: [None, None, None, None, None, None, None],
             incoming_streams_reader: None,
+            datagram_reader: None,
             finishing: FnvHashMap::default(),
             error: None,
             closing: None,
@@ -554,6 +564,9 @@ impl Pending {
         if let Some(x) = self.incoming_streams_reader.take() {
             x.notify();
         }
+        if let Some(x) = self.datagram_reader.take() {
+            x.notify();
+        }
         for (_, x) in self.finishing.drain() {
             let _ = x.send(Some(reason.clone()));
         }
@@ -569,6 +582,8 @@ pub struct NewConnection {
     pub connection: Connection,
     /// The stream of QUIC streams initiated by the client.
     pub incoming: IncomingStreams,
+    /// The stream of unreliable datagrams sent by the peer.
+    pub datagrams: Datagrams,
 }
 
 impl NewConnection {
@@ -580,7 +595,8 @@ impl NewConnection {
         });
         NewConnection {
             connection: Connection(conn.clone()),
-            incoming: IncomingStreams(conn),
+            incoming: IncomingStreams(conn.clone()),
+            datagrams: Datagrams(conn),
         }
     }
 }
@@ -591,6 +607,8 @@ pub struct NewClientConnection {
     pub connection: Connection,
     /// The stream of QUIC streams initiated by the client.
     pub incoming: IncomingStreams,
+    /// The stream of unreliable datagrams sent by the peer.
+    pub datagrams: Datagrams,
 }
 
 impl NewClientConnection {
@@ -598,6 +616,7 @@ impl NewClientConnection {
         Self {
             connection: Connection(conn.clone()),
             incoming: IncomingStreams(conn.clone()),
+            datagrams: Datagrams(conn),
         }
     }
 }
@@ -665,7 +684,7 @@ impl Connection {
     /// # Panics
     /// - If called more than once on handles to the same connection
     // FIXME: Infallible
-    pub fn close(&self, error_code: u16, reason: &[u8]) -> impl Future<Item = (), Error = ()> {
+    pub fn close(&self, error_code: u64, reason: &[u8]) -> impl Future<Item = (), Error = ()> {
         let (send, recv) = oneshot::channel();
         {
             let endpoint = &mut *self.0.endpoint.borrow_mut();
@@ -735,6 +754,94 @@ impl Connection {
             .map(|x| x.into())
     }
 
+    /// The certificate chain the peer authenticated with, if any
+    ///
+    /// Clients only present a chain when the server requests client authentication; see
+    /// `ServerConfigBuilder::set_client_auth`. Not available on connections that resumed an earlier
+    /// session, including any that sent 0-RTT data, because the chain isn't retained across
+    /// resumption.
+    ///
+    /// Only available once the handshake completes. Servers yield incoming connections before
+    /// then, so a client's chain may not be available yet when its connection is first yielded.
+    pub fn peer_certificates(&self) -> Option<CertificateChain> {
+        self.0
+            .endpoint
+            .borrow()
+            .inner
+            .connection(self.0.handle)
+            .peer_certificates()
+            .map(|certs| CertificateChain { certs })
+    }
+
+    /// Transmit `data` as an unreliable, unordered application datagram.
+    ///
+    /// Datagrams are dropped, oldest first, should they be submitted faster than they can be
+    /// sent. Fails if the peer doesn't support datagrams, or if `data` is larger than
+    /// `max_datagram_size`.
+    pub fn send_datagram(&self, data: Bytes) -> Result<(), SendDatagramError> {
+        let endpoint = &mut *self.0.endpoint.borrow_mut();
+        endpoint.inner.send_datagram(self.0.handle, data)?;
+        endpoint.notify();
+        Ok(())
+    }
+
+    /// The largest datagram that may currently be sent, or `None` if the peer doesn't accept them.
+    ///
+    /// May change over the lifetime of the connection as the path MTU is discovered.
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        self.0
+            .endpoint
+            .borrow()
+            .inner
+            .connection(self.0.handle)
+            .max_datagram_size()
+    }
+
+    /// Set the number of unidirectional streams the peer may have open concurrently
+    ///
+    /// Streams count as open until accepted. Lowering the limit takes effect only as streams are
+    /// accepted, since permission to open streams can't be revoked.
+    pub fn set_max_concurrent_uni_streams(&self, count: u64) {
+        self.set_max_concurrent_streams(Directionality::Uni, count);
+    }
+
+    /// Set the number of bidirectional streams the peer may have open concurrently
+    ///
+    /// See `set_max_concurrent_uni_streams`.
+    pub fn set_max_concurrent_bi_streams(&self, count: u64) {
+        self.set_max_concurrent_streams(Directionality::Bi, count);
+    }
+
+    fn set_max_concurrent_streams(&self, dir: Directionality, count: u64) {
+        let endpoint = &mut *self.0.endpoint.borrow_mut();
+        endpoint
+            .inner
+            .set_max_concurrent_streams(self.0.handle, dir, count);
+        endpoint.notify();
+    }
+
+    /// Set the number of bytes the peer may send beyond those read, across all streams
+    ///
+    /// Overrides the endpoint's `receive_window` for this connection and disables automatic
+    /// growth beyond it.
+    pub fn set_receive_window(&self, window: u64) {
+        let endpoint = &mut *self.0.endpoint.borrow_mut();
+        endpoint.inner.set_receive_window(self.0.handle, window);
+        endpoint.notify();
+    }
+
+    /// Set the number of bytes the peer may send beyond those read, on each stream
+    ///
+    /// Overrides the endpoint's `stream_receive_window` for this connection and disables automatic
+    /// growth beyond it.
+    pub fn set_stream_receive_window(&self, window: u64) {
+        let endpoint = &mut *self.0.endpoint.borrow_mut();
+        endpoint
+            .inner
+            .set_stream_receive_window(self.0.handle, window);
+        endpoint.notify();
+    }
+
     // Update traffic keys spontaneously for testing purposes.
     #[doc(hidden)]
     pub fn force_key_update(&self) {
@@ -803,6 +910,27 @@ impl FuturesStream for IncomingStreams {
     }
 }
 
+/// A stream of unreliable datagrams sent by a remote peer.
+pub struct Datagrams(Rc<ConnectionInner>);
+
+impl FuturesStream for Datagrams {
+    type Item = Bytes;
+    type Error = ConnectionError;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut endpoint = self.0.endpoint.borrow_mut();
+        if let Some(x) = endpoint.inner.recv_datagram(self.0.handle) {
+            return Ok(Async::Ready(Some(x)));
+        }
+        let pending = endpoint.pending.get_mut(&self.0.handle).unwrap();
+        if let Some(ref x) = pending.error {
+            Err(x.clone())
+        } else {
+            pending.datagram_reader = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
 /// A stream initiated by a remote peer.
 pub enum NewStream {
     /// A unidirectional stream.
@@ -838,6 +966,30 @@ impl BiStream {
             recvd: false,
         }
     }
+
+    /// Set the priority of the stream's outgoing data
+    ///
+    /// Data on streams of higher priority is sent before any data on streams of lower priority.
+    /// Streams of equal priority share bandwidth fairly. Streams start with a priority of 0.
+    pub fn set_priority(&mut self, priority: i32) {
+        let endpoint = &mut *self.conn.endpoint.borrow_mut();
+        endpoint
+            .inner
+            .set_priority(self.conn.handle, self.stream, priority);
+    }
+
+    /// Whether any data read from this stream may have arrived as 0-RTT data
+    ///
+    /// 0-RTT data can be replayed by an attacker, so requests carried by it should not be acted
+    /// upon unless they are idempotent.
+    pub fn is_0rtt(&self) -> bool {
+        self.conn
+            .endpoint
+            .borrow()
+            .inner
+            .connection(self.conn.handle)
+            .received_0rtt(self.stream)
+    }
 }
 
 impl Write for BiStream {
@@ -887,7 +1039,7 @@ impl Write for BiStream {
         }
     }
 
-    fn reset(&mut self, error_code: u16) {
+    fn reset(&mut self, error_code: u64) {
         let endpoint = &mut *self.conn.endpoint.borrow_mut();
         endpoint
             .inner
@@ -945,7 +1097,7 @@ impl Read for BiStream {
         }
     }
 
-    fn stop(&mut self, error_code: u16) {
+    fn stop(&mut self, error_code: u64) {
         let endpoint = &mut *self.conn.endpoint.borrow_mut();
         endpoint
             .inner
@@ -1035,6 +1187,15 @@ impl AsyncRead for BiStream {
 /// A stream that can only be used to send data
 pub struct SendStream(BiStream);
 
+impl SendStream {
+    /// Set the priority of the stream
+    ///
+    /// See `BiStream::set_priority`.
+    pub fn set_priority(&mut self, priority: i32) {
+        self.0.set_priority(priority);
+    }
+}
+
 impl Write for SendStream {
     fn poll_write(&mut self, buf: &[u8]) -> Poll<usize, WriteError> {
         Write::poll_write(&mut self.0, buf)
@@ -1042,7 +1203,7 @@ impl Write for SendStream {
     fn poll_finish(&mut self) -> Poll<(), ConnectionError> {
         self.0.poll_finish()
     }
-    fn reset(&mut self, error_code: u16) {
+    fn reset(&mut self, error_code: u64) {
         self.0.reset(error_code);
     }
 }
@@ -1065,6 +1226,15 @@ impl AsyncWrite for SendStream {
 /// A stream that can only be used to receive data
 pub struct RecvStream(BiStream);
 
+impl RecvStream {
+    /// Whether any data read from this stream may have arrived as 0-RTT data
+    ///
+    /// See `BiStream::is_0rtt`.
+    pub fn is_0rtt(&self) -> bool {
+        self.0.is_0rtt()
+    }
+}
+
 impl Read for RecvStream {
     fn poll_read_unordered(&mut self) -> Poll<(Bytes, u64), ReadError> {
         self.0.poll_read_unordered()
@@ -1072,7 +1242,7 @@ impl Read for RecvStream {
     fn poll_read(&mut self, buf: &mut [u8]) -> Poll<usize, ReadError> {
         Read::poll_read(&mut self.0, buf)
     }
-    fn stop(&mut self, error_code: u16) {
+    fn stop(&mut self, error_code: u64) {
         self.0.stop(error_code)
     }
 }
@@ -1181,7 +1351,8 @@ pub trait Read {
     /// Read a segment of data from any offset in the stream.
     ///
     /// Returns a segment of data and their offset in the stream. Segments may be received in any
-    /// order and may overlap.
+    /// order and may overlap. Segments that have already been received are returned in order of
+    /// offset rather than in the order they arrived.
     ///
     /// Unordered reads have reduced overhead and higher throughput, and should therefore be
     /// preferred when applicable.
@@ -1196,7 +1367,7 @@ pub trait Read {
     /// ignore the request entirely and continue sending until halted by flow control.
     ///
     /// Has no effect if the incoming stream already finished.
-    fn stop(&mut self, error_code: u16);
+    fn stop(&mut self, error_code: u64);
 }
 
 /// Errors that arise from reading from a stream.
@@ -1206,7 +1377,7 @@ pub enum ReadError {
     #[error(display = "stream reset by peer: error {}", error_code)]
     Reset {
         /// The error code supplied by the peer.
-        error_code: u16,
+        error_code: u64,
     },
     /// The data on this stream has been fully delivered and no more will be transmitted.
     #[error(display = "the stream has been completely received")]
@@ -1236,7 +1407,7 @@ pub trait Write {
     /// and previously transmitted data will no longer be retransmitted if lost. If `poll_finish`
     /// was called previously and all data has already been transmitted at least once, the peer
     /// may still receive all written data.
-    fn reset(&mut self, error_code: u16);
+    fn reset(&mut self, error_code: u64);
 }
 
 /// Errors that arise from writing to a stream
@@ -1246,7 +1417,7 @@ pub enum WriteError {
     #[error(display = "sending stopped by peer: error {}", error_code)]
     Stopped {
         /// The error code supplied by the peer.
-        error_code: u16,
+        error_code: u64,
     },
     /// The connection was closed.
     #[error(display = "connection closed: {}", _0)]
@@ -41,6 +41,20 @@ impl super::UdpExt for UdpSocket {
             if rc == -1 {
                 return Err(io::Error::last_os_error());
             }
+            // Set the don't-fragment bit, so that path MTU discovery probes are dropped rather than
+            // fragmented when too large
+            let rc = unsafe {
+                libc::setsockopt(
+                    self.as_raw_fd(),
+                    libc::IPPROTO_IP,
+                    libc::IP_MTU_DISCOVER,
+                    &libc::IP_PMTUDISC_PROBE as *const _ as _,
+                    mem::size_of::<libc::c_int>() as _,
+                )
+            };
+            if rc == -1 {
+                return Err(io::Error::last_os_error());
+            }
         }
         if addr.is_ipv6() {
             let on: libc::c_int = 1;
@@ -56,6 +70,18 @@ impl super::UdpExt for UdpSocket {
             if rc == -1 {
                 return Err(io::Error::last_os_error());
             }
+            let rc = unsafe {
+                libc::setsockopt(
+                    self.as_raw_fd(),
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_MTU_DISCOVER,
+                    &libc::IPV6_PMTUDISC_PROBE as *const _ as _,
+                    mem::size_of::<libc::c_int>() as _,
+                )
+            };
+            if rc == -1 {
+                return Err(io::Error::last_os_error());
+            }
         }
         Ok(())
     }
@@ -1,8 +1,9 @@
 use super::{
     read_to_end, ClientConfigBuilder, Config, Endpoint, EndpointBuilder, NewStream,
-    ServerConfigBuilder,
+    ServerConfigBuilder, SniResolver,
 };
 use futures::{Future, Stream};
+use rustls::ResolvesServerCert;
 use slog::{Drain, Logger, KV};
 use std::{
     fmt, io,
@@ -10,6 +11,7 @@ use std::{
     str,
 };
 use tokio;
+use webpki::DNSNameRef;
 
 #[test]
 fn echo_v6() {
@@ -102,6 +104,134 @@ fn run_echo(client_addr: SocketAddr, server_addr: SocketAddr) {
         .unwrap();
 }
 
+#[test]
+fn client_auth() {
+    let log = logger();
+    let generate = |name: &str| {
+        let cert = rcgen::generate_simple_self_signed(vec![name.into()]);
+        let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+        let cert = crate::Certificate::from_der(&cert.serialize_der()).unwrap();
+        let chain = crate::CertificateChain::from_certs(vec![cert.clone()]);
+        (chain, key, cert)
+    };
+    let (server_chain, server_key, server_cert) = generate("localhost");
+    let (client_chain, client_key, client_cert) = generate("client.example");
+
+    let mut server_config = ServerConfigBuilder::default();
+    server_config
+        .set_certificate(server_chain, server_key)
+        .unwrap()
+        .set_client_auth(&[client_cert.clone()], true)
+        .unwrap();
+    let mut server = EndpointBuilder::default();
+    server.logger(log.clone());
+    server.listen(server_config.build());
+    let server_sock = UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    let server_addr = server_sock.local_addr().unwrap();
+    let (_, server_driver, server_incoming) = server.from_socket(server_sock).unwrap();
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config
+        .add_certificate_authority(server_cert.clone())
+        .unwrap()
+        .set_certificate(client_chain, client_key)
+        .unwrap();
+    let mut client = Endpoint::new();
+    client.logger(log.clone());
+    client.default_client_config(client_config.build());
+    let (client, client_driver, _) = client
+        .bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0))
+        .unwrap();
+
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    runtime.spawn(server_driver.map_err(|e| panic!("server driver failed: {}", e)));
+    runtime.spawn(client_driver.map_err(|e| panic!("client driver failed: {}", e)));
+    // Servers yield connections before the client has authenticated, so wait for a stream
+    let accept = server_incoming
+        .into_future()
+        .map_err(|_| unreachable!())
+        .and_then(|(conn, _)| {
+            let conn = conn.unwrap();
+            let connection = conn.connection;
+            conn.incoming
+                .into_future()
+                .map(move |_| connection)
+                .map_err(|_| panic!("accepting stream failed"))
+        });
+    let connect = client
+        .connect(&server_addr, "localhost")
+        .unwrap()
+        .map_err(|e| panic!("connection failed: {}", e))
+        .and_then(|conn| {
+            let connection = conn.connection;
+            connection
+                .open_uni()
+                .map_err(|e| panic!("open: {}", e))
+                .and_then(|stream| {
+                    tokio::io::write_all(stream, b"hello".to_vec())
+                        .map_err(|e| panic!("write: {}", e))
+                })
+                .map(move |_| connection)
+        });
+    let (server_conn, client_conn) = runtime.block_on(accept.join(connect)).unwrap();
+
+    let der = |chain: Option<crate::CertificateChain>| {
+        chain
+            .expect("no peer certificates")
+            .certs
+            .into_iter()
+            .map(|x| x.0)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        der(server_conn.peer_certificates()),
+        vec![client_cert.inner.0]
+    );
+    assert_eq!(
+        der(client_conn.peer_certificates()),
+        vec![server_cert.inner.0]
+    );
+}
+
+#[test]
+fn sni_resolver() {
+    let resolver = SniResolver::new();
+    let generate = |name: &str| {
+        let cert = rcgen::generate_simple_self_signed(vec![name.into()]);
+        let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+        let der = cert.serialize_der();
+        let cert = crate::Certificate::from_der(&der).unwrap();
+        (crate::CertificateChain::from_certs(vec![cert]), key, der)
+    };
+    let resolve = |name: Option<&str>| {
+        let name = name.map(|x| DNSNameRef::try_from_ascii_str(x).unwrap());
+        resolver.resolve(name, &[]).map(|x| x.cert[0].0.clone())
+    };
+
+    let (a_chain, a_key, a) = generate("a.example");
+    let (b_chain, b_key, b) = generate("b.example");
+    let (fallback_chain, fallback_key, fallback) = generate("fallback.example");
+    assert!(resolver
+        .add("b.example", a_chain.clone(), a_key.clone())
+        .is_err());
+    resolver.add("a.example", a_chain, a_key).unwrap();
+    resolver.add("b.example", b_chain, b_key).unwrap();
+    assert_eq!(resolve(Some("a.example")), Some(a));
+    assert_eq!(resolve(Some("B.Example")), Some(b.clone()));
+    assert_eq!(resolve(Some("c.example")), None);
+    assert_eq!(resolve(None), None);
+
+    resolver.set_default(fallback_chain, fallback_key).unwrap();
+    assert_eq!(resolve(Some("c.example")), Some(fallback.clone()));
+    assert_eq!(resolve(None), Some(fallback.clone()));
+    assert!(resolver.remove("a.example"));
+    assert!(!resolver.remove("a.example"));
+    assert_eq!(resolve(Some("a.example")), Some(fallback));
+    assert_eq!(resolve(Some("b.example")), Some(b));
+    resolver.clear_default();
+    assert_eq!(resolve(Some("a.example")), None);
+}
+
 fn echo(stream: NewStream) -> Box<dyn Future<Item = (), Error = ()>> {
     match stream {
         NewStream::Bi(stream) => Box::new(
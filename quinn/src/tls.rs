@@ -1,8 +1,12 @@
 //! TLS-related helpers
 
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::{Arc, RwLock};
 
-use rustls::{self, internal::pemfile};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{self, internal::pemfile, ResolvesServerCert, SignatureScheme, TLSError};
+use webpki::DNSNameRef;
 
 /// A single TLS certificate
 #[derive(Debug, Clone)]
@@ -91,6 +95,113 @@ impl PrivateKey {
     }
 }
 
+/// Selects the certificate chain a server presents according to the name a client requests
+///
+/// Clients which request an unknown name, or none at all, are presented the default certificate if
+/// one is set, and fail the handshake otherwise. Certificates may be added and removed while an
+/// endpoint is running, affecting subsequent handshakes.
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # let (chain, key): (quinn::CertificateChain, quinn::PrivateKey) = unimplemented!();
+/// let resolver = Arc::new(quinn::SniResolver::new());
+/// resolver.add("example.com", chain, key).expect("certificate not valid for name");
+/// let mut server_config = quinn::ServerConfigBuilder::default();
+/// server_config.set_certificate_resolver(resolver.clone());
+/// ```
+#[derive(Default)]
+pub struct SniResolver {
+    by_name: RwLock<HashMap<String, CertifiedKey>>,
+    default: RwLock<Option<CertifiedKey>>,
+}
+
+impl SniResolver {
+    /// Construct a resolver with no certificates
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Present `cert_chain` to clients requesting `name`, replacing any previous chain for it
+    ///
+    /// Fails if `name` is not a valid DNS name, the certificate isn't valid for it, or the key is
+    /// unsupported.
+    pub fn add(
+        &self,
+        name: &str,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+    ) -> Result<(), TLSError> {
+        let dns_name = DNSNameRef::try_from_ascii_str(name)
+            .map_err(|_| TLSError::General("invalid DNS name".into()))?;
+        let certified = certified_key(cert_chain, key)?;
+        certified.cross_check_end_entity_cert(Some(dns_name))?;
+        self.by_name
+            .write()
+            .unwrap()
+            .insert(name.to_ascii_lowercase(), certified);
+        Ok(())
+    }
+
+    /// Stop presenting a dedicated certificate to clients requesting `name`
+    ///
+    /// Returns whether a certificate was registered for `name`.
+    pub fn remove(&self, name: &str) -> bool {
+        self.by_name
+            .write()
+            .unwrap()
+            .remove(&name.to_ascii_lowercase())
+            .is_some()
+    }
+
+    /// Present `cert_chain` to clients requesting a name with no dedicated certificate
+    pub fn set_default(
+        &self,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+    ) -> Result<(), TLSError> {
+        let certified = certified_key(cert_chain, key)?;
+        certified.cross_check_end_entity_cert(None)?;
+        *self.default.write().unwrap() = Some(certified);
+        Ok(())
+    }
+
+    /// Reject clients requesting a name with no dedicated certificate
+    pub fn clear_default(&self) {
+        *self.default.write().unwrap() = None;
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(
+        &self,
+        server_name: Option<DNSNameRef<'_>>,
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        if let Some(name) = server_name {
+            let name: &str = name.into();
+            if let Some(x) = self.by_name.read().unwrap().get(&name.to_ascii_lowercase()) {
+                return Some(x.clone());
+            }
+        }
+        self.default.read().unwrap().clone()
+    }
+}
+
+impl fmt::Debug for SniResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SniResolver")
+            .field("names", &self.by_name.read().unwrap().keys())
+            .field("default", &self.default.read().unwrap().is_some())
+            .finish()
+    }
+}
+
+fn certified_key(cert_chain: CertificateChain, key: PrivateKey) -> Result<CertifiedKey, TLSError> {
+    let key = sign::any_supported_type(&key.inner)
+        .map_err(|()| TLSError::General("unsupported private key".into()))?;
+    Ok(CertifiedKey::new(cert_chain.certs, Arc::new(key)))
+}
+
 /// Errors encountered while parsing a TLS certificate or private key
 #[derive(Debug, Clone)]
 pub struct ParseError(&'static str);